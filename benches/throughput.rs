@@ -0,0 +1,278 @@
+//! Benchmarks of the full send and receive path over an in-memory transport,
+//! and of the frames, headers and masking it is made of.
+//!
+//! Run with `cargo bench --features bench`, this works on stable.
+#[macro_use]
+extern crate criterion;
+extern crate uuid;
+extern crate websocket;
+
+use std::fmt;
+use std::io::{self, Cursor, Write};
+use criterion::{black_box, Bencher, BenchmarkId, Criterion, Throughput};
+use uuid::Uuid;
+use websocket::{Message, OwnedMessage};
+use websocket::dataframe::{DataFrame, Opcode};
+use websocket::header::{Header, HeaderFormat, Origin, WebSocketAccept, WebSocketExtensions,
+                        WebSocketKey, WebSocketProtocol, WebSocketVersion};
+use websocket::receiver::{PacketState, Receiver};
+use websocket::sender::Sender;
+use websocket::ws::Receiver as ReceiverTrait;
+use websocket::ws::Sender as SenderTrait;
+use websocket::ws::dataframe::DataFrame as DataFrameable;
+use websocket::ws::util::header::{self as dfh, DataFrameFlags, DataFrameHeader, ReaderState};
+use websocket::ws::util::mask;
+
+const SIZES: [usize; 3] = [16, 1024, 1024 * 1024];
+
+fn round_trip(b: &mut Bencher, size: usize, masked: bool) {
+	let message = Message::binary(vec![0x2a; size]);
+	let mut sender = Sender::new(masked);
+	let mut receiver = Receiver::new(masked, Uuid::new_v4());
+	let mut wire = Vec::with_capacity(size + 14);
+
+	b.iter(|| {
+		wire.clear();
+		sender.send_message(&mut wire, &message).unwrap();
+		let mut input = &wire[..];
+		receiver.recv_message(&mut input).unwrap()
+	});
+}
+
+fn send_text(b: &mut Bencher, size: usize, direct: bool) {
+	let text: String = ::std::iter::repeat('a').take(size).collect();
+	let mut sender = Sender::new(true);
+	let mut wire = Vec::with_capacity(size + 14);
+
+	b.iter(|| {
+		wire.clear();
+		if direct {
+			sender.send_text(&mut wire, &text).unwrap();
+		} else {
+			sender.send_message(&mut wire, &Message::text(text.clone())).unwrap();
+		}
+	});
+}
+
+fn send_receive(c: &mut Criterion) {
+	let mut group = c.benchmark_group("send_receive");
+	for &size in SIZES.iter() {
+		group.throughput(Throughput::Bytes(size as u64));
+		group.bench_with_input(BenchmarkId::new("unmasked", size),
+		                       &size,
+		                       |b, &size| round_trip(b, size, false));
+		group.bench_with_input(BenchmarkId::new("masked", size),
+		                       &size,
+		                       |b, &size| round_trip(b, size, true));
+	}
+	group.finish();
+}
+
+fn text(c: &mut Criterion) {
+	let mut group = c.benchmark_group("text");
+	for &size in SIZES.iter() {
+		group.throughput(Throughput::Bytes(size as u64));
+		group.bench_with_input(BenchmarkId::new("send_text", size),
+		                       &size,
+		                       |b, &size| send_text(b, size, true));
+		group.bench_with_input(BenchmarkId::new("send_message", size),
+		                       &size,
+		                       |b, &size| send_text(b, size, false));
+	}
+	group.finish();
+}
+
+fn batching(c: &mut Criterion) {
+	let messages: Vec<_> = (0..100).map(|i| OwnedMessage::Text(format!("message {}", i))).collect();
+	let mut group = c.benchmark_group("send_100_messages");
+	group.bench_function("individually", |b| {
+		let mut sender = Sender::new(true);
+		b.iter(|| {
+			       let mut writer = io::BufWriter::with_capacity(0, io::sink());
+			       for message in &messages {
+				       sender.send_message(&mut writer, message).unwrap();
+			       }
+			      });
+	});
+	group.bench_function("batched", |b| {
+		let mut sender = Sender::new(true);
+		b.iter(|| {
+			       let mut writer = io::BufWriter::with_capacity(0, io::sink());
+			       sender.send_all(&mut writer, messages.iter().cloned()).unwrap();
+			      });
+	});
+	group.finish();
+}
+
+fn frames(c: &mut Criterion) {
+	let text = DataFrame::new(true, Opcode::Text, b"The quick brown fox jumps over the lazy dog".to_vec());
+	let mut wire = Vec::new();
+	text.write_to(&mut wire, false).unwrap();
+
+	let mut group = c.benchmark_group("frames");
+	group.bench_function("read_dataframe", |b| {
+		b.iter(|| {
+			       DataFrame::read_dataframe(&mut &wire[..],
+			                                 false,
+			                                 Uuid::nil(),
+			                                 &mut PacketState::default(),
+			                                 &mut ReaderState::new())
+			           .unwrap()
+			      });
+	});
+	group.bench_function("write_dataframe", |b| {
+		let mut writer = Vec::with_capacity(45);
+		b.iter(|| {
+			       writer.clear();
+			       text.write_to(&mut writer, false).unwrap();
+			      });
+	});
+
+	// one write of the header and payload together against buffering them first
+	let binary = DataFrame::new(true, Opcode::Binary, vec![0; 4096]);
+	group.bench_function("write_vectored", |b| {
+		let mut output = Vec::with_capacity(5000);
+		b.iter(|| {
+			       output.clear();
+			       binary.write_to(&mut output, false).unwrap();
+			       black_box(&output);
+			      });
+	});
+	group.bench_function("write_buffered", |b| {
+		let mut output = Vec::with_capacity(5000);
+		b.iter(|| {
+			       output.clear();
+			       let mut buffered = Vec::with_capacity(binary.frame_size(false));
+			       let header = DataFrameHeader {
+			           flags: DataFrameFlags::FIN,
+			           opcode: binary.opcode(),
+			           mask: None,
+			           len: binary.size() as u64,
+			       };
+			       dfh::write_header(&mut buffered, header).unwrap();
+			       binary.write_payload(&mut buffered).unwrap();
+			       output.write_all(&buffered).unwrap();
+			       black_box(&output);
+			      });
+	});
+	group.finish();
+}
+
+fn read_chunked(b: &mut Bencher, chunk_size: usize) {
+	let mut input = Vec::new();
+	DataFrame::new(true, Opcode::Binary, vec![0; 10 * 1024 * 1024])
+		.write_to(&mut input, false)
+		.unwrap();
+
+	let mut receiver = Receiver::new(false, Uuid::new_v4());
+	receiver.set_read_chunk_size(chunk_size);
+	b.iter(|| {
+		       let uuid = receiver.uuid();
+		       receiver.recv_dataframe(&mut Cursor::new(&input[..]), uuid).unwrap()
+		      });
+}
+
+fn read_chunks(c: &mut Criterion) {
+	let mut group = c.benchmark_group("read_10mib_frame");
+	group.throughput(Throughput::Bytes(10 * 1024 * 1024));
+	for &chunk_size in [1024, 8 * 1024, 64 * 1024].iter() {
+		group.bench_with_input(BenchmarkId::new("chunks", chunk_size),
+		                       &chunk_size,
+		                       |b, &chunk_size| read_chunked(b, chunk_size));
+	}
+	group.finish();
+}
+
+fn frame_headers(c: &mut Criterion) {
+	let small_text = DataFrameHeader {
+		flags: DataFrameFlags::FIN,
+		opcode: 1,
+		mask: None,
+		len: 20,
+	};
+	let masked = DataFrameHeader {
+		flags: DataFrameFlags::RSV1,
+		opcode: 2,
+		mask: Some([2, 4, 8, 16]),
+		len: 512,
+	};
+	let wire = [0x42u8, 0xFE, 0x02, 0x00, 0x02, 0x04, 0x08, 0x10];
+
+	let mut group = c.benchmark_group("frame_headers");
+	group.bench_function("write_header_small_text", |b| {
+		let mut writer = Vec::with_capacity(2);
+		b.iter(|| {
+			       writer.clear();
+			       dfh::write_header(&mut writer, small_text).unwrap();
+			      });
+	});
+	group.bench_function("write_small_text_header", |b| {
+		let mut writer = Vec::with_capacity(2);
+		b.iter(|| {
+			       writer.clear();
+			       dfh::write_small_text_header(&mut writer, 20).unwrap();
+			      });
+	});
+	group.bench_function("read_header", |b| {
+		b.iter(|| dfh::read_header(&mut &wire[..], &mut ReaderState::new()).unwrap());
+	});
+	group.bench_function("write_header", |b| {
+		let mut writer = Vec::with_capacity(8);
+		b.iter(|| {
+			       writer.clear();
+			       dfh::write_header(&mut writer, masked).unwrap();
+			      });
+	});
+	group.finish();
+}
+
+fn masking(c: &mut Criterion) {
+	let buffer = b"The quick brown fox jumps over the lazy dog";
+	let key = mask::gen_mask();
+	c.bench_function("mask_data", |b| b.iter(|| mask::mask_data(key, buffer)));
+	c.bench_function("gen_mask", |b| b.iter(mask::gen_mask));
+}
+
+// formats a header as its value
+struct Formatted<'a, H: 'a>(&'a H);
+
+impl<'a, H: HeaderFormat> fmt::Display for Formatted<'a, H> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		self.0.fmt_header(f)
+	}
+}
+
+// parses `value` as the header `H` and formats it again
+fn handshake_header<H>(c: &mut Criterion, name: &str, value: &[u8])
+	where H: Header + HeaderFormat
+{
+	let raw = vec![value.to_vec()];
+	let parsed: H = Header::parse_header(&raw[..]).unwrap();
+	c.bench_function(&format!("{}_parse", name),
+	                 |b| b.iter(|| -> H { Header::parse_header(&raw[..]).unwrap() }));
+	c.bench_function(&format!("{}_format", name), |b| b.iter(|| Formatted(&parsed).to_string()));
+}
+
+fn handshake_headers(c: &mut Criterion) {
+	c.bench_function("header_key_new", |b| b.iter(WebSocketKey::new));
+	let key = WebSocketKey::new();
+	c.bench_function("header_accept_new", |b| b.iter(|| WebSocketAccept::new(&key)));
+
+	handshake_header::<WebSocketKey>(c, "header_key", b"QUFBQUFBQUFBQUFBQUFBQQ==");
+	handshake_header::<WebSocketAccept>(c, "header_accept", b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+	handshake_header::<WebSocketVersion>(c, "header_version", b"13");
+	handshake_header::<Origin>(c, "header_origin", b"foobar");
+	handshake_header::<WebSocketProtocol>(c, "header_protocol", b"foo, bar");
+	handshake_header::<WebSocketExtensions>(c, "header_extensions", b"foo, bar; baz; qux=quux");
+}
+
+criterion_group!(benches,
+                 send_receive,
+                 text,
+                 batching,
+                 frames,
+                 read_chunks,
+                 frame_headers,
+                 masking,
+                 handshake_headers);
+criterion_main!(benches);
@@ -1,140 +1,156 @@
+//! Runs the Autobahn test suite against this crate's client.
+//!
+//! Needs a running fuzzing server, e.g. `wstest -m fuzzingserver -s
+//! autobahn/fuzzingserver.json`, see `scripts/autobahn-client.sh`. The server
+//! is expected at `ws://127.0.0.1:9001`, set `AUTOBAHN_SERVER` to use another
+//! one and `AUTOBAHN_AGENT` to report under another name. Built with the
+//! `deflate` feature the client also offers permessage-deflate.
 extern crate websocket;
 
+use std::env;
+use std::process;
+
 use websocket::ClientBuilder;
 use websocket::OwnedMessage;
 use websocket::Message;
+use websocket::result::WebSocketError;
+use websocket::sync::{Client, StrictMode};
+use websocket::stream::sync::TcpStream;
 
 fn main() {
-	let addr = "ws://127.0.0.1:9001".to_string();
-	let agent = "rust-websocket";
+	let addr = env::var("AUTOBAHN_SERVER").unwrap_or_else(|_| "ws://127.0.0.1:9001".to_string());
+	let agent = env::var("AUTOBAHN_AGENT").unwrap_or_else(|_| "rust-websocket".to_string());
 
 	println!("Using fuzzingserver {}", addr);
 	println!("Using agent {}", agent);
 
-	println!("Running test suite...");
-
-	let mut current_case_id = 1;
-	let case_count = get_case_count(addr.clone());
-
-	while current_case_id <= case_count {
-		let case_id = current_case_id;
-		current_case_id += 1;
-		let url = addr.clone() + "/runCase?case=" + &case_id.to_string()[..] + "&agent=" + agent;
+	let case_count = match get_case_count(addr.clone()) {
+		Some(count) => count,
+		None => {
+			println!("Could not reach the fuzzingserver, is it running?");
+			process::exit(2);
+		}
+	};
 
-		let client = ClientBuilder::new(&url)
-			.unwrap()
-			.connect_insecure()
-			.unwrap();
+	println!("Running test suite...");
 
-		let (mut receiver, mut sender) = client.split().unwrap();
+	let mut failed = Vec::new();
+	for case_id in 1..case_count + 1 {
+		let url = addr.clone() + "/runCase?case=" + &case_id.to_string()[..] + "&agent=" + &agent;
 
 		println!("Executing test case: {}/{}", case_id, case_count);
+		run_case(&url);
 
-		for message in receiver.incoming_messages() {
-			let message = match message {
-				Ok(message) => message,
-				Err(e) => {
-					println!("Error: {:?}", e);
-					let _ = sender.send_message(&Message::close());
-					break;
-				}
-			};
-
-			match message {
-				OwnedMessage::Text(txt) => {
-					sender.send_message(&OwnedMessage::Text(txt)).unwrap();
-				}
-				OwnedMessage::Binary(bin) => {
-					sender.send_message(&OwnedMessage::Binary(bin)).unwrap();
-				}
-				OwnedMessage::Close(_) => {
-					let _ = sender.send_message(&OwnedMessage::Close(None));
-					break;
-				}
-				OwnedMessage::Ping(data) => {
-					sender.send_message(&OwnedMessage::Pong(data)).unwrap();
-				}
-				_ => (),
-			}
+		let status = get_case_status(addr.clone(), case_id, &agent);
+		match status.as_ref().map(|s| &s[..]) {
+			Some("OK") | Some("NON-STRICT") | Some("INFORMATIONAL") | Some("UNIMPLEMENTED") => (),
+			_ => failed.push((case_id, status)),
 		}
 	}
 
-	update_reports(addr.clone(), agent);
+	update_reports(addr.clone(), &agent);
+
+	println!("{} of {} cases passed", case_count - failed.len(), case_count);
+	for &(case_id, ref status) in &failed {
+		println!("Case {}: {}", case_id, status.as_ref().map_or("no status", |s| &s[..]));
+	}
+	if !failed.is_empty() {
+		process::exit(1);
+	}
 }
 
-fn get_case_count(addr: String) -> usize {
-	let url = addr + "/getCaseCount";
+#[cfg(feature = "deflate")]
+fn connect(url: &str) -> Option<Client<TcpStream>> {
+	use websocket::deflate::PerMessageDeflate;
+	use websocket::ws::extension::Extension;
 
-	let client = match ClientBuilder::new(&url).unwrap().connect_insecure() {
-		Ok(c) => c,
+	let offer = PerMessageDeflate::client().offer();
+	let mut client = match ClientBuilder::new(url).unwrap().add_extension(offer).connect_insecure() {
+		Ok(client) => client,
 		Err(e) => {
-			println!("{:?}", e);
-			return 0;
+			println!("Error: {:?}", e);
+			return None;
 		}
 	};
+	if let Err(e) = client.register_extension(Box::new(PerMessageDeflate::client())) {
+		println!("Error: {:?}", e);
+		return None;
+	}
+	Some(client)
+}
 
-	let (mut receiver, mut sender) = client.split().unwrap();
+#[cfg(not(feature = "deflate"))]
+fn connect(url: &str) -> Option<Client<TcpStream>> {
+	match ClientBuilder::new(url).unwrap().connect_insecure() {
+		Ok(client) => Some(client),
+		Err(e) => {
+			println!("Error: {:?}", e);
+			None
+		}
+	}
+}
 
-	let mut count = 0;
+// echoes every message back, strict mode fails the connection with the close
+// code the suite expects for invalid frames, text or close codes
+fn run_case(url: &str) {
+	let mut client = match connect(url) {
+		Some(client) => client,
+		None => return,
+	};
+	client.set_strict_mode(Some(StrictMode::new()));
+	client.set_auto_close(true);
 
-	for message in receiver.incoming_messages() {
-		let message = match message {
+	loop {
+		let message = match client.recv_message() {
 			Ok(message) => message,
+			Err(WebSocketError::ConnectionClosed) => return,
 			Err(e) => {
 				println!("Error: {:?}", e);
-				let _ = sender.send_message(&Message::close_because(1002, "".to_string()));
-				break;
+				return;
 			}
 		};
-		match message {
-			OwnedMessage::Text(txt) => {
-				count = txt.parse().unwrap();
-				println!("Will run {} cases...", count);
-			}
-			OwnedMessage::Close(_) => {
-				let _ = sender.send_message(&Message::close());
-				break;
-			}
-			OwnedMessage::Ping(data) => {
-				sender.send_message(&OwnedMessage::Pong(data)).unwrap();
-			}
-			_ => (),
+
+		let echoed = match message {
+			OwnedMessage::Text(txt) => client.send_text(&txt),
+			OwnedMessage::Binary(bin) => client.send_message(&OwnedMessage::Binary(bin)),
+			OwnedMessage::Ping(data) => client.send_message(&OwnedMessage::Pong(data)),
+			OwnedMessage::Close(_) => return,
+			OwnedMessage::Pong(_) => Ok(()),
+		};
+		if let Err(e) = echoed {
+			println!("Error: {:?}", e);
+			return;
 		}
 	}
-
-	count
 }
 
-fn update_reports(addr: String, agent: &str) {
-	let url = addr + "/updateReports?agent=" + agent;
-
-	let client = match ClientBuilder::new(&url).unwrap().connect_insecure() {
+// reads the text messages of a control url of the fuzzingserver until it closes
+fn read_texts(url: &str) -> Option<Vec<String>> {
+	let client = match ClientBuilder::new(url).unwrap().connect_insecure() {
 		Ok(c) => c,
 		Err(e) => {
 			println!("{:?}", e);
-			return;
+			return None;
 		}
 	};
 
 	let (mut receiver, mut sender) = client.split().unwrap();
 
-	println!("Updating reports...");
-
+	let mut texts = Vec::new();
 	for message in receiver.incoming_messages() {
 		let message = match message {
 			Ok(message) => message,
 			Err(e) => {
 				println!("Error: {:?}", e);
-				let _ = sender.send_message(&Message::close());
-				return;
+				let _ = sender.send_message(&Message::close_because(1002, "".to_string()));
+				break;
 			}
 		};
 		match message {
+			OwnedMessage::Text(txt) => texts.push(txt),
 			OwnedMessage::Close(_) => {
 				let _ = sender.send_message(&Message::close());
-				println!("Reports updated.");
-				println!("Test suite finished!");
-				return;
+				break;
 			}
 			OwnedMessage::Ping(data) => {
 				sender.send_message(&OwnedMessage::Pong(data)).unwrap();
@@ -142,4 +158,34 @@ fn update_reports(addr: String, agent: &str) {
 			_ => (),
 		}
 	}
+
+	Some(texts)
+}
+
+fn get_case_count(addr: String) -> Option<usize> {
+	let url = addr + "/getCaseCount";
+	let count = read_texts(&url)?.first()?.parse().ok()?;
+	println!("Will run {} cases...", count);
+	Some(count)
+}
+
+// the status is sent as `{"behavior": "OK"}`
+fn get_case_status(addr: String, case_id: usize, agent: &str) -> Option<String> {
+	let url = addr + "/getCaseStatus?case=" + &case_id.to_string()[..] + "&agent=" + agent;
+	let texts = read_texts(&url)?;
+	let status = texts.first()?;
+	let start = status.find("\"behavior\"")? + "\"behavior\"".len();
+	let value = status[start..].trim_start_matches(|c| c == ':' || c == ' ' || c == '"');
+	let end = value.find('"')?;
+	Some(value[..end].to_string())
+}
+
+fn update_reports(addr: String, agent: &str) {
+	let url = addr + "/updateReports?agent=" + agent;
+
+	println!("Updating reports...");
+	if read_texts(&url).is_some() {
+		println!("Reports updated.");
+		println!("Test suite finished!");
+	}
 }
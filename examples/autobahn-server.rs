@@ -1,7 +1,7 @@
 extern crate websocket;
 
 use std::thread;
-use websocket::{Message, OwnedMessage};
+use websocket::{Message, OwnedMessage, WebSocketError};
 use websocket::sync::Server;
 
 
@@ -20,7 +20,11 @@ fn main() {
 					Ok(message) => message,
 					Err(e) => {
 						println!("{:?}", e);
-						let _ = sender.send_message(&Message::close());
+						let close = match e {
+							WebSocketError::ProtocolError(_) => Message::close_because(1002, ""),
+							_ => Message::close(),
+						};
+						let _ = sender.send_message(&close);
 						return;
 					}
 				};
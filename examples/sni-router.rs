@@ -0,0 +1,74 @@
+//! A secure server routing connections by the host name clients sent with
+//! SNI. Needs the `sync-ssl` and `tls-sni` features and an `identity.pfx`
+//! with a certificate for the tenants' host names.
+#[cfg(all(feature = "sync-ssl", feature = "tls-sni"))]
+extern crate websocket;
+#[cfg(all(feature = "sync-ssl", feature = "tls-sni"))]
+extern crate native_tls;
+
+#[cfg(all(feature = "sync-ssl", feature = "tls-sni"))]
+mod router {
+	use std::fs::File;
+	use std::io::Read;
+	use std::thread;
+	use native_tls::{Pkcs12, TlsAcceptor};
+	use websocket::OwnedMessage;
+	use websocket::sync::Server;
+
+	fn tenant(hostname: Option<&str>) -> Option<&'static str> {
+		match hostname {
+			Some("chat.example.com") => Some("chat"),
+			Some("feeds.example.com") => Some("feeds"),
+			_ => None,
+		}
+	}
+
+	pub fn main() {
+		let mut pkcs12 = vec![];
+		File::open("identity.pfx").unwrap().read_to_end(&mut pkcs12).unwrap();
+		let pkcs12 = Pkcs12::from_der(&pkcs12, "hacktheplanet").unwrap();
+		let acceptor = TlsAcceptor::builder(pkcs12).unwrap().build().unwrap();
+		let server = Server::bind_secure("127.0.0.1:2794", acceptor).unwrap();
+
+		for request in server.filter_map(Result::ok) {
+			thread::spawn(move || {
+				let mut client = request.accept().unwrap();
+				let hostname = client.sni_hostname();
+				let tenant = match tenant(hostname.as_ref().map(|h| &h[..])) {
+					Some(tenant) => tenant,
+					None => {
+						println!("No tenant for {:?}", hostname);
+						let _ = client.send_message(&OwnedMessage::Close(None));
+						return;
+					}
+				};
+				println!("Connection for {}", tenant);
+
+				loop {
+					match client.recv_message() {
+						Ok(OwnedMessage::Text(text)) => {
+							let reply = format!("{}: {}", tenant, text);
+							client.send_message(&OwnedMessage::Text(reply)).unwrap();
+						}
+						Ok(OwnedMessage::Ping(data)) => client.send_message(&OwnedMessage::Pong(data)).unwrap(),
+						Ok(OwnedMessage::Close(_)) | Err(_) => {
+							let _ = client.send_message(&OwnedMessage::Close(None));
+							return;
+						}
+						Ok(_) => (),
+					}
+				}
+			});
+		}
+	}
+}
+
+#[cfg(all(feature = "sync-ssl", feature = "tls-sni"))]
+fn main() {
+	router::main();
+}
+
+#[cfg(not(all(feature = "sync-ssl", feature = "tls-sni")))]
+fn main() {
+	println!("Build this example with the sync-ssl and tls-sni features");
+}
@@ -3,15 +3,15 @@
 use std::borrow::Cow;
 pub use url::{Url, ParseError};
 use header::extensions::Extension;
-use header::{WebSocketKey, WebSocketVersion, WebSocketProtocol, WebSocketExtensions, Origin};
-use hyper::header::{Headers, Header, HeaderFormat};
+use header::{WebSocketKey, WebSocketVersion, WebSocketProtocol, WebSocketExtensions, Origin,
+             HandshakeHook, HandshakeDirection};
+use hyper::header::{Headers, Header, HeaderFormat, UserAgent};
 use hyper::version::HttpVersion;
 
 #[cfg(any(feature="sync", feature="async"))]
 mod common_imports {
 	pub use std::net::TcpStream;
 	pub use std::net::ToSocketAddrs;
-	pub use url::Position;
 	pub use hyper::http::h1::Incoming;
 	pub use hyper::http::RawStatus;
 	pub use hyper::status::StatusCode;
@@ -19,7 +19,8 @@ mod common_imports {
 	pub use hyper::method::Method;
 	pub use hyper::uri::RequestUri;
 	pub use hyper::http::h1::parse_response;
-	pub use hyper::header::{Host, Connection, ConnectionOption, Upgrade, Protocol, ProtocolName};
+	pub use hyper::header::{Host, Connection, ConnectionOption, Upgrade, Protocol, ProtocolName,
+	                        Location};
 	pub use unicase::UniCase;
 	pub use header::WebSocketAccept;
 	pub use result::{WSUrlErrorKind, WebSocketResult, WebSocketError};
@@ -55,6 +56,9 @@ mod async_imports {
 #[cfg(feature="async")]
 use self::async_imports::*;
 
+/// The User-Agent header sent by clients that don't set one.
+pub const DEFAULT_USER_AGENT: &str = concat!("rust-websocket/", env!("CARGO_PKG_VERSION"));
+
 /// Build clients with a builder-style API
 /// This makes it easy to create and configure a websocket
 /// connection:
@@ -107,6 +111,8 @@ pub struct ClientBuilder<'u> {
 	headers: Headers,
 	version_set: bool,
 	key_set: bool,
+	user_agent_set: bool,
+	handshake_hook: Option<HandshakeHook>,
 }
 
 impl<'u> ClientBuilder<'u> {
@@ -132,11 +138,14 @@ impl<'u> ClientBuilder<'u> {
 	/// Create a client builder from a URL string, this will
 	/// attempt to parse the URL immediately and return a `ParseError`
 	/// if the URL is invalid. URLs must be of the form:
-	/// `[ws or wss]://[domain]:[port]/[path]`
+	/// `[ws or wss]://[domain]:[port]/[path]?[query]`
 	/// The path of a URL is optional if no port is given then port
 	/// 80 will be used in the case of `ws://` and port `443` will be
 	/// used in the case of `wss://`.
 	///
+	/// Other schemes and URLs with a fragment are rejected with a
+	/// `WebSocketUrlError` when connecting.
+	///
 	/// ```rust
 	/// # use websocket::ClientBuilder;
 	/// let builder = ClientBuilder::new("wss://mycluster.club");
@@ -152,7 +161,9 @@ impl<'u> ClientBuilder<'u> {
 			version: HttpVersion::Http11,
 			version_set: false,
 			key_set: false,
+			user_agent_set: false,
 			headers: Headers::new(),
+			handshake_hook: None,
 		}
 	}
 
@@ -315,6 +326,30 @@ impl<'u> ClientBuilder<'u> {
 		self
 	}
 
+	/// Sets the User-Agent header of the handshake, instead of the default
+	/// `rust-websocket/<version>`.
+	///
+	/// ```rust
+	/// # use websocket::ClientBuilder;
+	/// # use websocket::header::UserAgent;
+	/// let builder = ClientBuilder::new("ws://moz.illest").unwrap()
+	///     .user_agent("my-bot/1.0".to_string());
+	///
+	/// assert_eq!(builder.get_header::<UserAgent>().unwrap().0, "my-bot/1.0");
+	/// ```
+	pub fn user_agent(mut self, user_agent: String) -> Self {
+		self.headers.set(UserAgent(user_agent));
+		self.user_agent_set = true;
+		self
+	}
+
+	/// Remove the User-Agent header, the handshake is then sent without one.
+	pub fn clear_user_agent(mut self) -> Self {
+		self.headers.remove::<UserAgent>();
+		self.user_agent_set = true;
+		self
+	}
+
 	/// Sets the Origin header of the handshake.
 	/// Normally in browsers this is used to protect against
 	/// unauthorized cross-origin use of a WebSocket server, but it is rarely
@@ -350,6 +385,23 @@ impl<'u> ClientBuilder<'u> {
 		self
 	}
 
+	/// Calls `hook` with the headers of the handshake request right before it
+	/// is sent, and with the headers of the response as soon as it is parsed,
+	/// before it is checked. This makes it possible to see exactly what a
+	/// server that rejects the handshake was sent.
+	///
+	/// ```rust
+	/// # use websocket::ClientBuilder;
+	/// let builder = ClientBuilder::new("ws://moz.illest").unwrap()
+	///     .on_handshake(|direction, headers| println!("{:?}:\n{}", direction, headers));
+	/// ```
+	pub fn on_handshake<F>(mut self, hook: F) -> Self
+		where F: Fn(HandshakeDirection, &Headers) + Send + Sync + 'static
+	{
+		self.handshake_hook = Some(HandshakeHook::new(hook));
+		self
+	}
+
 	/// Remove a type of header from the handshake, this is to be used
 	/// with the catch all `custom_headers`.
 	pub fn clear_header<H>(mut self) -> Self
@@ -474,12 +526,14 @@ impl<'u> ClientBuilder<'u> {
 	{
 		// send request
 		let resource = self.build_request();
+		self.notify(HandshakeDirection::Outgoing, &self.headers);
 		write!(stream, "GET {} {}\r\n", resource, self.version)?;
 		write!(stream, "{}\r\n", self.headers)?;
 
 		// wait for a response
 		let mut reader = BufReader::new(stream);
 		let response = parse_response(&mut reader)?;
+		self.notify(HandshakeDirection::Incoming, &response.headers);
 
 		// validate
 		self.validate(&response)?;
@@ -549,6 +603,8 @@ impl<'u> ClientBuilder<'u> {
 			headers: self.headers,
 			version_set: self.version_set,
 			key_set: self.key_set,
+			user_agent_set: self.user_agent_set,
+			handshake_hook: self.handshake_hook,
 		};
 
 		// check if we should connect over ssl or not
@@ -643,6 +699,8 @@ impl<'u> ClientBuilder<'u> {
 			headers: self.headers,
 			version_set: self.version_set,
 			key_set: self.key_set,
+			user_agent_set: self.user_agent_set,
+			handshake_hook: self.handshake_hook,
 		};
 
 		// put it all together
@@ -702,6 +760,8 @@ impl<'u> ClientBuilder<'u> {
 			headers: self.headers,
 			version_set: self.version_set,
 			key_set: self.key_set,
+			user_agent_set: self.user_agent_set,
+			handshake_hook: self.handshake_hook,
 		};
 
 		let future =
@@ -762,8 +822,11 @@ impl<'u> ClientBuilder<'u> {
 			headers: self.headers,
 			version_set: self.version_set,
 			key_set: self.key_set,
+			user_agent_set: self.user_agent_set,
+			handshake_hook: self.handshake_hook,
 		};
 		let resource = builder.build_request();
+		builder.notify(HandshakeDirection::Outgoing, &builder.headers);
 		let framed = stream.framed(::codec::http::HttpClientCodec);
 		let request = Incoming {
 			version: builder.version,
@@ -783,7 +846,10 @@ impl<'u> ClientBuilder<'u> {
               message
                   .ok_or(WebSocketError::ProtocolError(
                       "Connection closed before handshake could complete."))
-                  .and_then(|message| builder.validate(&message).map(|()| (message, stream)))
+                  .and_then(|message| {
+                      builder.notify(HandshakeDirection::Incoming, &message.headers);
+                      builder.validate(&message).map(|()| (message, stream))
+                  })
           })
 
           // output the final client and metadata
@@ -819,6 +885,13 @@ impl<'u> ClientBuilder<'u> {
 		Ok(async::TcpStream::connect(&address, handle))
 	}
 
+	#[cfg(any(feature="sync", feature="async"))]
+	fn notify(&self, direction: HandshakeDirection, headers: &Headers) {
+		if let Some(ref hook) = self.handshake_hook {
+			hook.call(direction, headers);
+		}
+	}
+
 	#[cfg(any(feature="sync", feature="async"))]
 	fn build_request(&mut self) -> String {
 		// enter host if available (unix sockets don't have hosts)
@@ -851,8 +924,20 @@ impl<'u> ClientBuilder<'u> {
 			self.headers.set(WebSocketKey::new());
 		}
 
-		// send request
-		let resource = self.url[Position::BeforePath..Position::AfterQuery].to_owned();
+		// a User-Agent given with `custom_headers` is kept as well
+		if !self.user_agent_set && !self.headers.has::<UserAgent>() {
+			self.headers.set(UserAgent(DEFAULT_USER_AGENT.to_string()));
+		}
+
+		// the request target is never empty, `ws://host` asks for `/`
+		let mut resource = self.url.path().to_owned();
+		if resource.is_empty() {
+			resource.push('/');
+		}
+		if let Some(query) = self.url.query() {
+			resource.push('?');
+			resource.push_str(query);
+		}
 		resource
 	}
 
@@ -861,7 +946,12 @@ impl<'u> ClientBuilder<'u> {
 		let status = StatusCode::from_u16(response.subject.0);
 
 		if status != StatusCode::SwitchingProtocols {
-			return Err(WebSocketError::ResponseError("Status code must be Switching Protocols"));
+			let location = if status.is_redirection() {
+				response.headers.get::<Location>().map(|l| l.0.clone())
+			} else {
+				None
+			};
+			return Err(WebSocketError::UnexpectedStatus(response.subject.0, location));
 		}
 
 		let key =
@@ -869,8 +959,9 @@ impl<'u> ClientBuilder<'u> {
 			    .get::<WebSocketKey>()
 			    .ok_or(WebSocketError::RequestError("Request Sec-WebSocket-Key was invalid"))?;
 
+		// a wrong accept means the server didn't process our handshake, never skip this
 		if response.headers.get() != Some(&(WebSocketAccept::new(key))) {
-			return Err(WebSocketError::ResponseError("Sec-WebSocket-Accept is invalid"));
+			return Err(WebSocketError::ProtocolError("Invalid Sec-WebSocket-Accept"));
 		}
 
 		if response.headers.get() !=
@@ -895,6 +986,14 @@ impl<'u> ClientBuilder<'u> {
 
 	#[cfg(any(feature="sync", feature="async"))]
 	fn extract_host_port(&self, secure: Option<bool>) -> WebSocketResult<(&str, u16)> {
+		match self.url.scheme() {
+			"ws" | "wss" => (),
+			_ => return Err(WebSocketError::WebSocketUrlError(WSUrlErrorKind::InvalidScheme)),
+		}
+		if self.url.fragment().is_some() {
+			return Err(WebSocketError::WebSocketUrlError(WSUrlErrorKind::CannotSetFragment));
+		}
+
 		let port = match (self.url.port(), secure) {
 			(Some(port), _) => port,
 			(None, None) if self.url.scheme() == "wss" => 443,
@@ -967,4 +1066,181 @@ mod tests {
 		assert!(protos.contains(&"electric".to_string()));
 		assert!(!protos.contains(&"rust-websocket".to_string()));
 	}
+
+	#[test]
+	fn build_request_uses_host_path_and_query() {
+		use super::*;
+		let mut builder = ClientBuilder::new("wss://example.org:8443/chat/room?user=1").unwrap();
+		assert_eq!(builder.build_request(), "/chat/room?user=1");
+		assert_eq!(builder.extract_host_port(None).unwrap(), ("example.org", 8443));
+		let host = builder.headers.get::<Host>().unwrap();
+		assert_eq!((&host.hostname as &str, host.port), ("example.org", Some(8443)));
+
+		let mut builder = ClientBuilder::new("ws://example.org").unwrap();
+		assert_eq!(builder.build_request(), "/");
+		assert_eq!(builder.extract_host_port(None).unwrap(), ("example.org", 80));
+		let builder = ClientBuilder::new("wss://example.org").unwrap();
+		assert_eq!(builder.extract_host_port(None).unwrap(), ("example.org", 443));
+	}
+
+	#[test]
+	#[cfg(feature="sync")]
+	fn request_line_has_a_target() {
+		use super::*;
+		use std::io::Cursor;
+		use stream::ReadWritePair;
+
+		fn request_line(url: &str) -> String {
+			let mut headers = Headers::new();
+			headers.set(WebSocketAccept::new(&WebSocketKey([1; 16])));
+			let response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+			                        Upgrade: websocket\r\n\
+			                        Connection: Upgrade\r\n\
+			                        {}\r\n",
+			                       headers);
+			let stream = ReadWritePair(Cursor::new(response.into_bytes()), Cursor::new(Vec::new()));
+			let client = ClientBuilder::new(url)
+				.unwrap()
+				.key([1; 16])
+				.connect_on(stream)
+				.unwrap();
+			let written = String::from_utf8((client.into_stream().0).1.into_inner()).unwrap();
+			written.lines().next().unwrap().to_string()
+		}
+
+		assert_eq!(request_line("ws://example.org"), "GET / HTTP/1.1");
+		assert_eq!(request_line("ws://example.org/"), "GET / HTTP/1.1");
+		assert_eq!(request_line("ws://example.org/path?x=1"), "GET /path?x=1 HTTP/1.1");
+		assert_eq!(request_line("ws://example.org?x=1"), "GET /?x=1 HTTP/1.1");
+	}
+
+	#[test]
+	fn invalid_urls_are_rejected() {
+		use super::*;
+		match ClientBuilder::new("http://example.org").unwrap().extract_host_port(None) {
+			Err(WebSocketError::WebSocketUrlError(WSUrlErrorKind::InvalidScheme)) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+		match ClientBuilder::new("ws://example.org/#frag").unwrap().extract_host_port(None) {
+			Err(WebSocketError::WebSocketUrlError(WSUrlErrorKind::CannotSetFragment)) => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+	}
+
+	#[test]
+	#[cfg(feature="sync")]
+	fn unexpected_status_is_reported() {
+		use super::*;
+		use std::io::Cursor;
+		use stream::ReadWritePair;
+
+		let response = b"HTTP/1.1 301 Moved Permanently\r\n\
+		                 Location: wss://example.org/moved\r\n\
+		                 \r\n";
+		let stream = ReadWritePair(Cursor::new(&response[..]), Cursor::new(Vec::new()));
+		match ClientBuilder::new("ws://example.org").unwrap().connect_on(stream) {
+			Err(WebSocketError::UnexpectedStatus(301, Some(ref location))) => {
+				assert_eq!(location, "wss://example.org/moved")
+			}
+			Err(e) => panic!("unexpected error {:?}", e),
+			Ok(_) => panic!("connection should fail"),
+		}
+
+		let response = b"HTTP/1.1 502 Bad Gateway\r\n\r\n";
+		let stream = ReadWritePair(Cursor::new(&response[..]), Cursor::new(Vec::new()));
+		match ClientBuilder::new("ws://example.org").unwrap().connect_on(stream) {
+			Err(WebSocketError::UnexpectedStatus(502, None)) => (),
+			Err(e) => panic!("unexpected error {:?}", e),
+			Ok(_) => panic!("connection should fail"),
+		}
+	}
+
+	#[test]
+	#[cfg(feature="sync")]
+	fn accept_must_match_key() {
+		use super::*;
+		use std::io::Cursor;
+		use stream::ReadWritePair;
+
+		fn connect(accept: WebSocketAccept) -> WebSocketResult<()> {
+			let mut headers = Headers::new();
+			headers.set(accept);
+			let response = format!("HTTP/1.1 101 Switching Protocols\r\n\
+			                        Upgrade: websocket\r\n\
+			                        Connection: Upgrade\r\n\
+			                        {}\r\n",
+			                       headers);
+			let stream = ReadWritePair(Cursor::new(response.into_bytes()), Cursor::new(Vec::new()));
+			ClientBuilder::new("ws://example.org")
+				.unwrap()
+				.key([1; 16])
+				.connect_on(stream)
+				.map(|_| ())
+		}
+
+		connect(WebSocketAccept::new(&WebSocketKey([1; 16]))).unwrap();
+
+		match connect(WebSocketAccept::new(&WebSocketKey([2; 16]))) {
+			Err(WebSocketError::ProtocolError("Invalid Sec-WebSocket-Accept")) => (),
+			other => panic!("expected an invalid accept error, got {:?}", other.err()),
+		}
+	}
+
+	#[test]
+	#[cfg(feature="sync")]
+	fn handshake_hook_sees_both_directions() {
+		use super::*;
+		use std::io::Cursor;
+		use std::sync::{Arc, Mutex};
+		use stream::ReadWritePair;
+
+		let response = b"HTTP/1.1 400 Bad Request\r\nX-Reason: no\r\n\r\n".to_vec();
+		let stream = ReadWritePair(Cursor::new(response), Cursor::new(Vec::new()));
+
+		let seen = Arc::new(Mutex::new(Vec::new()));
+		let log = seen.clone();
+		let result = ClientBuilder::new("ws://example.org")
+			.unwrap()
+			.on_handshake(move |direction, headers| {
+				              log.lock().unwrap().push((direction, headers.to_string()));
+				             })
+			.connect_on(stream);
+		assert!(result.is_err());
+
+		// the response is seen even though it fails validation
+		let seen = seen.lock().unwrap();
+		assert_eq!(seen.len(), 2);
+		assert_eq!(seen[0].0, HandshakeDirection::Outgoing);
+		assert!(seen[0].1.contains("Sec-WebSocket-Key"));
+		assert_eq!(seen[1], (HandshakeDirection::Incoming, "X-Reason: no\r\n".to_string()));
+	}
+
+	#[test]
+	#[cfg(feature="sync")]
+	fn user_agent_keeps_the_upgrade_intact() {
+		use super::*;
+		let mut builder = ClientBuilder::new("ws://example.org").unwrap();
+		builder.build_request();
+		assert_eq!(builder.headers.get::<UserAgent>().unwrap().0, DEFAULT_USER_AGENT);
+		assert!(DEFAULT_USER_AGENT.starts_with("rust-websocket/"));
+
+		// headers that make up the upgrade can't be overridden
+		let mut headers = Headers::new();
+		headers.set_raw("Upgrade", vec![b"h2c".to_vec()]);
+		headers.set_raw("Connection", vec![b"close".to_vec()]);
+		let mut builder = ClientBuilder::new("ws://example.org")
+			.unwrap()
+			.custom_headers(&headers)
+			.user_agent("my-bot/1.0".to_string());
+		builder.build_request();
+		let request = builder.headers.to_string();
+		assert!(request.contains("User-Agent: my-bot/1.0\r\n"), "{}", request);
+		assert!(request.contains("Upgrade: websocket\r\n"), "{}", request);
+		assert!(request.contains("Connection: Upgrade\r\n"), "{}", request);
+		assert_eq!(builder.version, HttpVersion::Http11);
+
+		let mut builder = ClientBuilder::new("ws://example.org").unwrap().clear_user_agent();
+		builder.build_request();
+		assert!(!builder.headers.has::<UserAgent>());
+	}
 }
@@ -21,3 +21,6 @@ pub mod async;
 
 #[cfg(feature="sync")]
 pub mod sync;
+
+#[cfg(feature="sync")]
+pub mod reconnect;
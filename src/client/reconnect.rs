@@ -0,0 +1,422 @@
+//! A client that reconnects by itself when its connection is lost.
+use std::cmp;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use rand;
+
+use ws;
+use client::builder::ClientBuilder;
+use client::sync::Client;
+use framing::FrameCodec;
+use message::OwnedMessage;
+use result::{WebSocketResult, WebSocketError};
+use stream::sync::Stream;
+use ws::util::header::TRUNCATED_FRAME;
+
+/// A change in the connection of a `ReconnectingClient`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+	/// A connection was established and the handshake completed.
+	Connected,
+	/// The connection was lost, a new one will be made.
+	Disconnected,
+	/// Connecting failed, the given attempt will be made after waiting `delay`.
+	Reconnecting {
+		/// The number of the next attempt, starting at 1 for the first retry.
+		attempt: u32,
+		/// How long is waited before the attempt.
+		delay: Duration,
+	},
+	/// Connecting failed more often than allowed, the last error is returned.
+	GaveUp,
+}
+
+// makes a connection with the builder of the client
+type Connector<S> = Box<FnMut(&mut ClientBuilder<'static>) -> WebSocketResult<Client<S>> + Send>;
+
+// called with every new connection
+type ConnectHook<S> = Box<FnMut(&mut Client<S>) -> WebSocketResult<()> + Send>;
+
+/// A client that transparently dials again when its connection is lost.
+///
+/// Every connection is made with the same `ClientBuilder`, so protocols and
+/// custom headers are sent again with each handshake. Failed attempts are
+/// retried with an exponential backoff, randomized by some jitter so that
+/// many clients don't reconnect in lockstep, up to an optional number of
+/// retries.
+///
+/// Settings made on the first connection, like the maximum message size, and
+/// its extensions are carried over to the following ones, see
+/// `Client::set_frame_codec`. A read or write error, or the end of the
+/// stream, counts as losing the connection. A message that fails to send is sent again once on the new
+/// connection, so it might be delivered twice. Other errors, like protocol
+/// violations, are returned as usual.
+///
+/// ```rust,no_run
+/// # use websocket::ClientBuilder;
+/// # use websocket::Message;
+/// use websocket::sync::client::ReconnectingClient;
+///
+/// let builder = ClientBuilder::new("ws://127.0.0.1:1234").unwrap();
+/// let mut client = ReconnectingClient::new(builder)
+///     .max_retries(10)
+///     .on_connect(|client| client.send_message(&Message::text("subscribe")))
+///     .on_state_change(|state| println!("{:?}", state));
+///
+/// loop {
+///     let message = client.recv_message().unwrap();
+///     println!("{:?}", message);
+/// }
+/// ```
+pub struct ReconnectingClient<S>
+	where S: Stream
+{
+	builder: ClientBuilder<'static>,
+	connector: Connector<S>,
+	client: Option<Client<S>>,
+	// the framing of the lost connection, for the next one
+	codec: Option<FrameCodec>,
+	on_connect: Option<ConnectHook<S>>,
+	on_state_change: Option<Box<FnMut(ConnectionState) + Send>>,
+	initial_delay: Duration,
+	max_delay: Duration,
+	jitter: f64,
+	max_retries: Option<u32>,
+	reconnect_on_close: bool,
+}
+
+impl ReconnectingClient<TcpStream> {
+	/// Creates a client making plain TCP connections with `builder`.
+	///
+	/// Nothing is connected until the client is first used, or `connect`
+	/// is called.
+	pub fn new(builder: ClientBuilder<'static>) -> Self {
+		ReconnectingClient::with_connector(builder, |builder| builder.connect_insecure())
+	}
+}
+
+impl<S> ReconnectingClient<S>
+    where S: Stream
+{
+	/// Creates a client using `connector` to make each connection, e.g. to
+	/// connect over TLS or another kind of stream.
+	pub fn with_connector<F>(builder: ClientBuilder<'static>, connector: F) -> Self
+		where F: FnMut(&mut ClientBuilder<'static>) -> WebSocketResult<Client<S>> + Send + 'static
+	{
+		ReconnectingClient {
+			builder,
+			connector: Box::new(connector),
+			client: None,
+			codec: None,
+			on_connect: None,
+			on_state_change: None,
+			initial_delay: Duration::from_millis(100),
+			max_delay: Duration::from_secs(30),
+			jitter: 0.5,
+			max_retries: None,
+			reconnect_on_close: false,
+		}
+	}
+
+	/// Sets the delay before the first retry, and the most it can grow to.
+	/// Each retry waits twice as long as the previous one.
+	/// Defaults to 100 milliseconds and 30 seconds.
+	pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+		self.initial_delay = initial;
+		self.max_delay = max;
+		self
+	}
+
+	/// Sets the fraction of each delay that is randomized, between 0 (the
+	/// delays are exact) and 1 (anything from no delay to the full delay).
+	/// Defaults to 0.5.
+	pub fn jitter(mut self, jitter: f64) -> Self {
+		self.jitter = jitter.clamp(0.0, 1.0);
+		self
+	}
+
+	/// Gives up after `retries` failed attempts in a row, allowing unlimited
+	/// retries otherwise (the default).
+	pub fn max_retries(mut self, retries: u32) -> Self {
+		self.max_retries = Some(retries);
+		self
+	}
+
+	/// Whether a close message from the server also causes a reconnect,
+	/// instead of being returned by `recv_message`. Defaults to false.
+	pub fn reconnect_on_close(mut self, reconnect: bool) -> Self {
+		self.reconnect_on_close = reconnect;
+		self
+	}
+
+	/// Calls `hook` with every new connection before it is used, e.g. to
+	/// authenticate or resubscribe. An error counts as a failed attempt.
+	pub fn on_connect<F>(mut self, hook: F) -> Self
+		where F: FnMut(&mut Client<S>) -> WebSocketResult<()> + Send + 'static
+	{
+		self.on_connect = Some(Box::new(hook));
+		self
+	}
+
+	/// Calls `hook` whenever the connection changes, see `ConnectionState`.
+	pub fn on_state_change<F>(mut self, hook: F) -> Self
+		where F: FnMut(ConnectionState) + Send + 'static
+	{
+		self.on_state_change = Some(Box::new(hook));
+		self
+	}
+
+	/// Connects now if there is no connection yet.
+	pub fn connect(&mut self) -> WebSocketResult<()> {
+		self.client()?;
+		Ok(())
+	}
+
+	/// The current connection, if there is one.
+	pub fn get_ref(&self) -> Option<&Client<S>> {
+		self.client.as_ref()
+	}
+
+	/// Sends a message, reconnecting first if needed.
+	pub fn send_message<M>(&mut self, message: &M) -> WebSocketResult<()>
+		where M: ws::Message
+	{
+		let result = self.client()?.send_message(message);
+		match result {
+			Err(ref e) if is_disconnect(e) => (),
+			result => return result,
+		}
+		self.disconnected();
+		self.client()?.send_message(message)
+	}
+
+	/// Reads the next message, reconnecting as often as needed.
+	pub fn recv_message(&mut self) -> WebSocketResult<OwnedMessage> {
+		loop {
+			match self.client()?.recv_message() {
+				Ok(OwnedMessage::Close(data)) => {
+					if !self.reconnect_on_close {
+						return Ok(OwnedMessage::Close(data));
+					}
+					// best effort, the connection is dropped either way
+					let _ = self.client()?.send_message(&OwnedMessage::Close(data));
+				}
+				Err(ref e) if is_disconnect(e) => (),
+				result => return result,
+			}
+			self.disconnected();
+		}
+	}
+
+	fn client(&mut self) -> WebSocketResult<&mut Client<S>> {
+		if self.client.is_none() {
+			let client = self.dial()?;
+			self.client = Some(client);
+		}
+		Ok(self.client.as_mut().unwrap())
+	}
+
+	fn dial(&mut self) -> WebSocketResult<Client<S>> {
+		let mut attempt = 0;
+		loop {
+			let mut result = (self.connector)(&mut self.builder);
+			if let (&mut Ok(ref mut client), Some(codec)) = (&mut result, self.codec.take()) {
+				if let Err(e) = client.set_frame_codec(codec) {
+					result = Err(e);
+				}
+			}
+			if let (&mut Ok(ref mut client), Some(ref mut hook)) = (&mut result, self.on_connect.as_mut()) {
+				if let Err(e) = hook(client) {
+					result = Err(e);
+				}
+			}
+
+			let error = match result {
+				Ok(client) => {
+					self.notify(ConnectionState::Connected);
+					return Ok(client);
+				}
+				Err(e) => e,
+			};
+
+			if self.max_retries.is_some_and(|max| attempt >= max) {
+				self.notify(ConnectionState::GaveUp);
+				return Err(error);
+			}
+			attempt += 1;
+			let delay = self.delay(attempt);
+			self.notify(ConnectionState::Reconnecting {
+			                attempt,
+			                delay,
+			            });
+			thread::sleep(delay);
+		}
+	}
+
+	fn delay(&self, attempt: u32) -> Duration {
+		let exponent = cmp::min(attempt - 1, 31);
+		let delay = self.initial_delay
+		                .checked_mul(1 << exponent)
+		                .map_or(self.max_delay, |delay| cmp::min(delay, self.max_delay));
+		let nanos = delay.as_secs() as f64 * 1e9 + delay.subsec_nanos() as f64;
+		let nanos = nanos * (1.0 - self.jitter * rand::random::<f64>());
+		Duration::new((nanos / 1e9) as u64, (nanos % 1e9) as u32)
+	}
+
+	fn disconnected(&mut self) {
+		self.codec = self.client.take().map(Client::into_frame_codec);
+		self.notify(ConnectionState::Disconnected);
+	}
+
+	fn notify(&mut self, state: ConnectionState) {
+		if let Some(ref mut hook) = self.on_state_change {
+			hook(state);
+		}
+	}
+}
+
+fn is_disconnect(error: &WebSocketError) -> bool {
+	match *error {
+		WebSocketError::IoError(_) |
+		WebSocketError::NoDataAvailable |
+		WebSocketError::ConnectionClosed => true,
+		WebSocketError::ProtocolError(reason) => reason == TRUNCATED_FRAME,
+		_ => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::{self, Cursor};
+	use std::sync::{Arc, Mutex};
+	use hyper::buffer::BufReader;
+	use hyper::header::Headers;
+	use stream::ReadWritePair;
+	use ws::sender::Sender;
+	use sender;
+
+	type Pipe = ReadWritePair<Cursor<Vec<u8>>, Vec<u8>>;
+
+	// a connection on which the server sends `messages` and then hangs up
+	fn connection(messages: &[OwnedMessage]) -> Client<Pipe> {
+		let mut input = Vec::new();
+		for message in messages {
+			sender::Sender::new(false).send_message(&mut input, message).unwrap();
+		}
+		let stream = ReadWritePair(Cursor::new(input), Vec::new());
+		Client::unchecked(BufReader::new(stream), Headers::new(), true, false)
+	}
+
+	fn refused() -> WebSocketError {
+		io::Error::new(io::ErrorKind::ConnectionRefused, "refused").into()
+	}
+
+	fn client<F>(connector: F) -> (ReconnectingClient<Pipe>, Arc<Mutex<Vec<ConnectionState>>>)
+		where F: FnMut(&mut ClientBuilder<'static>) -> WebSocketResult<Client<Pipe>> + Send + 'static
+	{
+		let states = Arc::new(Mutex::new(Vec::new()));
+		let log = states.clone();
+		let client = ReconnectingClient::with_connector(ClientBuilder::new("ws://example.org").unwrap(),
+		                                                connector)
+			.backoff(Duration::from_millis(1), Duration::from_millis(2))
+			.on_state_change(move |state| log.lock().unwrap().push(state));
+		(client, states)
+	}
+
+	#[test]
+	fn reconnects_after_connection_loss() {
+		let mut attempts = 0;
+		let (client, states) = client(move |_| {
+			attempts += 1;
+			match attempts {
+				1 => Ok(connection(&[OwnedMessage::Text("first".to_string())])),
+				2 => Err(refused()),
+				_ => Ok(connection(&[OwnedMessage::Text("second".to_string())])),
+			}
+		});
+		let subscribed = Arc::new(Mutex::new(0));
+		let count = subscribed.clone();
+		let mut client = client.on_connect(move |_| {
+			*count.lock().unwrap() += 1;
+			Ok(())
+		});
+
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Text("first".to_string()));
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Text("second".to_string()));
+		assert_eq!(*subscribed.lock().unwrap(), 2);
+
+		let states = states.lock().unwrap();
+		assert_eq!(states.len(), 4);
+		assert_eq!(states[0], ConnectionState::Connected);
+		assert_eq!(states[1], ConnectionState::Disconnected);
+		match states[2] {
+			ConnectionState::Reconnecting { attempt: 1, delay } => {
+				assert!(delay <= Duration::from_millis(1))
+			}
+			ref state => panic!("unexpected state {:?}", state),
+		}
+		assert_eq!(states[3], ConnectionState::Connected);
+	}
+
+	#[test]
+	fn settings_carry_over_to_the_next_connection() {
+		use dataframe::{DataFrame, Opcode};
+		use ws::dataframe::DataFrame as DataFrameable;
+
+		let mut attempts = 0;
+		let (client, _) = client(move |_| {
+			attempts += 1;
+			if attempts == 1 {
+				return Ok(connection(&[]));
+			}
+			let mut input = Vec::new();
+			for &(finished, opcode) in &[(false, Opcode::Text), (true, Opcode::Continuation)] {
+				DataFrame::new(finished, opcode, b"hi".to_vec()).write_to(&mut input, false).unwrap();
+			}
+			let stream = ReadWritePair(Cursor::new(input), Vec::new());
+			Ok(Client::unchecked(BufReader::new(stream), Headers::new(), true, false))
+		});
+		let mut first = true;
+		let mut client = client.on_connect(move |client| {
+			if first {
+				client.set_max_fragments_per_message(1);
+				first = false;
+			}
+			Ok(())
+		});
+
+		match client.recv_message() {
+			Err(WebSocketError::ProtocolError(_)) => (),
+			other => panic!("the fragment limit should be kept, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn gives_up_after_max_retries() {
+		let attempts = Arc::new(Mutex::new(0));
+		let count = attempts.clone();
+		let (client, states) = client(move |_| {
+			*count.lock().unwrap() += 1;
+			Err(refused())
+		});
+		let mut client = client.max_retries(2).jitter(0.0);
+
+		match client.connect() {
+			Err(WebSocketError::IoError(ref e)) if e.kind() == io::ErrorKind::ConnectionRefused => (),
+			other => panic!("unexpected result {:?}", other),
+		}
+		assert_eq!(*attempts.lock().unwrap(), 3);
+		assert_eq!(*states.lock().unwrap(),
+		           vec![ConnectionState::Reconnecting {
+		                    attempt: 1,
+		                    delay: Duration::from_millis(1),
+		                },
+		                ConnectionState::Reconnecting {
+		                    attempt: 2,
+		                    delay: Duration::from_millis(2),
+		                },
+		                ConnectionState::GaveUp]);
+	}
+}
@@ -2,25 +2,38 @@
 use std::net::TcpStream;
 use std::net::SocketAddr;
 use std::io::Result as IoResult;
-use std::io::{Read, Write};
+use std::io::{Read, Write, ErrorKind};
+use std::time::{Duration, Instant};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
 use hyper::header::Headers;
 use hyper::buffer::BufReader;
 use uuid::Uuid;
+use socket2::{SockRef, TcpKeepalive};
 
 use ws;
 use ws::sender::Sender as SenderTrait;
 use ws::receiver::{DataFrameIterator, MessageIterator};
 use ws::receiver::Receiver as ReceiverTrait;
-use message::OwnedMessage;
-use result::WebSocketResult;
-use stream::sync::{AsTcpStream, Stream, Splittable, Shutdown};
-use dataframe::DataFrame;
+use message::{OwnedMessage, CloseData};
+use result::{WebSocketResult, WebSocketError};
+use stream::sync::{AsTcpStream, BoxedStream, Stream, Splittable, Shutdown};
+use dataframe::{DataFrame, Opcode};
 use header::{WebSocketProtocol, WebSocketExtensions};
 use header::extensions::Extension;
+use ws::extension::Extension as FrameExtension;
 
 use ws::dataframe::DataFrame as DataFrameable;
-use sender::Sender;
-use receiver::Receiver;
+use sender::{Sender, FragmentSerializer};
+use receiver::{Receiver, Received, RateLimit, DeadlineReader, timed_out};
+use framing::FrameCodec;
+use strict::{self, StrictMode};
+use server::upgrade::RequestInfo;
+use server::limit::ConnectionGuard;
 pub use sender::Writer;
 pub use receiver::Reader;
 
@@ -61,6 +74,105 @@ pub struct Client<S>
 	sender: Sender,
 	receiver: Receiver,
 	uuid: Uuid,
+	state: ConnectionState,
+	config: Config<S>,
+	request: Option<RequestInfo>,
+	send_buffer: Option<SendBuffer>,
+}
+
+// where the connection is, checked by everything that sends or receives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionState {
+	Open,
+	// a close was sent, only pings and pongs may follow while the peer's close is awaited
+	CloseSent,
+	// the stream failed or ended, nothing can be sent anymore
+	Failed,
+	// the connection is over, nothing can be sent or received anymore
+	Closed,
+}
+
+// called with the payload of every unsolicited pong
+type PongHook = Box<FnMut(&[u8]) + Send>;
+
+// shuts the stream down after an idle close
+type IdleShutdown<S> = fn(&S) -> IoResult<()>;
+
+// how the client handles what it sends and receives, changed by its setters
+struct Config<S> {
+	// whether a timeout was set through this client, `WouldBlock` or `TimedOut` then means it expired
+	timeouts: bool,
+	strict: Option<StrictMode>,
+	auto_close: bool,
+	lenient_close: bool,
+	// the close code sent when the idle timeout expires, and how to shut the stream down after
+	idle_close: Option<(u16, IdleShutdown<S>)>,
+	// whether `ping_rtt` drops the frames it reads instead of keeping them
+	discard_during_ping: bool,
+	ignore_unsolicited_pongs: bool,
+	on_unsolicited_pong: Option<PongHook>,
+	raw_close_reasons: bool,
+}
+
+// frames sent while a send buffer is set, see `Client::set_send_buffer`
+struct SendBuffer {
+	bytes: Vec<u8>,
+	capacity: usize,
+}
+
+// what frames are sent through, the send buffer if there is one
+struct SendTarget<'a, S: 'a> {
+	stream: &'a mut S,
+	buffer: Option<&'a mut SendBuffer>,
+}
+
+impl<'a, S> Write for SendTarget<'a, S>
+    where S: Write
+{
+	fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+		match self.buffer {
+			Some(ref mut buffer) => {
+				buffer.bytes.extend_from_slice(buf);
+				if buffer.bytes.len() >= buffer.capacity {
+					write_out(self.stream, &mut buffer.bytes)?;
+				}
+				Ok(buf.len())
+			}
+			None => self.stream.write(buf),
+		}
+	}
+
+	fn flush(&mut self) -> IoResult<()> {
+		// every frame is flushed once written, buffered ones wait for `Client::flush`
+		match self.buffer {
+			Some(_) => Ok(()),
+			None => self.stream.flush(),
+		}
+	}
+}
+
+// writes the buffered bytes to `stream`, they are dropped if that fails as
+// part of a frame may have been written already
+fn write_out<W: Write>(stream: &mut W, bytes: &mut Vec<u8>) -> IoResult<()> {
+	let result = stream.write_all(bytes);
+	bytes.clear();
+	result
+}
+
+impl<S> Config<S> {
+	fn new() -> Config<S> {
+		Config {
+			timeouts: false,
+			strict: None,
+			auto_close: false,
+			lenient_close: false,
+			idle_close: None,
+			discard_during_ping: false,
+			ignore_unsolicited_pongs: false,
+			on_unsolicited_pong: None,
+			raw_close_reasons: false,
+		}
+	}
 }
 
 impl Client<TcpStream> {
@@ -98,6 +210,23 @@ impl<S> Client<S>
 		self.stream.get_ref().as_tcp().local_addr()
 	}
 
+	/// Whether the TLS handshake of this connection resumed an earlier
+	/// session, `None` for connections without TLS or if it isn't known.
+	///
+	/// Knowing needs the `tls-session` feature, see the `tls` module.
+	pub fn tls_session_reused(&self) -> Option<bool> {
+		self.stream.get_ref().tls_session_reused()
+	}
+
+	/// The host name the peer asked for with SNI, for connections accepted
+	/// by a secure server. `None` if it sent none, for connections without
+	/// TLS and the client end of a connection.
+	///
+	/// Knowing needs the `tls-sni` feature, see the `tls` module.
+	pub fn sni_hostname(&self) -> Option<String> {
+		self.stream.get_ref().tls_sni_hostname()
+	}
+
 	/// See [`TcpStream::set_nodelay`]
 	/// (https://doc.rust-lang.org/std/net/struct.TcpStream.html#method.set_nodelay).
 	pub fn set_nodelay(&mut self, nodelay: bool) -> IoResult<()> {
@@ -105,9 +234,257 @@ impl<S> Client<S>
 	}
 
 	/// Changes whether the stream is in nonblocking mode.
+	///
+	/// Don't combine this with `set_read_timeout` or `set_write_timeout`, a
+	/// read that would block is then reported as a timeout.
 	pub fn set_nonblocking(&self, nonblocking: bool) -> IoResult<()> {
 		self.stream.get_ref().as_tcp().set_nonblocking(nonblocking)
 	}
+
+	/// Sets the read timeout of the underlying socket, `None` blocks forever.
+	///
+	/// Reads that don't complete in time fail with `WebSocketError::Timeout`
+	/// on every platform. A partially read frame is kept and the next
+	/// read continues it.
+	pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()> {
+		let tcp = self.stream.get_ref().as_tcp();
+		tcp.set_read_timeout(timeout)?;
+		self.config.timeouts = timeout.is_some() || tcp.write_timeout()?.is_some();
+		Ok(())
+	}
+
+	/// Sets the write timeout of the underlying socket, `None` blocks forever.
+	///
+	/// Writes that don't complete in time fail with `WebSocketError::Timeout`.
+	pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()> {
+		let tcp = self.stream.get_ref().as_tcp();
+		tcp.set_write_timeout(timeout)?;
+		self.config.timeouts = timeout.is_some() || tcp.read_timeout()?.is_some();
+		Ok(())
+	}
+
+	/// Closes the connection when nothing is received for `timeout`, `None`
+	/// (the default) keeps it open however long it is idle.
+	///
+	/// When `recv_message` has waited for `timeout` since the last bytes
+	/// arrived, a close frame with `close_code` is sent, the socket is shut
+	/// down and `WebSocketError::Timeout` is returned. Later reads give
+	/// `ConnectionClosed`. Any frame, pings and pongs included, restarts the
+	/// timer. Servers usually close with 1001 (going away) this way, to get
+	/// rid of peers that vanished without closing.
+	///
+	/// The timer is the socket's read timeout, so this replaces the one set
+	/// with `set_read_timeout`.
+	pub fn set_idle_timeout(&mut self, timeout: Option<Duration>, close_code: u16) -> IoResult<()> {
+		self.set_read_timeout(timeout)?;
+		self.config.idle_close = timeout.map(|_| (close_code, shutdown_tcp::<S> as IdleShutdown<S>));
+		Ok(())
+	}
+
+	/// Enables TCP keepalive on the underlying socket, sending the first probe
+	/// after the connection has been idle for the given duration.
+	/// `None` disables keepalive.
+	///
+	/// This keeps long idle connections behind NATs alive and detects dead
+	/// peers at the transport level, it is a complement to websocket pings.
+	/// For SSL streams this is set on the inner TCP socket.
+	pub fn set_keepalive(&self, keepalive: Option<Duration>) -> IoResult<()> {
+		let socket = SockRef::from(self.stream.get_ref().as_tcp());
+		match keepalive {
+			Some(time) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time)),
+			None => socket.set_keepalive(false),
+		}
+	}
+
+	/// Sets `SO_LINGER` on the underlying socket, which decides what closing
+	/// it does with data that wasn't sent yet. For SSL streams this is set on
+	/// the inner TCP socket.
+	///
+	/// `None` (the default) lets the close return at once while the system
+	/// keeps sending in the background. `Some(timeout)` makes the close block
+	/// until the data is sent or `timeout` passed, and `Some(0)` resets the
+	/// connection instead, dropping what is unsent. That sheds abusive peers
+	/// fast, but a reset also drops a close frame still being sent. For a
+	/// clean close finish the closing handshake first, by sending a close
+	/// message and waiting for the answer with `drain_until_close`.
+	pub fn set_linger(&self, linger: Option<Duration>) -> IoResult<()> {
+		SockRef::from(self.stream.get_ref().as_tcp()).set_linger(linger)
+	}
+
+	/// Like `set_keepalive`, but also sets the interval between probes once
+	/// the connection went idle.
+	///
+	/// Some platforms only support whole seconds, so sub-second parts may be
+	/// ignored.
+	#[cfg(any(target_os = "linux",
+	          target_os = "android",
+	          target_os = "freebsd",
+	          target_os = "macos",
+	          target_os = "ios",
+	          windows))]
+	pub fn set_keepalive_interval(&self, time: Duration, interval: Duration) -> IoResult<()> {
+		let keepalive = TcpKeepalive::new().with_time(time).with_interval(interval);
+		SockRef::from(self.stream.get_ref().as_tcp()).set_tcp_keepalive(&keepalive)
+	}
+
+	/// Reads a single message, giving up if none arrives within `timeout`.
+	///
+	/// The socket's read timeout is set for the duration of the call and the
+	/// previous one is restored afterwards. If the timeout expires `Ok(None)`
+	/// is returned; any partially read frame is kept, so the next call picks
+	/// up where this one left off. Other errors are handled like those of
+	/// `recv_message`.
+	///
+	/// A `timeout` of zero is rejected by the operating system with an error.
+	pub fn recv_message_timeout(&mut self, timeout: Duration) -> WebSocketResult<Option<OwnedMessage>> {
+		self.check_readable()?;
+		let previous = self.stream.get_ref().as_tcp().read_timeout()?;
+		self.stream.get_ref().as_tcp().set_read_timeout(Some(timeout))?;
+
+		let result = loop {
+			let result = self.receiver.recv_message(&mut self.stream);
+			let result = self.fail_strict(result);
+			if !self.skip_unsolicited_pong(&result) {
+				break result;
+			}
+		};
+
+		// a message read is returned even if restoring the timeout fails
+		let restored = self.stream.get_ref().as_tcp().set_read_timeout(previous);
+		match result {
+			Err(WebSocketError::IoError(ref e)) if timed_out(e) => {
+				restored?;
+				Ok(None)
+			}
+			result => {
+				let result = self.finish_recv(result);
+				self.close_leniently(result).map(Some)
+			}
+		}
+	}
+
+	/// Reads a single message, failing with `WebSocketError::Timeout` unless
+	/// all of it arrived by `deadline`.
+	///
+	/// Unlike `recv_message_timeout`, the time limit covers the whole message
+	/// instead of every read: the socket's read timeout is shortened to what
+	/// is left before each read, so a peer trickling in fragments can't
+	/// stretch it. The previous read timeout is restored afterwards. The
+	/// fragments and partial frame read before a timeout are kept, a later
+	/// call continues the message. Other errors are handled like those of
+	/// `recv_message`.
+	pub fn recv_message_deadline(&mut self, deadline: Instant) -> WebSocketResult<OwnedMessage> {
+		self.check_readable()?;
+		let previous = self.stream.get_ref().as_tcp().read_timeout()?;
+
+		let result = loop {
+			let result = self.receiver.recv_message(&mut DeadlineReader {
+			                                             inner: &mut self.stream,
+			                                             deadline,
+			                                         });
+			let result = self.fail_strict(result);
+			if !self.skip_unsolicited_pong(&result) {
+				break result;
+			}
+		};
+
+		// a message read is returned even if restoring the timeout fails
+		let restored = self.stream.get_ref().as_tcp().set_read_timeout(previous);
+		match result {
+			Err(WebSocketError::IoError(ref e)) if timed_out(e) => {
+				restored?;
+				Err(WebSocketError::Timeout)
+			}
+			result => {
+				let result = self.finish_recv(result);
+				self.close_leniently(result)
+			}
+		}
+	}
+
+	/// Sends a ping and waits for the pong answering it, returning the round
+	/// trip time.
+	///
+	/// The pong is matched by its payload, so `payload` should be unique to
+	/// this call, a counter or a timestamp for example. Pongs with any other
+	/// payload don't count. Frames read while waiting are kept and returned
+	/// by the next reads, unless `set_discard_during_ping` was enabled.
+	///
+	/// Fails with `WebSocketError::Timeout` if the pong doesn't arrive within
+	/// `timeout`, and with a `ProtocolError` if a close frame arrives first.
+	/// The socket's read timeout is restored afterwards.
+	pub fn ping_rtt(&mut self, payload: &[u8], timeout: Duration) -> WebSocketResult<Duration> {
+		self.check_readable()?;
+		let previous = self.stream.get_ref().as_tcp().read_timeout()?;
+
+		// frames already set aside are returned before the ones read here
+		let mut kept = ::std::mem::take(self.receiver.pending_mut());
+		let result = self.await_pong(payload, timeout, &mut kept);
+		*self.receiver.pending_mut() = kept;
+
+		self.stream.get_ref().as_tcp().set_read_timeout(previous)?;
+		result
+	}
+
+	/// Sets whether `ping_rtt` drops the frames it reads while waiting for
+	/// the pong instead of keeping them for the next reads.
+	///
+	/// Pings are then answered right away. A fragmented message that loses
+	/// some of its frames like this is dropped as a whole, though frames of
+	/// it arriving after the pong are still read and fail as unexpected
+	/// continuations. Close frames are kept either way. Disabled by default.
+	pub fn set_discard_during_ping(&mut self, discard: bool) {
+		self.config.discard_during_ping = discard;
+	}
+
+	fn await_pong(&mut self, payload: &[u8], timeout: Duration, kept: &mut Vec<DataFrame>)
+		-> WebSocketResult<Duration> {
+		let start = Instant::now();
+		self.send_message(&OwnedMessage::Ping(payload.to_vec()))?;
+
+		loop {
+			let elapsed = start.elapsed();
+			if elapsed >= timeout {
+				return Err(WebSocketError::Timeout);
+			}
+			self.stream.get_ref().as_tcp().set_read_timeout(Some(timeout - elapsed))?;
+
+			let frame = match self.receiver.recv_dataframe(&mut self.stream, self.uuid) {
+				Ok(frame) => frame,
+				Err(WebSocketError::IoError(ref e)) if timed_out(e) => {
+					return Err(WebSocketError::Timeout);
+				}
+				Err(e) => return Err(e),
+			};
+
+			match frame.opcode {
+				Opcode::Pong if frame.data == payload => return Ok(start.elapsed()),
+				Opcode::Close => {
+					kept.push(frame);
+					return Err(WebSocketError::ProtocolError("Connection closed before the pong arrived"));
+				}
+				_ if !self.config.discard_during_ping => kept.push(frame),
+				Opcode::Ping => self.send_message(&OwnedMessage::Pong(frame.data))?,
+				opcode if opcode.is_data() => self.receiver.discard_partial_message(),
+				_ => (),
+			}
+		}
+	}
+}
+
+fn shutdown_tcp<S: AsTcpStream>(stream: &S) -> IoResult<()> {
+	stream.as_tcp().shutdown(Shutdown::Both)
+}
+
+impl Client<BoxedStream> {
+	/// Creates a client over a boxed stream **without a handshake**, like
+	/// `unchecked`, for connections whose transport is only known at runtime.
+	///
+	/// With `client_side` set the frames sent are masked and the received ones
+	/// must not be, otherwise the other way around.
+	pub fn from_boxed_stream(stream: BoxedStream, headers: Headers, client_side: bool) -> Self {
+		Client::unchecked(BufReader::new(stream), headers, client_side, !client_side)
+	}
 }
 
 impl<S> Client<S>
@@ -132,29 +509,232 @@ impl<S> Client<S>
 			sender: Sender::new(out_mask), // true
 			receiver: Receiver::new(in_mask, uuid), // false
 			uuid: uuid,
+			state: ConnectionState::Open,
+			config: Config::new(),
+			request: None,
+			send_buffer: None,
+		}
+	}
+
+	// the sender and where it sends to
+	fn sending(&mut self) -> (&mut Sender, SendTarget<'_, S>) {
+		(&mut self.sender,
+		 SendTarget {
+		     stream: self.stream.get_mut(),
+		     buffer: self.send_buffer.as_mut(),
+		 })
+	}
+
+	// writes out the send buffer and flushes the stream
+	fn flush_stream(&mut self) -> IoResult<()> {
+		if let Some(ref mut buffer) = self.send_buffer {
+			write_out(self.stream.get_mut(), &mut buffer.bytes)?;
+		}
+		self.stream.get_mut().flush()
+	}
+
+	// a close ends what is sent, so it isn't kept in the send buffer
+	fn close_sent(&mut self) -> WebSocketResult<()> {
+		if self.state == ConnectionState::Open {
+			self.state = ConnectionState::CloseSent;
 		}
+		self.flush_stream()?;
+		Ok(())
 	}
 
 	/// Sends a single data frame to the remote endpoint.
 	pub fn send_dataframe<D>(&mut self, dataframe: &D) -> WebSocketResult<()>
 		where D: DataFrameable
 	{
-		self.sender.send_dataframe(self.stream.get_mut(), dataframe)
+		let opcode = dataframe.opcode();
+		let control = opcode == Opcode::Ping as u8 || opcode == Opcode::Pong as u8;
+		self.check_open(opcode == Opcode::Close as u8, control)?;
+		let result = {
+			let (sender, mut target) = self.sending();
+			sender.send_dataframe(&mut target, dataframe)
+		};
+		let result = match result {
+			Ok(()) if opcode == Opcode::Close as u8 => self.close_sent(),
+			result => result,
+		};
+		self.map_timeout(result)
+	}
+
+	/// Starts sending a fragmented message, see `FragmentSerializer`.
+	///
+	/// The fragments are passed to the registered extensions like any other
+	/// frame. Fails with `WebSocketError::AlreadyClosed` once no more data
+	/// can be sent. The fragments aren't buffered, what is in the send buffer
+	/// is written out first.
+	pub fn fragments(&mut self) -> WebSocketResult<FragmentSerializer<'_, S>> {
+		self.check_open(false, false)?;
+		let result = self.flush_stream().map_err(WebSocketError::from);
+		self.map_timeout(result)?;
+		Ok(FragmentSerializer::with_sender(self.stream.get_mut(), &mut self.sender))
+	}
+
+	/// Force whether outgoing frames are masked, regardless of whether this is
+	/// the client or the server end of the connection.
+	///
+	/// **This violates RFC6455**, a conforming peer must fail the connection
+	/// when a server masks its frames (or a client doesn't). It is only meant
+	/// for conformance testing other implementations and needs the `testing`
+	/// feature.
+	#[cfg(feature="testing")]
+	pub fn force_masking(&mut self, mask: bool) {
+		self.sender.set_mask(mask);
+	}
+
+	/// Stops masking the frames this end sends, and stops requiring the
+	/// frames it receives to be masked.
+	///
+	/// **This violates RFC6455.** Masking protects intermediaries like proxy
+	/// caches from being poisoned by crafted payloads, skipping it saves the
+	/// cost of masking on links with no such intermediaries, between services
+	/// inside a datacenter for example. Both ends have to call this, a
+	/// conforming peer fails the connection on the first unmasked frame from
+	/// a client, and a server calling this rejects masked frames from
+	/// conforming clients. It needs the non-default `unmasked-client`
+	/// feature.
+	#[cfg(feature="unmasked-client")]
+	pub fn use_unmasked_frames(&mut self) {
+		self.sender.set_mask(false);
+		self.receiver.accept_unmasked();
 	}
 
 	/// Sends a single message to the remote endpoint.
 	pub fn send_message<M>(&mut self, message: &M) -> WebSocketResult<()>
 		where M: ws::Message
 	{
-		self.sender.send_message(self.stream.get_mut(), message)
+		self.check_open(message.is_close(), message.is_control() && !message.is_close())?;
+		let result = {
+			let (sender, mut target) = self.sending();
+			sender.send_message(&mut target, message)
+		};
+		let result = match result {
+			Ok(()) if message.is_close() => self.close_sent(),
+			result => result,
+		};
+		self.map_timeout(result)
+	}
+
+	/// Whether the connection is still open.
+	///
+	/// It stops being open once a close message was sent or received, or
+	/// the stream failed or ended. Timeouts don't count as failures. After a
+	/// close was sent, or the stream failed, sending data or another close
+	/// fails with `WebSocketError::AlreadyClosed`. Pings and pongs can still
+	/// be sent while waiting for the peer's close, and a received close can
+	/// still be answered.
+	pub fn is_open(&self) -> bool {
+		self.state == ConnectionState::Open && !self.receiver.close_received()
+	}
+
+	fn check_open(&self, close: bool, ping_or_pong: bool) -> WebSocketResult<()> {
+		match self.state {
+			ConnectionState::Open => Ok(()),
+			ConnectionState::CloseSent if ping_or_pong && !close => Ok(()),
+			_ => Err(WebSocketError::AlreadyClosed),
+		}
+	}
+
+	fn check_readable(&self) -> WebSocketResult<()> {
+		match self.state {
+			ConnectionState::Closed => Err(WebSocketError::ConnectionClosed),
+			_ => Ok(()),
+		}
+	}
+
+	fn fail(&mut self) {
+		if self.state != ConnectionState::Closed {
+			self.state = ConnectionState::Failed;
+		}
+	}
+
+	// ends the connection after the peer's close, answering it unless a close
+	// was sent already
+	fn finish_close(&mut self, data: Option<&CloseData>) -> WebSocketResult<()> {
+		let result = match self.state {
+			ConnectionState::Open => {
+				let code = data.map_or(1000, |data| data.status_code);
+				self.send_message(&OwnedMessage::Close(Some(CloseData::from_code(code))))
+			}
+			_ => Ok(()),
+		};
+		self.state = ConnectionState::Closed;
+		result
+	}
+
+	/// Sends a text message straight from `text`, see `Sender::send_text`.
+	pub fn send_text(&mut self, text: &str) -> WebSocketResult<()> {
+		self.check_open(false, false)?;
+		let result = {
+			let (sender, mut target) = self.sending();
+			sender.send_text(&mut target, text)
+		};
+		self.map_timeout(result)
+	}
+
+	/// Sends a batch of messages with a single write, see `Sender::send_all`.
+	pub fn send_all<I>(&mut self, messages: I) -> Result<(), (usize, WebSocketError)>
+		where I: IntoIterator,
+		      I::Item: ws::Message
+	{
+		if let Err(e) = self.check_open(false, false) {
+			return Err((0, e));
+		}
+		let result = {
+			let (sender, mut target) = self.sending();
+			sender.send_all(&mut target, messages)
+		};
+		result.map_err(|(sent, e)| (sent, self.map_timeout::<()>(Err(e)).unwrap_err()))
+	}
+
+	/// Writes out the send buffer and flushes the stream, so that everything
+	/// sent so far reaches the remote endpoint.
+	///
+	/// Without a send buffer every message is flushed once it was written,
+	/// this is then only for what was written with `writer_mut` to a stream
+	/// that buffers writes itself. A TLS stream is flushed through to the
+	/// socket.
+	pub fn flush(&mut self) -> WebSocketResult<()> {
+		let result = self.flush_stream().map_err(WebSocketError::from);
+		self.map_timeout(result)
+	}
+
+	/// Collects the frames sent in a buffer of `capacity` bytes instead of
+	/// writing each of them to the stream right away, or stops doing so.
+	///
+	/// The buffer is written out by `flush`, and with a single write once it
+	/// holds `capacity` bytes, which saves the system calls and packets for
+	/// bursts of small messages. Latency-sensitive code needs to call
+	/// `flush` after sending, pongs answering pings are buffered as well.
+	/// Closes write out the buffer and so do `fragments` and `split`, the
+	/// split `Writer` isn't buffered. What is still buffered when the client
+	/// is dropped or `into_stream` is called is lost. Changing the buffer
+	/// writes out what it holds first. Not buffered by default.
+	pub fn set_send_buffer(&mut self, capacity: Option<usize>) -> WebSocketResult<()> {
+		self.flush()?;
+		self.send_buffer = capacity.map(|capacity| {
+			SendBuffer {
+				bytes: Vec::with_capacity(capacity),
+				capacity,
+			}
+		});
+		Ok(())
 	}
 
 	/// Reads a single data frame from the remote endpoint.
 	pub fn recv_dataframe(&mut self) -> WebSocketResult<DataFrame> {
-		self.receiver.recv_dataframe(&mut self.stream, self.uuid)
+		self.check_readable()?;
+		let result = self.receiver.recv_dataframe(&mut self.stream, self.uuid);
+		self.map_timeout(result)
 	}
 
 	/// Returns an iterator over incoming data frames.
+	///
+	/// This is the low level counterpart to `incoming_messages`, fragmented
+	/// messages are not reassembled and every frame is yielded as it arrived.
 	pub fn incoming_dataframes(&mut self) -> DataFrameIterator<Receiver, BufReader<S>> {
 		self.receiver.incoming_dataframes(&mut self.stream)
 	}
@@ -172,8 +752,269 @@ impl<S> Client<S>
 	///
 	/// let response = client.recv_message().unwrap();
 	/// ```
+	///
+	/// With auto close enabled (see `set_auto_close`), this fails with
+	/// `WebSocketError::ConnectionClosed` once a close message was returned.
 	pub fn recv_message(&mut self) -> WebSocketResult<OwnedMessage> {
-		self.receiver.recv_message(&mut self.stream)
+		self.check_readable()?;
+		loop {
+			let result = self.receiver.recv_message(&mut self.stream);
+			let result = self.map_timeout(result);
+			let result = self.fail_strict(result);
+			let result = self.close_idle(result);
+			if !self.skip_unsolicited_pong(&result) {
+				let result = self.answer_close(result);
+				return self.close_leniently(result);
+			}
+		}
+	}
+
+	/// Makes `recv_message` and `recv_message_timeout` skip pongs instead of
+	/// returning them, disabled by default.
+	///
+	/// RFC6455 allows pongs that answer no ping, as a one way heartbeat, they
+	/// are never an error. Every pong these methods read is unsolicited,
+	/// `ping_rtt` consumes the pong it waits for itself.
+	pub fn set_ignore_unsolicited_pongs(&mut self, ignore: bool) {
+		self.config.ignore_unsolicited_pongs = ignore;
+	}
+
+	/// Calls `hook` with the payload of every unsolicited pong read by
+	/// `recv_message` or `recv_message_timeout`, whether it is ignored or
+	/// returned afterwards.
+	pub fn on_unsolicited_pong<F>(&mut self, hook: F)
+		where F: FnMut(&[u8]) + Send + 'static
+	{
+		self.config.on_unsolicited_pong = Some(Box::new(hook));
+	}
+
+	fn skip_unsolicited_pong(&mut self, result: &WebSocketResult<OwnedMessage>) -> bool {
+		match *result {
+			Ok(OwnedMessage::Pong(ref payload)) => {
+				if let Some(ref mut hook) = self.config.on_unsolicited_pong {
+					hook(payload);
+				}
+				self.config.ignore_unsolicited_pongs
+			}
+			_ => false,
+		}
+	}
+
+	/// Enables or disables answering close messages automatically, disabled
+	/// by default.
+	///
+	/// When `recv_message` or `recv_message_timeout` receive a close message
+	/// with this enabled, a close with the same status code (or 1000 if it
+	/// had none) is sent back before the message is returned, unless this end
+	/// sent a close first. That completes the closing handshake, after which
+	/// these methods fail with `WebSocketError::ConnectionClosed`.
+	pub fn set_auto_close(&mut self, enabled: bool) {
+		self.config.auto_close = enabled;
+	}
+
+	fn answer_close(&mut self, result: WebSocketResult<OwnedMessage>) -> WebSocketResult<OwnedMessage> {
+		if let Ok(OwnedMessage::Close(ref data)) = result {
+			if self.config.auto_close {
+				self.finish_close(data.as_ref())?;
+			}
+		}
+		result
+	}
+
+	/// Makes `recv_message` and `recv_message_timeout` treat a peer that ends
+	/// the stream without a close frame as if it had closed, disabled by
+	/// default.
+	///
+	/// Instead of failing with `WebSocketError::ConnectionClosed` they then
+	/// return a close message with status code 1006 (abnormal closure), like
+	/// browsers report it, and fail with `ConnectionClosed` afterwards. No
+	/// close is sent back, the peer is gone. A stream ending in the middle of
+	/// a frame is still a protocol error.
+	pub fn set_lenient_close(&mut self, lenient: bool) {
+		self.config.lenient_close = lenient;
+	}
+
+	fn close_leniently(&mut self, result: WebSocketResult<OwnedMessage>) -> WebSocketResult<OwnedMessage> {
+		match result {
+			Err(WebSocketError::ConnectionClosed) if self.config.lenient_close => {
+				self.state = ConnectionState::Closed;
+				Ok(OwnedMessage::Close(Some(CloseData::from_code(strict::ABNORMAL_CLOSURE))))
+			}
+			Err(WebSocketError::ConnectionClosed) => {
+				self.fail();
+				result
+			}
+			result => result,
+		}
+	}
+
+	/// Reads and discards incoming frames until the peer's close frame arrives.
+	///
+	/// Pings are still answered with pongs while waiting. This is meant for
+	/// shutting down a connection without processing what is still in flight,
+	/// closing a socket with unread data in it makes the operating system
+	/// reset the connection. The close frame itself is not answered, so reply
+	/// to it unless a close was already sent.
+	///
+	/// Fails with a `ProtocolError` once more than `max_bytes` of payload were
+	/// discarded without a close frame in sight.
+	pub fn drain_until_close(&mut self, max_bytes: usize) -> WebSocketResult<()> {
+		let mut discarded = 0usize;
+		loop {
+			let frame = self.recv_dataframe()?;
+			match frame.opcode {
+				Opcode::Close => return Ok(()),
+				Opcode::Ping => self.send_message(&OwnedMessage::Pong(frame.data.clone()))?,
+				_ => (),
+			}
+
+			discarded = discarded.saturating_add(frame.data.len());
+			if discarded > max_bytes {
+				return Err(WebSocketError::ProtocolError("Too much data received before close"));
+			}
+		}
+	}
+
+	/// Reads a binary message, writing its payload to `sink` as it arrives.
+	///
+	/// See `Receiver::recv_binary_to`.
+	pub fn recv_binary_to<W>(&mut self, sink: &mut W) -> WebSocketResult<u64>
+		where W: Write
+	{
+		self.check_readable()?;
+		let result = self.receiver.recv_binary_to(&mut self.stream, sink);
+		self.map_timeout(result)
+	}
+
+	/// Sets the largest number of payload bytes read from the stream at once.
+	///
+	/// See `Receiver::set_read_chunk_size`.
+	pub fn set_read_chunk_size(&mut self, size: usize) {
+		self.receiver.set_read_chunk_size(size);
+	}
+
+	/// Sets the largest number of frames a message may be made of.
+	///
+	/// See `Receiver::set_max_fragments_per_message`.
+	pub fn set_max_fragments_per_message(&mut self, max: usize) {
+		self.receiver.set_max_fragments_per_message(max);
+	}
+
+	/// Limits the rate frames are received at.
+	///
+	/// See `Receiver::set_rate_limit`.
+	pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+		self.receiver.set_rate_limit(limit);
+	}
+
+	/// Gives back the full allowance of the rate limit.
+	///
+	/// See `Receiver::reset_rate_limit`.
+	pub fn reset_rate_limit(&mut self) {
+		self.receiver.reset_rate_limit();
+	}
+
+	/// Enables or disables reading binary messages as a stream of bytes
+	/// through the `Read` impl of the client.
+	///
+	/// See `Receiver::set_binary_stream_mode`.
+	pub fn set_binary_stream_mode(&mut self, enabled: bool) {
+		self.receiver.set_binary_stream_mode(enabled);
+	}
+
+	/// Enables or disables recording where each frame was found on the wire.
+	///
+	/// See `Receiver::set_analyzer_mode`.
+	pub fn set_analyzer_mode(&mut self, enabled: bool) {
+		self.receiver.set_analyzer_mode(enabled);
+	}
+
+	/// Reads a single message along with the frames it was made from.
+	///
+	/// See `Receiver::recv_message_with_frames`.
+	pub fn recv_message_with_frames(&mut self) -> WebSocketResult<Received> {
+		self.check_readable()?;
+		let result = self.receiver.recv_message_with_frames(&mut self.stream);
+		let result = self.map_timeout(result);
+		self.fail_strict(result)
+	}
+
+	/// Enables strict mode with the given settings, or disables it with
+	/// `None`.
+	///
+	/// In strict mode, when `recv_message`, `recv_message_timeout` or
+	/// `recv_message_with_frames` receive a message that violates the
+	/// protocol, a close frame with the matching status code is sent before
+	/// the error is returned: 1002 for protocol errors, 1007 for invalid
+	/// UTF-8 and 1009 for messages over `StrictMode::max_message_size`. The
+	/// connection should be dropped after that. This works the same for the
+	/// client and the server side of a connection, see the `strict` module.
+	pub fn set_strict_mode(&mut self, strict: Option<StrictMode>) {
+		self.receiver.set_max_message_size(strict.and_then(|s| s.max_message_size));
+		self.receiver.set_raw_close_reasons(self.config.raw_close_reasons && strict.is_none());
+		self.config.strict = strict;
+	}
+
+	/// Keeps close reasons that aren't valid UTF-8 as received instead of
+	/// failing the message, disabled by default. See
+	/// `Receiver::set_raw_close_reasons`.
+	///
+	/// Strict mode overrides this: there such a reason fails with a
+	/// `Utf8Error` and the connection is closed with status code 1007.
+	pub fn set_raw_close_reasons(&mut self, raw: bool) {
+		self.config.raw_close_reasons = raw;
+		self.receiver.set_raw_close_reasons(raw && self.config.strict.is_none());
+	}
+
+	fn fail_strict<T>(&mut self, result: WebSocketResult<T>) -> WebSocketResult<T> {
+		if let Err(ref e) = result {
+			if let Some(code) = self.config.strict.and_then(|_| strict::close_code(e)) {
+				let close = OwnedMessage::Close(Some(CloseData::from_code(code)));
+				// the connection is failed either way, the original error is the one to report
+				let _ = self.send_message(&close);
+			}
+		}
+		result
+	}
+
+	// what `recv_message` does with errors and closes, apart from the
+	// timeouts set for a single call
+	fn finish_recv(&mut self, result: WebSocketResult<OwnedMessage>) -> WebSocketResult<OwnedMessage> {
+		let result = self.map_timeout(result);
+		let result = self.close_idle(result);
+		self.answer_close(result)
+	}
+
+	fn close_idle<T>(&mut self, result: WebSocketResult<T>) -> WebSocketResult<T> {
+		if let Err(WebSocketError::Timeout) = result {
+			if let Some((code, shutdown)) = self.config.idle_close {
+				debug!("Closing idle connection {} with code {}", self.uuid, code);
+				// the peer is likely gone, the close is sent on the off chance it isn't
+				let _ = self.send_message(&OwnedMessage::Close(Some(CloseData::from_code(code))));
+				let _ = shutdown(self.stream.get_ref());
+				self.state = ConnectionState::Closed;
+			}
+		}
+		result
+	}
+
+	fn map_timeout<T>(&mut self, result: WebSocketResult<T>) -> WebSocketResult<T> {
+		match result {
+			Err(WebSocketError::IoError(ref e)) if self.config.timeouts && timed_out(e) => {
+				Err(WebSocketError::Timeout)
+			}
+			Err(WebSocketError::IoError(ref e)) if e.kind() != ErrorKind::WouldBlock &&
+			                                       e.kind() != ErrorKind::Interrupted => {
+				self.fail();
+				result
+			}
+			Err(WebSocketError::ConnectionClosed) |
+			Err(WebSocketError::NoDataAvailable) => {
+				self.fail();
+				result
+			}
+			result => result,
+		}
 	}
 
 	/// Access the headers that were sent in the server's handshake response.
@@ -182,6 +1023,30 @@ impl<S> Client<S>
 		&self.headers
 	}
 
+	/// What the client asked for in its handshake request, for connections
+	/// accepted by a server. Clients that connected themselves give `None`.
+	pub fn request(&self) -> Option<&RequestInfo> {
+		self.request.as_ref()
+	}
+
+	pub(crate) fn set_request(&mut self, request: RequestInfo) {
+		self.request = Some(request);
+	}
+
+	pub(crate) fn set_connection_guard(&mut self, guard: Option<ConnectionGuard>) {
+		self.receiver.set_connection_guard(guard);
+	}
+
+	/// Takes the guard counting this connection against the `IpLimit` of the
+	/// server that accepted it, if it has one.
+	///
+	/// The connection counts until the client is dropped, or the guard once
+	/// it was taken. A split client counts until its `Reader` is dropped,
+	/// while `into_stream` and `into_frame_codec` drop the guard.
+	pub fn take_connection_guard(&mut self) -> Option<ConnectionGuard> {
+		self.receiver.take_connection_guard()
+	}
+
 	/// **If you supplied a protocol, you must check that it was accepted by
 	/// the server** using this function.
 	/// This is not done automatically because the terms of accepting a protocol
@@ -204,9 +1069,14 @@ impl<S> Client<S>
 		    .unwrap_or(&[])
 	}
 
-	/// If you supplied a protocol, be sure to check if it was accepted by the
-	/// server here. Since no extensions are implemented out of the box yet, using
-	/// one will require its own implementation.
+	/// The extensions agreed on in the handshake, with their parameters.
+	///
+	/// For a client these come from the server's response, for a connection
+	/// accepted by a server from the response it sent, including what
+	/// `Upgrade::negotiate_deflate` agreed to. If you offered an extension, be
+	/// sure to check here whether it was accepted. Apart from
+	/// permessage-deflate (see the `deflate` module) extensions need their
+	/// own implementation, see `register_extension`.
 	pub fn extensions(&self) -> &[Extension] {
 		self.headers
 		    .get::<WebSocketExtensions>()
@@ -214,6 +1084,59 @@ impl<S> Client<S>
 		    .unwrap_or(&[])
 	}
 
+	/// Registers an extension that changes the frames sent and received.
+	///
+	/// The extension is only used if it was agreed on in the handshake, i.e.
+	/// it appears in `extensions()`, and returns whether that was the case.
+	/// See `ws::extension::Extension` for the order in which several
+	/// extensions are applied. Extensions stay registered when the client is
+	/// split and are not used by the asynchronous client.
+	pub fn register_extension(&mut self, mut extension: Box<FrameExtension>) -> WebSocketResult<bool> {
+		let agreed = match self.extensions().iter().find(|e| e.name == extension.name()) {
+			Some(agreed) => agreed.clone(),
+			None => return Ok(false),
+		};
+		extension.negotiated(&agreed)?;
+
+		let extension = Arc::new(Mutex::new(extension));
+		self.sender.add_extension(extension.clone());
+		self.receiver.add_extension(extension);
+		Ok(true)
+	}
+
+	/// Replaces the framing of this connection with `codec`, e.g. the one of
+	/// a previous connection taken with `into_frame_codec`.
+	///
+	/// The codec is reset first. Its extensions are negotiated again with
+	/// what was agreed on in this connection's handshake and dropped if they
+	/// weren't, extensions registered on this client before are dropped. An
+	/// error from an extension drops the codec and leaves the client as it
+	/// was. Use this before anything was sent or received.
+	pub fn set_frame_codec(&mut self, mut codec: FrameCodec) -> WebSocketResult<()> {
+		codec.reset();
+		codec.renegotiate(self.extensions())?;
+		codec.set_uuid(self.uuid);
+		let (mut receiver, sender) = codec.into_parts();
+		receiver.set_connection_guard(self.receiver.take_connection_guard());
+		self.receiver = receiver;
+		self.sender = sender;
+		Ok(())
+	}
+
+	/// Takes the framing of this connection, with its settings and
+	/// extensions, to use it for another one. See `set_frame_codec`.
+	pub fn into_frame_codec(mut self) -> FrameCodec {
+		// the connection ends here, the codec may be used for another one
+		self.receiver.take_connection_guard();
+		FrameCodec::from_parts(self.receiver, self.sender)
+	}
+
+	/// Whether a permessage-deflate extension is registered, e.g. by
+	/// `Upgrade::negotiate_deflate`.
+	pub fn uses_deflate(&self) -> bool {
+		self.receiver.uses_deflate()
+	}
+
 	/// Get a reference to the stream.
 	/// Useful to be able to set options on the stream.
 	///
@@ -335,6 +1258,109 @@ impl<S> Client<S>
 	pub fn incoming_messages<'a>(&'a mut self) -> MessageIterator<'a, Receiver, BufReader<S>> {
 		self.receiver.incoming_messages(&mut self.stream)
 	}
+
+	/// Returns an iterator over the text and binary messages received,
+	/// handling control messages along the way.
+	///
+	/// Pings are answered with pongs and pongs are skipped (after the
+	/// unsolicited pong hook saw them, see `on_unsolicited_pong`). A close
+	/// is answered with one carrying the same status code, unless a close
+	/// was sent already, and ends the iteration. So does an error, other
+	/// than a timeout, after it was returned. Fragments are reassembled like
+	/// for `recv_message`. To see control messages yourself use
+	/// `incoming_messages` or `recv_message` instead.
+	///
+	///```no_run
+	///# extern crate websocket;
+	///# fn main() {
+	///use websocket::ClientBuilder;
+	///
+	///let mut client = ClientBuilder::new("ws://127.0.0.1:1234").unwrap()
+	///                     .connect_insecure().unwrap();
+	///
+	///for message in client.messages() {
+	///    println!("Recv: {:?}", message.unwrap());
+	///}
+	///# }
+	///```
+	pub fn messages(&mut self) -> Messages<'_, S> {
+		Messages {
+			client: self,
+			done: false,
+		}
+	}
+}
+
+/// An iterator over the data messages of a client, see `Client::messages`.
+pub struct Messages<'a, S>
+	where S: 'a + Stream
+{
+	client: &'a mut Client<S>,
+	done: bool,
+}
+
+impl<'a, S> Iterator for Messages<'a, S>
+    where S: Stream
+{
+	type Item = WebSocketResult<OwnedMessage>;
+
+	fn next(&mut self) -> Option<WebSocketResult<OwnedMessage>> {
+		while !self.done {
+			let answered = match self.client.recv_message() {
+				Ok(OwnedMessage::Ping(payload)) => self.client.send_message(&OwnedMessage::Pong(payload)),
+				Ok(OwnedMessage::Pong(_)) => Ok(()),
+				Ok(OwnedMessage::Close(data)) => {
+					self.done = true;
+					// the peer may be gone already, the iteration ends either way
+					let _ = self.client.finish_close(data.as_ref());
+					return None;
+				}
+				Ok(message) => return Some(Ok(message)),
+				Err(WebSocketError::Timeout) => return Some(Err(WebSocketError::Timeout)),
+				Err(e) => Err(e),
+			};
+			if let Err(e) = answered {
+				self.done = true;
+				return Some(Err(e));
+			}
+		}
+		None
+	}
+}
+
+/// Reads the payloads of binary messages as a stream of bytes, only in
+/// binary stream mode (see `Client::set_binary_stream_mode`).
+impl<S> Read for Client<S>
+    where S: Stream
+{
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+		if self.state == ConnectionState::Closed {
+			return Ok(0);
+		}
+		self.receiver.read_binary_stream(&mut self.stream, buf)
+	}
+}
+
+/// The file descriptor of the underlying TCP socket, for SSL streams that of
+/// the inner TCP stream.
+#[cfg(unix)]
+impl<S> AsRawFd for Client<S>
+    where S: AsTcpStream + Stream
+{
+	fn as_raw_fd(&self) -> RawFd {
+		self.stream.get_ref().as_tcp().as_raw_fd()
+	}
+}
+
+/// The handle of the underlying TCP socket, for SSL streams that of the inner
+/// TCP stream.
+#[cfg(windows)]
+impl<S> AsRawSocket for Client<S>
+    where S: AsTcpStream + Stream
+{
+	fn as_raw_socket(&self) -> RawSocket {
+		self.stream.get_ref().as_tcp().as_raw_socket()
+	}
 }
 
 impl<S> Client<S>
@@ -366,8 +1392,9 @@ impl<S> Client<S>
 	///# }
 	///```
 	pub fn split
-		(self)
+		(mut self)
 		 -> IoResult<(Reader<<S as Splittable>::Reader>, Writer<<S as Splittable>::Writer>)> {
+		self.flush_stream()?;
 		let (stream, buf, pos, cap) = self.stream.into_parts();
 		let (read, write) = stream.split()?;
 		Ok((Reader {
@@ -380,3 +1407,893 @@ impl<S> Client<S>
 		    }))
 	}
 }
+
+/// The side of a proxied connection that closed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxySide {
+	/// The first client given to `proxy`.
+	A,
+	/// The second client given to `proxy`.
+	B,
+}
+
+/// Forwards data frames between two clients until the connection is closed.
+///
+/// Frames are passed on as they arrive, fragments and extension bits
+/// included, and are masked according to the side they are written to, so
+/// one end can be a connection accepted by a server and the other one made
+/// by a client. Pings, pongs and closes are forwarded like any other frame,
+/// it is up to the endpoints to answer them. Once a side sends a close its
+/// direction ends, and `proxy` returns which side that was once the other
+/// side has answered with a close as well.
+///
+/// Both clients are split and each direction is forwarded on a thread of
+/// its own, so any streams that can be split will do, in-memory ones
+/// included. If a direction fails the other side is sent a close and the
+/// error is returned right away. The other direction's thread ends once
+/// that side answers the close or its stream fails, a read timeout bounds
+/// how long it can be left waiting.
+pub fn proxy<A, B>(a: Client<A>, b: Client<B>) -> WebSocketResult<ProxySide>
+	where A: Stream + Splittable,
+	      B: Stream + Splittable,
+	      A::Reader: Send + 'static,
+	      A::Writer: Send + 'static,
+	      B::Reader: Send + 'static,
+	      B::Writer: Send + 'static
+{
+	let proxied = Arc::new(Proxied {
+	                           initiator: Mutex::new(None),
+	                           failed: Mutex::new(false),
+	                       });
+	let (a_reader, a_writer) = a.split()?;
+	let (b_reader, b_writer) = b.split()?;
+
+	let (done, finished) = mpsc::channel();
+	spawn_forward(a_reader, b_writer, ProxySide::A, &proxied, &done);
+	spawn_forward(b_reader, a_writer, ProxySide::B, &proxied, &done);
+	drop(done);
+
+	for _ in 0..2 {
+		finished.recv().expect("a proxied direction panicked")?;
+	}
+	let initiator = *proxied.initiator.lock().unwrap();
+	initiator.ok_or(WebSocketError::ProtocolError("Proxied connection ended without a close"))
+}
+
+// what both directions of a proxied connection share
+struct Proxied {
+	// the side that sent the first close
+	initiator: Mutex<Option<ProxySide>>,
+	// whether a direction failed, only the first one sends a close
+	failed: Mutex<bool>,
+}
+
+fn spawn_forward<R, W>(
+	mut from: Reader<R>,
+	mut to: Writer<W>,
+	side: ProxySide,
+	proxied: &Arc<Proxied>,
+	done: &mpsc::Sender<WebSocketResult<()>>,
+) where R: Read + Send + 'static,
+        W: Write + Send + 'static
+{
+	let proxied = proxied.clone();
+	let done = done.clone();
+	thread::spawn(move || {
+		// `proxy` may have returned already
+		let _ = done.send(forward(&mut from, &mut to, side, &proxied));
+	});
+}
+
+fn forward<R, W>(
+	from: &mut Reader<R>,
+	to: &mut Writer<W>,
+	side: ProxySide,
+	proxied: &Proxied,
+) -> WebSocketResult<()>
+	where R: Read,
+	      W: Write
+{
+	let result = forward_frames(from, to, side, proxied);
+	if result.is_err() {
+		let mut failed = proxied.failed.lock().unwrap();
+		if !*failed {
+			*failed = true;
+			let _ = to.send_message(&OwnedMessage::Close(None));
+		}
+	}
+	result
+}
+
+fn forward_frames<R, W>(
+	from: &mut Reader<R>,
+	to: &mut Writer<W>,
+	side: ProxySide,
+	proxied: &Proxied,
+) -> WebSocketResult<()>
+	where R: Read,
+	      W: Write
+{
+	loop {
+		let frame = from.recv_dataframe()?;
+		let close = frame.opcode == Opcode::Close;
+		if close {
+			let mut initiator = proxied.initiator.lock().unwrap();
+			if initiator.is_none() {
+				*initiator = Some(side);
+			}
+		}
+
+		to.send_dataframe(&frame)?;
+		if close {
+			return Ok(());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::net::TcpListener;
+	use std::time::Duration;
+	use message::Message;
+	use ws::Message as MessageTrait;
+	use std::io::Cursor;
+	use std::sync::mpsc;
+	use stream::ReadWritePair;
+	use ws::extension::PERMESSAGE_DEFLATE;
+
+	#[test]
+	fn recv_message_timeout_resumes_partial_frames() {
+		let (mut client, mut remote) = tcp_client();
+
+		let mut frame = Vec::new();
+		Message::text("slow and steady").serialize(&mut frame, true).unwrap();
+
+		// nothing sent yet
+		let timeout = Duration::from_millis(50);
+		assert_eq!(client.recv_message_timeout(timeout).unwrap(), None);
+
+		// only part of the header and payload
+		remote.write_all(&frame[..4]).unwrap();
+		assert_eq!(client.recv_message_timeout(timeout).unwrap(), None);
+		remote.write_all(&frame[4..10]).unwrap();
+		assert_eq!(client.recv_message_timeout(timeout).unwrap(), None);
+
+		remote.write_all(&frame[10..]).unwrap();
+		assert_eq!(client.recv_message_timeout(timeout).unwrap(),
+		           Some(OwnedMessage::Text("slow and steady".to_string())));
+		assert_eq!(client.stream_ref().read_timeout().unwrap(), None);
+
+		// the remote going away ends the connection like in `recv_message`
+		drop(remote);
+		assert!(client.recv_message_timeout(timeout).is_err());
+		assert!(!client.is_open());
+		assert_eq!(client.stream_ref().read_timeout().unwrap(), None);
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn raw_fd_is_the_tcp_socket() {
+		let (client, _remote) = tcp_client();
+		assert_eq!(client.as_raw_fd(), client.stream_ref().as_raw_fd());
+	}
+
+	#[test]
+	fn expired_read_timeout_is_reported_as_timeout() {
+		let (mut client, _remote) = tcp_client();
+
+		client.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+		match client.recv_message() {
+			Err(WebSocketError::Timeout) => (),
+			other => panic!("expected a timeout, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn timed_out_reads_are_reported_as_timeout() {
+		// how an expired read timeout shows up on Windows
+		struct TimingOut;
+
+		impl Read for TimingOut {
+			fn read(&mut self, _: &mut [u8]) -> IoResult<usize> {
+				Err(::std::io::Error::new(ErrorKind::TimedOut, "timed out"))
+			}
+		}
+
+		let stream = ReadWritePair(TimingOut, Vec::new());
+		let mut client = Client::unchecked(BufReader::new(stream), Headers::new(), false, true);
+		// what `set_read_timeout` records, it needs a TCP stream
+		client.config.timeouts = true;
+		match client.recv_message() {
+			Err(WebSocketError::Timeout) => (),
+			other => panic!("expected a timeout, got {:?}", other),
+		}
+		assert!(client.is_open());
+	}
+
+	#[test]
+	fn idle_connections_are_closed() {
+		use std::io::Read;
+
+		let (mut client, mut remote) = tcp_client();
+		client.set_idle_timeout(Some(Duration::from_millis(100)), 1001).unwrap();
+
+		// any frame restarts the timer
+		let mut ping = Vec::new();
+		Message::ping(&b"hi"[..]).serialize(&mut ping, true).unwrap();
+		remote.write_all(&ping).unwrap();
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Ping(b"hi".to_vec()));
+
+		match client.recv_message() {
+			Err(WebSocketError::Timeout) => (),
+			other => panic!("expected a timeout, got {:?}", other),
+		}
+		match client.recv_message() {
+			Err(WebSocketError::ConnectionClosed) => (),
+			other => panic!("expected the connection to be closed, got {:?}", other),
+		}
+
+		let mut received = Vec::new();
+		remote.read_to_end(&mut received).unwrap();
+		let mut close = Vec::new();
+		OwnedMessage::Close(Some(CloseData::from_code(1001))).serialize(&mut close, false).unwrap();
+		assert_eq!(received, close);
+	}
+
+	#[test]
+	fn deadline_bounds_a_trickled_message() {
+		let (mut client, mut remote) = tcp_client();
+
+		// every gap is shorter than the deadline, all of them together longer
+		let sending = thread::spawn(move || for i in 0..6 {
+			let opcode = if i == 0 { Opcode::Text } else { Opcode::Continuation };
+			let mut frame = Vec::new();
+			DataFrame::new(i == 5, opcode, b"ab".to_vec()).write_to(&mut frame, true).unwrap();
+			remote.write_all(&frame).unwrap();
+			thread::sleep(Duration::from_millis(100));
+		});
+
+		let start = Instant::now();
+		match client.recv_message_deadline(start + Duration::from_millis(250)) {
+			Err(WebSocketError::Timeout) => (),
+			other => panic!("expected a timeout, got {:?}", other),
+		}
+		let elapsed = start.elapsed();
+		assert!(elapsed < Duration::from_millis(400), "took {:?}", elapsed);
+
+		// the fragments read so far are kept for the next call
+		let message = client.recv_message_deadline(Instant::now() + Duration::from_secs(5)).unwrap();
+		assert_eq!(message, OwnedMessage::Text("abababababab".to_string()));
+		assert_eq!(client.stream.get_ref().read_timeout().unwrap(), None);
+		sending.join().unwrap();
+	}
+
+	#[test]
+	fn deadline_passing_mid_frame_keeps_the_partial_frame() {
+		let (mut client, mut remote) = tcp_client();
+		let mut frame = Vec::new();
+		DataFrame::new(true, Opcode::Text, b"abcd".to_vec()).write_to(&mut frame, true).unwrap();
+		let (first, rest) = frame.split_at(frame.len() - 2);
+
+		remote.write_all(first).unwrap();
+		match client.recv_message_deadline(Instant::now() + Duration::from_millis(100)) {
+			Err(WebSocketError::Timeout) => (),
+			other => panic!("expected a timeout, got {:?}", other),
+		}
+		// the deadline passed before the next read even started
+		match client.recv_message_deadline(Instant::now()) {
+			Err(WebSocketError::Timeout) => (),
+			other => panic!("expected a timeout, got {:?}", other),
+		}
+		assert!(client.is_open());
+
+		remote.write_all(rest).unwrap();
+		let message = client.recv_message_deadline(Instant::now() + Duration::from_secs(5)).unwrap();
+		assert_eq!(message, OwnedMessage::Text("abcd".to_string()));
+	}
+
+	#[test]
+	fn lenient_close_reports_a_vanished_peer_as_closed() {
+		let (mut client, remote) = connection();
+		client.set_lenient_close(true);
+		client.set_auto_close(true);
+		let (mut remote, _) = remote.split().unwrap();
+
+		assert_eq!(client.recv_message().unwrap(),
+		           OwnedMessage::Close(Some(CloseData::from_code(1006))));
+		match client.recv_message() {
+			Err(WebSocketError::ConnectionClosed) => (),
+			other => panic!("expected the connection to be closed, got {:?}", other),
+		}
+
+		// no close was sent back
+		drop(client);
+		match remote.recv_message() {
+			Err(WebSocketError::ConnectionClosed) => (),
+			other => panic!("expected nothing to be sent, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn closed_connection_refuses_fragments_and_pings() {
+		let (mut client, mut server) = tcp_connection();
+		server.set_auto_close(true);
+
+		client.send_message(&Message::close()).unwrap();
+		match client.fragments() {
+			Err(WebSocketError::AlreadyClosed) => (),
+			Err(e) => panic!("expected the close to end sending, got {:?}", e),
+			Ok(_) => panic!("expected the close to end sending"),
+		}
+
+		assert_eq!(server.recv_message().unwrap(), OwnedMessage::Close(None));
+		match server.ping_rtt(b"ping", Duration::from_secs(1)) {
+			Err(WebSocketError::ConnectionClosed) => (),
+			other => panic!("expected the connection to be closed, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn close_read_by_ping_rtt_is_received_as_a_message() {
+		let (mut client, mut server) = tcp_connection();
+		server.send_message(&Message::text("first")).unwrap();
+		server.send_message(&Message::close()).unwrap();
+
+		match client.ping_rtt(b"ping", Duration::from_secs(5)) {
+			Err(WebSocketError::ProtocolError("Connection closed before the pong arrived")) => (),
+			other => panic!("expected the close to end the ping, got {:?}", other),
+		}
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Text("first".to_string()));
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Close(None));
+	}
+
+	#[test]
+	fn ping_rtt_waits_for_the_matching_pong() {
+		let (mut client, mut server) = tcp_connection();
+
+		server.send_message(&Message::text("before")).unwrap();
+		server.send_message(&Message::pong(b"stale".to_vec())).unwrap();
+		server.send_message(&Message::pong(b"1".to_vec())).unwrap();
+
+		client.ping_rtt(b"1", Duration::from_secs(5)).unwrap();
+		assert_eq!(server.recv_message().unwrap(), OwnedMessage::Ping(b"1".to_vec()));
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Text("before".to_string()));
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Pong(b"stale".to_vec()));
+
+		// nobody answers this one
+		match client.ping_rtt(b"2", Duration::from_millis(50)) {
+			Err(WebSocketError::Timeout) => (),
+			other => panic!("expected a timeout, got {:?}", other),
+		}
+		assert_eq!(client.stream_ref().read_timeout().unwrap(), None);
+
+		client.set_discard_during_ping(true);
+		server.send_message(&Message::text("dropped")).unwrap();
+		server.send_message(&Message::pong(b"3".to_vec())).unwrap();
+		server.send_message(&Message::text("after")).unwrap();
+		client.ping_rtt(b"3", Duration::from_secs(5)).unwrap();
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Text("after".to_string()));
+	}
+
+	#[test]
+	fn strict_mode_closes_with_the_matching_code() {
+		let connect = || {
+			let (mut client, server) = tcp_connection();
+			client.set_strict_mode(Some(StrictMode::new().max_message_size(8)));
+			(client, server)
+		};
+
+		let mut reserved = DataFrame::new(true, Opcode::Binary, b"rsv".to_vec());
+		reserved.reserved[1] = true;
+		let frames = vec![DataFrame::new(true, Opcode::Text, vec![0xC3, 0x28]),
+		                  DataFrame::new(true, Opcode::Binary, vec![0; 9]),
+		                  reserved];
+		// a fresh connection each time, nothing may follow the close
+		for (frame, code) in frames.into_iter().zip(vec![1007, 1009, 1002]) {
+			let (mut client, mut server) = connect();
+			server.send_dataframe(&frame).unwrap();
+			assert!(client.recv_message().is_err());
+			assert_eq!(server.recv_message().unwrap(),
+			           OwnedMessage::Close(Some(CloseData::from_code(code))));
+		}
+
+		// without strict mode errors are only returned
+		let (mut client, mut server) = connect();
+		client.set_strict_mode(None);
+		server.send_dataframe(&DataFrame::new(true, Opcode::Text, vec![0xC3, 0x28])).unwrap();
+		server.send_message(&Message::text("next")).unwrap();
+		assert!(client.recv_message().is_err());
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Text("next".to_string()));
+		client.send_message(&Message::text("still open")).unwrap();
+		assert_eq!(server.recv_message().unwrap(), OwnedMessage::Text("still open".to_string()));
+	}
+
+	#[test]
+	fn raw_close_reasons_are_kept_unless_strict() {
+		let connect = || {
+			let (mut client, server) = tcp_connection();
+			client.set_raw_close_reasons(true);
+			(client, server)
+		};
+		let close = DataFrame::new(true, Opcode::Close, vec![0x03, 0xE8, b'o', 0xFF, b'k']);
+
+		// relayed as received
+		let (mut client, mut server) = connect();
+		server.send_dataframe(&close).unwrap();
+		let received = match client.recv_message().unwrap() {
+			OwnedMessage::Close(Some(data)) => data,
+			other => panic!("expected a close, got {:?}", other),
+		};
+		assert_eq!(received.reason, "o\u{fffd}k");
+		assert_eq!(received.reason_raw, Some(vec![b'o', 0xFF, b'k']));
+		client.send_message(&OwnedMessage::Close(Some(received))).unwrap();
+		assert_eq!(server.recv_dataframe().unwrap().data, close.data);
+
+		// strict mode fails the connection, as without raw close reasons
+		let (mut client, mut server) = connect();
+		client.set_strict_mode(Some(StrictMode::new()));
+		server.send_dataframe(&close).unwrap();
+		match client.recv_message() {
+			Err(WebSocketError::Utf8Error(_)) => (),
+			other => panic!("expected a UTF-8 error, got {:?}", other),
+		}
+		assert_eq!(server.recv_message().unwrap(),
+		           OwnedMessage::Close(Some(CloseData::from_code(1007))));
+	}
+
+	#[test]
+	#[cfg(feature="unmasked-client")]
+	fn unmasked_links_work_both_ways() {
+		let (mut client, mut server) = tcp_connection();
+		client.use_unmasked_frames();
+		server.use_unmasked_frames();
+
+		client.send_message(&Message::text("unmasked")).unwrap();
+		assert_eq!(server.recv_message().unwrap(), OwnedMessage::Text("unmasked".to_string()));
+		server.send_message(&Message::text("as always")).unwrap();
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Text("as always".to_string()));
+	}
+
+	#[test]
+	fn unsolicited_pongs_are_accepted() {
+		use std::sync::{Arc, Mutex};
+
+		let (mut client, mut server) = tcp_connection();
+		let seen = Arc::new(Mutex::new(Vec::new()));
+		let record = seen.clone();
+		client.on_unsolicited_pong(move |payload| record.lock().unwrap().push(payload.to_vec()));
+
+		server.send_message(&Message::pong(b"beat".to_vec())).unwrap();
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Pong(b"beat".to_vec()));
+
+		client.set_ignore_unsolicited_pongs(true);
+		server.send_message(&Message::pong(b"skipped".to_vec())).unwrap();
+		server.send_message(&Message::text("after")).unwrap();
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Text("after".to_string()));
+		assert_eq!(*seen.lock().unwrap(), vec![b"beat".to_vec(), b"skipped".to_vec()]);
+	}
+
+	#[test]
+	fn auto_close_answers_the_closing_handshake() {
+		let (mut client, mut server) = tcp_connection();
+		client.set_auto_close(true);
+
+		let close = OwnedMessage::Close(Some(CloseData::new(1001, "bye".to_string())));
+		server.send_message(&close).unwrap();
+		assert_eq!(client.recv_message().unwrap(), close);
+		assert_eq!(server.recv_message().unwrap(),
+		           OwnedMessage::Close(Some(CloseData::from_code(1001))));
+		match client.recv_message() {
+			Err(WebSocketError::ConnectionClosed) => (),
+			other => panic!("expected the connection to be closed, got {:?}", other),
+		}
+
+		// the answer to our own close isn't answered again
+		let (mut client, mut server) = tcp_connection();
+		client.set_auto_close(true);
+
+		client.send_message(&OwnedMessage::Close(None)).unwrap();
+		server.send_message(&OwnedMessage::Close(None)).unwrap();
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Close(None));
+		drop(client);
+		assert_eq!(server.recv_message().unwrap(), OwnedMessage::Close(None));
+		match server.recv_message() {
+			Err(WebSocketError::ConnectionClosed) => (),
+			other => panic!("expected the stream to end, got {:?}", other),
+		}
+	}
+
+	#[test]
+	#[cfg(feature="testing")]
+	fn force_masking_masks_server_frames() {
+		use receiver::Receiver;
+		use ws::Receiver as ReceiverTrait;
+		use std::io::Cursor;
+
+		let (mut server, mut remote) = tcp_client();
+
+		server.force_masking(true);
+		server.send_message(&Message::text("masked")).unwrap();
+		server.shutdown().unwrap();
+
+		let mut written = Vec::new();
+		remote.read_to_end(&mut written).unwrap();
+		assert_eq!(written[1] & 0x80, 0x80);
+
+		// a conforming client rejects the frame
+		let mut receiver = Receiver::new(false, Uuid::new_v4());
+		assert!(receiver.recv_message(&mut Cursor::new(written)).is_err());
+	}
+
+	struct PipeReader {
+		chunks: mpsc::Receiver<Vec<u8>>,
+		current: Cursor<Vec<u8>>,
+	}
+
+	impl Read for PipeReader {
+		fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+			if self.current.position() as usize == self.current.get_ref().len() {
+				match self.chunks.recv() {
+					Ok(chunk) => self.current = Cursor::new(chunk),
+					Err(_) => return Ok(0),
+				}
+			}
+			self.current.read(buf)
+		}
+	}
+
+	struct PipeWriter(mpsc::Sender<Vec<u8>>);
+
+	impl Write for PipeWriter {
+		fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+			let _ = self.0.send(buf.to_vec());
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> IoResult<()> {
+			Ok(())
+		}
+	}
+
+	type Pipe = ReadWritePair<PipeReader, PipeWriter>;
+
+	fn pipe() -> (PipeReader, PipeWriter) {
+		let (tx, rx) = mpsc::channel();
+		(PipeReader {
+		     chunks: rx,
+		     current: Cursor::new(Vec::new()),
+		 },
+		 PipeWriter(tx))
+	}
+
+	// the client and server ends of an in memory connection
+	fn connection() -> (Client<Pipe>, Client<Pipe>) {
+		connection_with(Headers::new())
+	}
+
+	fn connection_with(headers: Headers) -> (Client<Pipe>, Client<Pipe>) {
+		let (client_read, server_write) = pipe();
+		let (server_read, client_write) = pipe();
+		let client = ReadWritePair(client_read, client_write);
+		let server = ReadWritePair(server_read, server_write);
+		(Client::unchecked(BufReader::new(client), headers.clone(), true, false),
+		 Client::unchecked(BufReader::new(server), headers, false, true))
+	}
+
+	// like `connection`, over TCP
+	fn tcp_connection() -> (Client<TcpStream>, Client<TcpStream>) {
+		let (server, client) = tcp_client();
+		(Client::unchecked(BufReader::new(client), Headers::new(), true, false), server)
+	}
+
+	// the server end of a TCP connection, and the raw stream of the other end
+	fn tcp_client() -> (Client<TcpStream>, TcpStream) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let stream = listener.accept().unwrap().0;
+		(Client::unchecked(BufReader::new(stream), Headers::new(), false, true), remote)
+	}
+
+	#[test]
+	fn boxed_streams_carry_messages() {
+		let (client_read, server_write) = pipe();
+		let (server_read, client_write) = pipe();
+		let client: BoxedStream = Box::new(ReadWritePair(client_read, client_write));
+		let server: BoxedStream = Box::new(ReadWritePair(server_read, server_write));
+		let mut client = Client::from_boxed_stream(client, Headers::new(), true);
+		let mut server = Client::from_boxed_stream(server, Headers::new(), false);
+
+		client.send_message(&Message::text("over a box")).unwrap();
+		assert_eq!(server.recv_message().unwrap(), OwnedMessage::Text("over a box".to_string()));
+		server.send_message(&Message::binary(vec![1, 2, 3])).unwrap();
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Binary(vec![1, 2, 3]));
+	}
+
+	// appends a marker byte to every frame and sets RSV1
+	struct Marker(&'static str, u8);
+
+	impl FrameExtension for Marker {
+		fn name(&self) -> &str {
+			self.0
+		}
+
+		fn on_send_frame(&mut self, frame: &mut DataFrame) {
+			frame.reserved[0] = true;
+			frame.data.push(self.1);
+		}
+
+		fn on_recv_frame(&mut self, frame: &mut DataFrame) -> WebSocketResult<()> {
+			if frame.data.pop() != Some(self.1) || !frame.reserved[0] {
+				return Err(WebSocketError::ProtocolError("Missing marker"));
+			}
+			if self.0 == "first" {
+				frame.reserved[0] = false;
+			}
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn extensions_change_frames_in_order() {
+		let mut headers = Headers::new();
+		headers.set(WebSocketExtensions(vec![Extension::new("first".to_string()),
+		                                     Extension::new("second".to_string())]));
+		let (mut client, mut server) = connection_with(headers);
+
+		for end in [&mut client, &mut server] {
+			assert!(end.register_extension(Box::new(Marker("first", b'1'))).unwrap());
+			assert!(end.register_extension(Box::new(Marker("second", b'2'))).unwrap());
+		}
+		let unknown = Box::new(Marker("third", b'3')) as Box<FrameExtension>;
+		assert!(!client.register_extension(unknown).unwrap());
+
+		client.send_message(&Message::text("hi")).unwrap();
+		assert_eq!(server.recv_message().unwrap(), OwnedMessage::Text("hi".to_string()));
+
+		server.send_dataframe(&DataFrame::new(true, Opcode::Binary, vec![1, 2])).unwrap();
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Binary(vec![1, 2]));
+	}
+
+	// "compresses" by flipping all bits, and sets RSV1 on every frame it sends
+	// like some broken implementations do
+	struct FakeDeflate {
+		compressed: bool,
+	}
+
+	impl FrameExtension for FakeDeflate {
+		fn name(&self) -> &str {
+			PERMESSAGE_DEFLATE
+		}
+
+		fn on_send_frame(&mut self, frame: &mut DataFrame) {
+			frame.reserved[0] = true;
+			for byte in &mut frame.data {
+				*byte = !*byte;
+			}
+		}
+
+		fn on_recv_frame(&mut self, frame: &mut DataFrame) -> WebSocketResult<()> {
+			if frame.opcode != Opcode::Continuation {
+				self.compressed = frame.reserved[0];
+			}
+			if self.compressed {
+				for byte in &mut frame.data {
+					*byte = !*byte;
+				}
+			}
+			frame.reserved[0] = false;
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn deflate_sets_rsv1_on_the_first_frame_only() {
+		let deflate = || Box::new(FakeDeflate { compressed: false }) as Box<FrameExtension>;
+		let fragments = || {
+			vec![DataFrame::new(false, Opcode::Text, b"com".to_vec()),
+			     DataFrame::new(false, Opcode::Continuation, b"pre".to_vec()),
+			     DataFrame::new(true, Opcode::Continuation, b"ssed".to_vec())]
+		};
+
+		let mut headers = Headers::new();
+		headers.set(WebSocketExtensions(vec![Extension::new(PERMESSAGE_DEFLATE.to_string())]));
+		let (mut client, mut server) = connection_with(headers);
+		assert!(client.register_extension(deflate()).unwrap());
+
+		for fragment in fragments() {
+			client.send_dataframe(&fragment).unwrap();
+		}
+		let rsv1: Vec<bool> = (0..3).map(|_| server.recv_dataframe().unwrap().reserved[0]).collect();
+		assert_eq!(rsv1, vec![true, false, false]);
+
+		assert!(server.register_extension(deflate()).unwrap());
+		for fragment in fragments() {
+			client.send_dataframe(&fragment).unwrap();
+		}
+		assert_eq!(server.recv_message().unwrap(), OwnedMessage::Text("compressed".to_string()));
+
+		// a continuation frame with RSV1 set is a protocol error
+		let mut fragments = fragments();
+		fragments[1].reserved[0] = true;
+		for fragment in fragments.iter().take(2) {
+			fragment.write_to(client.writer_mut(), true).unwrap();
+		}
+		match server.recv_message() {
+			Err(WebSocketError::ProtocolError("RSV1 set on a continuation frame")) => (),
+			other => panic!("expected a protocol error, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn drain_until_close_discards_data_and_answers_pings() {
+		let (mut client, mut server) = connection();
+
+		client.send_message(&Message::text("ignored")).unwrap();
+		client.send_message(&Message::ping(b"ping".to_vec())).unwrap();
+		client.send_message(&Message::binary(vec![0; 16])).unwrap();
+		client.send_message(&Message::close()).unwrap();
+		server.drain_until_close(100).unwrap();
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Pong(b"ping".to_vec()));
+
+		// nothing can follow the close on the same connection
+		let (mut client, mut server) = connection();
+		client.send_message(&Message::binary(vec![0; 16])).unwrap();
+		client.send_message(&Message::close()).unwrap();
+		match server.drain_until_close(10) {
+			Err(WebSocketError::ProtocolError(_)) => (),
+			other => panic!("expected a protocol error, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn is_open_follows_the_close_handshake() {
+		let (mut client, mut server) = connection();
+		assert!(client.is_open() && server.is_open());
+
+		client.send_message(&Message::close()).unwrap();
+		assert!(!client.is_open());
+		match client.send_message(&Message::text("late")) {
+			Err(WebSocketError::AlreadyClosed) => (),
+			other => panic!("expected the client to be closed, got {:?}", other),
+		}
+		client.send_message(&Message::pong(b"still answered".to_vec())).unwrap();
+
+		assert_eq!(server.recv_message().unwrap(), OwnedMessage::Close(None));
+		assert!(!server.is_open());
+		server.send_message(&Message::close()).unwrap();
+		match server.send_text("late") {
+			Err(WebSocketError::AlreadyClosed) => (),
+			other => panic!("expected the server to be closed, got {:?}", other),
+		}
+
+		// a peer that goes away fails the connection
+		let (mut client, server) = connection();
+		drop(server);
+		assert!(client.recv_message().is_err());
+		assert!(!client.is_open());
+		match client.send_message(&Message::ping(b"anyone?".to_vec())) {
+			Err(WebSocketError::AlreadyClosed) => (),
+			other => panic!("expected the client to be closed, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn messages_answers_control_messages() {
+		let (mut client, mut server) = connection();
+		server.send_message(&Message::ping(b"1".to_vec())).unwrap();
+		server.send_message(&Message::text("hello")).unwrap();
+		server.send_message(&Message::pong(b"2".to_vec())).unwrap();
+		server.send_message(&Message::binary(vec![3])).unwrap();
+		server.send_message(&Message::close_because(1001, "bye")).unwrap();
+
+		let messages: Vec<_> = client.messages().map(Result::unwrap).collect();
+		assert_eq!(messages,
+		           vec![OwnedMessage::Text("hello".to_string()), OwnedMessage::Binary(vec![3])]);
+		assert_eq!(server.recv_message().unwrap(), OwnedMessage::Pong(b"1".to_vec()));
+		assert_eq!(server.recv_message().unwrap(),
+		           OwnedMessage::Close(Some(CloseData::from_code(1001))));
+		assert!(!client.is_open());
+	}
+
+	#[test]
+	fn proxy_forwards_between_clients() {
+		let (mut client, proxied_server) = connection();
+		let (proxied_client, mut server) = connection();
+
+		let proxy = thread::spawn(move || proxy(proxied_server, proxied_client));
+
+		client.send_message(&Message::text("hello")).unwrap();
+		assert_eq!(server.recv_message().unwrap(), OwnedMessage::Text("hello".to_string()));
+
+		server.send_message(&Message::ping(b"still there?".to_vec())).unwrap();
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Ping(b"still there?".to_vec()));
+
+		client.send_message(&Message::close()).unwrap();
+		assert_eq!(server.recv_message().unwrap(), OwnedMessage::Close(None));
+		server.send_message(&Message::close()).unwrap();
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Close(None));
+
+		assert_eq!(proxy.join().unwrap().unwrap(), ProxySide::A);
+	}
+
+	#[test]
+	fn proxy_ends_both_directions_when_one_fails() {
+		let (mut client, proxied_server) = tcp_connection();
+		let (proxied_client, server) = tcp_connection();
+
+		let (done, result) = mpsc::channel();
+		thread::spawn(move || done.send(proxy(proxied_server, proxied_client)).unwrap());
+
+		// the client never sends anything, its direction is ended by the proxy
+		drop(server);
+		let result = result.recv_timeout(Duration::from_secs(5)).expect("the proxy should stop");
+		assert!(result.is_err());
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Close(None));
+	}
+
+	#[test]
+	fn addresses_are_available_after_split() {
+		let (client, remote) = tcp_client();
+
+		assert_eq!(client.local_addr().unwrap(), remote.peer_addr().unwrap());
+		assert_eq!(client.peer_addr().unwrap(), remote.local_addr().unwrap());
+
+		let (reader, writer) = client.split().unwrap();
+		assert_eq!(reader.local_addr().unwrap(), remote.peer_addr().unwrap());
+		assert_eq!(writer.peer_addr().unwrap(), remote.local_addr().unwrap());
+	}
+
+	#[test]
+	fn set_keepalive_toggles_socket_option() {
+		let (client, _remote) = tcp_client();
+
+		client.set_keepalive(Some(Duration::from_secs(30))).unwrap();
+		assert!(SockRef::from(client.stream_ref()).keepalive().unwrap());
+
+		client.set_keepalive(None).unwrap();
+		assert!(!SockRef::from(client.stream_ref()).keepalive().unwrap());
+	}
+
+	#[test]
+	fn set_linger_sets_socket_option() {
+		let (client, _remote) = tcp_client();
+
+		client.set_linger(Some(Duration::from_secs(0))).unwrap();
+		assert_eq!(SockRef::from(client.stream_ref()).linger().unwrap(), Some(Duration::from_secs(0)));
+
+		client.set_linger(None).unwrap();
+		assert_eq!(SockRef::from(client.stream_ref()).linger().unwrap(), None);
+	}
+
+	#[test]
+	fn buffered_sends_wait_for_flush() {
+		let (mut client, mut remote) = tcp_client();
+		client.set_send_buffer(Some(1024)).unwrap();
+		let mut expected = Vec::new();
+		let mut sender = Sender::new(false);
+		for text in &["first", "second"] {
+			client.send_message(&Message::text(*text)).unwrap();
+			sender.send_message(&mut expected, &Message::text(*text)).unwrap();
+		}
+
+		// nothing was written to the socket yet
+		remote.set_nonblocking(true).unwrap();
+		assert_eq!(remote.read(&mut [0]).unwrap_err().kind(), ErrorKind::WouldBlock);
+		remote.set_nonblocking(false).unwrap();
+
+		client.flush().unwrap();
+		let mut received = vec![0; expected.len()];
+		remote.read_exact(&mut received).unwrap();
+		assert_eq!(received, expected);
+
+		// a full buffer is written out without a flush
+		let large = Message::binary(vec![1; 2000]);
+		client.send_message(&large).unwrap();
+		let mut expected = Vec::new();
+		sender.send_message(&mut expected, &large).unwrap();
+		let mut received = vec![0; expected.len()];
+		remote.read_exact(&mut received).unwrap();
+		assert_eq!(received, expected);
+	}
+}
@@ -9,7 +9,6 @@
 
 use std::borrow::Borrow;
 use std::marker::PhantomData;
-use std::io::Cursor;
 use std::mem;
 
 use tokio_io::codec::Decoder;
@@ -17,11 +16,12 @@ use tokio_io::codec::Encoder;
 use bytes::BytesMut;
 use bytes::BufMut;
 
-use dataframe::DataFrame;
+use dataframe::{self, DataFrame, Opcode};
 use message::OwnedMessage;
 use ws::dataframe::DataFrame as DataFrameTrait;
 use ws::message::Message as MessageTrait;
-use ws::util::header::read_header;
+use ws::util::framing::decode_header;
+use ws::util::header;
 use result::WebSocketError;
 
 /// Even though a websocket connection may look perfectly symmetrical
@@ -59,7 +59,6 @@ pub enum Context {
 pub struct DataFrameCodec<D> {
 	is_server: bool,
 	frame_type: PhantomData<D>,
-	reader_state: ::ws::util::header::ReaderState,
 }
 
 impl DataFrameCodec<DataFrame> {
@@ -84,7 +83,6 @@ impl<D> DataFrameCodec<D> {
 		DataFrameCodec {
 			is_server: context == Context::Server,
 			frame_type: PhantomData,
-			reader_state: ::ws::util::header::ReaderState::new(),
 		}
 	}
 }
@@ -93,28 +91,23 @@ impl<D> Decoder for DataFrameCodec<D> {
 	type Item = DataFrame;
 	type Error = WebSocketError;
 
-	// TODO: do not retry to read the header on each new data (keep a buffer)
 	fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-		let (header, bytes_read) = {
-			// we'll make a fake reader and keep track of the bytes read
-			let mut reader = Cursor::new(src.as_ref());
-
-			// read header to get the size, bail if not enough
-			let header = match read_header(&mut reader, &mut self.reader_state) {
-				Ok(head) => head,
-				Err(WebSocketError::NoDataAvailable) => return Ok(None),
-				Err(e) => return Err(e),
-			};
-
-			(header, reader.position())
+		// bail if the whole header isn't there yet
+		let (header, bytes_read) = match decode_header(src.as_ref())? {
+			Some((header, len)) => (header, len as u64),
+			None => return Ok(None),
 		};
 
+		// on 32 bit targets the payload might not fit in memory at all
+		if header.len > usize::MAX as u64 {
+			return Err(header::too_large_for_platform(header.len));
+		}
+
 		// check if we have enough bytes to continue
 		if header.len + bytes_read > src.len() as u64 {
 			return Ok(None);
 		}
 
-		// TODO: using usize is not the right thing here (can be larger)
 		let _ = src.split_to(bytes_read as usize);
 		let body = src.split_to(header.len as usize).to_vec();
 
@@ -244,18 +237,17 @@ impl<M> Decoder for MessageCodec<M>
 			let is_first = self.buffer.is_empty();
 			let finished = frame.finished;
 
-			match frame.opcode as u8 {
-				// continuation code
-				0 if is_first => {
+			match frame.opcode {
+				Opcode::Continuation if is_first => {
 					return Err(WebSocketError::ProtocolError("Unexpected continuation data frame opcode",),);
 				}
-				// control frame
-				8...15 => {
+				opcode if opcode.is_control() => {
 					return Ok(Some(OwnedMessage::from_dataframes(vec![frame])?));
 				}
-				// data frame
-				1...7 if !is_first => {
-					return Err(WebSocketError::ProtocolError("Unexpected data frame opcode"));
+				// a data frame other than a continuation
+				opcode if opcode != Opcode::Continuation && !is_first => {
+					self.buffer.clear();
+					return Err(dataframe::interleaved_data_frame_error(opcode as u8));
 				}
 				// its good
 				_ => {
@@ -314,6 +306,7 @@ mod tests {
 			OwnedMessage::Close(Some(CloseData {
 			                             status_code: 64,
 			                             reason: "because".to_string(),
+			                             reason_raw: None,
 			                         })),
 		];
 
@@ -1,13 +1,18 @@
 //! Module containing the default implementation of data frames.
+use std::cmp;
 use std::io::{self, Read, Write};
 use result::{WebSocketResult, WebSocketError};
 use ws::dataframe::DataFrame as DataFrameable;
+use ws::util::framing;
 use ws::util::header::DataFrameHeader;
 use ws::util::header::{self as dfh, DataFrameFlags, ReaderState};
 use ws::util::mask;
 use uuid::Uuid;
 use ::receiver::PacketState;
 
+/// The largest part of a payload `DataFrame::read_dataframe` reads at once.
+pub const DEFAULT_READ_CHUNK_SIZE: usize = 8 * 1024;
+
 /// Represents a WebSocket data frame.
 ///
 /// The data held in a DataFrame is never masked.
@@ -82,6 +87,9 @@ impl DataFrame {
 	}
 
 	/// Reads a DataFrame from a Reader.
+	///
+	/// The payload is read in chunks of at most 8 KiB, see
+	/// `read_dataframe_chunked` to change that.
 	pub fn read_dataframe<R>(
 		reader: &mut R,
 		should_be_masked: bool,
@@ -91,6 +99,33 @@ impl DataFrame {
 	) -> WebSocketResult<Self>
 		where R: Read
 	{
+		DataFrame::read_dataframe_chunked(
+			reader,
+			should_be_masked,
+			uuid,
+			packet_state,
+			reader_state,
+			DEFAULT_READ_CHUNK_SIZE,
+		)
+	}
+
+	/// Reads a DataFrame from a Reader, reading at most `chunk_size` bytes of
+	/// the payload per call to `read`.
+	///
+	/// # Panics
+	///
+	/// Panics if `chunk_size` is 0.
+	pub fn read_dataframe_chunked<R>(
+		reader: &mut R,
+		should_be_masked: bool,
+		uuid: Uuid,
+		packet_state: &mut PacketState,
+		reader_state: &mut ReaderState,
+		chunk_size: usize,
+	) -> WebSocketResult<Self>
+		where R: Read
+	{
+		assert!(chunk_size > 0, "read chunk size must not be 0");
 		trace!("Reading data for {}", uuid);
 
 		let frame = {
@@ -101,30 +136,42 @@ impl DataFrame {
 
 			let header = packet_state.header.unwrap();
 
-			//	If this is a new packet, allocate space for it.
-			if packet_state.packet.capacity() != header.len as usize {
-				packet_state.packet = Vec::with_capacity(header.len as usize);
+			//	On 32 bit targets the payload might not fit in memory at all.
+			if header.len > usize::MAX as u64 {
+				packet_state.reset();
+				return Err(dfh::too_large_for_platform(header.len));
+			}
+
+			//	If this is a new packet, allocate space for its first chunk. The
+			//	announced length isn't trusted, the packet grows as chunks arrive.
+			if packet_state.packet.is_empty() {
+				packet_state.packet = Vec::with_capacity(cmp::min(header.len, chunk_size as u64) as usize);
 			}
 
-			let len = packet_state.packet.len();
-			let mut data = Vec::new();
-
-			if let Err(why) = reader.take(header.len - len as u64).read_to_end(&mut data) {
-				// Could not read entire packet at once
-				// Store what we got and return the error.
-				debug!("Read failure, read {} bytes", data.len());
-				packet_state.packet.append(&mut data);
-				debug!("Current packet size: {} / {}", packet_state.packet.len(), header.len);
-				return Err(WebSocketError::IoError(why));
-			};
-
-			//	Append the last of the data to the packet.
-			packet_state.packet.append(&mut data);
-
-			//	If there's still not enough data, then something is wrong.
-			if (packet_state.packet.len() as u64) < header.len {
-				debug!("Incomplete packet: {} / {}", packet_state.packet.len(), header.len);
-				return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "incomplete payload").into());
+			//	Read straight into the packet, keeping what was read if a read fails.
+			while (packet_state.packet.len() as u64) < header.len {
+				let start = packet_state.packet.len();
+				let remaining = header.len - start as u64;
+				let chunk = cmp::min(remaining, chunk_size as u64) as usize;
+				packet_state.packet.resize(start + chunk, 0);
+
+				match reader.read(&mut packet_state.packet[start..]) {
+					Ok(0) => {
+						//	The stream ended before the payload did.
+						packet_state.packet.truncate(start);
+						debug!("Incomplete packet: {} / {}", start, header.len);
+						return Err(WebSocketError::ProtocolError(dfh::TRUNCATED_FRAME));
+					}
+					Ok(read) => packet_state.packet.truncate(start + read),
+					Err(ref why) if why.kind() == io::ErrorKind::Interrupted => {
+						packet_state.packet.truncate(start)
+					}
+					Err(why) => {
+						packet_state.packet.truncate(start);
+						debug!("Read failure, current packet size: {} / {}", start, header.len);
+						return Err(WebSocketError::IoError(why));
+					}
+				}
 			}
 
 			match String::from_utf8(packet_state.packet.clone()) {
@@ -173,6 +220,11 @@ impl DataFrameable for DataFrame {
 	fn take_payload(self) -> Vec<u8> {
 		self.data
 	}
+
+	#[inline(always)]
+	fn payload_slice(&self) -> Option<&[u8]> {
+		Some(&self.data)
+	}
 }
 
 /// Represents a WebSocket data frame opcode
@@ -237,13 +289,44 @@ impl Opcode {
 		         _ => return None,
 		     })
 	}
+
+	/// Whether this is the opcode of a control frame: `Close`, `Ping`, `Pong`
+	/// or one of the reserved control opcodes.
+	pub fn is_control(&self) -> bool {
+		framing::is_control_opcode(*self as u8)
+	}
+
+	/// Whether this is the opcode of a data frame.
+	///
+	/// `Continuation` is a data opcode too, but only ever continues a
+	/// fragmented message that started with another data opcode.
+	pub fn is_data(&self) -> bool {
+		framing::is_data_opcode(*self as u8)
+	}
+}
+
+/// The error for a data frame other than a continuation arriving in the
+/// middle of a fragmented message.
+pub(crate) fn interleaved_data_frame_error(opcode: u8) -> WebSocketError {
+	WebSocketError::ProtocolError(match opcode {
+		1 => "Unexpected text data frame in a fragmented message, expected a continuation",
+		2 => "Unexpected binary data frame in a fragmented message, expected a continuation",
+		_ => "Unexpected reserved data frame in a fragmented message, expected a continuation",
+	})
 }
 
-#[cfg(all(feature = "nightly", test))]
+#[cfg(test)]
 mod tests {
 	use super::*;
 	use ws::dataframe::DataFrame as DataFrameable;
-	use test::Bencher;
+
+	fn read(input: &[u8]) -> WebSocketResult<DataFrame> {
+		DataFrame::read_dataframe(&mut &input[..],
+		                          false,
+		                          Uuid::nil(),
+		                          &mut PacketState::default(),
+		                          &mut ReaderState::new())
+	}
 
 	#[test]
 	fn test_read_dataframe() {
@@ -252,7 +335,7 @@ mod tests {
 		for i in data.iter() {
 			dataframe.push(*i);
 		}
-		let obtained = DataFrame::read_dataframe(&mut &dataframe[..], false).unwrap();
+		let obtained = read(&dataframe).unwrap();
 		let expected = DataFrame {
 			finished: true,
 			reserved: [false; 3],
@@ -266,30 +349,21 @@ mod tests {
 	fn read_incomplete_payloads() {
 		let mut data = vec![0x8au8, 0x08, 0x19, 0xac, 0xab, 0x8a, 0x52, 0x4e, 0x05, 0x00];
 		let payload = vec![25, 172, 171, 138, 82, 78, 5, 0];
-		let short_header = DataFrame::read_dataframe(&mut &data[..1], false);
-		let short_payload = DataFrame::read_dataframe(&mut &data[..6], false);
-		let full_payload = DataFrame::read_dataframe(&mut &data[..], false);
+		let short_header = read(&data[..1]);
+		let short_payload = read(&data[..6]);
+		let full_payload = read(&data[..]);
 		data.push(0xff);
-		let more_payload = DataFrame::read_dataframe(&mut &data[..], false);
+		let more_payload = read(&data[..]);
 
 		match (short_header.unwrap_err(), short_payload.unwrap_err()) {
-			(WebSocketError::NoDataAvailable, WebSocketError::NoDataAvailable) => (),
-			_ => assert!(false),
+			(WebSocketError::ProtocolError(header), WebSocketError::ProtocolError(payload))
+				if header == dfh::TRUNCATED_FRAME && payload == dfh::TRUNCATED_FRAME => (),
+			errors => panic!("expected truncated frames, got {:?}", errors),
 		};
 		assert_eq!(full_payload.unwrap().data, payload);
 		assert_eq!(more_payload.unwrap().data, payload);
 	}
 
-	#[bench]
-	fn bench_read_dataframe(b: &mut Bencher) {
-		let data = b"The quick brown fox jumps over the lazy dog";
-		let mut dataframe = vec![0x81, 0x2B];
-		for i in data.iter() {
-			dataframe.push(*i);
-		}
-		b.iter(|| { DataFrame::read_dataframe(&mut &dataframe[..], false).unwrap(); });
-	}
-
 	#[test]
 	fn test_write_dataframe() {
 		let data = b"The quick brown fox jumps over the lazy dog";
@@ -308,17 +382,4 @@ mod tests {
 
 		assert_eq!(&obtained[..], &expected[..]);
 	}
-
-	#[bench]
-	fn bench_write_dataframe(b: &mut Bencher) {
-		let data = b"The quick brown fox jumps over the lazy dog";
-		let dataframe = DataFrame {
-			finished: true,
-			reserved: [false; 3],
-			opcode: Opcode::Text,
-			data: data.to_vec(),
-		};
-		let mut writer = Vec::with_capacity(45);
-		b.iter(|| { dataframe.write_to(&mut writer, false).unwrap(); });
-	}
 }
@@ -0,0 +1,331 @@
+//! The permessage-deflate extension (RFC7692).
+//!
+//! Servers agree to it per connection with `Upgrade::negotiate_deflate`, the
+//! accepted client then compresses what it sends and decompresses what it
+//! receives, while other connections of the same server stay uncompressed.
+//! Clients offer `PerMessageDeflate::client().offer()` in the handshake and
+//! register the extension with `Client::register_extension` afterwards.
+//!
+//! Compression always uses a window of 32 KiB, offers asking the server for a
+//! smaller one are declined.
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use dataframe::{DataFrame, Opcode};
+use header::extensions::{Extension as ExtensionHeader, Parameter};
+use result::{WebSocketResult, WebSocketError};
+use ws::extension::{Extension, PERMESSAGE_DEFLATE};
+
+const SERVER_NO_CONTEXT_TAKEOVER: &str = "server_no_context_takeover";
+const CLIENT_NO_CONTEXT_TAKEOVER: &str = "client_no_context_takeover";
+const SERVER_MAX_WINDOW_BITS: &str = "server_max_window_bits";
+const CLIENT_MAX_WINDOW_BITS: &str = "client_max_window_bits";
+
+// what a sync flush ends with, left off the end of every compressed message
+const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Compresses and decompresses the messages of one connection.
+pub struct PerMessageDeflate {
+	server: bool,
+	compress: Compress,
+	decompress: Decompress,
+	// whether the compressor starts over for every message
+	reset_after_message: bool,
+	// whether the message being received is compressed
+	receiving_compressed: bool,
+}
+
+impl PerMessageDeflate {
+	/// The extension for the server side of a connection.
+	pub fn server() -> Self {
+		PerMessageDeflate::new(true)
+	}
+
+	/// The extension for the client side of a connection.
+	pub fn client() -> Self {
+		PerMessageDeflate::new(false)
+	}
+
+	fn new(server: bool) -> Self {
+		PerMessageDeflate {
+			server,
+			compress: Compress::new(Compression::default(), false),
+			decompress: Decompress::new(false),
+			reset_after_message: false,
+			receiving_compressed: false,
+		}
+	}
+
+	fn deflate(&mut self, data: &[u8]) -> Vec<u8> {
+		let mut out = Vec::with_capacity(data.len() / 2 + 64);
+		let start = self.compress.total_in();
+		loop {
+			let consumed = (self.compress.total_in() - start) as usize;
+			self.compress
+			    .compress_vec(&data[consumed..], &mut out, FlushCompress::Sync)
+			    .expect("deflating never fails");
+			let consumed = (self.compress.total_in() - start) as usize;
+			if consumed == data.len() && out.len() < out.capacity() {
+				return out;
+			}
+			let more = out.capacity().max(64);
+			out.reserve(more);
+		}
+	}
+
+	// fails as soon as more than `max_len` bytes came out, if given
+	fn inflate(&mut self, data: &[u8], max_len: Option<usize>) -> WebSocketResult<Vec<u8>> {
+		let mut out = Vec::with_capacity(data.len() * 2 + 64);
+		let start = self.decompress.total_in();
+		loop {
+			let consumed = (self.decompress.total_in() - start) as usize;
+			let status = self.decompress
+			                 .decompress_vec(&data[consumed..], &mut out, FlushDecompress::Sync)
+			                 .map_err(|_| WebSocketError::ProtocolError("Invalid compressed data"))?;
+			let consumed = (self.decompress.total_in() - start) as usize;
+			match max_len {
+				Some(max) if out.len() > max => {
+					return Err(WebSocketError::MessageTooLarge {
+					               limit: max as u64,
+					               actual: out.len() as u64,
+					               frame: true,
+					           });
+				}
+				_ => (),
+			}
+			if status == Status::StreamEnd || consumed == data.len() && out.len() < out.capacity() {
+				return Ok(out);
+			}
+			let more = out.capacity();
+			out.reserve(more);
+		}
+	}
+}
+
+/// Picks the first of the client's offers this implementation accepts and
+/// returns the entry to answer it with, if any.
+pub fn accept_offer(offers: &[ExtensionHeader]) -> Option<ExtensionHeader> {
+	offers.iter()
+	      .filter(|offer| offer.name == PERMESSAGE_DEFLATE)
+	      .filter_map(answer)
+	      .next()
+}
+
+fn answer(offer: &ExtensionHeader) -> Option<ExtensionHeader> {
+	let mut answer = ExtensionHeader::new(PERMESSAGE_DEFLATE.to_string());
+	let mut seen: Vec<&str> = Vec::new();
+	for param in &offer.params {
+		if seen.contains(&&*param.name) {
+			return None;
+		}
+		seen.push(&param.name);
+		match (&*param.name, param.value.as_deref()) {
+			(SERVER_NO_CONTEXT_TAKEOVER, None) |
+			(CLIENT_NO_CONTEXT_TAKEOVER, None) => {
+				answer.params.push(Parameter::new(param.name.clone(), None));
+			}
+			(SERVER_MAX_WINDOW_BITS, Some("15")) => {
+				answer.params.push(param.clone());
+			}
+			// the client uses whatever window it likes, inflating copes with all
+			(CLIENT_MAX_WINDOW_BITS, None) => (),
+			(CLIENT_MAX_WINDOW_BITS, Some(bits)) if window_bits(bits) => (),
+			_ => return None,
+		}
+	}
+	Some(answer)
+}
+
+fn window_bits(value: &str) -> bool {
+	match value.parse::<u8>() {
+		Ok(8..=15) => !value.starts_with('0'),
+		_ => false,
+	}
+}
+
+impl Extension for PerMessageDeflate {
+	fn name(&self) -> &str {
+		PERMESSAGE_DEFLATE
+	}
+
+	fn negotiated(&mut self, agreed: &ExtensionHeader) -> WebSocketResult<()> {
+		let (own_takeover, own_window) = if self.server {
+			(SERVER_NO_CONTEXT_TAKEOVER, SERVER_MAX_WINDOW_BITS)
+		} else {
+			(CLIENT_NO_CONTEXT_TAKEOVER, CLIENT_MAX_WINDOW_BITS)
+		};
+		for param in &agreed.params {
+			let value = param.value.as_deref();
+			if param.name == own_takeover {
+				self.reset_after_message = true;
+			} else if param.name == own_window {
+				if value != Some("15") {
+					return Err(WebSocketError::ProtocolError("Unsupported deflate window size"));
+				}
+			} else if param.name != SERVER_NO_CONTEXT_TAKEOVER &&
+			          param.name != CLIENT_NO_CONTEXT_TAKEOVER &&
+			          param.name != SERVER_MAX_WINDOW_BITS &&
+			          param.name != CLIENT_MAX_WINDOW_BITS {
+				return Err(WebSocketError::ProtocolError("Unknown deflate parameter"));
+			}
+		}
+		Ok(())
+	}
+
+	// `negotiated` is called again for the next connection
+	fn reset(&mut self) {
+		*self = PerMessageDeflate::new(self.server);
+	}
+
+	fn on_send_frame(&mut self, frame: &mut DataFrame) {
+		if frame.opcode.is_control() {
+			return;
+		}
+		let mut data = self.deflate(&frame.data);
+		if frame.finished {
+			if data.ends_with(&TRAILER) {
+				let len = data.len() - TRAILER.len();
+				data.truncate(len);
+			}
+			if self.reset_after_message {
+				self.compress.reset();
+			}
+		}
+		frame.data = data;
+		frame.reserved[0] = frame.opcode != Opcode::Continuation;
+	}
+
+	fn on_recv_frame(&mut self, frame: &mut DataFrame) -> WebSocketResult<()> {
+		self.on_recv_frame_within(frame, None)
+	}
+
+	fn on_recv_frame_within(&mut self, frame: &mut DataFrame, max_len: Option<usize>) -> WebSocketResult<()> {
+		if frame.opcode.is_control() {
+			return Ok(());
+		}
+		if frame.opcode != Opcode::Continuation {
+			self.receiving_compressed = frame.reserved[0];
+		}
+		frame.reserved[0] = false;
+		if !self.receiving_compressed {
+			return Ok(());
+		}
+		if frame.finished {
+			frame.data.extend_from_slice(&TRAILER);
+		}
+		frame.data = self.inflate(&frame.data, max_len)?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn offer(params: &[(&str, Option<&str>)]) -> ExtensionHeader {
+		let mut offer = ExtensionHeader::new(PERMESSAGE_DEFLATE.to_string());
+		for &(name, value) in params {
+			offer.params.push(Parameter::new(name.to_string(), value.map(|v| v.to_string())));
+		}
+		offer
+	}
+
+	#[test]
+	fn offers_are_answered_or_declined() {
+		assert_eq!(accept_offer(&[offer(&[])]), Some(offer(&[])));
+		assert_eq!(accept_offer(&[offer(&[(CLIENT_MAX_WINDOW_BITS, None),
+		                                  (SERVER_NO_CONTEXT_TAKEOVER, None)])]),
+		           Some(offer(&[(SERVER_NO_CONTEXT_TAKEOVER, None)])));
+		// a smaller server window is declined in favour of the fallback offer
+		assert_eq!(accept_offer(&[offer(&[(SERVER_MAX_WINDOW_BITS, Some("10"))]),
+		                          offer(&[(CLIENT_NO_CONTEXT_TAKEOVER, None)])]),
+		           Some(offer(&[(CLIENT_NO_CONTEXT_TAKEOVER, None)])));
+		assert_eq!(accept_offer(&[offer(&[("unknown", None)])]), None);
+		assert_eq!(accept_offer(&[ExtensionHeader::new("other".to_string())]), None);
+	}
+
+	#[test]
+	fn fragmented_messages_round_trip() {
+		let mut sender = PerMessageDeflate::client();
+		let mut receiver = PerMessageDeflate::server();
+		let agreed = offer(&[(CLIENT_NO_CONTEXT_TAKEOVER, None)]);
+		sender.negotiated(&agreed).unwrap();
+		receiver.negotiated(&agreed).unwrap();
+
+		for _ in 0..2 {
+			let mut frames = vec![DataFrame::new(false, Opcode::Text, b"hello hello ".to_vec()),
+			                      DataFrame::new(true, Opcode::Continuation, b"hello".to_vec())];
+			for frame in &mut frames {
+				sender.on_send_frame(frame);
+			}
+			assert_eq!(frames.iter().map(|f| f.reserved[0]).collect::<Vec<_>>(), vec![true, false]);
+
+			let mut data = Vec::new();
+			for frame in &mut frames {
+				receiver.on_recv_frame(frame).unwrap();
+				data.extend_from_slice(&frame.data);
+			}
+			assert_eq!(data, b"hello hello hello");
+		}
+
+		// RFC7692 7.2.3.1
+		let mut frame = DataFrame::new(true, Opcode::Text, vec![0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]);
+		frame.reserved[0] = true;
+		receiver.on_recv_frame(&mut frame).unwrap();
+		assert_eq!(frame.data, b"Hello");
+	}
+
+	#[test]
+	fn inflating_stops_at_the_message_size() {
+		use std::io::Cursor;
+		use std::sync::{Arc, Mutex};
+		use uuid::Uuid;
+		use receiver::Receiver;
+		use ws::dataframe::DataFrame as DataFrameable;
+		use ws::receiver::Receiver as ReceiverTrait;
+
+		// 64 MiB of zeros compress to a frame of less than 1 MiB
+		let mut sender = PerMessageDeflate::client();
+		let mut frame = DataFrame::new(true, Opcode::Binary, vec![0; 64 << 20]);
+		sender.on_send_frame(&mut frame);
+		assert!(frame.data.len() < 1 << 20);
+		let mut input = Vec::new();
+		frame.write_to(&mut input, false).unwrap();
+
+		let mut receiver = Receiver::new(false, Uuid::nil());
+		receiver.set_max_message_size(Some(1 << 20));
+		receiver.add_extension(Arc::new(Mutex::new(Box::new(PerMessageDeflate::server()))));
+		match receiver.recv_message(&mut Cursor::new(input)) {
+			Err(WebSocketError::MessageTooLarge { limit, actual, frame: true }) => {
+				assert_eq!(limit, 1 << 20);
+				// inflating stopped early instead of producing all 64 MiB
+				assert!(actual < 4 << 20, "inflated {} bytes", actual);
+			}
+			other => panic!("expected the message to be too large, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn negotiated_parameters_reach_both_clients() {
+		use std::thread;
+		use client::builder::ClientBuilder;
+		use server::sync::Server;
+
+		let mut server = Server::bind("127.0.0.1:0").unwrap();
+		let url = format!("ws://{}", server.local_addr().unwrap());
+		let accepting = thread::spawn(move || {
+			let mut upgrade = server.accept().ok().unwrap();
+			assert!(upgrade.negotiate_deflate());
+			let client = upgrade.accept().ok().unwrap();
+			(client.extensions().to_vec(), client.uses_deflate())
+		});
+
+		let client = ClientBuilder::new(&url)
+			.unwrap()
+			.add_extension(offer(&[(SERVER_NO_CONTEXT_TAKEOVER, None), (CLIENT_MAX_WINDOW_BITS, None)]))
+			.connect_insecure()
+			.unwrap();
+
+		let agreed = vec![offer(&[(SERVER_NO_CONTEXT_TAKEOVER, None)])];
+		assert_eq!(client.extensions(), &agreed[..]);
+		assert_eq!(accepting.join().unwrap(), (agreed, true));
+	}
+}
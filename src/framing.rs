@@ -0,0 +1,139 @@
+//! Framing state that can be carried over from one connection to the next.
+use uuid::Uuid;
+
+use header::extensions::Extension as ExtensionHeader;
+use receiver::Receiver;
+use result::WebSocketResult;
+use sender::Sender;
+
+/// The framing of a connection, the receiver turning bytes into messages and
+/// the sender turning messages into bytes, apart from the stream.
+///
+/// Everything configured on them, like the maximum message size, the rate
+/// limit or registered extensions, is kept when the codec is `reset`, so it
+/// can be handed to `Client::set_frame_codec` for the next connection instead
+/// of setting everything up again. `ReconnectingClient` does this by itself.
+///
+///```rust
+///use websocket::sync::FrameCodec;
+///
+///let mut codec = FrameCodec::new(true, false);
+///codec.receiver_mut().set_max_message_size(Some(64 * 1024));
+///codec.reset();
+///```
+pub struct FrameCodec {
+	receiver: Receiver,
+	sender: Sender,
+}
+
+impl FrameCodec {
+	/// Creates a codec with the default settings. `out_mask` is whether sent
+	/// frames are masked, `in_mask` whether received frames have to be, as
+	/// for the client end of a connection `FrameCodec::new(true, false)`.
+	pub fn new(out_mask: bool, in_mask: bool) -> FrameCodec {
+		FrameCodec {
+			receiver: Receiver::new(in_mask, Uuid::new_v4()),
+			sender: Sender::new(out_mask),
+		}
+	}
+
+	pub(crate) fn from_parts(receiver: Receiver, sender: Sender) -> FrameCodec {
+		FrameCodec {
+			receiver,
+			sender,
+		}
+	}
+
+	/// The receiving half.
+	pub fn receiver(&self) -> &Receiver {
+		&self.receiver
+	}
+
+	/// The receiving half, to change its settings.
+	pub fn receiver_mut(&mut self) -> &mut Receiver {
+		&mut self.receiver
+	}
+
+	/// The sending half.
+	pub fn sender(&self) -> &Sender {
+		&self.sender
+	}
+
+	/// The sending half, to change its settings.
+	pub fn sender_mut(&mut self) -> &mut Sender {
+		&mut self.sender
+	}
+
+	/// Forgets the state of the last connection while keeping the settings,
+	/// see `Receiver::reset`.
+	pub fn reset(&mut self) {
+		self.receiver.reset();
+	}
+
+	/// Returns the receiver and the sender.
+	pub fn into_parts(self) -> (Receiver, Sender) {
+		(self.receiver, self.sender)
+	}
+
+	pub(crate) fn set_uuid(&mut self, uuid: Uuid) {
+		self.receiver.set_uuid(uuid);
+	}
+
+	// tells the extensions what was agreed on for the next connection,
+	// dropping those that weren't agreed on
+	pub(crate) fn renegotiate(&mut self, agreed: &[ExtensionHeader]) -> WebSocketResult<()> {
+		let mut result = Ok(());
+		self.receiver
+		    .retain_extensions(|extension| {
+			let mut extension = extension.lock().unwrap();
+			match agreed.iter().find(|e| e.name == extension.name()) {
+				Some(entry) if result.is_ok() => {
+					result = extension.negotiated(entry);
+					result.is_ok()
+				}
+				_ => false,
+			}
+		});
+		let receiver = &self.receiver;
+		self.sender.retain_extensions(|extension| receiver.has_extension(extension));
+		result
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+	use dataframe::{DataFrame, Opcode};
+	use message::OwnedMessage;
+	use result::WebSocketError;
+	use ws::dataframe::DataFrame as DataFrameable;
+	use ws::receiver::Receiver as ReceiverTrait;
+
+	fn frame(finished: bool, data: &[u8]) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		DataFrame::new(finished, Opcode::Text, data.to_vec())
+			.write_to(&mut bytes, false)
+			.unwrap();
+		bytes
+	}
+
+	#[test]
+	fn reset_codec_reads_the_next_connection() {
+		let mut codec = FrameCodec::new(false, false);
+		codec.receiver_mut().set_max_message_size(Some(8));
+
+		// the first connection ends in the middle of a fragmented message
+		let mut first = Cursor::new(frame(false, b"hel"));
+		assert!(codec.receiver_mut().recv_message(&mut first).is_err());
+		codec.reset();
+
+		let mut second = Cursor::new([frame(true, b"hi"), frame(true, b"too large")].concat());
+		assert_eq!(codec.receiver_mut().recv_message(&mut second).unwrap(),
+		           OwnedMessage::Text("hi".to_string()));
+		match codec.receiver_mut().recv_message(&mut second) {
+			Err(WebSocketError::MessageTooLarge { limit: 8, .. }) => (),
+			other => panic!("the limit should be kept, got {:?}", other),
+		}
+	}
+}
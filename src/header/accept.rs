@@ -6,7 +6,6 @@ use std::fmt::{self, Debug};
 use std::str::FromStr;
 use header::WebSocketKey;
 use result::{WebSocketResult, WebSocketError};
-use sha1::Sha1;
 
 static MAGIC_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
@@ -48,10 +47,7 @@ impl WebSocketAccept {
 		let mut concat_key = String::with_capacity(serialized.len() + 36);
 		concat_key.push_str(&serialized[..]);
 		concat_key.push_str(MAGIC_GUID);
-		let mut sha1 = Sha1::new();
-		sha1.update(concat_key.as_bytes());
-		let bytes = sha1.digest().bytes();
-		WebSocketAccept(bytes)
+		WebSocketAccept(sha1(concat_key.as_bytes()))
 	}
 	/// Return the Base64 encoding of this WebSocketAccept
 	pub fn serialize(&self) -> String {
@@ -60,6 +56,21 @@ impl WebSocketAccept {
 	}
 }
 
+/// Hashes the handshake key with the pure Rust `sha1` crate, unless
+/// `openssl-sha1` is enabled.
+#[cfg(not(feature="openssl-sha1"))]
+fn sha1(data: &[u8]) -> [u8; 20] {
+	let mut sha1 = ::sha1::Sha1::new();
+	sha1.update(data);
+	sha1.digest().bytes()
+}
+
+/// Hashes the handshake key with OpenSSL, for builds that link it anyway.
+#[cfg(feature="openssl-sha1")]
+fn sha1(data: &[u8]) -> [u8; 20] {
+	::openssl::sha::sha1(data)
+}
+
 impl Header for WebSocketAccept {
 	fn header_name() -> &'static str {
 		"Sec-WebSocket-Accept"
@@ -76,13 +87,29 @@ impl HeaderFormat for WebSocketAccept {
 	}
 }
 
-#[cfg(all(feature = "nightly", test))]
+#[cfg(test)]
+mod backend_tests {
+	use super::*;
+	use std::str::FromStr;
+
+	#[test]
+	fn sha1_backend_matches_test_vectors() {
+		// FIPS 180-1 "abc" vector
+		assert_eq!(sha1(b"abc"),
+		           [0xa9, 0x99, 0x3e, 0x36, 0x47, 0x06, 0x81, 0x6a, 0xba, 0x3e, 0x25, 0x71, 0x78, 0x50,
+		            0xc2, 0x6c, 0x9c, 0xd0, 0xd8, 0x9d]);
+
+		// RFC6455 1.3 handshake example
+		let key = WebSocketKey::from_str("dGhlIHNhbXBsZSBub25jZQ==").unwrap();
+		assert_eq!(WebSocketAccept::new(&key).serialize(), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+	}
+}
+
+#[cfg(test)]
 mod tests {
 	use super::*;
-	use test;
 	use std::str::FromStr;
-	use header::{Headers, WebSocketKey};
-	use hyper::header::Header;
+	use header::Headers;
 
 	#[test]
 	fn test_header_accept() {
@@ -94,28 +121,4 @@ mod tests {
 		assert_eq!(&headers.to_string()[..],
 		           "Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n");
 	}
-	#[bench]
-	fn bench_header_accept_new(b: &mut test::Bencher) {
-		let key = WebSocketKey::new();
-		b.iter(|| {
-			       let mut accept = WebSocketAccept::new(&key);
-			       test::black_box(&mut accept);
-			      });
-	}
-	#[bench]
-	fn bench_header_accept_parse(b: &mut test::Bencher) {
-		let value = vec![b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_vec()];
-		b.iter(|| {
-			       let mut accept: WebSocketAccept = Header::parse_header(&value[..]).unwrap();
-			       test::black_box(&mut accept);
-			      });
-	}
-	#[bench]
-	fn bench_header_accept_format(b: &mut test::Bencher) {
-		let value = vec![b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=".to_vec()];
-		let val: WebSocketAccept = Header::parse_header(&value[..]).unwrap();
-		b.iter(|| {
-			       format!("{}", val.serialize());
-			      });
-	}
 }
@@ -128,15 +128,14 @@ impl fmt::Display for WebSocketExtensions {
 	}
 }
 
-#[cfg(all(feature = "nightly", test))]
+#[cfg(test)]
 mod tests {
 	use super::*;
 	use hyper::header::Header;
-	use test;
 	#[test]
 	fn test_header_extensions() {
 		use header::Headers;
-		let value = vec![b"foo, bar; baz; qux=quux".to_vec()];
+		let value = [b"foo, bar; baz; qux=quux".to_vec()];
 		let extensions: WebSocketExtensions = Header::parse_header(&value[..]).unwrap();
 
 		let mut headers = Headers::new();
@@ -145,21 +144,4 @@ mod tests {
 		assert_eq!(&headers.to_string()[..],
 		           "Sec-WebSocket-Extensions: foo, bar; baz; qux=quux\r\n");
 	}
-	#[bench]
-	fn bench_header_extensions_parse(b: &mut test::Bencher) {
-		let value = vec![b"foo, bar; baz; qux=quux".to_vec()];
-		b.iter(|| {
-			       let mut extensions: WebSocketExtensions = Header::parse_header(&value[..])
-			           .unwrap();
-			       test::black_box(&mut extensions);
-			      });
-	}
-	#[bench]
-	fn bench_header_extensions_format(b: &mut test::Bencher) {
-		let value = vec![b"foo, bar; baz; qux=quux".to_vec()];
-		let val: WebSocketExtensions = Header::parse_header(&value[..]).unwrap();
-		b.iter(|| {
-			       format!("{}", val);
-			      });
-	}
 }
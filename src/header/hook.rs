@@ -0,0 +1,49 @@
+//! Hooks to observe the headers of a handshake as they are sent and received.
+
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use hyper::header::Headers;
+
+/// Whether the headers given to a `HandshakeHook` were sent or received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeDirection {
+	/// The headers are about to be sent to the other endpoint.
+	Outgoing,
+	/// The headers were just received from the other endpoint.
+	Incoming,
+}
+
+/// A callback fired with the raw headers of a handshake in both directions,
+/// useful to log exactly what was exchanged with a peer that refuses the
+/// handshake.
+///
+/// ```rust
+/// # use websocket::header::{HandshakeHook, HandshakeDirection};
+/// let hook = HandshakeHook::new(|direction, headers| {
+///     println!("{:?}:\n{}", direction, headers);
+/// });
+/// ```
+#[derive(Clone)]
+pub struct HandshakeHook(Arc<HookFn>);
+
+type HookFn = Fn(HandshakeDirection, &Headers) + Send + Sync;
+
+impl HandshakeHook {
+	/// Creates a hook from a closure.
+	pub fn new<F>(hook: F) -> Self
+		where F: Fn(HandshakeDirection, &Headers) + Send + Sync + 'static
+	{
+		HandshakeHook(Arc::new(hook))
+	}
+
+	/// Fires the hook.
+	pub fn call(&self, direction: HandshakeDirection, headers: &Headers) {
+		(self.0)(direction, headers)
+	}
+}
+
+impl Debug for HandshakeHook {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str("HandshakeHook")
+	}
+}
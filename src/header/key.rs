@@ -35,7 +35,7 @@ impl FromStr for WebSocketKey {
 
 				Ok(WebSocketKey(array))
 			}
-			Err(_) => Err(WebSocketError::ProtocolError("Invalid Sec-WebSocket-Accept")),
+			Err(_) => Err(WebSocketError::ProtocolError("Invalid Sec-WebSocket-Key")),
 		}
 	}
 }
@@ -72,11 +72,9 @@ impl HeaderFormat for WebSocketKey {
 	}
 }
 
-#[cfg(all(feature = "nightly", test))]
+#[cfg(test)]
 mod tests {
 	use super::*;
-	use hyper::header::Header;
-	use test;
 	#[test]
 	fn test_header_key() {
 		use header::Headers;
@@ -88,27 +86,4 @@ mod tests {
 		assert_eq!(&headers.to_string()[..],
 		           "Sec-WebSocket-Key: QUFBQUFBQUFBQUFBQUFBQQ==\r\n");
 	}
-	#[bench]
-	fn bench_header_key_new(b: &mut test::Bencher) {
-		b.iter(|| {
-			       let mut key = WebSocketKey::new();
-			       test::black_box(&mut key);
-			      });
-	}
-	#[bench]
-	fn bench_header_key_parse(b: &mut test::Bencher) {
-		let value = vec![b"QUFBQUFBQUFBQUFBQUFBQQ==".to_vec()];
-		b.iter(|| {
-			       let mut key: WebSocketKey = Header::parse_header(&value[..]).unwrap();
-			       test::black_box(&mut key);
-			      });
-	}
-	#[bench]
-	fn bench_header_key_format(b: &mut test::Bencher) {
-		let value = vec![b"QUFBQUFBQUFBQUFBQUFBQQ==".to_vec()];
-		let val: WebSocketKey = Header::parse_header(&value[..]).unwrap();
-		b.iter(|| {
-			       format!("{}", val.serialize());
-			      });
-	}
 }
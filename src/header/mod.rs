@@ -5,10 +5,11 @@
 
 pub use self::key::WebSocketKey;
 pub use self::accept::WebSocketAccept;
-pub use self::protocol::WebSocketProtocol;
+pub use self::protocol::{WebSocketProtocol, ProtocolPriority, ProtocolSelection, SelectionReason};
 pub use self::version::WebSocketVersion;
 pub use self::extensions::WebSocketExtensions;
 pub use self::origin::Origin;
+pub use self::hook::{HandshakeHook, HandshakeDirection};
 pub use hyper::header::*;
 
 mod accept;
@@ -17,3 +18,4 @@ mod protocol;
 mod version;
 pub mod extensions;
 mod origin;
+mod hook;
@@ -38,11 +38,9 @@ impl fmt::Display for Origin {
 	}
 }
 
-#[cfg(all(feature = "nightly", test))]
+#[cfg(test)]
 mod tests {
 	use super::*;
-	use hyper::header::Header;
-	use test;
 	#[test]
 	fn test_header_origin() {
 		use header::Headers;
@@ -53,20 +51,4 @@ mod tests {
 
 		assert_eq!(&headers.to_string()[..], "Origin: foo bar\r\n");
 	}
-	#[bench]
-	fn bench_header_origin_parse(b: &mut test::Bencher) {
-		let value = vec![b"foobar".to_vec()];
-		b.iter(|| {
-			       let mut origin: Origin = Header::parse_header(&value[..]).unwrap();
-			       test::black_box(&mut origin);
-			      });
-	}
-	#[bench]
-	fn bench_header_origin_format(b: &mut test::Bencher) {
-		let value = vec![b"foobar".to_vec()];
-		let val: Origin = Header::parse_header(&value[..]).unwrap();
-		b.iter(|| {
-			       format!("{}", val);
-			      });
-	}
 }
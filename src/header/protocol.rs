@@ -40,11 +40,79 @@ impl fmt::Display for WebSocketProtocol {
 	}
 }
 
-#[cfg(all(feature = "nightly", test))]
+/// Whose order of preference decides between several common subprotocols.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolPriority {
+	/// Pick the protocol the client listed first.
+	Client,
+	/// Pick the protocol the server listed first.
+	Server,
+}
+
+/// Why `WebSocketProtocol::select` chose a subprotocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionReason {
+	/// It is the only protocol both sides support.
+	OnlyCommon,
+	/// Several protocols are common, the client prefers this one.
+	ClientPriority,
+	/// Several protocols are common, the server prefers this one.
+	ServerPriority,
+}
+
+/// A subprotocol chosen by `WebSocketProtocol::select`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtocolSelection {
+	/// The chosen protocol.
+	pub protocol: String,
+	/// Why it was chosen.
+	pub reason: SelectionReason,
+}
+
+impl WebSocketProtocol {
+	/// Chooses one of the protocols offered in this header out of the ones
+	/// in `supported`, which is ordered from most to least preferred.
+	///
+	/// Protocol names are compared case sensitively. Returns `None` if there
+	/// is no protocol in common.
+	///
+	/// ```rust
+	/// # use websocket::header::{WebSocketProtocol, ProtocolPriority};
+	/// let offered = WebSocketProtocol(vec!["chat.v1".to_string(), "chat.v2".to_string()]);
+	///
+	/// let selection = offered.select(&["chat.v2", "chat.v1"], ProtocolPriority::Server).unwrap();
+	/// assert_eq!(selection.protocol, "chat.v2");
+	/// ```
+	pub fn select<S>(&self, supported: &[S], priority: ProtocolPriority) -> Option<ProtocolSelection>
+		where S: AsRef<str>
+	{
+		let common = |offered: &String| supported.iter().any(|s| s.as_ref() == offered);
+		let count = self.0.iter().filter(|p| common(p)).count();
+
+		let protocol = match priority {
+			ProtocolPriority::Client => self.0.iter().find(|p| common(p)).cloned(),
+			ProtocolPriority::Server => {
+				supported.iter()
+				         .map(|s| s.as_ref())
+				         .find(|s| self.0.iter().any(|p| p == s))
+				         .map(|s| s.to_string())
+			}
+		}?;
+
+		Some(ProtocolSelection {
+		         protocol,
+		         reason: match priority {
+		             _ if count == 1 => SelectionReason::OnlyCommon,
+		             ProtocolPriority::Client => SelectionReason::ClientPriority,
+		             ProtocolPriority::Server => SelectionReason::ServerPriority,
+		         },
+		     })
+	}
+}
+
+#[cfg(test)]
 mod tests {
 	use super::*;
-	use hyper::header::Header;
-	use test;
 	#[test]
 	fn test_header_protocol() {
 		use header::Headers;
@@ -56,20 +124,38 @@ mod tests {
 		assert_eq!(&headers.to_string()[..],
 		           "Sec-WebSocket-Protocol: foo, bar\r\n");
 	}
-	#[bench]
-	fn bench_header_protocol_parse(b: &mut test::Bencher) {
-		let value = vec![b"foo, bar".to_vec()];
-		b.iter(|| {
-			       let mut protocol: WebSocketProtocol = Header::parse_header(&value[..]).unwrap();
-			       test::black_box(&mut protocol);
-			      });
+
+	fn offered() -> WebSocketProtocol {
+		WebSocketProtocol(vec!["a".to_string(), "b".to_string(), "c".to_string()])
+	}
+
+	#[test]
+	fn select_by_client_priority() {
+		assert_eq!(offered().select(&["c", "b", "x"], ProtocolPriority::Client),
+		           Some(ProtocolSelection {
+		                    protocol: "b".to_string(),
+		                    reason: SelectionReason::ClientPriority,
+		                }));
+	}
+
+	#[test]
+	fn select_by_server_priority() {
+		assert_eq!(offered().select(&["c", "b", "x"], ProtocolPriority::Server),
+		           Some(ProtocolSelection {
+		                    protocol: "c".to_string(),
+		                    reason: SelectionReason::ServerPriority,
+		                }));
 	}
-	#[bench]
-	fn bench_header_protocol_format(b: &mut test::Bencher) {
-		let value = vec![b"foo, bar".to_vec()];
-		let val: WebSocketProtocol = Header::parse_header(&value[..]).unwrap();
-		b.iter(|| {
-			       format!("{}", val);
-			      });
+
+	#[test]
+	fn single_or_no_common_protocol() {
+		for &priority in &[ProtocolPriority::Client, ProtocolPriority::Server] {
+			assert_eq!(offered().select(&["x", "b"], priority),
+			           Some(ProtocolSelection {
+			                    protocol: "b".to_string(),
+			                    reason: SelectionReason::OnlyCommon,
+			                }));
+			assert_eq!(offered().select(&["B", "x"], priority), None);
+		}
 	}
 }
@@ -46,11 +46,9 @@ impl fmt::Display for WebSocketVersion {
 	}
 }
 
-#[cfg(all(feature = "nightly", test))]
+#[cfg(test)]
 mod tests {
 	use super::*;
-	use hyper::header::Header;
-	use test;
 	#[test]
 	fn test_websocket_version() {
 		use header::Headers;
@@ -61,20 +59,4 @@ mod tests {
 
 		assert_eq!(&headers.to_string()[..], "Sec-WebSocket-Version: 13\r\n");
 	}
-	#[bench]
-	fn bench_header_version_parse(b: &mut test::Bencher) {
-		let value = vec![b"13".to_vec()];
-		b.iter(|| {
-			       let mut version: WebSocketVersion = Header::parse_header(&value[..]).unwrap();
-			       test::black_box(&mut version);
-			      });
-	}
-	#[bench]
-	fn bench_header_version_format(b: &mut test::Bencher) {
-		let value = vec![b"13".to_vec()];
-		let val: WebSocketVersion = Header::parse_header(&value[..]).unwrap();
-		b.iter(|| {
-			       format!("{}", val);
-			      });
-	}
 }
@@ -1,6 +1,4 @@
 #![warn(missing_docs)]
-#![cfg_attr(all(test, feature = "nightly"), feature(test))]
-
 #![deny(unused_mut)]
 
 //! Rust-WebSocket is a WebSocket (RFC6455) library written in Rust.
@@ -33,13 +31,25 @@ extern crate unicase;
 pub extern crate url;
 extern crate rand;
 extern crate byteorder;
+extern crate core;
+#[cfg(not(feature="openssl-sha1"))]
 extern crate sha1;
+#[cfg(any(feature="openssl-sha1", feature="tls-alpn", feature="tls-session", feature="tls-sni"))]
+extern crate openssl;
+#[cfg(feature="tls-session")]
+extern crate openssl_sys;
 extern crate base64;
 extern crate uuid;
 #[macro_use]
 extern crate log;
 #[cfg(any(feature="sync-ssl", feature="async-ssl"))]
 extern crate native_tls;
+#[cfg(feature="sync")]
+extern crate socket2;
+#[cfg(all(feature="sync", unix))]
+extern crate libc;
+#[cfg(feature="deflate")]
+extern crate flate2;
 #[cfg(feature="async")]
 extern crate tokio_core;
 #[cfg(feature="async")]
@@ -53,9 +63,6 @@ extern crate tokio_tls;
 #[macro_use]
 extern crate bitflags;
 
-#[cfg(all(feature = "nightly", test))]
-extern crate test;
-
 macro_rules! upsert_header {
     ($headers:expr; $header:ty; {
         Some($pat:pat) => $some_match:expr,
@@ -84,6 +91,18 @@ pub mod codec;
 pub mod receiver;
 #[cfg(feature="sync")]
 pub mod sender;
+#[cfg(feature="sync")]
+pub mod queue;
+#[cfg(feature="sync")]
+pub mod framing;
+#[cfg(feature="sync")]
+pub mod strict;
+#[cfg(feature="deflate")]
+pub mod deflate;
+#[cfg(all(any(feature="tls-alpn", feature="tls-session", feature="tls-sni"),
+          any(feature="sync-ssl", feature="async-ssl"),
+          not(any(target_os="macos", target_os="ios", windows))))]
+pub mod tls;
 
 pub mod client;
 pub mod server;
@@ -93,7 +112,12 @@ pub mod stream;
 #[cfg(feature="sync")]
 pub mod sync {
 	pub use sender;
-	pub use sender::Writer;
+	pub use sender::{Writer, SharedWriter};
+
+	pub use queue;
+	pub use queue::{QueuedWriter, OverflowPolicy};
+	pub use framing;
+	pub use framing::FrameCodec;
 
 	pub use receiver;
 	pub use receiver::Reader;
@@ -101,12 +125,15 @@ pub mod sync {
 	pub use stream::sync::Stream;
 	pub use stream::sync as stream;
 
+	pub use strict::StrictMode;
+
 	/// A collection of handy synchronous-only parts of the `server` module.
 	pub mod server {
 		pub use server::sync::*;
 		pub use server::upgrade::sync::Upgrade;
 		pub use server::upgrade::sync::IntoWs;
 		pub use server::upgrade::sync as upgrade;
+		pub use server::hub::Hub;
 	}
 	pub use server::sync::Server;
 
@@ -114,6 +141,7 @@ pub mod sync {
 	pub mod client {
 		pub use client::sync::*;
 		pub use client::builder::ClientBuilder;
+		pub use client::reconnect::{ReconnectingClient, ConnectionState};
 	}
 	pub use client::sync::Client;
 }
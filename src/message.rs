@@ -3,8 +3,10 @@ use std::str::from_utf8;
 use std::io;
 use std::io::Write;
 use std::borrow::Cow;
+use std::fmt;
+use std::convert::TryFrom;
 use result::{WebSocketResult, WebSocketError};
-use dataframe::Opcode;
+use dataframe::{DataFrame, Opcode};
 use ws::dataframe::DataFrame as DataFrameTrait;
 use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian};
 use ws::util::bytes_to_string;
@@ -12,6 +14,21 @@ use ws;
 
 const FALSE_RESERVED_BITS: &'static [bool; 3] = &[false; 3];
 
+/// How many bytes of a binary payload `Debug` shows, the rest is left out.
+pub const DEBUG_PAYLOAD_LEN: usize = 32;
+
+// formats a payload for `Debug`, leaving out all but the first bytes of long ones
+struct DebugPayload<'a>(&'a [u8]);
+
+impl<'a> fmt::Debug for DebugPayload<'a> {
+	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+		if self.0.len() <= DEBUG_PAYLOAD_LEN {
+			return fmt::Debug::fmt(self.0, fmt);
+		}
+		write!(fmt, "{:?}.. ({} bytes)", &self.0[..DEBUG_PAYLOAD_LEN], self.0.len())
+	}
+}
+
 /// Valid types of messages (in the default implementation)
 #[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum Type {
@@ -35,7 +52,10 @@ pub enum Type {
 ///
 /// Incidentally this (the default implementation of Message) implements the DataFrame trait
 /// because this message just gets sent as one single DataFrame.
-#[derive(Eq, PartialEq, Clone, Debug)]
+///
+/// The `Debug` output shows text payloads as text and only the first
+/// `DEBUG_PAYLOAD_LEN` bytes of others.
+#[derive(Eq, PartialEq, Clone)]
 pub struct Message<'a> {
 	/// Type of WebSocket message
 	pub opcode: Type,
@@ -116,6 +136,16 @@ impl<'a> Message<'a> {
 			Err(())
 		}
 	}
+
+	/// The message as one unfragmented frame, with the close status code
+	/// (if any) in front of the payload.
+	///
+	/// The payload is taken as is, so a text message is only valid UTF-8 if
+	/// it was built from a string.
+	pub fn into_single_frame(self) -> DataFrame {
+		let opcode = Opcode::new(self.opcode as u8).expect("message types are valid opcodes");
+		DataFrame::new(true, opcode, self.take_payload())
+	}
 }
 
 impl<'a> ws::dataframe::DataFrame for Message<'a> {
@@ -157,6 +187,13 @@ impl<'a> ws::dataframe::DataFrame for Message<'a> {
 			self.payload.into_owned()
 		}
 	}
+
+	fn payload_slice(&self) -> Option<&[u8]> {
+		match self.cd_status_code {
+			Some(_) => None,
+			None => Some(&*self.payload),
+		}
+	}
 }
 
 impl<'a> ws::Message for Message<'a> {
@@ -165,6 +202,18 @@ impl<'a> ws::Message for Message<'a> {
 		self.write_to(writer, masked)
 	}
 
+	fn is_close(&self) -> bool {
+		self.opcode == Type::Close
+	}
+
+	fn to_single_frame(&self) -> Option<DataFrame> {
+		Some(self.clone().into_single_frame())
+	}
+
+	fn is_control(&self) -> bool {
+		matches!(self.opcode, Type::Close | Type::Ping | Type::Pong)
+	}
+
 	/// Returns how many bytes this message will take up
 	fn message_size(&self, masked: bool) -> usize {
 		self.frame_size(masked)
@@ -179,7 +228,10 @@ impl<'a> ws::Message for Message<'a> {
 		                   .map(|d| d.opcode())?;
 		let opcode = Opcode::new(opcode);
 
-		let payload_size = frames.iter().map(|d| d.size()).sum();
+		let payload_size = frames.iter()
+		                         .map(|d| d.size())
+		                         .try_fold(0usize, |total, size| total.checked_add(size))
+		                         .ok_or(WebSocketError::DataFrameError("Message too large for this platform"))?;
 
 		let mut data = Vec::with_capacity(payload_size);
 
@@ -209,8 +261,15 @@ impl<'a> ws::Message for Message<'a> {
 			}
 			Some(Opcode::Binary) => Message::binary(data),
 			Some(Opcode::Close) => {
+				if data.len() == 1 {
+					// the status code needs two bytes (RFC6455 5.5.1)
+					return Err(WebSocketError::ProtocolError("Close frame payload of one byte"));
+				}
 				if data.len() > 0 {
 					let status_code = (&data[..]).read_u16::<BigEndian>()?;
+					if !CloseData::is_valid_status_code(status_code) {
+						return Err(WebSocketError::ProtocolError("Invalid close status code received"));
+					}
 					let reason = bytes_to_string(&data[2..])?;
 					Message::close_because(status_code, reason)
 				} else {
@@ -233,7 +292,10 @@ impl<'a> ws::Message for Message<'a> {
 /// please use the `Message` struct (which contains a `Cow`).
 ///
 /// Note that `OwnedMessage` can `Message` can be converted into each other.
-#[derive(Eq, PartialEq, Clone, Debug)]
+///
+/// Like for `Message`, `Debug` only shows the first `DEBUG_PAYLOAD_LEN` bytes
+/// of binary, ping and pong payloads.
+#[derive(Eq, PartialEq, Clone)]
 pub enum OwnedMessage {
 	/// A message containing UTF-8 text data
 	Text(String),
@@ -251,7 +313,50 @@ pub enum OwnedMessage {
 	Pong(Vec<u8>),
 }
 
+impl<'a> fmt::Debug for Message<'a> {
+	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+		let mut debug = fmt.debug_struct("Message");
+		debug.field("opcode", &self.opcode).field("cd_status_code", &self.cd_status_code);
+		match (self.opcode, from_utf8(&self.payload)) {
+			(Type::Text, Ok(text)) => debug.field("payload", &text),
+			_ => debug.field("payload", &DebugPayload(&self.payload)),
+		};
+		debug.finish()
+	}
+}
+
+impl fmt::Debug for OwnedMessage {
+	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			OwnedMessage::Text(ref text) => fmt.debug_tuple("Text").field(text).finish(),
+			OwnedMessage::Binary(ref data) => fmt.debug_tuple("Binary").field(&DebugPayload(data)).finish(),
+			OwnedMessage::Close(ref data) => fmt.debug_tuple("Close").field(data).finish(),
+			OwnedMessage::Ping(ref data) => fmt.debug_tuple("Ping").field(&DebugPayload(data)).finish(),
+			OwnedMessage::Pong(ref data) => fmt.debug_tuple("Pong").field(&DebugPayload(data)).finish(),
+		}
+	}
+}
+
 impl OwnedMessage {
+	/// The message as one unfragmented frame, however many frames it was
+	/// received in.
+	///
+	/// This is meant for relays that want to forward messages without their
+	/// original fragmentation. The opcode is kept and text stays valid UTF-8.
+	///
+	///```rust
+	///# use websocket::OwnedMessage;
+	///# use websocket::dataframe::Opcode;
+	///let frame = OwnedMessage::Text("Hello".to_string()).into_single_frame();
+	///assert!(frame.finished);
+	///assert_eq!(frame.opcode, Opcode::Text);
+	///assert_eq!(frame.data, b"Hello");
+	///```
+	pub fn into_single_frame(self) -> DataFrame {
+		let opcode = Opcode::new(self.opcode()).expect("message types are valid opcodes");
+		DataFrame::new(true, opcode, self.take_payload())
+	}
+
 	/// Checks if this message is a close message.
 	///
 	///```rust
@@ -331,6 +436,18 @@ impl ws::Message for OwnedMessage {
 		self.write_to(writer, masked)
 	}
 
+	fn to_single_frame(&self) -> Option<DataFrame> {
+		Some(self.clone().into_single_frame())
+	}
+
+	fn is_close(&self) -> bool {
+		OwnedMessage::is_close(self)
+	}
+
+	fn is_control(&self) -> bool {
+		OwnedMessage::is_control(self)
+	}
+
 	/// Returns how many bytes this message will take up
 	fn message_size(&self, masked: bool) -> usize {
 		self.frame_size(masked)
@@ -373,9 +490,9 @@ impl ws::dataframe::DataFrame for OwnedMessage {
 			OwnedMessage::Ping(ref data) => data.len(),
 			OwnedMessage::Pong(ref data) => data.len(),
 			OwnedMessage::Close(ref data) => {
-				match data {
-					&Some(ref c) => c.reason.len() + 2,
-					&None => 0,
+				match *data {
+					Some(ref c) => c.reason_bytes().len() + 2,
+					None => 0,
 				}
 			}
 		}
@@ -391,7 +508,7 @@ impl ws::dataframe::DataFrame for OwnedMessage {
 				match data {
 					&Some(ref c) => {
 						socket.write_u16::<BigEndian>(c.status_code)?;
-						socket.write_all(c.reason.as_bytes())?
+						socket.write_all(c.reason_bytes())?
 					}
 					&None => (),
 				}
@@ -400,6 +517,16 @@ impl ws::dataframe::DataFrame for OwnedMessage {
 		Ok(())
 	}
 
+	fn payload_slice(&self) -> Option<&[u8]> {
+		match *self {
+			OwnedMessage::Text(ref txt) => Some(txt.as_bytes()),
+			OwnedMessage::Binary(ref bin) => Some(bin),
+			OwnedMessage::Ping(ref data) => Some(data),
+			OwnedMessage::Pong(ref data) => Some(data),
+			OwnedMessage::Close(_) => None,
+		}
+	}
+
 	fn take_payload(self) -> Vec<u8> {
 		match self {
 			OwnedMessage::Text(txt) => txt.into_bytes(),
@@ -409,10 +536,14 @@ impl ws::dataframe::DataFrame for OwnedMessage {
 			OwnedMessage::Close(data) => {
 				match data {
 					Some(c) => {
-						let mut buf = Vec::with_capacity(2 + c.reason.len());
+						let reason = match c.reason_raw {
+							Some(raw) => raw,
+							None => c.reason.into_bytes(),
+						};
+						let mut buf = Vec::with_capacity(2 + reason.len());
 						buf.write_u16::<BigEndian>(c.status_code)
 						   .expect("failed to write close code in take_payload");
-						buf.append(&mut c.reason.into_bytes());
+						buf.extend_from_slice(&reason);
 						buf
 					}
 					None => vec![],
@@ -432,12 +563,8 @@ impl<'m> From<Message<'m>> for OwnedMessage {
 			Type::Close => {
 				match message.cd_status_code {
 					Some(code) => {
-						OwnedMessage::Close(Some(CloseData {
-						                             status_code: code,
-						                             reason:
-							                             String::from_utf8_lossy(&message.payload)
-							                                 .into_owned(),
-						                         }))
+						let reason = String::from_utf8_lossy(&message.payload).into_owned();
+						OwnedMessage::Close(Some(CloseData::new(code, reason)))
 					}
 					None => OwnedMessage::Close(None),
 				}
@@ -456,6 +583,9 @@ impl<'m> From<OwnedMessage> for Message<'m> {
 			OwnedMessage::Binary(bin) => Message::binary(bin),
 			OwnedMessage::Close(because) => {
 				match because {
+					Some(CloseData { status_code, reason_raw: Some(raw), .. }) => {
+						Message::new(Type::Close, Some(status_code), Cow::Owned(raw))
+					}
 					Some(c) => Message::close_because(c.status_code, c.reason),
 					None => Message::close(),
 				}
@@ -466,6 +596,32 @@ impl<'m> From<OwnedMessage> for Message<'m> {
 	}
 }
 
+/// Converts a single, unfragmented frame into a message, the payload is
+/// checked like in `from_dataframes`. Continuation frames and frames without
+/// the FIN bit are rejected since they are only part of a message.
+impl<'m> TryFrom<DataFrame> for Message<'m> {
+	type Error = WebSocketError;
+
+	fn try_from(frame: DataFrame) -> WebSocketResult<Self> {
+		if frame.opcode == Opcode::Continuation {
+			return Err(WebSocketError::ProtocolError("Continuation frame is not a whole message"));
+		}
+		if !frame.finished {
+			return Err(WebSocketError::ProtocolError("Fragmented frame is not a whole message"));
+		}
+		<Message as ws::Message>::from_dataframes(vec![frame])
+	}
+}
+
+/// See the conversion of a frame into a `Message`.
+impl TryFrom<DataFrame> for OwnedMessage {
+	type Error = WebSocketError;
+
+	fn try_from(frame: DataFrame) -> WebSocketResult<Self> {
+		Message::try_from(frame).map(OwnedMessage::from)
+	}
+}
+
 /// Represents data contained in a Close message
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub struct CloseData {
@@ -473,6 +629,11 @@ pub struct CloseData {
 	pub status_code: u16,
 	/// The reason-phrase of the CloseData
 	pub reason: String,
+	/// The reason as received, if it wasn't valid UTF-8. Only receivers that
+	/// keep raw close reasons set this, see `Receiver::set_raw_close_reasons`,
+	/// `reason` then holds a lossy decoding of it. These bytes are sent
+	/// instead of `reason` when set, so a relay forwards the reason unchanged.
+	pub reason_raw: Option<Vec<u8>>,
 }
 
 impl CloseData {
@@ -481,15 +642,68 @@ impl CloseData {
 		CloseData {
 			status_code: status_code,
 			reason: reason,
+			reason_raw: None,
 		}
 	}
+
+	/// Parses the payload of a close frame keeping a reason that isn't
+	/// UTF-8 in `reason_raw`. The status code is checked like for any close.
+	pub(crate) fn from_payload_keeping_raw(payload: &[u8]) -> WebSocketResult<Option<CloseData>> {
+		match payload.len() {
+			0 => return Ok(None),
+			1 => return Err(WebSocketError::ProtocolError("Close frame payload of one byte")),
+			_ => (),
+		}
+		let status_code = (&payload[..]).read_u16::<BigEndian>()?;
+		if !CloseData::is_valid_status_code(status_code) {
+			return Err(WebSocketError::ProtocolError("Invalid close status code received"));
+		}
+		let reason = &payload[2..];
+		Ok(Some(match from_utf8(reason) {
+			Ok(text) => CloseData::new(status_code, text.to_string()),
+			Err(_) => CloseData {
+				status_code,
+				reason: String::from_utf8_lossy(reason).into_owned(),
+				reason_raw: Some(reason.to_vec()),
+			},
+		}))
+	}
+
+	fn reason_bytes(&self) -> &[u8] {
+		match self.reason_raw {
+			Some(ref raw) => raw,
+			None => self.reason.as_bytes(),
+		}
+	}
+
+	/// Create a CloseData with only a status code, sent as a two byte payload.
+	pub fn from_code(status_code: u16) -> CloseData {
+		CloseData::new(status_code, String::new())
+	}
+
+	/// Checks whether a status code may be sent in a Close message.
+	///
+	/// These are the codes defined for use by RFC6455 (1000 - 1003 and
+	/// 1007 - 1011) and the ranges for libraries and applications
+	/// (3000 - 4999). Codes such as 1005, 1006 or 1015 are reserved to signal
+	/// conditions locally and must never appear on the wire.
+	///
+	///```rust
+	///# use websocket::CloseData;
+	///assert!(CloseData::is_valid_status_code(1000));
+	///assert!(CloseData::is_valid_status_code(4000));
+	///assert!(!CloseData::is_valid_status_code(1005));
+	///assert!(!CloseData::is_valid_status_code(2000));
+	///```
+	pub fn is_valid_status_code(status_code: u16) -> bool {
+		matches!(status_code, 1000..=1003 | 1007..=1011 | 3000..=4999)
+	}
+
 	/// Convert this into a vector of bytes
 	pub fn into_bytes(self) -> io::Result<Vec<u8>> {
 		let mut buf = Vec::new();
 		buf.write_u16::<BigEndian>(self.status_code)?;
-		for i in self.reason.as_bytes().iter() {
-			buf.push(*i);
-		}
+		buf.extend_from_slice(self.reason_bytes());
 		Ok(buf)
 	}
 }
@@ -518,3 +732,127 @@ impl<'a> IntoCowBytes<'a> for Cow<'a, [u8]> {
 		self
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dataframe::DataFrame;
+	use ws::Message as MessageTrait;
+
+	#[test]
+	fn serialized_len_matches_the_written_frame() {
+		for &len in &[0, 125, 126, 65535, 65536] {
+			let message = Message::binary(vec![0; len]);
+			for &masked in &[false, true] {
+				let mut written = Vec::new();
+				message.serialize(&mut written, masked).unwrap();
+				assert_eq!(message.serialized_len(masked), written.len());
+			}
+		}
+		assert_eq!(Message::text("").serialized_len(false), 2);
+		assert_eq!(Message::binary(vec![0; 126]).serialized_len(true), 2 + 2 + 4 + 126);
+		assert_eq!(Message::binary(vec![0; 65536]).serialized_len(false), 2 + 8 + 65536);
+	}
+
+	#[test]
+	fn single_frames_convert_into_messages() {
+		let convert = |opcode, data: &[u8]| OwnedMessage::try_from(DataFrame::new(true, opcode, data.to_vec()));
+		assert_eq!(convert(Opcode::Text, b"hi").unwrap(), OwnedMessage::Text("hi".to_string()));
+		assert_eq!(convert(Opcode::Binary, b"\x00").unwrap(), OwnedMessage::Binary(vec![0]));
+		assert_eq!(convert(Opcode::Close, &[0x03, 0xE8, b'o', b'k']).unwrap(),
+		           OwnedMessage::Close(Some(CloseData::new(1000, "ok".to_string()))));
+		assert_eq!(convert(Opcode::Ping, b"p").unwrap(), OwnedMessage::Ping(b"p".to_vec()));
+		assert_eq!(convert(Opcode::Pong, b"").unwrap(), OwnedMessage::Pong(vec![]));
+
+		match convert(Opcode::Text, &[0xff]) {
+			Err(WebSocketError::Utf8Error(_)) => (),
+			other => panic!("expected a UTF-8 error, got {:?}", other),
+		}
+		for &opcode in &[Opcode::Continuation, Opcode::NonControl1, Opcode::Control1] {
+			match convert(opcode, b"") {
+				Err(WebSocketError::ProtocolError(_)) => (),
+				other => panic!("expected a protocol error for {:?}, got {:?}", opcode, other),
+			}
+		}
+		match OwnedMessage::try_from(DataFrame::new(false, Opcode::Text, b"part".to_vec())) {
+			Err(WebSocketError::ProtocolError(_)) => (),
+			other => panic!("expected a protocol error, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn debug_leaves_out_most_of_long_payloads() {
+		assert_eq!(format!("{:?}", OwnedMessage::Binary(vec![1, 2])), "Binary([1, 2])");
+		let long = format!("{:?}", OwnedMessage::Binary(vec![7; 1000]));
+		assert_eq!(long, format!("Binary({:?}.. (1000 bytes))", vec![7; DEBUG_PAYLOAD_LEN]));
+		assert_eq!(format!("{:?}", OwnedMessage::Pong(vec![0; 33])),
+		           format!("Pong({:?}.. (33 bytes))", vec![0; DEBUG_PAYLOAD_LEN]));
+
+		let text = "a".repeat(100);
+		assert_eq!(format!("{:?}", OwnedMessage::Text(text.clone())), format!("Text({:?})", text));
+		assert_eq!(format!("{:?}", Message::text(text.clone())),
+		           format!("Message {{ opcode: Text, cd_status_code: None, payload: {:?} }}", text));
+		assert!(format!("{:?}", Message::binary(vec![7; 1000])).ends_with(".. (1000 bytes) }"));
+	}
+
+	#[test]
+	fn fragmented_messages_become_single_frames() {
+		let frames = vec![DataFrame::new(false, Opcode::Text, b"He".to_vec()),
+		                  DataFrame::new(false, Opcode::Continuation, b"ll".to_vec()),
+		                  DataFrame::new(true, Opcode::Continuation, b"o".to_vec())];
+		let message = OwnedMessage::from_dataframes(frames).unwrap();
+		assert_eq!(message.clone().into_single_frame(), DataFrame::new(true, Opcode::Text, b"Hello".to_vec()));
+		assert_eq!(OwnedMessage::try_from(message.into_single_frame()).unwrap(),
+		           OwnedMessage::Text("Hello".to_string()));
+
+		let close = Message::close_because(1000, "bye").into_single_frame();
+		assert_eq!(close, DataFrame::new(true, Opcode::Close, b"\x03\xe8bye".to_vec()));
+	}
+
+	#[test]
+	fn close_payload_of_one_byte_is_rejected() {
+		let frame = DataFrame::new(true, Opcode::Close, vec![0x03]);
+		match OwnedMessage::from_dataframes(vec![frame]) {
+			Err(WebSocketError::ProtocolError(_)) => (),
+			other => panic!("expected a protocol error, got {:?}", other),
+		}
+
+		let frame = DataFrame::new(true, Opcode::Close, vec![]);
+		assert_eq!(OwnedMessage::from_dataframes(vec![frame]).unwrap(),
+		           OwnedMessage::Close(None));
+
+		let frame = DataFrame::new(true, Opcode::Close, vec![0x03, 0xE8]);
+		assert_eq!(OwnedMessage::from_dataframes(vec![frame]).unwrap(),
+		           OwnedMessage::Close(Some(CloseData::new(1000, String::new()))));
+	}
+
+	#[test]
+	fn close_with_only_a_code_round_trips() {
+		let close = OwnedMessage::Close(Some(CloseData::from_code(1001)));
+		let mut frame = Vec::new();
+		close.serialize(&mut frame, false).unwrap();
+		assert_eq!(frame, vec![0x88, 0x02, 0x03, 0xE9]);
+
+		let mut borrowed = Vec::new();
+		Message::close_because(1001, "").serialize(&mut borrowed, false).unwrap();
+		assert_eq!(borrowed, frame);
+
+		let received = DataFrame::new(true, Opcode::Close, frame[2..].to_vec());
+		assert_eq!(OwnedMessage::from_dataframes(vec![received]).unwrap(), close);
+	}
+
+	#[test]
+	fn invalid_close_status_codes_are_rejected() {
+		for &code in [0u16, 999, 1004, 1005, 1006, 1012, 1015, 1016, 2999, 5000].iter() {
+			let frame = DataFrame::new(true, Opcode::Close, vec![(code >> 8) as u8, code as u8]);
+			match OwnedMessage::from_dataframes(vec![frame]) {
+				Err(WebSocketError::ProtocolError(_)) => (),
+				other => panic!("expected {} to be rejected, got {:?}", code, other),
+			}
+		}
+		for &code in [1000u16, 1003, 1007, 1011, 3000, 4999].iter() {
+			let frame = DataFrame::new(true, Opcode::Close, vec![(code >> 8) as u8, code as u8]);
+			assert!(OwnedMessage::from_dataframes(vec![frame]).is_ok());
+		}
+	}
+}
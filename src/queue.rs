@@ -0,0 +1,333 @@
+//! A bounded queue of outgoing messages, written by a background thread.
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use message::{OwnedMessage, CloseData};
+use result::{WebSocketResult, WebSocketError};
+use sender::{Sender, Writer};
+use strict::POLICY_VIOLATION;
+use ws;
+use ws::sender::Sender as SenderTrait;
+
+/// What `QueuedWriter::send_message` does when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+	/// Wait until the background thread made room.
+	Block,
+	/// Drop the oldest message still waiting and queue the new one.
+	DropOldest,
+	/// Drop everything waiting and close the connection with status code
+	/// 1008 (policy violation).
+	Close,
+}
+
+struct Queue {
+	messages: VecDeque<Vec<u8>>,
+	sender: Sender,
+	// a close was queued or a write failed, nothing more is accepted
+	closed: bool,
+	// the `QueuedWriter` is gone, what is still queued is written
+	shutdown: bool,
+	dropped: usize,
+}
+
+struct Shared {
+	queue: Mutex<Queue>,
+	changed: Condvar,
+}
+
+/// Sends messages through a queue of at most `capacity` messages, so a slow
+/// peer doesn't hold up the thread sending to it.
+///
+/// Messages are serialized when they are queued and a background thread
+/// writes them in order. What happens once `capacity` messages are waiting
+/// is up to the `OverflowPolicy`. After a close message was queued, the
+/// queue overflowed with `OverflowPolicy::Close` or a write failed, sending
+/// fails with `WebSocketError::AlreadyClosed`.
+///
+/// Dropping messages breaks permessage-deflate, whose messages depend on
+/// the ones before, so only `Block` suits writers with extensions.
+///
+///```rust,no_run
+///# extern crate websocket;
+///# fn main() {
+///use websocket::Message;
+///use websocket::sync::Server;
+///use websocket::sync::queue::{QueuedWriter, OverflowPolicy};
+///
+///let mut server = Server::bind("127.0.0.1:1234").unwrap();
+///let client = server.accept().ok().unwrap().accept().unwrap();
+///let (_, writer) = client.split().unwrap();
+///let queue = QueuedWriter::new(writer, 64, OverflowPolicy::DropOldest);
+///queue.send_message(&Message::text("tick")).unwrap();
+///# }
+///```
+pub struct QueuedWriter {
+	shared: Arc<Shared>,
+	capacity: usize,
+	policy: OverflowPolicy,
+}
+
+impl QueuedWriter {
+	/// Starts the background thread writing to `writer`.
+	///
+	/// # Panics
+	///
+	/// Panics if `capacity` is 0.
+	pub fn new<W>(writer: Writer<W>, capacity: usize, policy: OverflowPolicy) -> QueuedWriter
+		where W: Write + Send + 'static
+	{
+		assert!(capacity > 0, "a send queue needs room for a message");
+		let Writer { mut stream, sender } = writer;
+		let shared = Arc::new(Shared {
+		                          queue: Mutex::new(Queue {
+		                                                messages: VecDeque::with_capacity(capacity),
+		                                                sender,
+		                                                closed: false,
+		                                                shutdown: false,
+		                                                dropped: 0,
+		                                            }),
+		                          changed: Condvar::new(),
+		                      });
+
+		let drain = shared.clone();
+		thread::spawn(move || loop {
+			let bytes = {
+				let mut queue = drain.queue.lock().unwrap();
+				loop {
+					if let Some(bytes) = queue.messages.pop_front() {
+						break bytes;
+					}
+					if queue.shutdown || queue.closed {
+						return;
+					}
+					queue = drain.changed.wait(queue).unwrap();
+				}
+			};
+			drain.changed.notify_all();
+
+			if let Err(e) = stream.write_all(&bytes).and_then(|_| stream.flush()) {
+				debug!("Send queue stopped after a failed write: {}", e);
+				let mut queue = drain.queue.lock().unwrap();
+				queue.closed = true;
+				queue.messages.clear();
+				drain.changed.notify_all();
+				return;
+			}
+		});
+
+		QueuedWriter {
+			shared,
+			capacity,
+			policy,
+		}
+	}
+
+	/// Queues a message to be sent.
+	///
+	/// With `OverflowPolicy::Close` the message that overflows the queue
+	/// fails with `WebSocketError::AlreadyClosed` and isn't sent.
+	pub fn send_message<M>(&self, message: &M) -> WebSocketResult<()>
+		where M: ws::Message
+	{
+		let mut queue = self.shared.queue.lock().unwrap();
+		if queue.closed {
+			return Err(WebSocketError::AlreadyClosed);
+		}
+		while queue.messages.len() >= self.capacity {
+			match self.policy {
+				OverflowPolicy::Block => {
+					queue = self.shared.changed.wait(queue).unwrap();
+					if queue.closed {
+						return Err(WebSocketError::AlreadyClosed);
+					}
+				}
+				OverflowPolicy::DropOldest => {
+					queue.messages.pop_front();
+					queue.dropped += 1;
+				}
+				OverflowPolicy::Close => {
+					queue.dropped += queue.messages.len();
+					queue.messages.clear();
+					let close = OwnedMessage::Close(Some(CloseData::from_code(POLICY_VIOLATION)));
+					let mut bytes = Vec::new();
+					queue.sender.send_message(&mut bytes, &close)?;
+					queue.messages.push_back(bytes);
+					queue.closed = true;
+					self.shared.changed.notify_all();
+					return Err(WebSocketError::AlreadyClosed);
+				}
+			}
+		}
+
+		let mut bytes = Vec::with_capacity(message.message_size(queue.sender.is_masked()));
+		queue.sender.send_message(&mut bytes, message)?;
+		queue.messages.push_back(bytes);
+		if message.is_close() {
+			queue.closed = true;
+		}
+		self.shared.changed.notify_all();
+		Ok(())
+	}
+
+	/// The number of messages waiting to be written, not counting the one
+	/// being written.
+	pub fn len(&self) -> usize {
+		self.shared.queue.lock().unwrap().messages.len()
+	}
+
+	/// Whether no messages are waiting to be written.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// The number of messages dropped because the queue was full.
+	pub fn dropped(&self) -> usize {
+		self.shared.queue.lock().unwrap().dropped
+	}
+
+	/// Whether messages are no longer accepted, see `QueuedWriter`.
+	pub fn is_closed(&self) -> bool {
+		self.shared.queue.lock().unwrap().closed
+	}
+}
+
+/// The messages still queued are written before the background thread
+/// ends, dropping doesn't wait for that.
+impl Drop for QueuedWriter {
+	fn drop(&mut self) {
+		self.shared.queue.lock().unwrap().shutdown = true;
+		self.shared.changed.notify_all();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io;
+	use std::sync::mpsc::{self, Receiver as ChannelReceiver, Sender as ChannelSender};
+	use std::time::{Duration, Instant};
+
+	// writes only once allowed to, one write per permit
+	struct Gate {
+		permits: ChannelReceiver<()>,
+		written: Arc<Mutex<Vec<u8>>>,
+	}
+
+	impl Write for Gate {
+		fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+			self.permits
+			    .recv()
+			    .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "closed"))?;
+			self.written.lock().unwrap().extend_from_slice(data);
+			Ok(data.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	fn gated(capacity: usize, policy: OverflowPolicy)
+		-> (QueuedWriter, ChannelSender<()>, Arc<Mutex<Vec<u8>>>) {
+		let (permit, permits) = mpsc::channel();
+		let written = Arc::new(Mutex::new(Vec::new()));
+		let writer = Writer {
+			stream: Gate {
+				permits,
+				written: written.clone(),
+			},
+			sender: Sender::new(false),
+		};
+		(QueuedWriter::new(writer, capacity, policy), permit, written)
+	}
+
+	fn text(text: &str) -> Vec<u8> {
+		let mut bytes = Vec::new();
+		Sender::new(false).send_message(&mut bytes, &OwnedMessage::Text(text.to_string())).unwrap();
+		bytes
+	}
+
+	// waits for the background thread to take the first message and block on it
+	fn wait_until_taken(queue: &QueuedWriter) {
+		let start = Instant::now();
+		while !queue.is_empty() {
+			assert!(start.elapsed() < Duration::from_secs(5), "the message was never taken");
+			thread::sleep(Duration::from_millis(1));
+		}
+	}
+
+	fn wait_for(written: &Arc<Mutex<Vec<u8>>>, expected: &[u8]) {
+		let start = Instant::now();
+		while &written.lock().unwrap()[..] != expected {
+			assert!(start.elapsed() < Duration::from_secs(5), "the messages were never written");
+			thread::sleep(Duration::from_millis(1));
+		}
+	}
+
+	#[test]
+	fn block_waits_for_room() {
+		let (queue, permit, written) = gated(1, OverflowPolicy::Block);
+		queue.send_message(&OwnedMessage::Text("1".to_string())).unwrap();
+		wait_until_taken(&queue);
+		queue.send_message(&OwnedMessage::Text("2".to_string())).unwrap();
+
+		let queue = Arc::new(queue);
+		let (done, finished) = mpsc::channel();
+		let blocked = queue.clone();
+		thread::spawn(move || {
+			blocked.send_message(&OwnedMessage::Text("3".to_string())).unwrap();
+			done.send(()).unwrap();
+		});
+		assert!(finished.recv_timeout(Duration::from_millis(50)).is_err());
+
+		for _ in 0..3 {
+			permit.send(()).unwrap();
+		}
+		finished.recv_timeout(Duration::from_secs(5)).unwrap();
+		wait_for(&written, &[text("1"), text("2"), text("3")].concat());
+		assert_eq!(queue.dropped(), 0);
+	}
+
+	#[test]
+	fn drop_oldest_keeps_the_newest() {
+		let (queue, permit, written) = gated(2, OverflowPolicy::DropOldest);
+		queue.send_message(&OwnedMessage::Text("1".to_string())).unwrap();
+		wait_until_taken(&queue);
+		for message in &["2", "3", "4"] {
+			queue.send_message(&OwnedMessage::Text(message.to_string())).unwrap();
+		}
+		assert_eq!(queue.dropped(), 1);
+
+		for _ in 0..3 {
+			permit.send(()).unwrap();
+		}
+		wait_for(&written, &[text("1"), text("3"), text("4")].concat());
+	}
+
+	#[test]
+	fn close_policy_closes_with_1008() {
+		let (queue, permit, written) = gated(1, OverflowPolicy::Close);
+		queue.send_message(&OwnedMessage::Text("1".to_string())).unwrap();
+		wait_until_taken(&queue);
+		queue.send_message(&OwnedMessage::Text("2".to_string())).unwrap();
+		match queue.send_message(&OwnedMessage::Text("3".to_string())) {
+			Err(WebSocketError::AlreadyClosed) => (),
+			other => panic!("expected the queue to close, got {:?}", other),
+		}
+		assert!(queue.is_closed());
+		assert!(queue.send_message(&OwnedMessage::Text("4".to_string())).is_err());
+
+		for _ in 0..2 {
+			permit.send(()).unwrap();
+		}
+		let mut close = Vec::new();
+		Sender::new(false)
+			.send_message(&mut close, &OwnedMessage::Close(Some(CloseData::from_code(1008))))
+			.unwrap();
+		wait_for(&written, &[text("1"), close].concat());
+		assert_eq!(queue.dropped(), 1);
+	}
+}
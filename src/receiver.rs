@@ -1,21 +1,115 @@
 //! The default implementation of a WebSocket Receiver.
 
-use std::io::Read;
+use std::cmp;
+use std::io::{self, Read, Write, ErrorKind};
 use std::io::Result as IoResult;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
 
 use hyper::buffer::BufReader;
 use uuid::Uuid;
 
-use dataframe::{DataFrame, Opcode};
+use dataframe::{self, DataFrame, Opcode, DEFAULT_READ_CHUNK_SIZE};
 use result::{WebSocketResult, WebSocketError};
 use ws;
 use ws::receiver::Receiver as ReceiverTrait;
 use ws::receiver::{MessageIterator, DataFrameIterator};
-use ws::util::header::{DataFrameHeader, ReaderState};
-use message::OwnedMessage;
+use ws::util::header::{self as dfh, DataFrameFlags, DataFrameHeader, ReaderState};
+use ws::extension::{SharedExtension, PERMESSAGE_DEFLATE};
+use message::{OwnedMessage, CloseData};
+use server::limit::ConnectionGuard;
 use stream::sync::{AsTcpStream, Stream};
 pub use stream::sync::Shutdown;
 
+/// The default for the number of frames a message may be made of, see
+/// `Receiver::set_max_fragments_per_message`.
+pub const DEFAULT_MAX_FRAGMENTS_PER_MESSAGE: usize = 1024 * 1024;
+
+/// A limit on the rate frames are received at, see `Receiver::set_rate_limit`.
+///
+///```rust
+///# use websocket::sync::receiver::RateLimit;
+///let limit = RateLimit::new().frames_per_sec(100).bytes_per_sec(1024 * 1024);
+///assert_eq!(limit.frames_per_sec, Some(100));
+///```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimit {
+	/// The number of frames that may be received a second, `None` for no
+	/// limit.
+	pub frames_per_sec: Option<u32>,
+	/// The number of payload bytes that may be received a second, `None` for
+	/// no limit.
+	pub bytes_per_sec: Option<u64>,
+}
+
+impl RateLimit {
+	/// A limit that lets everything through.
+	pub fn new() -> Self {
+		RateLimit::default()
+	}
+
+	/// Limits the number of frames a second.
+	pub fn frames_per_sec(mut self, frames: u32) -> Self {
+		self.frames_per_sec = Some(frames);
+		self
+	}
+
+	/// Limits the number of payload bytes a second.
+	pub fn bytes_per_sec(mut self, bytes: u64) -> Self {
+		self.bytes_per_sec = Some(bytes);
+		self
+	}
+}
+
+// token buckets holding up to one second's worth of frames and bytes
+#[derive(Debug)]
+struct RateLimiter {
+	limit: RateLimit,
+	frames: f64,
+	bytes: f64,
+	refilled: Instant,
+}
+
+impl RateLimiter {
+	fn new(limit: RateLimit) -> RateLimiter {
+		RateLimiter {
+			limit,
+			frames: limit.frames_per_sec.unwrap_or(0) as f64,
+			bytes: limit.bytes_per_sec.unwrap_or(0) as f64,
+			refilled: Instant::now(),
+		}
+	}
+
+	fn take(&mut self, payload_len: u64) -> WebSocketResult<()> {
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.refilled);
+		let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+		self.refilled = now;
+
+		fn refill(tokens: &mut f64, rate: f64, elapsed: f64) {
+			*tokens = (*tokens + rate * elapsed).min(rate);
+		}
+
+		let frames = self.limit.frames_per_sec.map(|rate| rate as f64);
+		let bytes = self.limit.bytes_per_sec.map(|rate| rate as f64);
+		if let Some(rate) = frames {
+			refill(&mut self.frames, rate, elapsed);
+		}
+		if let Some(rate) = bytes {
+			refill(&mut self.bytes, rate, elapsed);
+		}
+
+		if (frames.is_some() && self.frames < 1.0) ||
+		   (bytes.is_some() && self.bytes < payload_len as f64) {
+			return Err(WebSocketError::RateLimited);
+		}
+		self.frames -= 1.0;
+		self.bytes -= payload_len as f64;
+		Ok(())
+	}
+}
+
 #[derive(Debug, Default)]
 /// A state for a reader to contain a buffer for incomplete reads to recover.
 pub struct PacketState {
@@ -36,6 +130,69 @@ impl PacketState {
 	}
 }
 
+/// Where a single frame was found on the wire, recorded by a `Receiver` in
+/// analyzer mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameSpan {
+	/// Position of the first header byte, counted from the first byte read
+	/// after analyzer mode was enabled.
+	pub offset: u64,
+	/// The size of the header, including the extended length and masking key.
+	pub header_len: usize,
+	/// The size of the payload.
+	pub payload_len: u64,
+	/// The opcode of the frame.
+	pub opcode: Opcode,
+	/// Whether the FIN bit was set.
+	pub fin: bool,
+}
+
+/// A reassembled message together with the frames it was made from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Received {
+	/// The message.
+	pub message: OwnedMessage,
+	/// The frames that made up the message, in the order they were received.
+	/// This is empty unless analyzer mode is enabled on the receiver.
+	pub frames: Vec<FrameSpan>,
+	/// The payload of a close message exactly as it was received, status
+	/// code and reason included, to relay it without serializing it again.
+	/// `None` for other messages.
+	pub raw_close: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+struct Analyzer {
+	offset: u64,
+	frame_start: u64,
+	last: Option<FrameSpan>,
+	buffer: Vec<FrameSpan>,
+}
+
+#[derive(Debug, Default)]
+struct BinaryStream {
+	// the payload of the last frame, handed out from `pos` on
+	data: Vec<u8>,
+	pos: usize,
+	fragmented: bool,
+	closed: bool,
+}
+
+struct CountingReader<'a, R: 'a> {
+	inner: &'a mut R,
+	count: &'a mut u64,
+}
+
+impl<'a, R> Read for CountingReader<'a, R>
+	where R: Read
+{
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+		let read = self.inner.read(buf)?;
+		*self.count += read as u64;
+		Ok(read)
+	}
+}
+
 /// This reader bundles an existing stream with a parsing algorithm.
 /// It is used by the client in its `.split()` function as the reading component.
 pub struct Reader<R>
@@ -57,6 +214,9 @@ impl<R> Reader<R>
 	}
 
 	/// Returns an iterator over incoming data frames.
+	///
+	/// This is the low level counterpart to `incoming_messages`, fragmented
+	/// messages are not reassembled and every frame is yielded as it arrived.
 	pub fn incoming_dataframes(&mut self) -> DataFrameIterator<Receiver, BufReader<R>> {
 		self.receiver.incoming_dataframes(&mut self.stream)
 	}
@@ -66,6 +226,22 @@ impl<R> Reader<R>
 		self.receiver.recv_message(&mut self.stream)
 	}
 
+	/// Reads a single message along with the frames it was made from.
+	///
+	/// See `Receiver::recv_message_with_frames`.
+	pub fn recv_message_with_frames(&mut self) -> WebSocketResult<Received> {
+		self.receiver.recv_message_with_frames(&mut self.stream)
+	}
+
+	/// Reads a binary message, writing its payload to `sink` as it arrives.
+	///
+	/// See `Receiver::recv_binary_to`.
+	pub fn recv_binary_to<W>(&mut self, sink: &mut W) -> WebSocketResult<u64>
+		where W: Write
+	{
+		self.receiver.recv_binary_to(&mut self.stream, sink)
+	}
+
 	/// An iterator over incoming messsages.
 	/// This iterator will block until new messages arrive and will never halt.
 	pub fn incoming_messages<'a>(&'a mut self) -> MessageIterator<'a, Receiver, BufReader<R>> {
@@ -73,6 +249,16 @@ impl<R> Reader<R>
 	}
 }
 
+/// Reads the payloads of binary messages as a stream of bytes, only in
+/// binary stream mode (see `Receiver::set_binary_stream_mode`).
+impl<R> Read for Reader<R>
+    where R: Read
+{
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+		self.receiver.read_binary_stream(&mut self.stream, buf)
+	}
+}
+
 impl<S> Reader<S>
     where S: AsTcpStream + Stream + Read
 {
@@ -87,16 +273,136 @@ impl<S> Reader<S>
 	pub fn shutdown_all(&self) -> IoResult<()> {
 		self.stream.get_ref().as_tcp().shutdown(Shutdown::Both)
 	}
+
+	/// See [`TcpStream::peer_addr`]
+	/// (https://doc.rust-lang.org/std/net/struct.TcpStream.html#method.peer_addr).
+	pub fn peer_addr(&self) -> IoResult<SocketAddr> {
+		self.stream.get_ref().as_tcp().peer_addr()
+	}
+
+	/// See [`TcpStream::local_addr`]
+	/// (https://doc.rust-lang.org/std/net/struct.TcpStream.html#method.local_addr).
+	pub fn local_addr(&self) -> IoResult<SocketAddr> {
+		self.stream.get_ref().as_tcp().local_addr()
+	}
+
+	/// Reads a single message, failing with `WebSocketError::Timeout` unless
+	/// all of it arrived by `deadline`.
+	///
+	/// See `Client::recv_message_deadline` for details.
+	pub fn recv_message_deadline(&mut self, deadline: Instant) -> WebSocketResult<OwnedMessage> {
+		let previous = self.stream.get_ref().as_tcp().read_timeout()?;
+		let result = self.receiver.recv_message(&mut DeadlineReader {
+		                                             inner: &mut self.stream,
+		                                             deadline,
+		                                         });
+
+		// a message read is returned even if restoring the timeout fails
+		let restored = self.stream.get_ref().as_tcp().set_read_timeout(previous);
+		match result {
+			Err(WebSocketError::IoError(ref e)) if timed_out(e) => {
+				restored?;
+				Err(WebSocketError::Timeout)
+			}
+			result => result,
+		}
+	}
+
+	/// Reads a single message, giving up if none arrives within `timeout`.
+	///
+	/// See `Client::recv_message_timeout` for details.
+	pub fn recv_message_timeout(&mut self, timeout: Duration) -> WebSocketResult<Option<OwnedMessage>> {
+		let previous = self.stream.get_ref().as_tcp().read_timeout()?;
+		self.stream.get_ref().as_tcp().set_read_timeout(Some(timeout))?;
+
+		let result = self.receiver.recv_message(&mut self.stream);
+
+		// a message read is returned even if restoring the timeout fails
+		let restored = self.stream.get_ref().as_tcp().set_read_timeout(previous);
+		match result {
+			Ok(message) => Ok(Some(message)),
+			Err(WebSocketError::IoError(ref e)) if timed_out(e) => {
+				restored?;
+				Ok(None)
+			}
+			Err(e) => Err(e),
+		}
+	}
+}
+
+// whether a read failed because a read timeout or deadline expired, sockets
+// report `WouldBlock` on Unix and `TimedOut` on Windows, `DeadlineReader` the latter
+pub(crate) fn timed_out(error: &io::Error) -> bool {
+	matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+// limits every read from the socket to what is left until the deadline,
+// buffered bytes are returned regardless
+pub(crate) struct DeadlineReader<'a, S: 'a> {
+	pub(crate) inner: &'a mut BufReader<S>,
+	pub(crate) deadline: Instant,
+}
+
+impl<'a, S> Read for DeadlineReader<'a, S>
+    where S: Read + AsTcpStream
+{
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+		if self.inner.get_buf().is_empty() {
+			let now = Instant::now();
+			if now >= self.deadline {
+				return Err(io::Error::new(ErrorKind::TimedOut, "deadline passed"));
+			}
+			self.inner.get_ref().as_tcp().set_read_timeout(Some(self.deadline - now))?;
+		}
+		self.inner.read(buf)
+	}
 }
 
 /// A Receiver that wraps a Reader and provides a default implementation using
 /// DataFrames and Messages.
 pub struct Receiver {
 	buffer: Vec<DataFrame>,
+	// frames set aside while streaming a message or waiting for a pong,
+	// returned before reading more
+	pending: Vec<DataFrame>,
+	// payload bytes of the fragments in `buffer`
+	buffered: usize,
+	analyzer: Option<Analyzer>,
+	binary_stream: Option<BinaryStream>,
+	extensions: Vec<SharedExtension>,
+	deflate: bool,
+	read_chunk_size: usize,
+	max_message_size: Option<usize>,
+	max_fragments: usize,
 	mask: bool,
 	packet_state: PacketState,
 	reader_state: ReaderState,
 	uuid: Uuid,
+	// whether a close frame was returned, after which the peer must not send data
+	close_received: bool,
+	rate_limiter: Option<RateLimiter>,
+	raw_close_reasons: bool,
+	// the binary message `recv_binary_to` is in the middle of
+	streamed: Option<Streamed>,
+	// kept here so the connection counts against a server's limit for as
+	// long as the reading half of a split client
+	connection_guard: Option<ConnectionGuard>,
+}
+
+// how far `recv_binary_to` got with a message, so a call that failed with an
+// I/O error, like a timeout, can be repeated to continue it
+#[derive(Debug, Default)]
+struct Streamed {
+	// payload bytes of the message written to the sink
+	total: u64,
+	// frames of the message read so far
+	frames: usize,
+	// whether the limits were checked for the frame being read
+	checked: bool,
+	// payload bytes of the frame being read
+	frame_read: u64,
+	// why the frame being read is dropped instead of written to the sink
+	rejected: Option<WebSocketError>,
 }
 
 impl Receiver {
@@ -104,10 +410,700 @@ impl Receiver {
 	pub fn new(mask: bool, uuid: Uuid) -> Receiver {
 		Receiver {
 			buffer: Vec::new(),
+			pending: Vec::new(),
+			buffered: 0,
+			analyzer: None,
+			binary_stream: None,
+			extensions: Vec::new(),
+			deflate: false,
+			read_chunk_size: DEFAULT_READ_CHUNK_SIZE,
+			max_message_size: None,
+			max_fragments: DEFAULT_MAX_FRAGMENTS_PER_MESSAGE,
 			mask: mask,
 			packet_state: PacketState::default(),
 			reader_state: ReaderState::new(),
 			uuid: uuid,
+			close_received: false,
+			rate_limiter: None,
+			raw_close_reasons: false,
+			streamed: None,
+			connection_guard: None,
+		}
+	}
+
+	/// Sets the largest number of payload bytes read from the stream at once,
+	/// 8 KiB by default.
+	///
+	/// Larger chunks mean fewer reads for big frames, smaller ones make reads
+	/// return sooner on slow connections.
+	///
+	/// # Panics
+	///
+	/// Panics if `size` is 0.
+	pub fn set_read_chunk_size(&mut self, size: usize) {
+		assert!(size > 0, "read chunk size must not be 0");
+		self.read_chunk_size = size;
+	}
+
+	/// Stops requiring received frames to be masked, for the server end of
+	/// an unmasked link. See `Client::use_unmasked_frames`.
+	#[cfg(feature="unmasked-client")]
+	pub(crate) fn accept_unmasked(&mut self) {
+		self.mask = false;
+	}
+
+	/// Sets the largest total payload of a message, `None` (the default)
+	/// for no limit.
+	///
+	/// Messages over the limit fail with `WebSocketError::MessageTooLarge`
+	/// and the fragments received of them are dropped. A frame announcing a
+	/// payload over the limit fails as soon as its header was read, before
+	/// any of the payload is; the payload is then left unread, so the
+	/// connection can't be read from anymore.
+	pub fn set_max_message_size(&mut self, size: Option<usize>) {
+		self.max_message_size = size;
+	}
+
+	/// Sets the largest number of frames a message may be made of,
+	/// `DEFAULT_MAX_FRAGMENTS_PER_MESSAGE` by default.
+	///
+	/// Unlike `set_max_message_size` this guards against the cost of
+	/// handling a message split into a huge number of tiny frames. Messages
+	/// with more frames fail with a `ProtocolError` and the fragments
+	/// received of them are dropped.
+	///
+	/// # Panics
+	///
+	/// Panics if `max` is 0.
+	pub fn set_max_fragments_per_message(&mut self, max: usize) {
+		assert!(max > 0, "a message has at least one frame");
+		self.max_fragments = max;
+	}
+
+	/// Limits the rate frames are received at, `None` (the default) for no
+	/// limit.
+	///
+	/// Up to one second's worth of frames and payload bytes may arrive at
+	/// once, after that they are let through at the given rate. A frame over
+	/// the limit is read and then fails with `WebSocketError::RateLimited`,
+	/// which strict mode answers with close code 1008 (policy violation). A
+	/// single frame with more payload than `bytes_per_sec` never gets through.
+	///
+	/// Setting a limit starts with the full allowance.
+	pub fn set_rate_limit(&mut self, limit: Option<RateLimit>) {
+		self.rate_limiter = limit.map(RateLimiter::new);
+	}
+
+	/// Gives back the full allowance of the rate limit, if there is one.
+	pub fn reset_rate_limit(&mut self) {
+		if let Some(ref mut limiter) = self.rate_limiter {
+			*limiter = RateLimiter::new(limiter.limit);
+		}
+	}
+
+	fn check_rate(&mut self, payload_len: u64) -> WebSocketResult<()> {
+		match self.rate_limiter {
+			Some(ref mut limiter) => limiter.take(payload_len),
+			None => Ok(()),
+		}
+	}
+
+	/// Makes close messages keep a reason that isn't valid UTF-8, instead of
+	/// failing with a `Utf8Error`. Disabled by default.
+	///
+	/// The reason is then decoded lossily and the bytes as received are kept
+	/// in `CloseData::reason_raw`, which also get sent when the close is
+	/// passed on. This is meant for relays that shouldn't alter what a
+	/// non-conforming peer sent, conforming endpoints should leave it off.
+	pub fn set_raw_close_reasons(&mut self, raw: bool) {
+		self.raw_close_reasons = raw;
+	}
+
+	/// Whether a close frame was received.
+	pub(crate) fn close_received(&self) -> bool {
+		self.close_received
+	}
+
+	pub(crate) fn set_uuid(&mut self, uuid: Uuid) {
+		self.uuid = uuid;
+	}
+
+	pub(crate) fn set_connection_guard(&mut self, guard: Option<ConnectionGuard>) {
+		self.connection_guard = guard;
+	}
+
+	pub(crate) fn take_connection_guard(&mut self) -> Option<ConnectionGuard> {
+		self.connection_guard.take()
+	}
+
+	/// Forgets everything about the connection read so far, to read another
+	/// one with the same settings.
+	///
+	/// Partly read frames and fragments are dropped, the close handshake
+	/// starts over and the rate limit gets its full allowance. Limits and
+	/// the modes set on the receiver are kept, and registered extensions
+	/// are reset as well.
+	pub fn reset(&mut self) {
+		self.buffer.clear();
+		self.buffered = 0;
+		self.pending.clear();
+		if self.analyzer.is_some() {
+			self.analyzer = Some(Analyzer::default());
+		}
+		if self.binary_stream.is_some() {
+			self.binary_stream = Some(BinaryStream::default());
+		}
+		self.packet_state.reset();
+		self.reader_state = ReaderState::new();
+		self.close_received = false;
+		self.streamed = None;
+		self.reset_rate_limit();
+		for extension in &self.extensions {
+			extension.lock().unwrap().reset();
+		}
+	}
+
+	fn to_message(&self, dataframes: Vec<DataFrame>) -> WebSocketResult<OwnedMessage> {
+		use ws::Message;
+
+		if self.raw_close_reasons && dataframes[0].opcode == Opcode::Close {
+			return Ok(OwnedMessage::Close(CloseData::from_payload_keeping_raw(&dataframes[0].data)?));
+		}
+		OwnedMessage::from_dataframes(dataframes)
+	}
+
+	/// Enables or disables analyzer mode.
+	///
+	/// In analyzer mode the receiver counts the bytes it reads and records a
+	/// `FrameSpan` for every frame, which `recv_message_with_frames` returns
+	/// alongside the message. This is meant for tools that need to know how a
+	/// message was fragmented on the wire, normal usage should leave it off.
+	///
+	/// Offsets are counted from the first byte read after enabling it, so
+	/// enable it before reading anything to get offsets into the whole stream.
+	pub fn set_analyzer_mode(&mut self, enabled: bool) {
+		if !enabled {
+			self.analyzer = None;
+		} else if self.analyzer.is_none() {
+			self.analyzer = Some(Analyzer::default());
+		}
+	}
+
+	/// Enables or disables binary stream mode.
+	///
+	/// In binary stream mode the payloads of successive binary messages are
+	/// read as one continuous stream of bytes with `read_binary_stream`, which
+	/// is what the `Read` impls of `Reader` and `Client` use. A close message
+	/// ends the stream, after which reads return `Ok(0)`. Text messages are an
+	/// error, ping and pong frames are skipped without being answered.
+	///
+	/// The mode is meant to be chosen once, before anything is read; switching
+	/// it in the middle of a connection is not supported.
+	pub fn set_binary_stream_mode(&mut self, enabled: bool) {
+		if !enabled {
+			self.binary_stream = None;
+		} else if self.binary_stream.is_none() {
+			self.binary_stream = Some(BinaryStream::default());
+		}
+	}
+
+	/// Reads bytes from the payloads of incoming binary messages, see
+	/// `set_binary_stream_mode`. Fails unless binary stream mode is on.
+	pub fn read_binary_stream<R>(&mut self, reader: &mut R, buf: &mut [u8]) -> IoResult<usize>
+		where R: Read
+	{
+		let uuid = self.uuid;
+		loop {
+			{
+				let stream = match self.binary_stream {
+					Some(ref mut stream) => stream,
+					None => {
+						return Err(io::Error::other("binary stream mode is not enabled"));
+					}
+				};
+				if stream.pos < stream.data.len() {
+					let n = cmp::min(buf.len(), stream.data.len() - stream.pos);
+					buf[..n].copy_from_slice(&stream.data[stream.pos..stream.pos + n]);
+					stream.pos += n;
+					return Ok(n);
+				}
+				if stream.closed || buf.is_empty() {
+					return Ok(0);
+				}
+			}
+
+			let frame = match self.recv_dataframe(reader, uuid) {
+				Ok(frame) => frame,
+				Err(WebSocketError::IoError(e)) => return Err(e),
+				Err(e) => return Err(io::Error::new(ErrorKind::InvalidData, e)),
+			};
+			let stream = self.binary_stream.as_mut().unwrap();
+			match frame.opcode {
+				Opcode::Binary if !stream.fragmented => (),
+				Opcode::Continuation if stream.fragmented => (),
+				Opcode::Close => {
+					stream.closed = true;
+					continue;
+				}
+				Opcode::Ping | Opcode::Pong => continue,
+				Opcode::Text => {
+					return Err(io::Error::new(ErrorKind::InvalidData,
+					                          "Unexpected text message in binary stream mode"));
+				}
+				Opcode::Continuation => {
+					return Err(io::Error::new(ErrorKind::InvalidData,
+					                          "Unexpected continuation data frame opcode"));
+				}
+				opcode => {
+					return Err(io::Error::new(ErrorKind::InvalidData,
+					                          dataframe::interleaved_data_frame_error(opcode as u8)));
+				}
+			}
+			stream.fragmented = !frame.finished;
+			stream.data = frame.data;
+			stream.pos = 0;
+		}
+	}
+
+	/// Reads a single message along with the frames it was made from.
+	///
+	/// The frames are only recorded in analyzer mode (see `set_analyzer_mode`),
+	/// otherwise `frames` is always empty. A control frame received in the
+	/// middle of a fragmented message is returned on its own, the fragments
+	/// read before it are kept for the message they belong to.
+	///
+	/// For close messages `raw_close` holds the payload as received, besides
+	/// the parsed `CloseData` of the message.
+	pub fn recv_message_with_frames<R>(&mut self, reader: &mut R) -> WebSocketResult<Received>
+		where R: Read
+	{
+		let dataframes = self.recv_message_dataframes(reader)?;
+		let control = dataframes.len() == 1 && dataframes[0].opcode.is_control();
+
+		let frames = match self.analyzer {
+			Some(ref mut analyzer) if control => analyzer.last.take().into_iter().collect(),
+			Some(ref mut analyzer) => ::std::mem::take(&mut analyzer.buffer),
+			None => Vec::new(),
+		};
+
+		let raw_close = match dataframes[0].opcode {
+			Opcode::Close => Some(dataframes[0].data.clone()),
+			_ => None,
+		};
+
+		Ok(Received {
+		       message: self.to_message(dataframes)?,
+		       frames,
+		       raw_close,
+		   })
+	}
+
+	/// Reads a binary message, writing its unmasked payload to `sink` as it
+	/// arrives instead of collecting it in memory. Returns the size of the
+	/// payload.
+	///
+	/// If the next message is not a binary one an error is returned and the
+	/// message is left to be read by the other `recv_*` methods. Control
+	/// frames received between the fragments are kept and returned by the
+	/// next call to `recv_message` or `recv_dataframe`.
+	///
+	/// Since the payload is written as it is read, an error in the middle of
+	/// the message leaves the part already received in `sink`. If reading
+	/// failed, e.g. with a timeout on a non-blocking stream, calling this
+	/// again continues with the rest of the message and returns the size of
+	/// the whole payload; other `recv_*` methods fail until it is done. An
+	/// error writing to `sink` loses what was read for it.
+	///
+	/// The maximum message size, the maximum number of fragments, the rate
+	/// limit and the close handshake are checked against each frame header
+	/// before its payload is written. A frame over a limit is still read, but
+	/// dropped instead of written, and then the error is returned.
+	///
+	/// Frames read like this are not recorded in analyzer mode, and with
+	/// extensions registered each frame is collected before it is written.
+	pub fn recv_binary_to<R, W>(&mut self, reader: &mut R, sink: &mut W) -> WebSocketResult<u64>
+		where R: Read,
+		      W: Write
+	{
+		if self.streamed.is_none() {
+			if !self.buffer.is_empty() {
+				return Err(WebSocketError::ProtocolError("A fragmented message is already being received"));
+			}
+			if !self.pending.is_empty() {
+				return Err(WebSocketError::ProtocolError("Expected a binary message"));
+			}
+			self.streamed = Some(Streamed::default());
+		}
+
+		let result = self.stream_binary(reader, sink);
+		match result {
+			// can be continued by calling again
+			Err(WebSocketError::IoError(_)) => (),
+			_ => self.streamed = None,
+		}
+		result
+	}
+
+	fn stream_binary<R, W>(&mut self, reader: &mut R, sink: &mut W) -> WebSocketResult<u64>
+		where R: Read,
+		      W: Write
+	{
+		let uuid = self.uuid;
+		loop {
+			if self.packet_state.header.is_none() {
+				self.packet_state.header = Some(dfh::read_header(reader, &mut self.reader_state)?);
+			}
+			let header = self.packet_state.header.unwrap();
+			let first = {
+				// a frame is counted once it was checked
+				let streamed = self.streamed.as_ref().unwrap();
+				streamed.frames == 0 || (streamed.frames == 1 && streamed.checked)
+			};
+
+			match header.opcode {
+				2 if first => (),
+				_ if !first && header.is_continuation() => (),
+				_ if !first && header.is_control() => {
+					let control = self.read_extended(reader, uuid)?;
+					self.pending.push(control);
+					continue;
+				}
+				_ if first => return Err(WebSocketError::ProtocolError("Expected a binary message")),
+				opcode => return Err(dataframe::interleaved_data_frame_error(opcode)),
+			}
+
+			if !self.extensions.is_empty() {
+				let frame = self.read_extended(reader, uuid)?;
+				let len = frame.data.len() as u64;
+				if let Some(e) = self.check_streamed_frame(len) {
+					return Err(e);
+				}
+				sink.write_all(&frame.data)?;
+				let streamed = self.streamed.as_mut().unwrap();
+				streamed.total += len;
+				streamed.checked = false;
+				if frame.finished {
+					return Ok(streamed.total);
+				}
+				continue;
+			}
+
+			if header.flags.intersects(DataFrameFlags::RSV1 | DataFrameFlags::RSV2 | DataFrameFlags::RSV3) {
+				return Err(WebSocketError::ProtocolError("Unsupported reserved bits received"));
+			}
+			match header.mask {
+				Some(_) if !self.mask => {
+					return Err(WebSocketError::DataFrameError("Expected unmasked data frame"))
+				}
+				None if self.mask => return Err(WebSocketError::DataFrameError("Expected masked data frame")),
+				_ => (),
+			}
+
+			if !self.streamed.as_ref().unwrap().checked {
+				let rejected = match self.check_rate(header.len) {
+					Ok(()) => self.check_streamed_frame(header.len),
+					Err(e) => Some(e),
+				};
+				self.streamed.as_mut().unwrap().rejected = rejected;
+			}
+
+			let rejected = self.streamed.as_ref().unwrap().rejected.is_some();
+			let len = if rejected {
+				self.stream_payload(reader, &mut io::sink(), header)?
+			} else {
+				self.stream_payload(reader, sink, header)?
+			};
+			self.packet_state.reset();
+
+			let streamed = self.streamed.as_mut().unwrap();
+			streamed.checked = false;
+			streamed.frame_read = 0;
+			if let Some(e) = streamed.rejected.take() {
+				return Err(e);
+			}
+			streamed.total += len;
+			if header.flags.contains(DataFrameFlags::FIN) {
+				return Ok(streamed.total);
+			}
+		}
+	}
+
+	// the checks `recv_message` does for a frame of `len` payload bytes once
+	// it was read, apart from the rate limit
+	fn check_streamed_frame(&mut self, len: u64) -> Option<WebSocketError> {
+		let streamed = self.streamed.as_mut().unwrap();
+		streamed.checked = true;
+		if self.close_received {
+			return Some(WebSocketError::ProtocolError("Data frame received after close"));
+		}
+		streamed.frames += 1;
+		if streamed.frames > self.max_fragments {
+			return Some(WebSocketError::ProtocolError("Too many frames in a fragmented message"));
+		}
+		match self.max_message_size {
+			Some(max) if streamed.total.saturating_add(len) > max as u64 => {
+				Some(WebSocketError::MessageTooLarge {
+				         limit: max as u64,
+				         actual: streamed.total.saturating_add(len),
+				         frame: len > max as u64,
+				     })
+			}
+			_ => None,
+		}
+	}
+
+	fn stream_payload<R, W>(&mut self, reader: &mut R, sink: &mut W, header: DataFrameHeader) -> WebSocketResult<u64>
+		where R: Read,
+		      W: Write
+	{
+		fn unmask(key: Option<[u8; 4]>, data: &mut [u8], offset: u64) {
+			if let Some(key) = key {
+				for (i, byte) in data.iter_mut().enumerate() {
+					*byte ^= key[((offset + i as u64) % 4) as usize];
+				}
+			}
+		}
+
+		let streamed = self.streamed.as_mut().unwrap();
+		// a `recv_message` may have failed after part of the payload arrived
+		if !self.packet_state.packet.is_empty() {
+			unmask(header.mask, &mut self.packet_state.packet, 0);
+			sink.write_all(&self.packet_state.packet)?;
+			streamed.frame_read = self.packet_state.packet.len() as u64;
+			self.packet_state.packet.clear();
+		}
+
+		let mut chunk = vec![0; cmp::min(header.len - streamed.frame_read, self.read_chunk_size as u64) as usize];
+		while streamed.frame_read < header.len {
+			let len = cmp::min(header.len - streamed.frame_read, chunk.len() as u64) as usize;
+			match reader.read(&mut chunk[..len]) {
+				Ok(0) => return Err(WebSocketError::ProtocolError(dfh::TRUNCATED_FRAME)),
+				Ok(n) => {
+					unmask(header.mask, &mut chunk[..n], streamed.frame_read);
+					streamed.frame_read += n as u64;
+					sink.write_all(&chunk[..n])?;
+				}
+				Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) => return Err(WebSocketError::IoError(e)),
+			}
+		}
+		Ok(streamed.frame_read)
+	}
+
+	pub(crate) fn add_extension(&mut self, extension: SharedExtension) {
+		if extension.lock().unwrap().name() == PERMESSAGE_DEFLATE {
+			self.deflate = true;
+		}
+		self.extensions.push(extension);
+	}
+
+	pub(crate) fn retain_extensions<F>(&mut self, keep: F)
+		where F: FnMut(&SharedExtension) -> bool
+	{
+		self.extensions.retain(keep);
+		self.deflate = self.extensions
+		                   .iter()
+		                   .any(|e| e.lock().unwrap().name() == PERMESSAGE_DEFLATE);
+	}
+
+	pub(crate) fn has_extension(&self, extension: &SharedExtension) -> bool {
+		self.extensions.iter().any(|e| Arc::ptr_eq(e, extension))
+	}
+
+	pub(crate) fn uses_deflate(&self) -> bool {
+		self.deflate
+	}
+
+	fn read_dataframe<R>(&mut self, reader: &mut R, uuid: Uuid) -> WebSocketResult<DataFrame>
+		where R: Read
+	{
+		let frame = self.read_counted(reader, uuid)?;
+		self.check_rate(frame.data.len() as u64)?;
+		Ok(frame)
+	}
+
+	fn read_counted<R>(&mut self, reader: &mut R, uuid: Uuid) -> WebSocketResult<DataFrame>
+		where R: Read
+	{
+		if self.packet_state.header.is_none() {
+			let header = match self.analyzer {
+				Some(ref mut analyzer) => {
+					dfh::read_header(&mut CountingReader {
+						                 inner: &mut *reader,
+						                 count: &mut analyzer.offset,
+						             },
+					                 &mut self.reader_state)?
+				}
+				None => dfh::read_header(reader, &mut self.reader_state)?,
+			};
+			self.packet_state.header = Some(header);
+			if let Err(e) = self.check_announced(header) {
+				self.packet_state.reset();
+				return Err(e);
+			}
+		}
+
+		let analyzer = match self.analyzer {
+			Some(ref mut analyzer) => analyzer,
+			None => {
+				return DataFrame::read_dataframe_chunked(
+					reader,
+					self.mask,
+					uuid,
+					&mut self.packet_state,
+					&mut self.reader_state,
+					self.read_chunk_size,
+				)
+			}
+		};
+
+		let frame = DataFrame::read_dataframe_chunked(
+			&mut CountingReader {
+				inner: reader,
+				count: &mut analyzer.offset,
+			},
+			self.mask,
+			uuid,
+			&mut self.packet_state,
+			&mut self.reader_state,
+			self.read_chunk_size,
+		)?;
+
+		let total = analyzer.offset - analyzer.frame_start;
+		let payload_len = frame.data.len() as u64;
+		analyzer.last = Some(FrameSpan {
+			offset: analyzer.frame_start,
+			header_len: (total - payload_len) as usize,
+			payload_len,
+			opcode: frame.opcode,
+			fin: frame.finished,
+		});
+		analyzer.frame_start = analyzer.offset;
+
+		Ok(frame)
+	}
+
+	fn read_extended<R>(&mut self, reader: &mut R, uuid: Uuid) -> WebSocketResult<DataFrame>
+		where R: Read
+	{
+		let mut frame = self.read_dataframe(reader, uuid)?;
+		// RFC7692 6.1: only the first frame of a compressed message has RSV1 set
+		if self.deflate && frame.opcode == Opcode::Continuation && frame.reserved[0] {
+			self.discard_partial_message();
+			return Err(WebSocketError::ProtocolError("RSV1 set on a continuation frame"));
+		}
+		// what is left of the message size for the frame once it went through
+		// the extensions, so a compressed frame can't inflate past it
+		let received = match self.streamed {
+			Some(ref streamed) => streamed.total as usize,
+			None => self.buffered,
+		};
+		let received = if frame.opcode == Opcode::Continuation { received } else { 0 };
+		let max_len = self.max_message_size.map(|max| max.saturating_sub(received));
+		let result = self.extensions.iter().rev().try_for_each(|extension| {
+			extension.lock().unwrap().on_recv_frame_within(&mut frame, max_len)
+		});
+		match (result, self.max_message_size) {
+			(Ok(()), _) => Ok(frame),
+			(Err(WebSocketError::MessageTooLarge { actual, .. }), Some(max)) => {
+				self.discard_partial_message();
+				Err(WebSocketError::MessageTooLarge {
+				        limit: max as u64,
+				        actual: (received as u64).saturating_add(actual),
+				        frame: actual > max as u64,
+				    })
+			}
+			(Err(e), _) => Err(e),
+		}
+	}
+
+	fn record_span(&mut self) {
+		if let Some(ref mut analyzer) = self.analyzer {
+			analyzer.buffer.extend(analyzer.last.take());
+		}
+	}
+
+	// rejects a frame announcing more payload than the message may have,
+	// before any of it is read
+	fn check_announced(&mut self, header: DataFrameHeader) -> WebSocketResult<()> {
+		match self.max_message_size {
+			Some(max) if header.len > max as u64 => {
+				let actual = if header.is_continuation() {
+					(self.buffered as u64).saturating_add(header.len)
+				} else {
+					header.len
+				};
+				if header.is_continuation() {
+					self.discard_partial_message();
+				}
+				Err(WebSocketError::MessageTooLarge {
+				        limit: max as u64,
+				        actual,
+				        frame: true,
+				    })
+			}
+			_ => Ok(()),
+		}
+	}
+
+	// adds a fragment to the message being received, checking its limits
+	fn push_fragment(&mut self, frame: DataFrame) -> WebSocketResult<()> {
+		let last = frame.data.len();
+		self.buffered = self.buffered.saturating_add(last);
+		self.buffer.push(frame);
+		self.record_span();
+
+		if self.buffer.len() > self.max_fragments {
+			self.discard_partial_message();
+			return Err(WebSocketError::ProtocolError("Too many frames in a fragmented message"));
+		}
+		if let Some(max) = self.max_message_size {
+			let size = self.buffered;
+			if size > max {
+				self.discard_partial_message();
+				return Err(WebSocketError::MessageTooLarge {
+				               limit: max as u64,
+				               actual: size as u64,
+				               frame: last > max,
+				           });
+			}
+		}
+		Ok(())
+	}
+
+	/// The frames returned by `recv_dataframe` before any more are read.
+	pub(crate) fn pending_mut(&mut self) -> &mut Vec<DataFrame> {
+		&mut self.pending
+	}
+
+	/// Drops the fragments of a message that was partly received.
+	pub(crate) fn discard_partial_message(&mut self) {
+		self.buffer.clear();
+		self.buffered = 0;
+		if let Some(ref mut analyzer) = self.analyzer {
+			analyzer.buffer.clear();
+		}
+	}
+
+	// reads the next frame of a message. Once a close frame was read the peer
+	// may not send anything else (RFC6455 5.5.1): data frames and another
+	// close fail with a `ProtocolError`, pings and pongs are dropped. Frames
+	// read on their own aren't checked, so those read by `Client::ping_rtt`
+	// can still be received as messages.
+	fn recv_message_frame<R>(&mut self, reader: &mut R, uuid: Uuid) -> WebSocketResult<DataFrame>
+		where R: Read
+	{
+		loop {
+			let frame = self.recv_dataframe(reader, uuid)?;
+			if !self.close_received {
+				self.close_received = frame.opcode == Opcode::Close;
+				return Ok(frame);
+			}
+			match frame.opcode {
+				Opcode::Ping | Opcode::Pong => debug!("Dropping {:?} frame received after close", frame.opcode),
+				Opcode::Close => return Err(WebSocketError::ProtocolError("Second close frame received")),
+				_ => return Err(WebSocketError::ProtocolError("Data frame received after close")),
+			}
 		}
 	}
 }
@@ -122,17 +1118,25 @@ impl ws::Receiver for Receiver {
 		self.uuid
 	}
 
+	fn recv_message<'m, R>(&mut self, reader: &mut R) -> WebSocketResult<OwnedMessage>
+		where R: Read
+	{
+		let dataframes = self.recv_message_dataframes(reader)?;
+		self.to_message(dataframes)
+	}
+
 	/// Reads a single data frame from the remote endpoint.
 	fn recv_dataframe<R>(&mut self, reader: &mut R, uuid: Uuid) -> WebSocketResult<DataFrame>
 		where R: Read
 	{
-		DataFrame::read_dataframe(
-			reader,
-			self.mask,
-			uuid,
-			&mut self.packet_state,
-			&mut self.reader_state,
-		)
+		if self.streamed.is_some() {
+			return Err(WebSocketError::ProtocolError("A binary message is being streamed"));
+		}
+		if self.pending.is_empty() {
+			self.read_extended(reader, uuid)
+		} else {
+			Ok(self.pending.remove(0))
+		}
 	}
 
 	/// Returns the data frames that constitute one message.
@@ -141,35 +1145,535 @@ impl ws::Receiver for Receiver {
 	{
 		let uuid = self.uuid;
 		let mut finished = if self.buffer.is_empty() {
-			let first = self.recv_dataframe(reader, uuid)?;
+			let first = self.recv_message_frame(reader, uuid)?;
 
 			if first.opcode == Opcode::Continuation {
 				return Err(WebSocketError::ProtocolError("Unexpected continuation data frame opcode",),);
 			}
 
 			let finished = first.finished;
-			self.buffer.push(first);
+			self.push_fragment(first)?;
 			finished
 		} else {
 			false
 		};
 
 		while !finished {
-			let next = self.recv_dataframe(reader, uuid)?;
+			let next = self.recv_message_frame(reader, uuid)?;
 			finished = next.finished;
 
-			match next.opcode as u8 {
-				// Continuation opcode
-				0 => self.buffer.push(next),
-				// Control frame
-				8...15 => {
-					return Ok(vec![next]);
+			if next.opcode == Opcode::Continuation {
+				self.push_fragment(next)?;
+			} else if next.opcode.is_control() {
+				return Ok(vec![next]);
+			} else {
+				// other data frames can't be part of this message
+				self.discard_partial_message();
+				return Err(dataframe::interleaved_data_frame_error(next.opcode as u8));
+			}
+		}
+
+		self.buffered = 0;
+		Ok(::std::mem::take(&mut self.buffer))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+	use message::CloseData;
+	use ws::dataframe::DataFrame as DataFrameable;
+
+	#[test]
+	fn end_of_stream_between_and_inside_frames() {
+		let mut input = Vec::new();
+		DataFrame::new(true, Opcode::Text, b"first".to_vec()).write_to(&mut input, false).unwrap();
+		let boundary = input.len();
+		DataFrame::new(true, Opcode::Binary, vec![7; 300]).write_to(&mut input, false).unwrap();
+
+		for end in 0..input.len() + 1 {
+			let mut receiver = Receiver::new(false, Uuid::nil());
+			let mut stream = Cursor::new(&input[..end]);
+			let mut frames = 0;
+			let error = loop {
+				match receiver.recv_dataframe(&mut stream, Uuid::nil()) {
+					Ok(_) => frames += 1,
+					Err(e) => break e,
+				}
+			};
+			match error {
+				WebSocketError::ConnectionClosed if end == 0 || end == boundary || end == input.len() => (),
+				WebSocketError::ProtocolError(reason) if reason == dfh::TRUNCATED_FRAME => {
+					assert!(end != 0 && end != boundary && end != input.len())
 				}
-				// Others
-				_ => return Err(WebSocketError::ProtocolError("Unexpected data frame opcode")),
+				other => panic!("unexpected error at {}: {:?}", end, other),
+			}
+			assert_eq!(frames, if end < boundary { 0 } else if end < input.len() { 1 } else { 2 });
+		}
+	}
+
+	#[test]
+	fn frames_after_close_are_rejected() {
+		let mut close = Vec::new();
+		DataFrame::new(true, Opcode::Close, Vec::new()).write_to(&mut close, false).unwrap();
+		let after = |opcode, data: &[u8]| {
+			let mut input = close.clone();
+			DataFrame::new(true, Opcode::Ping, b"dropped".to_vec()).write_to(&mut input, false).unwrap();
+			DataFrame::new(true, opcode, data.to_vec()).write_to(&mut input, false).unwrap();
+			let mut receiver = Receiver::new(false, Uuid::nil());
+			let mut stream = Cursor::new(input);
+			assert_eq!(receiver.recv_message(&mut stream).unwrap(), OwnedMessage::Close(None));
+			receiver.recv_message(&mut stream)
+		};
+
+		for &(opcode, reason) in &[(Opcode::Text, "Data frame received after close"),
+		                          (Opcode::Binary, "Data frame received after close"),
+		                          (Opcode::Close, "Second close frame received")] {
+			match after(opcode, b"") {
+				Err(WebSocketError::ProtocolError(r)) => assert_eq!(r, reason),
+				other => panic!("expected a protocol error for {:?}, got {:?}", opcode, other),
+			}
+		}
+		match after(Opcode::Pong, b"") {
+			Err(WebSocketError::ConnectionClosed) => (),
+			other => panic!("expected the pong to be dropped, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn messages_with_too_many_fragments_are_rejected() {
+		let mut input = Vec::new();
+		DataFrame::new(false, Opcode::Binary, vec![1]).write_to(&mut input, false).unwrap();
+		for _ in 0..3 {
+			DataFrame::new(false, Opcode::Continuation, vec![2]).write_to(&mut input, false).unwrap();
+		}
+		DataFrame::new(true, Opcode::Continuation, vec![3]).write_to(&mut input, false).unwrap();
+		DataFrame::new(true, Opcode::Text, b"next".to_vec()).write_to(&mut input, false).unwrap();
+
+		let mut receiver = Receiver::new(false, Uuid::nil());
+		receiver.set_max_fragments_per_message(5);
+		assert_eq!(receiver.recv_message(&mut Cursor::new(&input)).unwrap(),
+		           OwnedMessage::Binary(vec![1, 2, 2, 2, 3]));
+
+		let mut stream = Cursor::new(&input);
+		receiver.set_max_fragments_per_message(4);
+		match receiver.recv_message(&mut stream) {
+			Err(WebSocketError::ProtocolError(_)) => (),
+			other => panic!("expected a protocol error, got {:?}", other),
+		}
+		assert_eq!(receiver.recv_message(&mut stream).unwrap(), OwnedMessage::Text("next".to_string()));
+	}
+
+	#[test]
+	fn oversized_messages_report_their_size() {
+		let mut input = Vec::new();
+		DataFrame::new(false, Opcode::Binary, vec![0; 3]).write_to(&mut input, false).unwrap();
+		DataFrame::new(true, Opcode::Continuation, vec![0; 3]).write_to(&mut input, false).unwrap();
+		DataFrame::new(true, Opcode::Binary, vec![0; 5]).write_to(&mut input, false).unwrap();
+
+		let mut stream = Cursor::new(&input);
+		let mut receiver = Receiver::new(false, Uuid::nil());
+		receiver.set_max_message_size(Some(4));
+		match receiver.recv_message(&mut stream) {
+			Err(WebSocketError::MessageTooLarge { limit: 4, actual: 6, frame: false }) => (),
+			other => panic!("expected the fragments to be too large, got {:?}", other),
+		}
+		match receiver.recv_message(&mut stream) {
+			Err(WebSocketError::MessageTooLarge { limit: 4, actual: 5, frame: true }) => (),
+			other => panic!("expected the frame to be too large, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn message_size_counts_the_current_message_only() {
+		let mut input = Vec::new();
+		for _ in 0..3 {
+			DataFrame::new(false, Opcode::Binary, vec![0; 2]).write_to(&mut input, false).unwrap();
+			DataFrame::new(true, Opcode::Continuation, vec![0; 2]).write_to(&mut input, false).unwrap();
+		}
+
+		let mut stream = Cursor::new(&input);
+		let mut receiver = Receiver::new(false, Uuid::nil());
+		receiver.set_max_message_size(Some(4));
+		for _ in 0..3 {
+			assert_eq!(receiver.recv_message(&mut stream).unwrap(), OwnedMessage::Binary(vec![0; 4]));
+		}
+	}
+
+	#[test]
+	fn close_payload_is_kept_raw() {
+		let mut input = Vec::new();
+		DataFrame::new(true, Opcode::Text, b"text".to_vec()).write_to(&mut input, false).unwrap();
+		let payload = b"\x03\xe8going away".to_vec();
+		DataFrame::new(true, Opcode::Close, payload.clone()).write_to(&mut input, false).unwrap();
+
+		let mut receiver = Receiver::new(false, Uuid::nil());
+		let mut stream = Cursor::new(&input);
+		assert_eq!(receiver.recv_message_with_frames(&mut stream).unwrap().raw_close, None);
+
+		let close = receiver.recv_message_with_frames(&mut stream).unwrap();
+		assert_eq!(close.message,
+		           OwnedMessage::Close(Some(CloseData::new(1000, "going away".to_string()))));
+		assert_eq!(close.raw_close, Some(payload));
+	}
+
+	#[test]
+	fn incoming_dataframes_does_not_reassemble() {
+		let frames = vec![
+			DataFrame::new(false, Opcode::Text, b"fragm".to_vec()),
+			DataFrame::new(true, Opcode::Ping, b"hi".to_vec()),
+			DataFrame::new(true, Opcode::Continuation, b"ented".to_vec()),
+		];
+		let mut input = Vec::new();
+		for frame in frames.iter() {
+			frame.write_to(&mut input, false).unwrap();
+		}
+
+		let mut reader = reader(input, false);
+
+		let received: Vec<DataFrame> = reader.incoming_dataframes()
+		                                     .take(3)
+		                                     .map(Result::unwrap)
+		                                     .collect();
+		assert_eq!(received, frames);
+	}
+
+	// a reader over `input`, expecting masked frames if `mask` is set
+	fn reader(input: Vec<u8>, mask: bool) -> Reader<Cursor<Vec<u8>>> {
+		Reader {
+			stream: BufReader::new(Cursor::new(input)),
+			receiver: Receiver::new(mask, Uuid::new_v4()),
+		}
+	}
+
+	fn receive(frames: Vec<DataFrame>, count: usize) -> Vec<WebSocketResult<OwnedMessage>> {
+		let mut input = Vec::new();
+		for frame in frames.iter() {
+			frame.write_to(&mut input, false).unwrap();
+		}
+
+		let mut reader = reader(input, false);
+		(0..count).map(|_| reader.recv_message()).collect()
+	}
+
+	// autobahn cases 5.17, 5.18 and a mixed variant
+	#[test]
+	fn data_frames_in_fragmented_messages_are_rejected() {
+		for &(first, second, expected) in
+			&[(Opcode::Text, Opcode::Text, "text"),
+			  (Opcode::Binary, Opcode::Binary, "binary"),
+			  (Opcode::Text, Opcode::Binary, "binary"),
+			  (Opcode::Binary, Opcode::NonControl1, "reserved")] {
+			let received = receive(vec![
+				DataFrame::new(false, first, b"frag".to_vec()),
+				DataFrame::new(true, second, b"ment".to_vec()),
+				DataFrame::new(true, Opcode::Text, b"after".to_vec()),
+			], 2);
+
+			match received[0] {
+				Err(WebSocketError::ProtocolError(message)) => assert!(message.contains(expected)),
+				ref other => panic!("expected a protocol error, got {:?}", other),
+			}
+			// the broken message is dropped entirely
+			assert_eq!(received[1].as_ref().unwrap(), &OwnedMessage::Text("after".to_string()));
+		}
+	}
+
+	#[test]
+	fn analyzer_mode_records_frame_spans() {
+		let frames = [
+			DataFrame::new(false, Opcode::Text, vec![b'a'; 200]),
+			DataFrame::new(true, Opcode::Ping, b"hi".to_vec()),
+			DataFrame::new(true, Opcode::Continuation, b"ented".to_vec()),
+		];
+		let mut input = Vec::new();
+		for frame in frames.iter() {
+			frame.write_to(&mut input, true).unwrap();
+		}
+
+		let mut reader = reader(input, true);
+		reader.receiver.set_analyzer_mode(true);
+
+		let ping = reader.recv_message_with_frames().unwrap();
+		assert_eq!(ping.message, OwnedMessage::Ping(b"hi".to_vec()));
+		assert_eq!(ping.frames,
+		           vec![FrameSpan {
+		                    offset: 208,
+		                    header_len: 6,
+		                    payload_len: 2,
+		                    opcode: Opcode::Ping,
+		                    fin: true,
+		                }]);
+
+		let text = reader.recv_message_with_frames().unwrap();
+		let mut expected = vec![b'a'; 200];
+		expected.extend_from_slice(b"ented");
+		assert_eq!(text.message, OwnedMessage::Text(String::from_utf8(expected).unwrap()));
+		assert_eq!(text.frames,
+		           vec![FrameSpan {
+		                    offset: 0,
+		                    header_len: 8,
+		                    payload_len: 200,
+		                    opcode: Opcode::Text,
+		                    fin: false,
+		                },
+		                FrameSpan {
+		                    offset: 216,
+		                    header_len: 6,
+		                    payload_len: 5,
+		                    opcode: Opcode::Continuation,
+		                    fin: true,
+		                }]);
+	}
+
+	#[test]
+	fn recv_binary_to_streams_fragments() {
+		let frames = [
+			DataFrame::new(false, Opcode::Binary, vec![1; 5000]),
+			DataFrame::new(true, Opcode::Ping, b"hi".to_vec()),
+			DataFrame::new(true, Opcode::Continuation, vec![2; 3000]),
+			DataFrame::new(true, Opcode::Text, b"text".to_vec()),
+		];
+		let mut input = Vec::new();
+		for frame in frames.iter() {
+			frame.write_to(&mut input, true).unwrap();
+		}
+
+		let mut reader = reader(input, true);
+		reader.receiver.set_read_chunk_size(1024);
+
+		let mut sink = Vec::new();
+		assert_eq!(reader.recv_binary_to(&mut sink).unwrap(), 8000);
+		let mut expected = vec![1; 5000];
+		expected.extend(vec![2; 3000]);
+		assert_eq!(sink, expected);
+
+		assert_eq!(reader.recv_message().unwrap(), OwnedMessage::Ping(b"hi".to_vec()));
+
+		// the text message is left alone
+		assert!(reader.recv_binary_to(&mut sink).is_err());
+		assert_eq!(sink.len(), 8000);
+		assert_eq!(reader.recv_message().unwrap(), OwnedMessage::Text("text".to_string()));
+	}
+
+	// fails with `WouldBlock` once, when `at` bytes were read
+	struct StallOnce {
+		inner: Cursor<Vec<u8>>,
+		at: u64,
+	}
+
+	impl Read for StallOnce {
+		fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+			let pos = self.inner.position();
+			if pos == self.at {
+				self.at = u64::MAX;
+				return Err(io::Error::new(ErrorKind::WouldBlock, "stalled"));
 			}
+			let len = if pos < self.at {
+				cmp::min(buf.len() as u64, self.at - pos) as usize
+			} else {
+				buf.len()
+			};
+			self.inner.read(&mut buf[..len])
+		}
+	}
+
+	#[test]
+	fn recv_binary_to_continues_after_would_block() {
+		let frames = [
+			DataFrame::new(false, Opcode::Binary, (0..300).map(|i| i as u8).collect()),
+			DataFrame::new(true, Opcode::Ping, b"hi".to_vec()),
+			DataFrame::new(true, Opcode::Continuation, vec![9; 200]),
+		];
+		let mut input = Vec::new();
+		for frame in frames.iter() {
+			frame.write_to(&mut input, true).unwrap();
+		}
+		let mut expected: Vec<u8> = (0..300).map(|i| i as u8).collect();
+		expected.extend(vec![9; 200]);
+
+		for at in 0..input.len() as u64 {
+			let mut receiver = Receiver::new(true, Uuid::nil());
+			receiver.set_read_chunk_size(64);
+			let mut stream = StallOnce {
+				inner: Cursor::new(input.clone()),
+				at,
+			};
+			let mut sink = Vec::new();
+			match receiver.recv_binary_to(&mut stream, &mut sink) {
+				Err(WebSocketError::IoError(ref e)) if e.kind() == ErrorKind::WouldBlock => (),
+				other => panic!("expected to stall at {}, got {:?}", at, other),
+			}
+			assert!(receiver.recv_message(&mut stream).is_err());
+			assert_eq!(receiver.recv_binary_to(&mut stream, &mut sink).unwrap(), 500);
+			assert_eq!(sink, expected, "stalled at {}", at);
+			assert_eq!(receiver.recv_message(&mut stream).unwrap(), OwnedMessage::Ping(b"hi".to_vec()));
+		}
+	}
+
+	#[test]
+	fn recv_binary_to_checks_limits_before_writing() {
+		let frames = [
+			DataFrame::new(false, Opcode::Binary, vec![1; 60]),
+			DataFrame::new(true, Opcode::Continuation, vec![2; 60]),
+			DataFrame::new(true, Opcode::Text, b"next".to_vec()),
+		];
+		let mut input = Vec::new();
+		for frame in frames.iter() {
+			frame.write_to(&mut input, false).unwrap();
+		}
+
+		let mut receiver = Receiver::new(false, Uuid::nil());
+		receiver.set_max_message_size(Some(100));
+		let mut stream = Cursor::new(input);
+		let mut sink = Vec::new();
+		match receiver.recv_binary_to(&mut stream, &mut sink) {
+			Err(WebSocketError::MessageTooLarge { limit: 100, actual: 120, frame: false }) => (),
+			other => panic!("expected the message to be too large, got {:?}", other),
+		}
+		assert_eq!(sink, vec![1; 60]);
+		// the frame over the limit was read past
+		assert_eq!(receiver.recv_message(&mut stream).unwrap(), OwnedMessage::Text("next".to_string()));
+	}
+
+	#[test]
+	fn binary_stream_mode_reads_payloads_until_close() {
+		let frames = [
+			DataFrame::new(true, Opcode::Binary, b"one ".to_vec()),
+			DataFrame::new(false, Opcode::Binary, b"tw".to_vec()),
+			DataFrame::new(true, Opcode::Ping, b"hi".to_vec()),
+			DataFrame::new(true, Opcode::Continuation, b"o ".to_vec()),
+			DataFrame::new(true, Opcode::Binary, b"three".to_vec()),
+			DataFrame::new(true, Opcode::Close, Vec::new()),
+		];
+		let mut input = Vec::new();
+		for frame in frames.iter() {
+			frame.write_to(&mut input, false).unwrap();
+		}
+		DataFrame::new(true, Opcode::Text, b"text".to_vec()).write_to(&mut input, false).unwrap();
+
+		let mut binary = reader(input.clone(), false);
+		assert!(binary.read(&mut [0; 4]).is_err());
+		binary.receiver.set_binary_stream_mode(true);
+
+		let mut received = String::new();
+		binary.read_to_string(&mut received).unwrap();
+		assert_eq!(received, "one two three");
+		assert_eq!(binary.read(&mut [0; 4]).unwrap(), 0);
+
+		// text is rejected
+		let text = input.split_off(input.len() - 6);
+		let mut text = reader(text, false);
+		text.receiver.set_binary_stream_mode(true);
+		assert_eq!(text.read(&mut [0; 4]).unwrap_err().kind(), ErrorKind::InvalidData);
+	}
+
+	struct CountReads<R>(R, usize);
+
+	impl<R: Read> Read for CountReads<R> {
+		fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+			self.1 += 1;
+			self.0.read(buf)
+		}
+	}
+
+	#[test]
+	fn payload_is_read_in_chunks() {
+		let mut input = Vec::new();
+		DataFrame::new(true, Opcode::Binary, vec![3; 10000]).write_to(&mut input, false).unwrap();
+
+		let mut receiver = Receiver::new(false, Uuid::new_v4());
+		receiver.set_read_chunk_size(4096);
+		let mut reader = CountReads(Cursor::new(input), 0);
+		let uuid = receiver.uuid;
+		let frame = receiver.recv_dataframe(&mut reader, uuid).unwrap();
+
+		assert_eq!(frame.data, vec![3; 10000]);
+		// the 4 header bytes are read one at a time
+		assert_eq!(reader.1, 4 + 3);
+	}
+
+	#[test]
+	fn bursts_over_the_rate_are_limited() {
+		let mut input = Vec::new();
+		for _ in 0..6 {
+			DataFrame::new(true, Opcode::Binary, vec![0; 10]).write_to(&mut input, false).unwrap();
+		}
+
+		let mut receiver = Receiver::new(false, Uuid::new_v4());
+		receiver.set_rate_limit(Some(RateLimit::new().frames_per_sec(5)));
+		let mut reader = Cursor::new(input.clone());
+		for _ in 0..5 {
+			receiver.recv_message(&mut reader).unwrap();
+		}
+		match receiver.recv_message(&mut reader) {
+			Err(WebSocketError::RateLimited) => (),
+			other => panic!("expected the rate limit to trip, got {:?}", other),
+		}
+
+		// the allowance comes back with a reset
+		receiver.reset_rate_limit();
+		let mut reader = Cursor::new(input.clone());
+		for _ in 0..5 {
+			receiver.recv_message(&mut reader).unwrap();
+		}
+
+		// 20 bytes a second lets two of the frames through
+		receiver.set_rate_limit(Some(RateLimit::new().bytes_per_sec(20)));
+		let mut reader = Cursor::new(input);
+		receiver.recv_message(&mut reader).unwrap();
+		receiver.recv_message(&mut reader).unwrap();
+		match receiver.recv_message(&mut reader) {
+			Err(WebSocketError::RateLimited) => (),
+			other => panic!("expected the rate limit to trip, got {:?}", other),
 		}
+	}
+
+	fn read_frame(input: Vec<u8>) -> WebSocketResult<DataFrame> {
+		let mut reader = reader(input, false);
+		reader.recv_dataframe()
+	}
 
-		Ok(::std::mem::replace(&mut self.buffer, Vec::new()))
+	#[test]
+	fn length_with_most_significant_bit_is_rejected() {
+		let input = vec![0x82, 0x7F, 0x80, 0, 0, 0, 0, 0, 0, 0];
+		match read_frame(input) {
+			Err(WebSocketError::DataFrameError(_)) => (),
+			other => panic!("expected a data frame error, got {:?}", other),
+		}
+	}
+
+	#[test]
+	#[cfg(target_pointer_width = "32")]
+	fn huge_announced_length_is_rejected() {
+		// announces a 16 GiB payload
+		let input = vec![0x82, 0x7F, 0, 0, 0, 0x04, 0, 0, 0, 0];
+		match read_frame(input) {
+			Err(WebSocketError::MessageTooLarge { actual: 0x4_0000_0000, frame: true, .. }) => (),
+			other => panic!("expected the frame to be too large, got {:?}", other),
+		}
+	}
+
+	#[test]
+	#[cfg(target_pointer_width = "64")]
+	fn huge_announced_length_is_not_reserved() {
+		// announces a payload of 2^62 bytes and sends a few of them
+		let mut input = vec![0x82, 0x7F, 0x40, 0, 0, 0, 0, 0, 0, 0];
+		input.extend_from_slice(&[0; 16]);
+		match read_frame(input.clone()) {
+			Err(WebSocketError::ProtocolError(reason)) => assert_eq!(reason, dfh::TRUNCATED_FRAME),
+			other => panic!("expected a truncated frame, got {:?}", other),
+		}
+
+		let mut reader = reader(input, false);
+		reader.receiver.set_max_message_size(Some(1024));
+		// rejected before the payload is read, which would find it truncated
+		match reader.recv_dataframe() {
+			Err(WebSocketError::MessageTooLarge { limit: 1024, actual, frame: true }) => {
+				assert_eq!(actual, 1 << 62)
+			}
+			other => panic!("expected the frame to be too large, got {:?}", other),
+		}
 	}
 }
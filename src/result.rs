@@ -8,6 +8,7 @@ use std::fmt;
 use hyper::Error as HttpError;
 use url::ParseError;
 use server::upgrade::HyperIntoWsError;
+use ws::util::framing::FramingError;
 
 #[cfg(any(feature="sync-ssl", feature="async-ssl"))]
 use native_tls::Error as TlsError;
@@ -38,10 +39,45 @@ pub enum WebSocketError {
 	RequestError(&'static str),
 	/// Invalid WebSocket response error
 	ResponseError(&'static str),
+	/// The server answered the handshake with a status other than
+	/// `101 Switching Protocols`. Contains the status code and, for
+	/// redirects, the value of the `Location` header.
+	UnexpectedStatus(u16, Option<String>),
 	/// Invalid WebSocket data frame error
 	DataFrameError(&'static str),
+	/// A received frame or message is larger than the maximum size
+	MessageTooLarge {
+		/// The maximum size in bytes, for frames that don't fit in memory
+		/// the largest `usize`
+		limit: u64,
+		/// The size in bytes that went over the maximum
+		actual: u64,
+		/// Whether a single frame was too large, rather than the fragments of
+		/// a message taken together
+		frame: bool,
+	},
+	/// Frames arrived faster than the receiver's rate limit allows, see
+	/// `Receiver::set_rate_limit`.
+	RateLimited,
+	/// A message was sent after the connection was closed, by sending a
+	/// close message or because the stream failed. See `Client::is_open`.
+	AlreadyClosed,
 	/// No data available
 	NoDataAvailable,
+	/// The stream ended between two data frames.
+	///
+	/// The peer went away without finishing a closing handshake, or the
+	/// stream was read after it. A stream that ends in the middle of a frame
+	/// gives a `ProtocolError` instead.
+	ConnectionClosed,
+	/// A read or write didn't complete before the socket's timeout expired.
+	///
+	/// Operating systems report an expired timeout as either `TimedOut` or
+	/// `WouldBlock`, this variant is used for both so timeouts can be matched
+	/// the same way everywhere. A socket in nonblocking mode that has nothing
+	/// to read still gives an `IoError` of kind `WouldBlock`, since that is not
+	/// a timeout.
+	Timeout,
 	/// An input/output error
 	IoError(io::Error),
 	/// An HTTP parsing error
@@ -67,6 +103,16 @@ impl fmt::Display for WebSocketError {
 	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
 		fmt.write_str("WebSocketError: ")?;
 		fmt.write_str(self.description())?;
+		if let WebSocketError::MessageTooLarge { limit, actual, frame } = *self {
+			let what = if frame { "frame" } else { "message" };
+			write!(fmt, " ({} of {} bytes, limit {})", what, actual, limit)?;
+		}
+		if let WebSocketError::UnexpectedStatus(code, ref location) = *self {
+			write!(fmt, " {}", code)?;
+			if let Some(ref location) = *location {
+				write!(fmt, " (Location: {})", location)?;
+			}
+		}
 		Ok(())
 	}
 }
@@ -77,8 +123,14 @@ impl Error for WebSocketError {
 			WebSocketError::ProtocolError(_) => "WebSocket protocol error",
 			WebSocketError::RequestError(_) => "WebSocket request error",
 			WebSocketError::ResponseError(_) => "WebSocket response error",
+			WebSocketError::UnexpectedStatus(..) => "Unexpected handshake response status",
 			WebSocketError::DataFrameError(_) => "WebSocket data frame error",
+			WebSocketError::MessageTooLarge { .. } => "Message larger than the maximum message size",
+			WebSocketError::RateLimited => "Receive rate limit exceeded",
+			WebSocketError::AlreadyClosed => "Connection already closed",
 			WebSocketError::NoDataAvailable => "No data available",
+			WebSocketError::ConnectionClosed => "Connection closed",
+			WebSocketError::Timeout => "Operation timed out",
 			WebSocketError::IoError(_) => "I/O failure",
 			WebSocketError::HttpError(_) => "HTTP failure",
 			WebSocketError::UrlError(_) => "URL failure",
@@ -112,10 +164,22 @@ impl From<io::Error> for WebSocketError {
 		if err.kind() == io::ErrorKind::UnexpectedEof {
 			return WebSocketError::NoDataAvailable;
 		}
+		if err.kind() == io::ErrorKind::TimedOut {
+			return WebSocketError::Timeout;
+		}
 		WebSocketError::IoError(err)
 	}
 }
 
+impl From<FramingError> for WebSocketError {
+	fn from(err: FramingError) -> WebSocketError {
+		match err {
+			FramingError::FragmentedControlFrame => WebSocketError::ProtocolError(err.description()),
+			_ => WebSocketError::DataFrameError(err.description()),
+		}
+	}
+}
+
 impl From<HttpError> for WebSocketError {
 	fn from(err: HttpError) -> WebSocketError {
 		WebSocketError::HttpError(err)
@@ -178,10 +242,15 @@ impl From<HyperIntoWsError> for WebSocketError {
 			UnsupportedHttpVersion => ProtocolError("Unsupported request HTTP version"),
 			UnsupportedWebsocketVersion => ProtocolError("Unsupported WebSocket version"),
 			NoSecWsKeyHeader => ProtocolError("Missing Sec-WebSocket-Key header"),
+			InvalidSecWsKeyHeader => ProtocolError("Invalid Sec-WebSocket-Key header"),
+			NoHostHeader => ProtocolError("Missing Host header"),
 			NoWsUpgradeHeader => ProtocolError("Invalid Upgrade WebSocket header"),
 			NoUpgradeHeader => ProtocolError("Missing Upgrade WebSocket header"),
 			NoWsConnectionHeader => ProtocolError("Invalid Connection WebSocket header"),
 			NoConnectionHeader => ProtocolError("Missing Connection WebSocket header"),
+			RequestHasBody => ProtocolError("Upgrade request must not have a body"),
+			HyperIntoWsError::Timeout => WebSocketError::Timeout,
+			TooManyConnections => RequestError("Too many connections from the same address"),
 		}
 	}
 }
@@ -1,12 +1,21 @@
 //! The default implementation of a WebSocket Sender.
 
-use std::io::Write;
+use std::io::{self, ErrorKind, Write};
 use std::io::Result as IoResult;
-use result::WebSocketResult;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use result::{WebSocketResult, WebSocketError};
+use dataframe::{DataFrame as OwnedDataFrame, Opcode};
+use receiver::PacketState;
 use ws::dataframe::DataFrame;
+use ws::extension::{SharedExtension, PERMESSAGE_DEFLATE};
+use ws::util::framing::{self, DataFrameFlags};
+use ws::util::header::ReaderState;
+use uuid::Uuid;
 use stream::sync::AsTcpStream;
 use ws;
 use ws::sender::Sender as SenderTrait;
+use message::Message;
 pub use stream::sync::Shutdown;
 
 /// A writer that bundles a stream with a serializer to send the messages.
@@ -37,6 +46,42 @@ impl<W> Writer<W>
 	{
 		self.sender.send_message(&mut self.stream, message)
 	}
+
+	/// Sends a text message straight from `text`, see `Sender::send_text`.
+	pub fn send_text(&mut self, text: &str) -> WebSocketResult<()> {
+		self.sender.send_text(&mut self.stream, text)
+	}
+
+	/// Sends a batch of messages with a single write, see `Sender::send_all`.
+	pub fn send_all<I>(&mut self, messages: I) -> Result<(), (usize, WebSocketError)>
+		where I: IntoIterator,
+		      I::Item: ws::Message
+	{
+		self.sender.send_all(&mut self.stream, messages)
+	}
+
+	/// Starts sending a fragmented message, see `FragmentSerializer`. The
+	/// fragments are passed to the registered extensions.
+	pub fn fragments(&mut self) -> FragmentSerializer<'_, W> {
+		FragmentSerializer::with_sender(&mut self.stream, &mut self.sender)
+	}
+
+	/// Sends a message made of the given fragments, see
+	/// `Sender::send_fragmented`.
+	pub fn send_fragmented(&mut self, opcode: Opcode, fragments: &[&[u8]]) -> WebSocketResult<()> {
+		self.sender.send_fragmented(&mut self.stream, opcode, fragments)
+	}
+
+	/// Sends one frame exactly as given, see `Sender::send_frame_raw`.
+	pub fn send_frame_raw(&mut self, opcode: Opcode, fin: bool, rsv: DataFrameFlags, payload: &[u8]) -> WebSocketResult<()> {
+		self.sender.send_frame_raw(&mut self.stream, opcode, fin, rsv, payload)
+	}
+
+	/// Flushes the stream, see `Client::flush`.
+	pub fn flush(&mut self) -> WebSocketResult<()> {
+		self.stream.flush()?;
+		Ok(())
+	}
 }
 
 impl<S> Writer<S>
@@ -53,18 +98,262 @@ impl<S> Writer<S>
 	pub fn shutdown_all(&self) -> IoResult<()> {
 		self.stream.as_tcp().shutdown(Shutdown::Both)
 	}
+
+	/// See [`TcpStream::peer_addr`]
+	/// (https://doc.rust-lang.org/std/net/struct.TcpStream.html#method.peer_addr).
+	pub fn peer_addr(&self) -> IoResult<SocketAddr> {
+		self.stream.as_tcp().peer_addr()
+	}
+
+	/// See [`TcpStream::local_addr`]
+	/// (https://doc.rust-lang.org/std/net/struct.TcpStream.html#method.local_addr).
+	pub fn local_addr(&self) -> IoResult<SocketAddr> {
+		self.stream.as_tcp().local_addr()
+	}
+}
+
+/// A `Writer` that several threads can send through, clones share it.
+///
+/// Each method holds the writer's lock until it is done, so the frames of
+/// one call are never interleaved with those of another thread.
+pub struct SharedWriter<W> {
+	inner: Arc<Mutex<Writer<W>>>,
+}
+
+impl<W> SharedWriter<W>
+    where W: Write
+{
+	/// Shares `writer`, e.g. the writing half of a split client.
+	pub fn new(writer: Writer<W>) -> Self {
+		SharedWriter { inner: Arc::new(Mutex::new(writer)) }
+	}
+
+	/// Sends a single message to the remote endpoint.
+	pub fn send_message<M>(&self, message: &M) -> WebSocketResult<()>
+		where M: ws::Message
+	{
+		self.inner.lock().unwrap().send_message(message)
+	}
+
+	/// Sends `messages` back to back, no other thread's frames can come
+	/// between them. Like `Sender::send_all` they are written with a single
+	/// write, on failure the number of messages completely written is
+	/// returned along with the error.
+	///
+	/// The lock is held until the whole batch was written and flushed, which
+	/// takes as long as the peer needs to take it in. Every other thread
+	/// sending on this writer waits for that long, so keep batches small or
+	/// set a write timeout on the stream when peers may be slow.
+	pub fn send_atomic<M>(&self, messages: &[M]) -> Result<(), (usize, WebSocketError)>
+		where M: ws::Message
+	{
+		let mut writer = self.inner.lock().unwrap();
+		let writer = &mut *writer;
+		writer.sender.send_slice(&mut writer.stream, messages)
+	}
+
+	/// Locks the writer for any sequence of sends, e.g. a fragmented
+	/// message. Other threads wait until the guard is dropped.
+	pub fn lock(&self) -> MutexGuard<'_, Writer<W>> {
+		self.inner.lock().unwrap()
+	}
+}
+
+impl<W> Clone for SharedWriter<W> {
+	fn clone(&self) -> Self {
+		SharedWriter { inner: self.inner.clone() }
+	}
 }
 
 /// A Sender that wraps a Writer and provides a default implementation using
 /// DataFrames and Messages.
 pub struct Sender {
 	mask: bool,
+	extensions: Vec<SharedExtension>,
+	deflate: bool,
 }
 
 impl Sender {
 	/// Create a new WebSocketSender using the specified Writer.
 	pub fn new(mask: bool) -> Sender {
-		Sender { mask: mask }
+		Sender {
+			mask,
+			extensions: Vec::new(),
+			deflate: false,
+		}
+	}
+
+	pub(crate) fn add_extension(&mut self, extension: SharedExtension) {
+		if extension.lock().unwrap().name() == PERMESSAGE_DEFLATE {
+			self.deflate = true;
+		}
+		self.extensions.push(extension);
+	}
+
+	pub(crate) fn retain_extensions<F>(&mut self, keep: F)
+		where F: FnMut(&SharedExtension) -> bool
+	{
+		self.extensions.retain(keep);
+		self.deflate = self.extensions
+		                   .iter()
+		                   .any(|e| e.lock().unwrap().name() == PERMESSAGE_DEFLATE);
+	}
+
+	fn send_extended<W>(&mut self, writer: &mut W, mut frame: OwnedDataFrame) -> WebSocketResult<()>
+		where W: Write
+	{
+		for extension in &self.extensions {
+			extension.lock().unwrap().on_send_frame(&mut frame);
+		}
+		// RFC7692 6.1: only the first frame of a compressed message has RSV1 set
+		if self.deflate && frame.opcode == Opcode::Continuation {
+			frame.reserved[0] = false;
+		}
+		frame.write_to(writer, self.mask)
+	}
+
+	/// Sends `text` as a single text frame.
+	///
+	/// This is the same as sending `Message::text(text)`, but writes the bytes
+	/// of `text` directly without building a message first. Masking happens
+	/// on the stack or while streaming the payload, so nothing is allocated.
+	/// With extensions the message goes through `send_message` instead.
+	pub fn send_text<W>(&mut self, writer: &mut W, text: &str) -> WebSocketResult<()>
+		where W: Write
+	{
+		if !self.extensions.is_empty() {
+			return self.send_message(writer, &Message::text(text));
+		}
+		Fragment {
+			fin: true,
+			opcode: Opcode::Text as u8,
+			reserved: [false; 3],
+			data: text.as_bytes(),
+		}
+		.write_to(writer, self.mask)
+	}
+
+	/// Sends a message whose fragments are all known up front, one frame
+	/// per fragment.
+	///
+	/// The first frame carries `opcode`, the rest are continuation frames and
+	/// only the last has FIN set. An empty list of fragments and control
+	/// opcodes are rejected. Like `FragmentSerializer`, the frames don't pass
+	/// through extensions.
+	pub fn send_fragmented<W>(&mut self, writer: &mut W, opcode: Opcode, fragments: &[&[u8]]) -> WebSocketResult<()>
+		where W: Write
+	{
+		let (last, rest) = match fragments.split_last() {
+			Some(split) => split,
+			None => return Err(WebSocketError::ProtocolError("Fragmented message has no fragments")),
+		};
+		let mut serializer = FragmentSerializer::new(writer, self.mask);
+		serializer.begin(opcode)?;
+		for fragment in rest {
+			serializer.push(fragment)?;
+		}
+		serializer.finish(last)
+	}
+
+	/// Sends exactly one frame with the given opcode, FIN bit and reserved
+	/// bits, masked if this is the sender of a client.
+	///
+	/// Only the RSV bits of `rsv` are used, FIN is set by `fin`. Control
+	/// frames must have FIN set and at most 125 bytes of payload. Nothing
+	/// else is checked: it is up to the caller to send a valid sequence of
+	/// frames and, since the frame doesn't pass through extensions, to only
+	/// set reserved bits an extension agreed on.
+	pub fn send_frame_raw<W>(
+		&mut self,
+		writer: &mut W,
+		opcode: Opcode,
+		fin: bool,
+		rsv: DataFrameFlags,
+		payload: &[u8],
+	) -> WebSocketResult<()>
+		where W: Write
+	{
+		let mut flags = rsv & (DataFrameFlags::RSV1 | DataFrameFlags::RSV2 | DataFrameFlags::RSV3);
+		flags.set(DataFrameFlags::FIN, fin);
+		framing::check_control(opcode as u8, flags, payload.len() as u64)?;
+
+		Fragment {
+			fin,
+			opcode: opcode as u8,
+			reserved: [flags.contains(DataFrameFlags::RSV1),
+			           flags.contains(DataFrameFlags::RSV2),
+			           flags.contains(DataFrameFlags::RSV3)],
+			data: payload,
+		}
+		.write_to(writer, self.mask)
+	}
+
+	/// Sends a batch of messages.
+	///
+	/// The messages are serialized into one buffer which is written and
+	/// flushed once, so a burst of small messages doesn't cost a system call
+	/// each. On failure the number of messages that were completely written
+	/// is returned along with the error.
+	pub fn send_all<I, W>(&mut self, writer: &mut W, messages: I) -> Result<(), (usize, WebSocketError)>
+		where I: IntoIterator,
+		      I::Item: ws::Message,
+		      W: Write
+	{
+		let messages: Vec<I::Item> = messages.into_iter().collect();
+		self.send_slice(writer, &messages)
+	}
+
+	fn send_slice<M, W>(&mut self, writer: &mut W, messages: &[M]) -> Result<(), (usize, WebSocketError)>
+		where M: ws::Message,
+		      W: Write
+	{
+		let mut buffer = Vec::new();
+		// where each message ends in the buffer
+		let mut ends = Vec::new();
+		let mut failure = None;
+		for message in messages {
+			if let Err(e) = self.send_message(&mut buffer, message) {
+				// only write out the messages before the one that failed
+				buffer.truncate(ends.last().cloned().unwrap_or(0));
+				failure = Some(e);
+				break;
+			}
+			ends.push(buffer.len());
+		}
+
+		let mut written = 0;
+		while written < buffer.len() {
+			match writer.write(&buffer[written..]) {
+				Ok(0) => {
+					failure = Some(io::Error::new(ErrorKind::WriteZero, "failed to write whole batch").into());
+					break;
+				}
+				Ok(n) => written += n,
+				Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+				Err(e) => {
+					failure = Some(e.into());
+					break;
+				}
+			}
+		}
+		let sent = ends.iter().take_while(|&&end| end <= written).count();
+
+		match failure {
+			Some(e) => Err((sent, e)),
+			None => writer.flush().map_err(|e| (sent, e.into())),
+		}
+	}
+
+	/// Change whether the frames sent are masked.
+	///
+	/// **This violates RFC6455** when used to mask frames sent by a server
+	/// or to stop masking frames sent by a client. It only exists to build
+	/// tools that check how other implementations react to such frames and
+	/// needs the `testing` feature, or `unmasked-client` (see
+	/// `Client::use_unmasked_frames`).
+	#[cfg(any(feature="testing", feature="unmasked-client"))]
+	pub fn set_mask(&mut self, mask: bool) {
+		self.mask = mask;
 	}
 }
 
@@ -72,4 +361,522 @@ impl ws::Sender for Sender {
 	fn is_masked(&self) -> bool {
 		self.mask
 	}
+
+	fn send_dataframe<D, W>(&mut self, writer: &mut W, dataframe: &D) -> WebSocketResult<()>
+		where D: DataFrame,
+		      W: Write
+	{
+		if self.extensions.is_empty() {
+			return dataframe.write_to(writer, self.mask);
+		}
+
+		let mut data = Vec::with_capacity(dataframe.size());
+		dataframe.write_payload(&mut data)?;
+		let frame = OwnedDataFrame {
+			finished: dataframe.is_last(),
+			reserved: *dataframe.reserved(),
+			opcode: Opcode::new(dataframe.opcode())
+				.ok_or(WebSocketError::DataFrameError("Invalid data frame opcode"))?,
+			data,
+		};
+		self.send_extended(writer, frame)
+	}
+
+	fn send_message<M, W>(&mut self, writer: &mut W, message: &M) -> WebSocketResult<()>
+		where M: ws::Message,
+		      W: Write
+	{
+		if self.extensions.is_empty() {
+			return message.serialize(writer, self.mask);
+		}
+		if let Some(frame) = message.to_single_frame() {
+			return self.send_extended(writer, frame);
+		}
+
+		// the extensions work on frames, so take other messages apart again
+		let mut serialized = Vec::with_capacity(message.message_size(false));
+		message.serialize(&mut serialized, false)?;
+
+		let mut input = &serialized[..];
+		let mut packet_state = PacketState::default();
+		let mut reader_state = ReaderState::new();
+		while !input.is_empty() {
+			let frame = OwnedDataFrame::read_dataframe(
+				&mut input,
+				false,
+				Uuid::nil(),
+				&mut packet_state,
+				&mut reader_state,
+			)?;
+			self.send_extended(writer, frame)?;
+		}
+		Ok(())
+	}
+}
+
+/// Sends a single message as a sequence of fragments.
+///
+/// The opcode of the message is given once to `begin`, the first fragment is
+/// sent with it and all later ones as continuation frames. `finish` sends the
+/// last fragment with the FIN bit set, after which nothing else can be sent.
+///
+/// ```rust
+/// # use websocket::dataframe::Opcode;
+/// # use websocket::sender::FragmentSerializer;
+/// # let mut stream = Vec::new();
+/// let mut fragments = FragmentSerializer::new(&mut stream, true);
+/// fragments.begin(Opcode::Text).unwrap();
+/// fragments.push(b"Hello ").unwrap();
+/// fragments.finish(b"world!").unwrap();
+/// ```
+pub struct FragmentSerializer<'a, W: 'a> {
+	writer: &'a mut W,
+	mask: bool,
+	// sends the fragments through the extensions of a sender
+	sender: Option<&'a mut Sender>,
+	opcode: Option<Opcode>,
+	started: bool,
+	finished: bool,
+}
+
+impl<'a, W> FragmentSerializer<'a, W>
+    where W: Write
+{
+	/// Creates a serializer writing to `writer`, masking the frames if `mask`
+	/// is set.
+	pub fn new(writer: &'a mut W, mask: bool) -> Self {
+		FragmentSerializer {
+			writer,
+			mask,
+			sender: None,
+			opcode: None,
+			started: false,
+			finished: false,
+		}
+	}
+
+	pub(crate) fn with_sender(writer: &'a mut W, sender: &'a mut Sender) -> Self {
+		FragmentSerializer {
+			writer,
+			mask: sender.mask,
+			sender: Some(sender),
+			opcode: None,
+			started: false,
+			finished: false,
+		}
+	}
+
+	/// Sets the opcode of the message.
+	///
+	/// Control frames can't be fragmented so their opcodes are rejected, as is
+	/// calling this more than once.
+	pub fn begin(&mut self, opcode: Opcode) -> WebSocketResult<()> {
+		if self.opcode.is_some() {
+			return Err(WebSocketError::ProtocolError("Fragmented message already begun"));
+		}
+		match opcode {
+			Opcode::Text | Opcode::Binary | Opcode::NonControl1 | Opcode::NonControl2 |
+			Opcode::NonControl3 | Opcode::NonControl4 | Opcode::NonControl5 => (),
+			_ => return Err(WebSocketError::ProtocolError("Cannot fragment a control or continuation opcode")),
+		}
+		self.opcode = Some(opcode);
+		Ok(())
+	}
+
+	/// Sends a fragment that is not the last one.
+	pub fn push(&mut self, data: &[u8]) -> WebSocketResult<()> {
+		self.send(false, data)
+	}
+
+	/// Sends the last fragment, completing the message.
+	pub fn finish(&mut self, data: &[u8]) -> WebSocketResult<()> {
+		self.send(true, data)?;
+		self.finished = true;
+		Ok(())
+	}
+
+	fn send(&mut self, fin: bool, data: &[u8]) -> WebSocketResult<()> {
+		if self.finished {
+			return Err(WebSocketError::ProtocolError("Fragmented message already finished"));
+		}
+		let opcode = match self.opcode {
+			Some(_) if self.started => Opcode::Continuation,
+			Some(opcode) => opcode,
+			None => return Err(WebSocketError::ProtocolError("Fragmented message not begun")),
+		};
+
+		let fragment = Fragment {
+			fin,
+			opcode: opcode as u8,
+			reserved: [false; 3],
+			data,
+		};
+		match self.sender {
+			Some(ref mut sender) => sender.send_dataframe(self.writer, &fragment)?,
+			None => fragment.write_to(self.writer, self.mask)?,
+		}
+		self.started = true;
+		Ok(())
+	}
+}
+
+struct Fragment<'a> {
+	fin: bool,
+	opcode: u8,
+	reserved: [bool; 3],
+	data: &'a [u8],
+}
+
+impl<'a> DataFrame for Fragment<'a> {
+	fn is_last(&self) -> bool {
+		self.fin
+	}
+
+	fn opcode(&self) -> u8 {
+		self.opcode
+	}
+
+	fn reserved(&self) -> &[bool; 3] {
+		&self.reserved
+	}
+
+	fn size(&self) -> usize {
+		self.data.len()
+	}
+
+	fn write_payload(&self, socket: &mut Write) -> WebSocketResult<()> {
+		socket.write_all(self.data)?;
+		Ok(())
+	}
+
+	fn take_payload(self) -> Vec<u8> {
+		self.data.to_vec()
+	}
+
+	fn payload_slice(&self) -> Option<&[u8]> {
+		Some(self.data)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dataframe::DataFrame as OwnedDataFrame;
+	use receiver::PacketState;
+	use ws::util::header::ReaderState;
+	use uuid::Uuid;
+
+	fn read_frames(input: &[u8]) -> Vec<(bool, Opcode, Vec<u8>)> {
+		let mut input = input;
+		let mut packet_state = PacketState::default();
+		let mut reader_state = ReaderState::new();
+		let mut frames = Vec::new();
+		while !input.is_empty() {
+			let frame = OwnedDataFrame::read_dataframe(&mut input,
+			                                           true,
+			                                           Uuid::new_v4(),
+			                                           &mut packet_state,
+			                                           &mut reader_state)
+				.unwrap();
+			frames.push((frame.finished, frame.opcode, frame.data));
+		}
+		frames
+	}
+
+	// marks every frame it is handed with RSV3
+	struct MarkFrames;
+
+	impl ws::extension::Extension for MarkFrames {
+		fn name(&self) -> &str {
+			"mark-frames"
+		}
+
+		fn on_send_frame(&mut self, frame: &mut OwnedDataFrame) {
+			frame.reserved[2] = true;
+		}
+
+		fn on_recv_frame(&mut self, _: &mut OwnedDataFrame) -> WebSocketResult<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn messages_reach_extensions_as_single_frames() {
+		let mut sender = Sender::new(false);
+		sender.add_extension(Arc::new(Mutex::new(Box::new(MarkFrames))));
+		let mut output = Vec::new();
+		sender.send_message(&mut output, &Message::close_because(1000, "bye")).unwrap();
+
+		let mut expected = Message::close_because(1000, "bye").into_single_frame();
+		expected.reserved[2] = true;
+		let mut bytes = Vec::new();
+		expected.write_to(&mut bytes, false).unwrap();
+		assert_eq!(output, bytes);
+	}
+
+	#[test]
+	fn writer_flush_flushes_the_stream() {
+		let mut writer = Writer {
+			stream: ::std::io::BufWriter::new(Vec::new()),
+			sender: Sender::new(false),
+		};
+
+		// frames are flushed as they are written
+		writer.send_message(&Message::text("sent")).unwrap();
+		let sent = writer.stream.get_ref().len();
+		assert!(sent > 0);
+
+		// bytes written to the stream directly wait for a flush
+		writer.stream.write_all(b"raw").unwrap();
+		assert_eq!(writer.stream.get_ref().len(), sent);
+		writer.flush().unwrap();
+		assert_eq!(&writer.stream.get_ref()[sent..], b"raw");
+	}
+
+	#[test]
+	fn send_text_matches_send_message() {
+		for &len in &[0, 5, 125, 126, 70000] {
+			let text = "\u{e9}".repeat(len);
+			let mut direct = Vec::new();
+			let mut message = Vec::new();
+			let mut sender = Sender::new(false);
+			sender.send_text(&mut direct, &text).unwrap();
+			sender.send_message(&mut message, &Message::text(&text[..])).unwrap();
+			assert_eq!(direct, message);
+
+			// masked with another key, but the same frame underneath
+			let mut masked = Vec::new();
+			Sender::new(true).send_text(&mut masked, &text).unwrap();
+			assert_eq!(read_frames(&masked), vec![(true, Opcode::Text, text.into_bytes())]);
+		}
+	}
+
+	#[test]
+	fn fragments_use_continuation_after_the_first() {
+		let mut output = Vec::new();
+		{
+			let mut fragments = FragmentSerializer::new(&mut output, true);
+			fragments.begin(Opcode::Binary).unwrap();
+			fragments.push(b"a").unwrap();
+			fragments.push(b"b").unwrap();
+			fragments.finish(b"c").unwrap();
+		}
+
+		assert_eq!(read_frames(&output),
+		           vec![(false, Opcode::Binary, b"a".to_vec()),
+		                (false, Opcode::Continuation, b"b".to_vec()),
+		                (true, Opcode::Continuation, b"c".to_vec())]);
+	}
+
+	#[test]
+	fn send_fragmented_reassembles() {
+		use message::OwnedMessage;
+		use ws::Message;
+
+		let mut output = Vec::new();
+		let mut sender = Sender::new(true);
+		sender.send_fragmented(&mut output, Opcode::Text, &[b"He", b"ll", b"o"]).unwrap();
+
+		let frames = read_frames(&output);
+		assert_eq!(frames.iter().map(|f| (f.0, f.1)).collect::<Vec<_>>(),
+		           vec![(false, Opcode::Text),
+		                (false, Opcode::Continuation),
+		                (true, Opcode::Continuation)]);
+		let frames = frames.into_iter()
+		                   .map(|(fin, opcode, data)| OwnedDataFrame::new(fin, opcode, data))
+		                   .collect();
+		assert_eq!(OwnedMessage::from_dataframes(frames).unwrap(),
+		           OwnedMessage::Text("Hello".to_string()));
+
+		assert!(sender.send_fragmented(&mut output, Opcode::Binary, &[]).is_err());
+		assert!(sender.send_fragmented(&mut output, Opcode::Ping, &[b"a"]).is_err());
+	}
+
+	#[test]
+	fn raw_frames_build_a_fragmented_message() {
+		let mut output = Vec::new();
+		let mut sender = Sender::new(true);
+		sender.send_frame_raw(&mut output, Opcode::Binary, false, DataFrameFlags::RSV1, b"ab").unwrap();
+		sender.send_frame_raw(&mut output, Opcode::Ping, true, DataFrameFlags::empty(), b"p").unwrap();
+		// FIN in the reserved flags is ignored
+		sender.send_frame_raw(&mut output, Opcode::Continuation, true, DataFrameFlags::FIN, b"c").unwrap();
+
+		assert_eq!(output[0], 0x42);
+		assert_eq!(read_frames(&output),
+		           vec![(false, Opcode::Binary, b"ab".to_vec()),
+		                (true, Opcode::Ping, b"p".to_vec()),
+		                (true, Opcode::Continuation, b"c".to_vec())]);
+
+		let empty = DataFrameFlags::empty();
+		assert!(sender.send_frame_raw(&mut output, Opcode::Pong, false, empty, b"").is_err());
+		assert!(sender.send_frame_raw(&mut output, Opcode::Close, true, empty, &[0; 126]).is_err());
+	}
+
+	#[test]
+	fn single_fragment_keeps_opcode() {
+		let mut output = Vec::new();
+		{
+			let mut fragments = FragmentSerializer::new(&mut output, true);
+			fragments.begin(Opcode::Text).unwrap();
+			fragments.finish(b"hi").unwrap();
+		}
+
+		assert_eq!(read_frames(&output), vec![(true, Opcode::Text, b"hi".to_vec())]);
+	}
+
+	#[test]
+	fn misuse_is_rejected() {
+		let mut output = Vec::new();
+		{
+			let mut fragments = FragmentSerializer::new(&mut output, false);
+			assert!(fragments.push(b"too early").is_err());
+			assert!(fragments.begin(Opcode::Ping).is_err());
+			assert!(fragments.begin(Opcode::Continuation).is_err());
+			fragments.begin(Opcode::Text).unwrap();
+			assert!(fragments.begin(Opcode::Text).is_err());
+			fragments.finish(b"done").unwrap();
+			assert!(fragments.push(b"too late").is_err());
+			assert!(fragments.finish(b"too late").is_err());
+		}
+
+		assert_eq!(output, b"\x81\x04done");
+	}
+
+	// accepts a limited number of bytes, a few at a time
+	struct Limited {
+		written: Vec<u8>,
+		writes: usize,
+		limit: usize,
+	}
+
+	impl Write for Limited {
+		fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+			if self.written.len() == self.limit {
+				return Err(io::Error::other("limit reached"));
+			}
+			let n = buf.len().min(self.limit - self.written.len()).min(5);
+			self.written.extend_from_slice(&buf[..n]);
+			self.writes += 1;
+			Ok(n)
+		}
+
+		fn flush(&mut self) -> IoResult<()> {
+			Ok(())
+		}
+	}
+
+	// fails every other write with `Interrupted`, like a signal arriving
+	struct Interrupting(Vec<u8>, bool);
+
+	impl Write for Interrupting {
+		fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+			self.1 = !self.1;
+			if self.1 {
+				return Err(io::Error::new(ErrorKind::Interrupted, "signal"));
+			}
+			self.0.write(buf)
+		}
+
+		fn flush(&mut self) -> IoResult<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn interrupted_writes_are_retried() {
+		use message::OwnedMessage;
+
+		let messages = vec![OwnedMessage::Text("one".to_string()), OwnedMessage::Binary(vec![2; 300])];
+		let mut expected = Vec::new();
+		let mut sender = Sender::new(false);
+		for message in &messages {
+			sender.send_message(&mut expected, message).unwrap();
+		}
+
+		let mut single = Interrupting(Vec::new(), false);
+		for message in &messages {
+			sender.send_message(&mut single, message).unwrap();
+		}
+		let mut batched = Interrupting(Vec::new(), false);
+		sender.send_all(&mut batched, messages).unwrap();
+		assert_eq!(single.0, expected);
+		assert_eq!(batched.0, expected);
+	}
+
+	// takes a few bytes per write and lets other threads run in between
+	struct Trickle(Vec<u8>);
+
+	impl Write for Trickle {
+		fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+			::std::thread::yield_now();
+			let n = buf.len().min(3);
+			self.0.extend_from_slice(&buf[..n]);
+			Ok(n)
+		}
+
+		fn flush(&mut self) -> IoResult<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn atomic_batches_are_not_interleaved() {
+		use std::thread;
+		use message::OwnedMessage;
+
+		let writer = SharedWriter::new(Writer {
+		                                   stream: Trickle(Vec::new()),
+		                                   sender: Sender::new(true),
+		                               });
+		let threads: Vec<_> = (0..4u8)
+			.map(|id| {
+				let writer = writer.clone();
+				thread::spawn(move || for _ in 0..20 {
+					let batch: Vec<_> = (0..3).map(|part| OwnedMessage::Binary(vec![id, part])).collect();
+					writer.send_atomic(&batch).unwrap();
+				})
+			})
+			.collect();
+		for thread in threads {
+			thread.join().unwrap();
+		}
+
+		let frames = read_frames(&writer.lock().stream.0);
+		assert_eq!(frames.len(), 4 * 20 * 3);
+		for batch in frames.chunks(3) {
+			let id = batch[0].2[0];
+			let parts: Vec<_> = batch.iter().map(|frame| frame.2.clone()).collect();
+			assert_eq!(parts, vec![vec![id, 0], vec![id, 1], vec![id, 2]]);
+		}
+	}
+
+	#[test]
+	fn send_all_batches_messages() {
+		use message::OwnedMessage;
+
+		let messages = vec![OwnedMessage::Text("one".to_string()),
+		                    OwnedMessage::Binary(vec![2; 2]),
+		                    OwnedMessage::Text("three".to_string())];
+
+		let mut output = Vec::new();
+		Sender::new(false).send_all(&mut output, messages.clone()).unwrap();
+		let mut expected = Vec::new();
+		for message in &messages {
+			Sender::new(false).send_message(&mut expected, message).unwrap();
+		}
+		assert_eq!(output, expected);
+
+		// the second message is cut off, so only the first one counts
+		let mut limited = Limited {
+			written: Vec::new(),
+			writes: 0,
+			limit: 7,
+		};
+		let (sent, _) = Sender::new(false)
+			.send_all(&mut limited, messages)
+			.unwrap_err();
+		assert_eq!(sent, 1);
+		assert_eq!(limited.written, &expected[..7]);
+		assert!(limited.writes > 1);
+	}
 }
@@ -38,6 +38,12 @@ impl WsServer<NoTlsAcceptor, TcpListener> {
 		Ok(Server {
 		       listener: TcpListener::from_listener(tcp, &address, handle)?,
 		       ssl_acceptor: NoTlsAcceptor,
+		       handshake_hook: None,
+		       handshake_timeout: None,
+		       #[cfg(feature="sync")]
+		       shutdown: None,
+		       #[cfg(feature="sync")]
+		       ip_limit: None,
 		   })
 	}
 
@@ -59,6 +65,7 @@ impl WsServer<NoTlsAcceptor, TcpListener> {
 			                              parsed: None,
 			                              buffer: None,
 			                              error: e.into(),
+			                              tcp_stream: None,
 			                          }
 			                         })
 		                 .and_then(|(stream, a)| {
@@ -69,6 +76,7 @@ impl WsServer<NoTlsAcceptor, TcpListener> {
 				                   parsed: req,
 				                   buffer: Some(buf),
 				                   error: err,
+				                   tcp_stream: None,
 				               }
 				              })
 			      .map(move |u| (u, a))
@@ -96,6 +104,12 @@ impl WsServer<TlsAcceptor, TcpListener> {
 		Ok(Server {
 		       listener: TcpListener::from_listener(tcp, &address, handle)?,
 		       ssl_acceptor: acceptor,
+		       handshake_hook: None,
+		       handshake_timeout: None,
+		       #[cfg(feature="sync")]
+		       shutdown: None,
+		       #[cfg(feature="sync")]
+		       ip_limit: None,
 		   })
 	}
 
@@ -118,6 +132,7 @@ impl WsServer<TlsAcceptor, TcpListener> {
 			                              parsed: None,
 			                              buffer: None,
 			                              error: e.into(),
+			                              tcp_stream: None,
 			                          }
 			                         })
 		                 .and_then(move |(stream, a)| {
@@ -129,6 +144,7 @@ impl WsServer<TlsAcceptor, TcpListener> {
 					buffer: None,
 					// TODO: better error types
 					error: io::Error::new(io::ErrorKind::Other, e).into(),
+					tcp_stream: None,
 				}
 			})
 			        .map(move |s| (s, a))
@@ -141,6 +157,7 @@ impl WsServer<TlsAcceptor, TcpListener> {
 				                   parsed: req,
 				                   buffer: Some(buf),
 				                   error: err,
+				                   tcp_stream: None,
 				               }
 				              })
 			      .map(move |u| (u, a))
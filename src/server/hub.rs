@@ -0,0 +1,247 @@
+//! A hub to broadcast messages to many connected clients.
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender as ChannelSender};
+use std::thread;
+
+use ws::Message;
+use result::WebSocketResult;
+use sender::Writer;
+
+/// The default number of bytes that may be queued for a client before it is
+/// dropped, 1 MiB.
+pub const DEFAULT_MAX_QUEUED_BYTES: usize = 1024 * 1024;
+
+/// Identifies a client registered with a `Hub`.
+pub type ClientId = usize;
+
+struct Entry {
+	id: ClientId,
+	queue: ChannelSender<Arc<Vec<u8>>>,
+	queued: Arc<AtomicUsize>,
+}
+
+/// Keeps track of the writing halves of connected clients and sends
+/// messages to all of them.
+///
+/// Every registered writer gets a thread that writes what is broadcast to
+/// it, so a slow client doesn't hold up the others. A client that has more
+/// than `max_queued_bytes` waiting to be written is dropped instead of
+/// queueing more, as is one whose writes fail.
+///
+/// A broadcast message is serialized once and the same bytes are written to
+/// every client. They are not masked and frame extensions of the writers are
+/// skipped, so the hub is meant for the server side of connections without
+/// extensions.
+///
+///```rust,no_run
+///# extern crate websocket;
+///# fn main() {
+///use std::sync::Arc;
+///use std::thread;
+///use websocket::Message;
+///use websocket::sync::Server;
+///use websocket::sync::server::Hub;
+///
+///let server = Server::bind("127.0.0.1:1234").unwrap();
+///let hub = Arc::new(Hub::new());
+///
+///for upgrade in server.filter_map(Result::ok) {
+///    let hub = hub.clone();
+///    thread::spawn(move || {
+///        let client = upgrade.accept().unwrap();
+///        let (mut reader, writer) = client.split().unwrap();
+///        let id = hub.register(writer);
+///        for message in reader.incoming_messages().filter_map(Result::ok) {
+///            if let websocket::OwnedMessage::Text(text) = message {
+///                hub.broadcast(&Message::text(text)).unwrap();
+///            }
+///        }
+///        hub.deregister(id);
+///    });
+///}
+///# }
+///```
+pub struct Hub {
+	clients: Mutex<Vec<Entry>>,
+	next_id: AtomicUsize,
+	max_queued_bytes: usize,
+}
+
+impl Hub {
+	/// Creates an empty hub that drops clients with more than
+	/// `DEFAULT_MAX_QUEUED_BYTES` queued.
+	pub fn new() -> Hub {
+		Hub::with_max_queued_bytes(DEFAULT_MAX_QUEUED_BYTES)
+	}
+
+	/// Creates an empty hub that drops clients with more than
+	/// `max_queued_bytes` queued.
+	pub fn with_max_queued_bytes(max_queued_bytes: usize) -> Hub {
+		Hub {
+			clients: Mutex::new(Vec::new()),
+			next_id: AtomicUsize::new(0),
+			max_queued_bytes,
+		}
+	}
+
+	/// Adds a client, returning the id to deregister it with.
+	pub fn register<W>(&self, writer: Writer<W>) -> ClientId
+		where W: Write + Send + 'static
+	{
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		let (queue, pending) = mpsc::channel::<Arc<Vec<u8>>>();
+		let queued = Arc::new(AtomicUsize::new(0));
+
+		let written = queued.clone();
+		let mut stream = writer.stream;
+		thread::spawn(move || {
+			for bytes in pending {
+				if stream.write_all(&bytes).and_then(|_| stream.flush()).is_err() {
+					// dropping `pending` makes the next broadcast prune this client
+					return;
+				}
+				written.fetch_sub(bytes.len(), Ordering::SeqCst);
+			}
+		});
+
+		self.clients.lock().unwrap().push(Entry {
+		                                      id,
+		                                      queue,
+		                                      queued,
+		                                  });
+		id
+	}
+
+	/// Removes a client, returns whether it was still registered.
+	///
+	/// What was already queued for it is still written.
+	pub fn deregister(&self, id: ClientId) -> bool {
+		let mut clients = self.clients.lock().unwrap();
+		let before = clients.len();
+		clients.retain(|entry| entry.id != id);
+		clients.len() != before
+	}
+
+	/// The number of registered clients.
+	pub fn len(&self) -> usize {
+		self.clients.lock().unwrap().len()
+	}
+
+	/// Whether no clients are registered.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Queues a message for every client, returning how many it was queued
+	/// for.
+	///
+	/// Clients whose writes failed or that would have more than the maximum
+	/// number of bytes queued are removed. Only serializing the message can
+	/// fail.
+	pub fn broadcast<M>(&self, message: &M) -> WebSocketResult<usize>
+		where M: Message
+	{
+		let mut bytes = Vec::with_capacity(message.message_size(false));
+		message.serialize(&mut bytes, false)?;
+		let bytes = Arc::new(bytes);
+
+		let mut clients = self.clients.lock().unwrap();
+		let max = self.max_queued_bytes;
+		clients.retain(|entry| {
+			let queued = entry.queued.fetch_add(bytes.len(), Ordering::SeqCst) + bytes.len();
+			if queued > max {
+				debug!("Dropping client {} from the hub with {} bytes queued", entry.id, queued);
+				return false;
+			}
+			entry.queue.send(bytes.clone()).is_ok()
+		});
+		Ok(clients.len())
+	}
+}
+
+impl Default for Hub {
+	fn default() -> Self {
+		Hub::new()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io;
+	use std::sync::mpsc::Receiver as ChannelReceiver;
+	use std::time::{Duration, Instant};
+	use sender::Sender;
+	use message::OwnedMessage;
+
+	#[derive(Clone)]
+	struct Shared(Arc<Mutex<Vec<u8>>>);
+
+	impl Write for Shared {
+		fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+			self.0.lock().unwrap().extend_from_slice(data);
+			Ok(data.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	// blocks every write until it is allowed through
+	struct Stalled(Mutex<ChannelReceiver<()>>);
+
+	impl Write for Stalled {
+		fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+			match self.0.lock().unwrap().recv() {
+				Ok(()) => Ok(data.len()),
+				Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed")),
+			}
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	fn writer<W: Write>(stream: W) -> Writer<W> {
+		Writer {
+			stream,
+			sender: Sender::new(false),
+		}
+	}
+
+	#[test]
+	fn slow_clients_are_dropped() {
+		let hub = Hub::with_max_queued_bytes(20);
+		let fast = Shared(Arc::new(Mutex::new(Vec::new())));
+		let (_unblock, blocked) = mpsc::channel();
+		hub.register(writer(fast.clone()));
+		let slow = hub.register(writer(Stalled(Mutex::new(blocked))));
+
+		let message = OwnedMessage::Text("hello".to_string());
+		let mut frame = Vec::new();
+		message.serialize(&mut frame, false).unwrap();
+
+		// the slow client takes the first message and blocks writing it, the
+		// fast one is given time to write each message before the next
+		let mut sent = 0;
+		while hub.len() == 2 {
+			hub.broadcast(&message).unwrap();
+			sent += 1;
+			assert!(sent <= 4, "the slow client was never dropped");
+
+			let expected = frame.repeat(sent);
+			let start = Instant::now();
+			while *fast.0.lock().unwrap() != expected {
+				assert!(start.elapsed() < Duration::from_secs(5), "messages never arrived");
+				thread::sleep(Duration::from_millis(5));
+			}
+		}
+		assert_eq!(sent, 3);
+		assert_eq!(hub.len(), 1);
+		assert!(!hub.deregister(slow));
+	}
+}
@@ -0,0 +1,114 @@
+//! Limits on the number of connections a server keeps open.
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+
+/// Counts the open connections of every source address and refuses new ones
+/// from an address that is at the limit, see `Server::set_ip_limit`.
+///
+/// Clones share their counts, so several servers, e.g. one per address
+/// family, can enforce one limit together. IPv4 clients of a dual-stack
+/// server count as their IPv4 address.
+///
+///```rust
+///use websocket::sync::server::IpLimit;
+///
+///let limit = IpLimit::new(1);
+///let ip = "192.0.2.1".parse().unwrap();
+///let guard = limit.acquire(ip).unwrap();
+///assert!(limit.acquire(ip).is_none());
+///drop(guard);
+///assert!(limit.acquire(ip).is_some());
+///```
+#[derive(Debug, Clone)]
+pub struct IpLimit {
+	max: usize,
+	active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl IpLimit {
+	/// Allows `max` connections per source address.
+	///
+	/// # Panics
+	///
+	/// Panics if `max` is 0.
+	pub fn new(max: usize) -> IpLimit {
+		assert!(max > 0, "a limit of 0 refuses every connection");
+		IpLimit {
+			max,
+			active: Arc::new(Mutex::new(HashMap::new())),
+		}
+	}
+
+	/// The number of connections allowed per source address.
+	pub fn max(&self) -> usize {
+		self.max
+	}
+
+	/// Counts a connection from `ip`, unless it is at the limit already. The
+	/// connection counts until the guard is dropped.
+	pub fn acquire(&self, ip: IpAddr) -> Option<ConnectionGuard> {
+		let ip = unmapped(ip);
+		let mut active = self.active.lock().unwrap();
+		let count = active.entry(ip).or_insert(0);
+		if *count >= self.max {
+			return None;
+		}
+		*count += 1;
+		Some(ConnectionGuard {
+		         active: self.active.clone(),
+		         ip,
+		     })
+	}
+
+	/// The number of open connections from `ip`.
+	pub fn active(&self, ip: IpAddr) -> usize {
+		self.active.lock().unwrap().get(&unmapped(ip)).cloned().unwrap_or(0)
+	}
+}
+
+// `::ffff:a.b.c.d` is how a dual-stack socket shows the IPv4 client a.b.c.d
+fn unmapped(ip: IpAddr) -> IpAddr {
+	if let IpAddr::V6(v6) = ip {
+		let s = v6.segments();
+		if s[..6] == [0, 0, 0, 0, 0, 0xffff] {
+			return IpAddr::V4(Ipv4Addr::new((s[6] >> 8) as u8, s[6] as u8, (s[7] >> 8) as u8, s[7] as u8));
+		}
+	}
+	ip
+}
+
+/// A connection counted by an `IpLimit`, until this is dropped.
+///
+/// A server with a limit hands the guard to the `Upgrade` it accepts, and
+/// from there to the `Client` made by accepting it. Splitting the client hands
+/// it on to the `Reader`.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+	active: Arc<Mutex<HashMap<IpAddr, usize>>>,
+	ip: IpAddr,
+}
+
+impl ConnectionGuard {
+	/// The address the connection is counted for.
+	pub fn ip(&self) -> IpAddr {
+		self.ip
+	}
+}
+
+impl Drop for ConnectionGuard {
+	fn drop(&mut self) {
+		let mut active = self.active.lock().unwrap();
+		let gone = match active.get_mut(&self.ip) {
+			Some(count) => {
+				*count -= 1;
+				*count == 0
+			}
+			None => false,
+		};
+		// addresses without connections don't keep taking up room
+		if gone {
+			active.remove(&self.ip);
+		}
+	}
+}
@@ -4,8 +4,12 @@ use native_tls::TlsAcceptor;
 
 use stream::Stream;
 use self::upgrade::{Request, HyperIntoWsError};
+use header::HandshakeHook;
+use std::net::TcpStream;
+use std::time::Duration;
 
 pub mod upgrade;
+pub mod limit;
 
 #[cfg(feature="async")]
 pub mod async;
@@ -13,6 +17,9 @@ pub mod async;
 #[cfg(feature="sync")]
 pub mod sync;
 
+#[cfg(feature="sync")]
+pub mod hub;
+
 /// Marker struct for a struct not being secure
 #[derive(Clone)]
 pub struct NoTlsAcceptor;
@@ -45,6 +52,12 @@ pub struct InvalidConnection<S, B>
 	pub buffer: Option<B>,
 	/// the cause of the failed websocket connection setup
 	pub error: HyperIntoWsError,
+	/// the plain TCP connection, if `stream` is `None` only because the TLS
+	/// handshake failed, e.g. when a plain HTTP health check reached a secure
+	/// server, or because the connection was over the server's `IpLimit`. It can still be used to send a plain response, but whatever the
+	/// client sent during the failed handshake has already been read.
+	/// Only the synchronous server can recover it.
+	pub tcp_stream: Option<TcpStream>,
 }
 
 /// Represents a WebSocket server which can work with either normal
@@ -91,4 +104,10 @@ pub struct WsServer<S, L>
 	listener: L,
 	/// The SSL acceptor given to the server
 	pub ssl_acceptor: S,
+	handshake_hook: Option<HandshakeHook>,
+	handshake_timeout: Option<Duration>,
+	#[cfg(feature="sync")]
+	shutdown: Option<sync::ShutdownHandle>,
+	#[cfg(feature="sync")]
+	ip_limit: Option<limit::IpLimit>,
 }
@@ -1,12 +1,32 @@
 //! Provides an implementation of a WebSocket server
-use std::net::{SocketAddr, ToSocketAddrs, TcpListener, TcpStream};
-use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, TcpListener, TcpStream, IpAddr, Ipv4Addr, Ipv6Addr};
+use std::io::{self, Write};
 use std::convert::Into;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+#[cfg(unix)]
+use std::time::Instant;
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use libc;
+#[cfg(windows)]
+use std::os::windows::io::{AsRawSocket, RawSocket};
+use socket2::{Socket, Domain, Type, Protocol};
 #[cfg(feature="sync-ssl")]
-use native_tls::{TlsStream, TlsAcceptor};
+use native_tls::{TlsStream, TlsAcceptor, HandshakeError, MidHandshakeTlsStream};
 use server::{WsServer, OptionalTlsAcceptor, NoTlsAcceptor, InvalidConnection};
-use server::upgrade::sync::{Upgrade, IntoWs, Buffer};
+use server::upgrade::sync::{Upgrade, IntoWs, Buffer, into_ws_within};
 pub use server::upgrade::{Request, HyperIntoWsError};
+pub use server::limit::{IpLimit, ConnectionGuard};
+use stream::sync::Stream;
+use header::{Headers, HandshakeHook, HandshakeDirection};
+use message::{OwnedMessage, CloseData};
+use ws::Message;
 
 #[cfg(feature="async")]
 use tokio_core::reactor::Handle;
@@ -79,6 +99,156 @@ impl<S> WsServer<S, TcpListener>
 		self.listener.set_nonblocking(nonblocking)
 	}
 
+	/// Get a reference to the underlying listener, to configure socket options
+	/// this crate doesn't wrap. For example the backlog can be changed by
+	/// listening again with `socket2`:
+	///
+	/// ```rust,no_run
+	/// # extern crate socket2;
+	/// # extern crate websocket;
+	/// # fn main() {
+	/// use socket2::SockRef;
+	/// use websocket::sync::Server;
+	///
+	/// let server = Server::bind("127.0.0.1:0").unwrap();
+	/// SockRef::from(server.listener_ref()).listen(1024).unwrap();
+	/// # }
+	/// ```
+	///
+	/// Changing the listener while connections are being accepted on it, in
+	/// particular from another thread or via a clone, can interact with
+	/// `accept` in unexpected ways.
+	pub fn listener_ref(&self) -> &TcpListener {
+		&self.listener
+	}
+
+	/// Get a mutable reference to the underlying listener, see `listener_ref`.
+	pub fn listener_mut(&mut self) -> &mut TcpListener {
+		&mut self.listener
+	}
+
+	/// Consumes the server, returning the underlying listener.
+	pub fn into_inner(self) -> TcpListener {
+		self.listener
+	}
+
+	/// Limits the time `accept` waits for the handshake request of a new
+	/// connection, counting from when the connection was accepted.
+	///
+	/// Unlike a read timeout on the socket this bounds the total time, so a
+	/// client trickling in the request a byte at a time can't hold on to
+	/// the server. When the budget runs out `accept` fails with a
+	/// `HyperIntoWsError::Timeout`. `None` (the default) waits forever.
+	pub fn set_handshake_timeout(&mut self, budget: Option<Duration>) {
+		self.handshake_timeout = budget;
+	}
+
+	/// Limits the number of connections open at once from one source
+	/// address, `None` (the default) for no limit.
+	///
+	/// A connection over the limit is refused by `accept` before its request
+	/// is read, with a `HyperIntoWsError::TooManyConnections` whose
+	/// `status_code` is 503. The stream of the refused connection is part of
+	/// the error, dropping it closes the connection. Accepted connections
+	/// count until the `Upgrade` is dropped or rejected, or until the client
+	/// it was accepted as is dropped, see `ConnectionGuard`. Clones of the
+	/// server made afterwards share the counts.
+	pub fn set_ip_limit(&mut self, limit: Option<IpLimit>) {
+		self.ip_limit = limit;
+	}
+
+	// counts a new connection against the limit, if there is one
+	fn admit(&self, addr: SocketAddr) -> Result<Option<ConnectionGuard>, HyperIntoWsError> {
+		match self.ip_limit {
+			Some(ref limit) => {
+				limit.acquire(addr.ip())
+				     .map(Some)
+				     .ok_or(HyperIntoWsError::TooManyConnections)
+			}
+			None => Ok(None),
+		}
+	}
+
+	/// Calls `hook` with the headers of every handshake request as soon as it
+	/// is parsed, and with the headers of the response right before it is
+	/// sent by `accept` or `reject`.
+	pub fn on_handshake<F>(&mut self, hook: F)
+		where F: Fn(HandshakeDirection, &Headers) + Send + Sync + 'static
+	{
+		self.handshake_hook = Some(HandshakeHook::new(hook));
+	}
+
+	/// Get a handle to stop this server from another thread.
+	///
+	/// Once `shutdown` is called on the handle, iterating over the server
+	/// returns `None`; a blocked `next` is woken up for it. Every call returns
+	/// a handle to the same shutdown, which is shared with clones of the
+	/// server made afterwards.
+	pub fn shutdown_handle(&mut self) -> io::Result<ShutdownHandle> {
+		if self.shutdown.is_none() {
+			self.shutdown = Some(ShutdownHandle::new(self.local_addr()?));
+		}
+		Ok(self.shutdown.clone().unwrap())
+	}
+
+	fn is_shut_down(&self) -> bool {
+		self.shutdown.as_ref().is_some_and(ShutdownHandle::is_shut_down)
+	}
+
+	// waits until the listener has a connection to accept or `timeout` passed
+	#[cfg(unix)]
+	fn poll_listener(&self, timeout: Duration) -> io::Result<bool> {
+		let mut fd = libc::pollfd {
+			fd: self.listener.as_raw_fd(),
+			events: libc::POLLIN,
+			revents: 0,
+		};
+		let deadline = Instant::now() + timeout;
+		loop {
+			let left = deadline.saturating_duration_since(Instant::now());
+			// round up so a timeout below a millisecond doesn't become a busy loop
+			let millis = (left.as_secs() * 1000 + u64::from(left.subsec_nanos()).div_ceil(1_000_000))
+				.min(libc::c_int::MAX as u64) as libc::c_int;
+			match unsafe { libc::poll(&mut fd, 1, millis) } {
+				-1 => {
+					let e = io::Error::last_os_error();
+					if e.kind() != io::ErrorKind::Interrupted {
+						return Err(e);
+					}
+				}
+				0 => return Ok(false),
+				_ => return Ok(true),
+			}
+		}
+	}
+
+	fn hook_upgrade<T: Stream>(&self, mut upgrade: Upgrade<T>) -> Upgrade<T> {
+		if let Some(ref hook) = self.handshake_hook {
+			hook.call(HandshakeDirection::Incoming, &upgrade.request.headers);
+			upgrade.handshake_hook = Some(hook.clone());
+		}
+		upgrade
+	}
+
+	/// Create a new independently owned handle to the underlying socket.
+	///
+	/// The returned server shares the same listening socket, so clones can
+	/// be moved to other threads and `accept` on all of them concurrently;
+	/// each incoming connection is handed to exactly one of them.
+	pub fn try_clone(&self) -> io::Result<Self>
+		where S: Clone
+	{
+		let inner = self.listener.try_clone()?;
+		Ok(Server {
+		       listener: inner,
+		       ssl_acceptor: self.ssl_acceptor.clone(),
+		       handshake_hook: self.handshake_hook.clone(),
+		       handshake_timeout: self.handshake_timeout,
+		       shutdown: self.shutdown.clone(),
+		       ip_limit: self.ip_limit.clone(),
+		   })
+	}
+
 	/// Turns an existing synchronous server into an asynchronous one.
 	/// This will only work if the stream used for this server `S` already implements
 	/// `AsyncRead + AsyncWrite`. Useful if you would like some blocking things to happen
@@ -89,6 +259,10 @@ impl<S> WsServer<S, TcpListener>
 		Ok(WsServer {
 		       listener: AsyncTcpListener::from_listener(self.listener, &addr, handle)?,
 		       ssl_acceptor: self.ssl_acceptor,
+		       handshake_hook: self.handshake_hook,
+		       handshake_timeout: self.handshake_timeout,
+		       shutdown: None,
+		       ip_limit: None,
 		   })
 	}
 }
@@ -141,61 +315,188 @@ impl WsServer<TlsAcceptor, TcpListener> {
 		Ok(Server {
 		       listener: TcpListener::bind(&addr)?,
 		       ssl_acceptor: acceptor,
+		       handshake_hook: None,
+		       handshake_timeout: None,
+		       shutdown: None,
+		       ip_limit: None,
 		   })
 	}
 
+	/// Like `accept`, but waits at most `timeout` for a connection to arrive
+	/// and returns `None` if none did.
+	///
+	/// The listener stays in blocking mode, it is polled for the connection
+	/// instead. Only the wait for the connection is bounded, see
+	/// `set_handshake_timeout` to limit the handshake too. If a clone of the
+	/// server accepts the connection first, this blocks until the next one.
+	#[cfg(unix)]
+	pub fn accept_timeout(&mut self, timeout: Duration) -> Option<AcceptResult<TlsStream<TcpStream>>> {
+		match self.poll_listener(timeout) {
+			Ok(true) => Some(self.accept()),
+			Ok(false) => None,
+			Err(e) => Some(Err(listener_error(e))),
+		}
+	}
+
 	/// Wait for and accept an incoming WebSocket connection, returning a WebSocketRequest
 	pub fn accept(&mut self) -> AcceptResult<TlsStream<TcpStream>> {
-		let stream = match self.listener.accept() {
-			Ok(s) => s.0,
+		let (stream, addr) = match self.listener.accept() {
+			Ok(s) => s,
 			Err(e) => {
 				return Err(InvalidConnection {
 				               stream: None,
 				               parsed: None,
 				               buffer: None,
 				               error: e.into(),
+				               tcp_stream: None,
+				           })
+			}
+		};
+		let guard = match self.admit(addr) {
+			Ok(guard) => guard,
+			Err(e) => {
+				return Err(InvalidConnection {
+				               stream: None,
+				               parsed: None,
+				               buffer: None,
+				               error: e,
+				               tcp_stream: Some(stream),
 				           })
 			}
 		};
 
-		let stream = match self.ssl_acceptor.accept(stream) {
-			Ok(s) => s,
+		// the acceptor takes the stream, keep a handle to give back if it fails
+		let raw = stream.try_clone().ok();
+		let stream = match tls_handshake(self.ssl_acceptor.accept(stream)) {
+			Ok(TlsHandshake::Done(s)) => s,
+			Ok(TlsHandshake::Pending(_)) => {
+				return Err(InvalidConnection {
+				               stream: None,
+				               parsed: None,
+				               buffer: None,
+				               error: io::Error::new(io::ErrorKind::WouldBlock,
+				                                     "TLS handshake would block").into(),
+				               tcp_stream: raw,
+				           })
+			}
 			Err(err) => {
 				return Err(InvalidConnection {
 				               stream: None,
 				               parsed: None,
 				               buffer: None,
-				               error: io::Error::new(io::ErrorKind::Other, err).into(),
+				               error: err.into(),
+				               tcp_stream: raw,
 				           })
 			}
 		};
 
-		match stream.into_ws() {
-			Ok(u) => Ok(u),
+		let upgrade = match self.handshake_timeout {
+			Some(budget) => into_ws_within(stream, budget),
+			None => stream.into_ws(),
+		};
+		match upgrade {
+			Ok(u) => {
+				let mut u = self.hook_upgrade(u);
+				u.connection_guard = guard;
+				Ok(u)
+			}
 			Err((s, r, b, e)) => {
 				Err(InvalidConnection {
 				        stream: Some(s),
 				        parsed: r,
 				        buffer: b,
 				        error: e.into(),
+				        tcp_stream: None,
 				    })
 			}
 		}
 	}
 }
 
+/// The progress of a TLS handshake started by `accept_nonblocking`.
+#[cfg(feature="sync-ssl")]
+pub enum TlsHandshake {
+	/// The handshake completed.
+	Done(TlsStream<TcpStream>),
+	/// The socket wasn't ready, continue with `resume_handshake` once
+	/// `get_ref()` of the pending handshake is readable or writable.
+	Pending(MidHandshakeTlsStream<TcpStream>),
+}
+
+/// Separates handshakes that only need to wait for the socket from failed
+/// ones, which become `InvalidData` errors.
+#[cfg(feature="sync-ssl")]
+fn tls_handshake(result: Result<TlsStream<TcpStream>, HandshakeError<TcpStream>>) -> io::Result<TlsHandshake> {
+	match result {
+		Ok(stream) => Ok(TlsHandshake::Done(stream)),
+		Err(HandshakeError::Interrupted(pending)) => Ok(TlsHandshake::Pending(pending)),
+		Err(HandshakeError::Failure(err)) => Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+	}
+}
+
+#[cfg(feature="sync-ssl")]
+impl WsServer<TlsAcceptor, TcpListener> {
+	/// Accepts a connection and starts its TLS handshake without blocking,
+	/// for servers driven by an event loop.
+	///
+	/// The accepted socket is put in nonblocking mode, a handshake that needs
+	/// to wait for it is returned as `TlsHandshake::Pending`. A failed
+	/// handshake is an `InvalidData` error, and a listener in nonblocking
+	/// mode with no connection waiting fails with `WouldBlock`. Once done,
+	/// read the upgrade request with `into_ws`, after making the stream
+	/// blocking again.
+	///
+	/// `accept` reports a handshake that would block as a `WouldBlock` error
+	/// instead, the handshake is lost then.
+	pub fn accept_nonblocking(&mut self) -> io::Result<TlsHandshake> {
+		let (stream, _) = self.listener.accept()?;
+		stream.set_nonblocking(true)?;
+		tls_handshake(self.ssl_acceptor.accept(stream))
+	}
+
+	/// Continues a handshake returned as pending by `accept_nonblocking` or
+	/// an earlier call to this.
+	pub fn resume_handshake(pending: MidHandshakeTlsStream<TcpStream>) -> io::Result<TlsHandshake> {
+		tls_handshake(pending.handshake())
+	}
+
+	/// Accept connections on a pool of `workers` threads, calling `handler`
+	/// with every successfully parsed upgrade request.
+	///
+	/// See the non-secure `serve` for details.
+	pub fn serve<F>(mut self, workers: usize, handler: F) -> io::Result<ServeHandle>
+		where F: Fn(Upgrade<TlsStream<TcpStream>>) + Send + Sync + 'static
+	{
+		let addr = self.local_addr()?;
+		serve_with(addr, workers, move || self.accept(), handler)
+	}
+}
+
 #[cfg(feature="sync-ssl")]
 impl Iterator for WsServer<TlsAcceptor, TcpListener> {
 	type Item = AcceptResult<TlsStream<TcpStream>>;
 
 	fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-		Some(self.accept())
+		if self.is_shut_down() {
+			return None;
+		}
+		let result = self.accept();
+		// the connection that woke us up might be the one made by `shutdown`
+		if self.is_shut_down() {
+			return None;
+		}
+		Some(result)
 	}
 }
 
 impl WsServer<NoTlsAcceptor, TcpListener> {
 	/// Bind this Server to this socket
 	///
+	/// Whether binding to `[::]` also accepts IPv4 connections depends on the
+	/// platform's default for `IPV6_V6ONLY`: Linux usually accepts both,
+	/// Windows and the BSDs only IPv6. Use `bind_dual_stack` to accept both
+	/// everywhere it is supported.
+	///
 	/// # Non-secure Servers
 	///
 	/// ```no_run
@@ -224,43 +525,152 @@ impl WsServer<NoTlsAcceptor, TcpListener> {
 		Ok(Server {
 		       listener: TcpListener::bind(&addr)?,
 		       ssl_acceptor: NoTlsAcceptor,
+		       handshake_hook: None,
+		       handshake_timeout: None,
+		       shutdown: None,
+		       ip_limit: None,
+		   })
+	}
+
+	/// Binds a server to `port` on every IPv6 and IPv4 address of the host,
+	/// using one IPv6 socket with `IPV6_V6ONLY` disabled.
+	///
+	/// IPv4 clients show up with IPv4-mapped addresses (`::ffff:a.b.c.d`).
+	/// Platforms without dual-stack sockets, like OpenBSD, or hosts with IPv6
+	/// disabled give an error, bind one server per address family there.
+	pub fn bind_dual_stack(port: u16) -> io::Result<Self> {
+		Ok(Server {
+		       listener: dual_stack_listener(port)?,
+		       ssl_acceptor: NoTlsAcceptor,
+		       handshake_hook: None,
+		       handshake_timeout: None,
+		       shutdown: None,
+		       ip_limit: None,
 		   })
 	}
 
+	/// Like `accept`, but waits at most `timeout` for a connection to arrive
+	/// and returns `None` if none did.
+	///
+	/// The listener stays in blocking mode, it is polled for the connection
+	/// instead. Only the wait for the connection is bounded, see
+	/// `set_handshake_timeout` to limit the handshake too. If a clone of the
+	/// server accepts the connection first, this blocks until the next one.
+	#[cfg(unix)]
+	pub fn accept_timeout(&mut self, timeout: Duration) -> Option<AcceptResult<TcpStream>> {
+		match self.poll_listener(timeout) {
+			Ok(true) => Some(self.accept()),
+			Ok(false) => None,
+			Err(e) => Some(Err(listener_error(e))),
+		}
+	}
+
 	/// Wait for and accept an incoming WebSocket connection, returning a WebSocketRequest
 	pub fn accept(&mut self) -> AcceptResult<TcpStream> {
-		let stream = match self.listener.accept() {
-			Ok(s) => s.0,
+		let (stream, addr) = match self.listener.accept() {
+			Ok(s) => s,
 			Err(e) => {
 				return Err(InvalidConnection {
 				               stream: None,
 				               parsed: None,
 				               buffer: None,
 				               error: e.into(),
+				               tcp_stream: None,
+				           })
+			}
+		};
+		let guard = match self.admit(addr) {
+			Ok(guard) => guard,
+			Err(e) => {
+				return Err(InvalidConnection {
+				               stream: Some(stream),
+				               parsed: None,
+				               buffer: None,
+				               error: e,
+				               tcp_stream: None,
 				           })
 			}
 		};
 
-		match stream.into_ws() {
-			Ok(u) => Ok(u),
+		let upgrade = match self.handshake_timeout {
+			Some(budget) => into_ws_within(stream, budget),
+			None => stream.into_ws(),
+		};
+		match upgrade {
+			Ok(u) => {
+				let mut u = self.hook_upgrade(u);
+				u.connection_guard = guard;
+				Ok(u)
+			}
 			Err((s, r, b, e)) => {
 				Err(InvalidConnection {
 				        stream: Some(s),
 				        parsed: r,
 				        buffer: b,
 				        error: e.into(),
+				        tcp_stream: None,
 				    })
 			}
 		}
 	}
 
-	/// Create a new independently owned handle to the underlying socket.
-	pub fn try_clone(&self) -> io::Result<Self> {
-		let inner = self.listener.try_clone()?;
-		Ok(Server {
-		       listener: inner,
-		       ssl_acceptor: self.ssl_acceptor.clone(),
-		   })
+}
+
+impl WsServer<NoTlsAcceptor, TcpListener> {
+	/// Accept connections on a pool of `workers` threads, calling `handler`
+	/// with every successfully parsed upgrade request.
+	///
+	/// This replaces the usual `for connection in server` + `thread::spawn`
+	/// loop with bounded concurrency: at most `workers` handlers run at once,
+	/// and the server stops accepting while they are all busy.
+	/// Connections that fail the handshake are dropped.
+	///
+	/// The returned `ServeHandle` stops the server and waits for the workers
+	/// to finish their current connection.
+	///
+	/// ```no_run
+	/// # extern crate websocket;
+	/// # fn main() {
+	/// use websocket::Message;
+	/// use websocket::sync::Server;
+	///
+	/// let server = Server::bind("127.0.0.1:1234").unwrap();
+	///
+	/// let handle = server.serve(4, |upgrade| {
+	///     let mut client = upgrade.accept().ok().unwrap();
+	///     let _ = client.send_message(&Message::text("Hello, client!"));
+	/// }).unwrap();
+	///
+	/// // ...
+	///
+	/// handle.shutdown();
+	/// # }
+	/// ```
+	pub fn serve<F>(mut self, workers: usize, handler: F) -> io::Result<ServeHandle>
+		where F: Fn(Upgrade<TcpStream>) + Send + Sync + 'static
+	{
+		let addr = self.local_addr()?;
+		serve_with(addr, workers, move || self.accept(), handler)
+	}
+}
+
+/// The file descriptor of the listening socket.
+#[cfg(unix)]
+impl<S> AsRawFd for WsServer<S, TcpListener>
+    where S: OptionalTlsAcceptor
+{
+	fn as_raw_fd(&self) -> RawFd {
+		self.listener.as_raw_fd()
+	}
+}
+
+/// The handle of the listening socket.
+#[cfg(windows)]
+impl<S> AsRawSocket for WsServer<S, TcpListener>
+    where S: OptionalTlsAcceptor
+{
+	fn as_raw_socket(&self) -> RawSocket {
+		self.listener.as_raw_socket()
 	}
 }
 
@@ -268,11 +678,314 @@ impl Iterator for WsServer<NoTlsAcceptor, TcpListener> {
 	type Item = AcceptResult<TcpStream>;
 
 	fn next(&mut self) -> Option<<Self as Iterator>::Item> {
-		Some(self.accept())
+		if self.is_shut_down() {
+			return None;
+		}
+		let result = self.accept();
+		// the connection that woke us up might be the one made by `shutdown`
+		if self.is_shut_down() {
+			return None;
+		}
+		Some(result)
+	}
+}
+
+/// A handle to a server started with `serve`.
+///
+/// Dropping the handle leaves the server running in the background,
+/// call `shutdown` to stop it.
+pub struct ServeHandle {
+	addr: SocketAddr,
+	stopped: Arc<AtomicBool>,
+	acceptor: JoinHandle<()>,
+	workers: Vec<JoinHandle<()>>,
+}
+
+impl ServeHandle {
+	/// Get the socket address the server is listening on.
+	pub fn local_addr(&self) -> SocketAddr {
+		self.addr
+	}
+
+	/// Stop accepting new connections and wait for the workers to finish
+	/// handling the connections they already accepted.
+	pub fn shutdown(self) {
+		self.stopped.store(true, Ordering::SeqCst);
+
+		// the acceptor is blocked in `accept`, wake it up with a connection
+		wake_listener(self.addr);
+
+		let _ = self.acceptor.join();
+		for worker in self.workers {
+			let _ = worker.join();
+		}
+	}
+}
+
+#[cfg(unix)]
+fn listener_error<S: Stream>(e: io::Error) -> InvalidConnection<S, Buffer> {
+	InvalidConnection {
+		stream: None,
+		parsed: None,
+		buffer: None,
+		error: e.into(),
+		tcp_stream: None,
+	}
+}
+
+fn wake_listener(addr: SocketAddr) {
+	let mut wake = addr;
+	match wake.ip() {
+		IpAddr::V4(ip) if ip.is_unspecified() => wake.set_ip(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))),
+		IpAddr::V6(ip) if ip.is_unspecified() => wake.set_ip(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))),
+		_ => (),
+	}
+	let _ = TcpStream::connect(wake);
+}
+
+/// Identifies a connection registered with a `ShutdownHandle`.
+pub type ConnectionId = usize;
+
+struct ShutdownState {
+	addr: SocketAddr,
+	stopped: AtomicBool,
+	connections: Mutex<Vec<(ConnectionId, TcpStream)>>,
+	next_id: AtomicUsize,
+}
+
+/// A handle to stop a server iterating over its connections, obtained with
+/// `Server::shutdown_handle`.
+///
+/// Connections accepted from the server can be registered with the handle,
+/// so that on shutdown they are sent a close frame and their handlers get
+/// the chance to finish the closing handshake instead of being cut off.
+///
+///```rust,no_run
+///# extern crate websocket;
+///# fn main() {
+///use std::thread;
+///use websocket::OwnedMessage;
+///use websocket::sync::Server;
+///
+///let mut server = Server::bind("127.0.0.1:1234").unwrap();
+///let shutdown = server.shutdown_handle().unwrap();
+///
+///let stopper = shutdown.clone();
+///thread::spawn(move || {
+///    // ... when told to stop
+///    stopper.shutdown();
+///    stopper.close_all(1001, "going away");
+///});
+///
+///for upgrade in server.filter_map(Result::ok) {
+///    let shutdown = shutdown.clone();
+///    thread::spawn(move || {
+///        let mut client = upgrade.accept().unwrap();
+///        let id = shutdown.register(client.stream_ref()).unwrap();
+///        for message in client.incoming_messages().filter_map(Result::ok) {
+///            if let OwnedMessage::Close(_) = message {
+///                let _ = client.send_message(&OwnedMessage::Close(None));
+///                break;
+///            }
+///        }
+///        shutdown.deregister(id);
+///    });
+///}
+///# }
+///```
+#[derive(Clone)]
+pub struct ShutdownHandle {
+	state: Arc<ShutdownState>,
+}
+
+impl ShutdownHandle {
+	fn new(addr: SocketAddr) -> ShutdownHandle {
+		ShutdownHandle {
+			state: Arc::new(ShutdownState {
+			                    addr,
+			                    stopped: AtomicBool::new(false),
+			                    connections: Mutex::new(Vec::new()),
+			                    next_id: AtomicUsize::new(0),
+			                }),
+		}
+	}
+
+	/// Stop the server from accepting new connections.
+	///
+	/// Registered connections are left alone, use `close_all` to ask them
+	/// to close.
+	pub fn shutdown(&self) {
+		if !self.state.stopped.swap(true, Ordering::SeqCst) {
+			wake_listener(self.state.addr);
+		}
+	}
+
+	/// Whether `shutdown` was called.
+	pub fn is_shut_down(&self) -> bool {
+		self.state.stopped.load(Ordering::SeqCst)
 	}
+
+	/// Adds a connection, returning the id to deregister it with once it is
+	/// done.
+	pub fn register(&self, stream: &TcpStream) -> io::Result<ConnectionId> {
+		let stream = stream.try_clone()?;
+		let id = self.state.next_id.fetch_add(1, Ordering::Relaxed);
+		self.state.connections.lock().unwrap().push((id, stream));
+		Ok(id)
+	}
+
+	/// Removes a connection, returns whether it was still registered.
+	pub fn deregister(&self, id: ConnectionId) -> bool {
+		let mut connections = self.state.connections.lock().unwrap();
+		let before = connections.len();
+		connections.retain(|&(other, _)| other != id);
+		connections.len() != before
+	}
+
+	/// The number of registered connections.
+	pub fn len(&self) -> usize {
+		self.state.connections.lock().unwrap().len()
+	}
+
+	/// Whether no connections are registered.
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Sends a close frame with `status_code` and `reason` to every
+	/// registered connection, returning how many it was written to.
+	///
+	/// The frame is written straight to the socket, unmasked as a server
+	/// sends it, so it must not be called while a handler is in the middle
+	/// of writing a message. The connections stay registered until their
+	/// handlers deregister them after the closing handshake.
+	pub fn close_all(&self, status_code: u16, reason: &str) -> usize {
+		let close = OwnedMessage::Close(Some(CloseData::new(status_code, reason.to_owned())));
+		let mut frame = Vec::new();
+		if close.serialize(&mut frame, false).is_err() {
+			return 0;
+		}
+		let mut written = 0;
+		for (_, stream) in self.state.connections.lock().unwrap().iter() {
+			let mut stream = stream;
+			if stream.write_all(&frame).is_ok() {
+				written += 1;
+			}
+		}
+		written
+	}
+}
+
+fn serve_with<S, A, F>(
+	addr: SocketAddr,
+	workers: usize,
+	mut accept: A,
+	handler: F,
+) -> io::Result<ServeHandle>
+	where S: Stream + Send + 'static,
+	      A: FnMut() -> AcceptResult<S> + Send + 'static,
+	      F: Fn(Upgrade<S>) + Send + Sync + 'static
+{
+	if workers == 0 {
+		return Err(io::Error::new(io::ErrorKind::InvalidInput, "at least one worker is required"));
+	}
+
+	// the queue holds at most one pending connection per worker, after which
+	// the acceptor blocks until a worker frees up
+	let (tx, rx) = mpsc::sync_channel::<Upgrade<S>>(workers);
+	let rx = Arc::new(Mutex::new(rx));
+	let handler = Arc::new(handler);
+	let stopped = Arc::new(AtomicBool::new(false));
+
+	let workers = (0..workers)
+		.map(|_| {
+			     let rx = rx.clone();
+			     let handler = handler.clone();
+			     thread::spawn(move || loop {
+				                   let upgrade = match rx.lock().unwrap().recv() {
+					                   Ok(u) => u,
+					                   Err(_) => break,
+				                   };
+				                   if panic::catch_unwind(AssertUnwindSafe(|| handler(upgrade))).is_err() {
+					                   warn!("websocket connection handler panicked");
+				                   }
+				                  })
+			    })
+		.collect();
+
+	let acceptor = {
+		let stopped = stopped.clone();
+		thread::spawn(move || while !stopped.load(Ordering::SeqCst) {
+			              let upgrade = match accept() {
+				              Ok(u) => u,
+				              Err(e) => {
+					              debug!("Dropping invalid connection: {}", e.error);
+					              continue;
+				              }
+			              };
+			              if stopped.load(Ordering::SeqCst) || tx.send(upgrade).is_err() {
+				              break;
+			              }
+			             })
+	};
+
+	Ok(ServeHandle {
+	       addr,
+	       stopped,
+	       acceptor,
+	       workers,
+	   })
+}
+
+fn dual_stack_listener(port: u16) -> io::Result<TcpListener> {
+	let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+	if let Err(e) = socket.set_only_v6(false) {
+		return Err(io::Error::new(e.kind(), format!("dual-stack sockets are not supported: {}", e)));
+	}
+	// std sets this for its listeners too, so restarted servers can rebind
+	#[cfg(unix)]
+	socket.set_reuse_address(true)?;
+	socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+	socket.listen(128)?;
+	Ok(socket.into())
 }
 
 mod tests {
+	#[test]
+	fn listener_is_accessible() {
+		use super::*;
+
+		let mut server = Server::bind("127.0.0.1:0").unwrap();
+		let addr = server.local_addr().unwrap();
+		assert_eq!(server.listener_ref().local_addr().unwrap(), addr);
+		server.listener_mut().set_ttl(42).unwrap();
+		assert_eq!(server.into_inner().ttl().unwrap(), 42);
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn raw_fd_is_the_listener() {
+		use super::*;
+
+		let server = Server::bind("127.0.0.1:0").unwrap();
+		assert_eq!(server.as_raw_fd(), server.listener_ref().as_raw_fd());
+	}
+
+	#[test]
+	fn dual_stack_servers_accept_ipv4() {
+		use super::*;
+
+		// not every test machine has IPv6
+		let server = match Server::bind_dual_stack(0) {
+			Ok(server) => server,
+			Err(_) => return,
+		};
+		let port = server.local_addr().unwrap().port();
+		assert!(server.local_addr().unwrap().is_ipv6());
+		let _ipv4 = TcpStream::connect(("127.0.0.1", port)).unwrap();
+		let _ipv6 = TcpStream::connect(("::1", port)).unwrap();
+	}
+
 	#[test]
 	// test the set_nonblocking() method for Server<NoSslAcceptor>.
 	// Some of this is copied from
@@ -303,4 +1016,234 @@ mod tests {
 		}
 
 	}
+
+	#[test]
+	// clones of a server share one listener, so they can accept concurrently
+	fn try_clone_accepts_concurrently() {
+		use super::*;
+		use std::thread;
+		use client::builder::ClientBuilder;
+
+		let mut server = Server::bind("127.0.0.1:0").unwrap();
+		let mut cloned = server.try_clone().unwrap();
+		let addr = server.local_addr().unwrap();
+		assert_eq!(addr, cloned.local_addr().unwrap());
+
+		let other = thread::spawn(move || cloned.accept().ok().unwrap().accept().ok().unwrap());
+
+		let clients: Vec<_> = (0..2)
+			.map(|_| {
+				     let url = format!("ws://{}", addr);
+				     thread::spawn(move || {
+					                   ClientBuilder::new(&url)
+					                       .unwrap()
+					                       .connect_insecure()
+					                       .unwrap()
+					                  })
+				    })
+			.collect();
+
+		let here = server.accept().ok().unwrap().accept().ok().unwrap();
+		let there = other.join().unwrap();
+		assert!(here.peer_addr().unwrap() != there.peer_addr().unwrap());
+
+		for client in clients {
+			client.join().unwrap();
+		}
+	}
+
+	#[test]
+	fn plain_http_requests_can_be_answered() {
+		use super::*;
+		use std::io::{Read, Write};
+		use std::thread;
+
+		let mut server = Server::bind("127.0.0.1:0").unwrap();
+		let addr = server.local_addr().unwrap();
+
+		let health_check = thread::spawn(move || {
+			let mut stream = TcpStream::connect(addr).unwrap();
+			stream.write_all(b"GET /health HTTP/1.1\r\nHost: example.org\r\n\r\n").unwrap();
+			let mut response = String::new();
+			stream.read_to_string(&mut response).unwrap();
+			response
+		});
+
+		let invalid = server.accept().err().unwrap();
+		assert!(invalid.tcp_stream.is_none());
+		assert_eq!(invalid.parsed.unwrap().subject.1.to_string(), "/health");
+		let mut stream = invalid.stream.unwrap();
+		stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").unwrap();
+		drop(stream);
+
+		assert!(health_check.join().unwrap().starts_with("HTTP/1.1 200 OK"));
+	}
+
+	#[test]
+	fn handshake_timeout_bounds_slow_requests() {
+		use super::*;
+		use std::io::Write;
+		use std::thread;
+		use std::time::Instant;
+
+		let mut server = Server::bind("127.0.0.1:0").unwrap();
+		server.set_handshake_timeout(Some(Duration::from_millis(200)));
+		let addr = server.local_addr().unwrap();
+
+		// every byte comes well within any per-read timeout, the request never ends
+		let trickle = thread::spawn(move || {
+			let mut stream = TcpStream::connect(addr).unwrap();
+			for &byte in b"GET / HTTP/1.1\r\nHost: example.org\r\n".iter().cycle().take(100) {
+				if stream.write_all(&[byte]).is_err() {
+					break;
+				}
+				thread::sleep(Duration::from_millis(20));
+			}
+		});
+
+		let start = Instant::now();
+		match server.accept() {
+			Err(InvalidConnection { error: HyperIntoWsError::Timeout, .. }) => (),
+			Err(e) => panic!("expected a timeout, got {}", e.error),
+			Ok(_) => panic!("the handshake should have timed out"),
+		}
+		assert!(start.elapsed() < Duration::from_secs(1));
+		trickle.join().unwrap();
+	}
+
+	#[test]
+	fn connections_over_the_ip_limit_are_refused() {
+		use super::*;
+		use std::io::Write;
+
+		let mut server = Server::bind("127.0.0.1:0").unwrap();
+		let limit = IpLimit::new(3);
+		server.set_ip_limit(Some(limit.clone()));
+		let addr = server.local_addr().unwrap();
+		let localhost = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+
+		let connect = || {
+			let mut stream = TcpStream::connect(addr).unwrap();
+			stream.write_all(b"GET / HTTP/1.1\r\nHost: example.org\r\n\
+			                   Upgrade: websocket\r\nConnection: Upgrade\r\n\
+			                   Sec-WebSocket-Version: 13\r\n\
+			                   Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n")
+			      .unwrap();
+			stream
+		};
+
+		// the listener queues the connections until they are accepted
+		let _clients: Vec<_> = (0..10).map(|_| connect()).collect();
+
+		let mut accepted = Vec::new();
+		let mut refused = 0;
+		for _ in 0..10 {
+			match server.accept() {
+				Ok(upgrade) => accepted.push(upgrade),
+				Err(InvalidConnection { error: HyperIntoWsError::TooManyConnections, .. }) => refused += 1,
+				Err(e) => panic!("unexpected error {}", e.error),
+			}
+		}
+		assert_eq!((accepted.len(), refused), (3, 7));
+		assert_eq!(limit.active(localhost), 3);
+
+		// the accepted client keeps counting, dropped upgrades don't
+		let client = accepted.pop().unwrap().accept().ok().unwrap();
+		accepted.clear();
+		assert_eq!(limit.active(localhost), 1);
+		drop(client);
+		assert_eq!(limit.active(localhost), 0);
+
+		// a split client counts as long as its reader
+		let _stream = connect();
+		let client = server.accept().ok().unwrap().accept().ok().unwrap();
+		let (reader, writer) = client.split().unwrap();
+		drop(writer);
+		assert_eq!(limit.active(localhost), 1);
+		drop(reader);
+		assert_eq!(limit.active(localhost), 0);
+	}
+
+	#[test]
+	#[cfg(unix)]
+	fn accept_timeout_gives_up_or_accepts() {
+		use super::*;
+		use std::thread;
+		use std::time::Instant;
+		use client::builder::ClientBuilder;
+
+		let mut server = Server::bind("127.0.0.1:0").unwrap();
+		let start = Instant::now();
+		assert!(server.accept_timeout(Duration::from_millis(100)).is_none());
+		assert!(start.elapsed() >= Duration::from_millis(100));
+
+		let url = format!("ws://{}", server.local_addr().unwrap());
+		let client = thread::spawn(move || ClientBuilder::new(&url).unwrap().connect_insecure().unwrap());
+		let upgrade = server.accept_timeout(Duration::from_secs(5)).unwrap().ok().unwrap();
+		upgrade.accept().ok().unwrap();
+		client.join().unwrap();
+	}
+
+	#[test]
+	fn serve_handles_connections_and_shuts_down() {
+		use super::*;
+		use client::builder::ClientBuilder;
+		use message::{Message, OwnedMessage};
+
+		let server = Server::bind("127.0.0.1:0").unwrap();
+		let handle = server.serve(2, |upgrade| {
+			                           let mut client = upgrade.accept().ok().unwrap();
+			                           client.send_message(&Message::text("hi")).unwrap();
+			                          })
+		                   .unwrap();
+
+		let url = format!("ws://{}", handle.local_addr());
+		for _ in 0..3 {
+			let mut client = ClientBuilder::new(&url).unwrap().connect_insecure().unwrap();
+			assert_eq!(client.recv_message().unwrap(), OwnedMessage::Text("hi".to_string()));
+		}
+
+		handle.shutdown();
+		assert!(ClientBuilder::new(&url).unwrap().connect_insecure().is_err());
+	}
+
+	#[test]
+	fn shutdown_ends_iteration_and_closes_connections() {
+		use super::*;
+		use std::sync::mpsc;
+		use std::thread;
+		use client::builder::ClientBuilder;
+		use message::{CloseData, OwnedMessage};
+
+		let mut server = Server::bind("127.0.0.1:0").unwrap();
+		let addr = server.local_addr().unwrap();
+		let shutdown = server.shutdown_handle().unwrap();
+
+		let (registered, wait) = mpsc::channel();
+		let registry = shutdown.clone();
+		let accepting = thread::spawn(move || {
+			let mut connections = Vec::new();
+			for upgrade in server.filter_map(Result::ok) {
+				let client = upgrade.accept().ok().unwrap();
+				registry.register(client.stream_ref()).unwrap();
+				connections.push(client);
+				registered.send(()).unwrap();
+			}
+			connections
+		});
+
+		let mut client = ClientBuilder::new(&format!("ws://{}", addr))
+			.unwrap()
+			.connect_insecure()
+			.unwrap();
+		wait.recv().unwrap();
+
+		shutdown.shutdown();
+		assert!(shutdown.is_shut_down());
+		assert_eq!(accepting.join().unwrap().len(), 1);
+
+		assert_eq!(shutdown.close_all(1001, "going away"), 1);
+		assert_eq!(client.recv_message().unwrap(),
+		           OwnedMessage::Close(Some(CloseData::new(1001, "going away".to_string()))));
+	}
 }
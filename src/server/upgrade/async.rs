@@ -9,6 +9,7 @@ use super::{HyperIntoWsError, WsUpgrade, Request, validate};
 use std::io::{self, ErrorKind};
 use tokio_io::codec::{Framed, FramedParts};
 use hyper::header::Headers;
+use header::HandshakeDirection;
 use hyper::http::h1::Incoming;
 use hyper::status::StatusCode;
 use stream::async::Stream;
@@ -83,7 +84,10 @@ impl<S> WsUpgrade<S, BytesMut>
 
 	fn internal_accept(mut self, custom_headers: Option<&Headers>) -> ClientNew<S> {
 		let status = self.prepare_headers(custom_headers);
-		let WsUpgrade { headers, stream, request, buffer } = self;
+		if let Some(ref hook) = self.handshake_hook {
+			hook.call(HandshakeDirection::Outgoing, &self.headers);
+		}
+		let WsUpgrade { headers, stream, request, buffer, .. } = self;
 
 		let duplex = Framed::from_parts(FramedParts {
 		                                    inner: stream,
@@ -125,6 +129,9 @@ impl<S> WsUpgrade<S, BytesMut>
 		if let Some(custom) = headers {
 			self.headers.extend(custom.iter());
 		}
+		if let Some(ref hook) = self.handshake_hook {
+			hook.call(HandshakeDirection::Outgoing, &self.headers);
+		}
 		let duplex = Framed::from_parts(FramedParts {
 		                                    inner: self.stream,
 		                                    readbuf: self.buffer,
@@ -234,6 +241,9 @@ impl<S> IntoWs for S
                   stream: stream,
                   request: m,
                   buffer: buffer,
+                  handshake_hook: None,
+                  deflate: false,
+                  connection_guard: None,
               }
           });
 		Box::new(future)
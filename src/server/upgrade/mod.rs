@@ -4,16 +4,19 @@ use std::error::Error;
 use std::io;
 use std::fmt::{self, Formatter, Display};
 use stream::Stream;
+use server::limit::ConnectionGuard;
 use header::extensions::Extension;
 use header::{WebSocketAccept, WebSocketKey, WebSocketVersion, WebSocketProtocol,
-             WebSocketExtensions, Origin};
+             WebSocketExtensions, Origin, HandshakeHook, HandshakeDirection, ProtocolPriority,
+             ProtocolSelection};
 
 use unicase::UniCase;
 use hyper::status::StatusCode;
 use hyper::http::h1::Incoming;
 use hyper::method::Method;
 use hyper::uri::RequestUri;
-use hyper::header::{Headers, Upgrade, Protocol, ProtocolName, Connection, ConnectionOption};
+use hyper::header::{Headers, HeadersItems, Upgrade, Protocol, ProtocolName, Connection, ConnectionOption,
+                    ContentLength, TransferEncoding};
 
 #[cfg(any(feature="sync", feature="async"))]
 use hyper::version::HttpVersion;
@@ -32,7 +35,9 @@ pub type Request = Incoming<(Method, RequestUri)>;
 /// accept the protocols requested, route the path, etc.
 ///
 /// Users should then call `accept` or `reject` to complete the handshake
-/// and start a session.
+/// and start a session. Nothing is written to the stream before one of them
+/// is called, so the request can be inspected (e.g. to authenticate the
+/// client) for as long as needed, and the response is sent with one write.
 /// Note: if the stream in use is `AsyncRead + AsyncWrite`, then asynchronous
 /// functions will be available when completing the handshake.
 /// Otherwise if the stream is simply `Read + Write` blocking functions will be
@@ -48,6 +53,14 @@ pub struct WsUpgrade<S, B>
 	pub request: Request,
 	/// Some buffered data from the stream, if it exists.
 	pub buffer: B,
+	/// Called with the response headers right before they are sent.
+	pub handshake_hook: Option<HandshakeHook>,
+	/// Whether permessage-deflate was agreed on with `negotiate_deflate`, the
+	/// accepted client then compresses its messages.
+	pub deflate: bool,
+	/// Counts the connection against the server's `IpLimit`, the accepted
+	/// client keeps it.
+	pub connection_guard: Option<ConnectionGuard>,
 }
 
 impl<S, B> WsUpgrade<S, B>
@@ -64,6 +77,23 @@ impl<S, B> WsUpgrade<S, B>
 		self
 	}
 
+	/// Chooses one of the protocols requested by the client out of the ones in
+	/// `supported`, ordered from most to least preferred, and selects it for
+	/// the handshake response. See `WebSocketProtocol::select`.
+	///
+	/// Nothing is selected if there is no protocol in common.
+	pub fn negotiate_protocol<P>(&mut self, supported: &[P], priority: ProtocolPriority)
+		-> Option<ProtocolSelection>
+		where P: AsRef<str>
+	{
+		let selection = self.request
+		                    .headers
+		                    .get::<WebSocketProtocol>()
+		                    .and_then(|offered| offered.select(supported, priority))?;
+		self.headers.set(WebSocketProtocol(vec![selection.protocol.clone()]));
+		Some(selection)
+	}
+
 	/// Select an extension to use in the handshake response.
 	pub fn use_extension(mut self, extension: Extension) -> Self {
 		upsert_header!(self.headers; WebSocketExtensions; {
@@ -92,6 +122,11 @@ impl<S, B> WsUpgrade<S, B>
 	}
 
 	/// A list of protocols requested from the client.
+	///
+	/// Clients can offer protocols in one comma-separated
+	/// `Sec-WebSocket-Protocol` header or in several of them, the values of
+	/// all the headers are split on commas and trimmed, in the order they
+	/// appear.
 	pub fn protocols(&self) -> &[String] {
 		self.request
 		    .headers
@@ -124,11 +159,104 @@ impl<S, B> WsUpgrade<S, B>
 		self.request.headers.get::<Origin>().map(|o| &o.0 as &str)
 	}
 
+	/// The value of the client's `Host` header. Upgrades always have one,
+	/// `None` means it isn't valid UTF-8.
+	pub fn host(&self) -> Option<&str> {
+		first_value(&self.request.headers, "Host")
+	}
+
+	/// The host named by the `trusted` header, like `X-Forwarded-Host`, or
+	/// the `Host` header if the request doesn't have it.
+	///
+	/// Load balancers that rewrite `Host` usually pass the original one on
+	/// like this, only trust such a header if every request goes through one
+	/// that sets it. Of a comma separated list the first host is returned.
+	pub fn host_from(&self, trusted: &str) -> Option<&str> {
+		match first_value(&self.request.headers, trusted) {
+			Some(forwarded) => forwarded.split(',').next().map(str::trim),
+			None => self.host(),
+		}
+	}
+
+	/// The path of the request target, useful for routing connections.
+	///
+	/// Both origin-form (`/chat?room=1`) and absolute-form
+	/// (`ws://example.com/chat?room=1`) targets give `/chat`. The authority and
+	/// asterisk forms have no path and give an empty string and `*` respectively.
+	pub fn path(&self) -> &str {
+		match self.request.subject.1 {
+			RequestUri::AbsolutePath(ref target) => {
+				let target = target.split('#').next().unwrap_or("");
+				target.split('?').next().unwrap_or("")
+			}
+			RequestUri::AbsoluteUri(ref url) => url.path(),
+			RequestUri::Authority(_) => "",
+			RequestUri::Star => "*",
+		}
+	}
+
+	/// The query of the request target without the leading `?`, if there is one.
+	pub fn query(&self) -> Option<&str> {
+		match self.request.subject.1 {
+			RequestUri::AbsolutePath(ref target) => {
+				let target = target.split('#').next().unwrap_or("");
+				target.find('?').map(|i| &target[i + 1..])
+			}
+			RequestUri::AbsoluteUri(ref url) => url.query(),
+			RequestUri::Authority(_) | RequestUri::Star => None,
+		}
+	}
+
+	/// The value of a header of the handshake request, looked up by name
+	/// without regard to case.
+	///
+	/// For a header sent on several lines the first one is returned.
+	/// Values that aren't valid UTF-8 give `None`.
+	pub fn header(&self, name: &str) -> Option<&str> {
+		first_value(&self.request.headers, name)
+	}
+
+	/// All headers of the handshake request as name and value pairs, see
+	/// `header`.
+	pub fn request_headers(&self) -> RequestHeaders<'_> {
+		RequestHeaders {
+			headers: &self.request.headers,
+			names: self.request.headers.iter(),
+		}
+	}
+
+	/// Copies the parts of the request commonly needed after the handshake,
+	/// along with the protocol selected so far. The synchronous `accept` keeps
+	/// this in the client, see `Client::request`.
+	pub fn request_info(&self) -> RequestInfo {
+		RequestInfo {
+			path: self.path().to_string(),
+			query: self.query().map(str::to_string),
+			origin: self.origin().map(str::to_string),
+			host: self.host().map(str::to_string),
+			protocols: self.protocols().to_vec(),
+			selected_protocol: self.headers
+			                       .get::<WebSocketProtocol>()
+			                       .and_then(|p| p.0.first().cloned()),
+			headers: self.request.headers.clone(),
+		}
+	}
+
 	#[cfg(feature="sync")]
 	fn send(&mut self, status: StatusCode) -> io::Result<()> {
-		write!(&mut self.stream, "{} {}\r\n", self.request.version, status)?;
-		write!(&mut self.stream, "{}\r\n", self.headers)?;
-		Ok(())
+		self.send_with_body(status, &[])
+	}
+
+	fn send_with_body(&mut self, status: StatusCode, body: &[u8]) -> io::Result<()> {
+		if let Some(ref hook) = self.handshake_hook {
+			hook.call(HandshakeDirection::Outgoing, &self.headers);
+		}
+		// build the whole response first so that it goes out in a single write
+		let mut response = format!("{} {}\r\n{}\r\n", self.request.version, status, self.headers)
+			.into_bytes();
+		response.extend_from_slice(body);
+		self.stream.write_all(&response)?;
+		self.stream.flush()
 	}
 
 	#[doc(hidden)]
@@ -162,6 +290,10 @@ pub enum HyperIntoWsError {
 	UnsupportedWebsocketVersion,
 	/// A websocket upgrade request must contain a key
 	NoSecWsKeyHeader,
+	/// A websocket upgrade request's key must be 16 bytes encoded in base64
+	InvalidSecWsKeyHeader,
+	/// A websocket upgrade request must contain a `Host` header
+	NoHostHeader,
 	/// A websocket upgrade request must ask to upgrade to a `websocket`
 	NoWsUpgradeHeader,
 	/// A websocket upgrade request must contain an `Upgrade` header
@@ -170,6 +302,14 @@ pub enum HyperIntoWsError {
 	NoWsConnectionHeader,
 	/// A websocket upgrade request must contain a `Connection` header
 	NoConnectionHeader,
+	/// A websocket upgrade request must not have a body, any bytes after it
+	/// would be read as websocket frames
+	RequestHasBody,
+	/// The request wasn't received within the time allowed for the handshake
+	Timeout,
+	/// The source address has as many connections open as its `IpLimit`
+	/// allows, the request wasn't read
+	TooManyConnections,
 	/// IO error from reading the underlying socket
 	Io(io::Error),
 	/// Error while parsing an incoming request
@@ -190,10 +330,15 @@ impl Error for HyperIntoWsError {
 			UnsupportedHttpVersion => "Unsupported request HTTP version",
 			UnsupportedWebsocketVersion => "Unsupported WebSocket version",
 			NoSecWsKeyHeader => "Missing Sec-WebSocket-Key header",
+			InvalidSecWsKeyHeader => "Invalid Sec-WebSocket-Key header",
+			NoHostHeader => "Missing Host header",
 			NoWsUpgradeHeader => "Invalid Upgrade WebSocket header",
 			NoUpgradeHeader => "Missing Upgrade WebSocket header",
 			NoWsConnectionHeader => "Invalid Connection WebSocket header",
 			NoConnectionHeader => "Missing Connection WebSocket header",
+			RequestHasBody => "Upgrade request must not have a body",
+			Timeout => "Handshake request timed out",
+			TooManyConnections => "Too many connections from the same address",
 			Io(ref e) => e.description(),
 			Parsing(ref e) => e.description(),
 		}
@@ -208,6 +353,21 @@ impl Error for HyperIntoWsError {
 	}
 }
 
+impl HyperIntoWsError {
+	/// The HTTP status to reject the request with, `None` if the request was
+	/// never read completely and no response should be sent.
+	///
+	/// Requests that aren't valid upgrades get `400 Bad Request`.
+	pub fn status_code(&self) -> Option<u16> {
+		match *self {
+			HyperIntoWsError::Io(_) => None,
+			HyperIntoWsError::Timeout => Some(408),
+			HyperIntoWsError::TooManyConnections => Some(503),
+			_ => Some(400),
+		}
+	}
+}
+
 impl From<io::Error> for HyperIntoWsError {
 	fn from(err: io::Error) -> Self {
 		HyperIntoWsError::Io(err)
@@ -230,6 +390,51 @@ impl From<::codec::http::HttpCodecError> for HyperIntoWsError {
 	}
 }
 
+/// An iterator over the headers of a handshake request, created by
+/// `WsUpgrade::request_headers`.
+pub struct RequestHeaders<'a> {
+	headers: &'a Headers,
+	names: HeadersItems<'a>,
+}
+
+impl<'a> Iterator for RequestHeaders<'a> {
+	type Item = (&'a str, &'a str);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let headers = self.headers;
+		self.names
+		    .by_ref()
+		    .filter_map(|view| first_value(headers, view.name()).map(|value| (view.name(), value)))
+		    .next()
+	}
+}
+
+/// What a client asked for in its handshake request, kept after the
+/// connection was accepted. Created by `WsUpgrade::request_info`.
+#[derive(Debug, Clone)]
+pub struct RequestInfo {
+	/// The path of the request target, see `WsUpgrade::path`.
+	pub path: String,
+	/// The query of the request target, see `WsUpgrade::query`.
+	pub query: Option<String>,
+	/// The client's `Origin` header.
+	pub origin: Option<String>,
+	/// The client's `Host` header, see `WsUpgrade::host`.
+	pub host: Option<String>,
+	/// The protocols the client requested.
+	pub protocols: Vec<String>,
+	/// The protocol selected for the response, if any.
+	pub selected_protocol: Option<String>,
+	/// All headers of the request.
+	pub headers: Headers,
+}
+
+fn first_value<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+	headers.get_raw(name)
+	       .and_then(|lines| lines.first())
+	       .and_then(|line| ::std::str::from_utf8(line).ok())
+}
+
 #[cfg(any(feature="sync", feature="async"))]
 /// Check whether an incoming request is a valid WebSocket upgrade attempt.
 pub fn validate(
@@ -251,10 +456,19 @@ pub fn validate(
 		}
 	}
 
+	// the key only parses if it decodes to 16 bytes
 	if headers.get::<WebSocketKey>().is_none() {
+		if headers.get_raw("Sec-WebSocket-Key").is_some() {
+			return Err(HyperIntoWsError::InvalidSecWsKeyHeader);
+		}
 		return Err(HyperIntoWsError::NoSecWsKeyHeader);
 	}
 
+	// RFC6455 4.1: the client must send a Host, it isn't parsed any further
+	if headers.get_raw("Host").is_none() {
+		return Err(HyperIntoWsError::NoHostHeader);
+	}
+
 	match headers.get() {
 		Some(&Upgrade(ref upgrade)) => {
 			if upgrade.iter().all(|u| u.name != ProtocolName::WebSocket) {
@@ -284,5 +498,131 @@ pub fn validate(
 		None => return Err(HyperIntoWsError::NoConnectionHeader),
 	};
 
+	if headers.has::<TransferEncoding>() ||
+	   headers.get::<ContentLength>().is_some_and(|len| len.0 > 0) {
+		return Err(HyperIntoWsError::RequestHasBody);
+	}
+
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+	use hyper::version::HttpVersion;
+
+	fn upgrade(uri: RequestUri) -> WsUpgrade<Cursor<Vec<u8>>, ()> {
+		WsUpgrade {
+			headers: Headers::new(),
+			stream: Cursor::new(Vec::new()),
+			request: Incoming {
+				version: HttpVersion::Http11,
+				headers: Headers::new(),
+				subject: (Method::Get, uri),
+			},
+			buffer: (),
+			handshake_hook: None,
+			deflate: false,
+			connection_guard: None,
+		}
+	}
+
+	#[test]
+	fn request_headers_are_readable_by_name() {
+		let mut upgrade = upgrade(RequestUri::AbsolutePath("/".to_string()));
+		upgrade.request.headers.set_raw("Authorization", vec![b"Bearer token".to_vec()]);
+		upgrade.request.headers.set_raw("X-Api-Key", vec![b"first".to_vec(), b"second".to_vec()]);
+		upgrade.request.headers.set_raw("X-Binary", vec![vec![0xFF]]);
+
+		assert_eq!(upgrade.header("authorization"), Some("Bearer token"));
+		assert_eq!(upgrade.header("X-API-KEY"), Some("first"));
+		assert_eq!(upgrade.header("X-Binary"), None);
+		assert_eq!(upgrade.header("Cookie"), None);
+
+		let mut all: Vec<_> = upgrade.request_headers().collect();
+		all.sort();
+		assert_eq!(all, vec![("Authorization", "Bearer token"), ("X-Api-Key", "first")]);
+	}
+
+	#[test]
+	fn host_can_come_from_a_trusted_header() {
+		let mut upgrade = upgrade(RequestUri::AbsolutePath("/".to_string()));
+		upgrade.request.headers.set_raw("Host", vec![b"10.0.0.7:8080".to_vec()]);
+		assert_eq!(upgrade.host(), Some("10.0.0.7:8080"));
+		assert_eq!(upgrade.host_from("X-Forwarded-Host"), Some("10.0.0.7:8080"));
+
+		upgrade.request.headers.set_raw("X-Forwarded-Host", vec![b"chat.example.com, lb.internal".to_vec()]);
+		assert_eq!(upgrade.host_from("x-forwarded-host"), Some("chat.example.com"));
+		assert_eq!(upgrade.host(), Some("10.0.0.7:8080"));
+	}
+
+	#[test]
+	fn request_info_outlives_the_upgrade() {
+		let mut upgrade = upgrade(RequestUri::AbsolutePath("/chat?room=1".to_string()));
+		upgrade.request.headers.set_raw("Host", vec![b"example.com".to_vec()]);
+		upgrade.request.headers.set(Origin("https://example.com".to_string()));
+		upgrade.request.headers.set(WebSocketProtocol(vec!["mqtt".to_string(), "wamp".to_string()]));
+		let upgrade = upgrade.use_protocol("wamp");
+
+		let info = upgrade.request_info();
+		drop(upgrade);
+		assert_eq!(info.path, "/chat");
+		assert_eq!(info.query, Some("room=1".to_string()));
+		assert_eq!(info.origin, Some("https://example.com".to_string()));
+		assert_eq!(info.host, Some("example.com".to_string()));
+		assert_eq!(info.protocols, vec!["mqtt".to_string(), "wamp".to_string()]);
+		assert_eq!(info.selected_protocol, Some("wamp".to_string()));
+		assert!(info.headers.get::<Origin>().is_some());
+	}
+
+	#[test]
+	fn path_and_query_of_origin_form() {
+		let with_query = upgrade(RequestUri::AbsolutePath("/chat?room=1&user=2".to_string()));
+		assert_eq!(with_query.path(), "/chat");
+		assert_eq!(with_query.query(), Some("room=1&user=2"));
+
+		let without_query = upgrade(RequestUri::AbsolutePath("/notifications".to_string()));
+		assert_eq!(without_query.path(), "/notifications");
+		assert_eq!(without_query.query(), None);
+	}
+
+	#[test]
+	fn path_and_query_of_absolute_form() {
+		let url = "ws://example.com/chat?room=1".parse().unwrap();
+		let upgrade = upgrade(RequestUri::AbsoluteUri(url));
+		assert_eq!(upgrade.path(), "/chat");
+		assert_eq!(upgrade.query(), Some("room=1"));
+	}
+
+	#[test]
+	fn protocols_of_one_or_many_headers() {
+		let mut single = upgrade(RequestUri::Star);
+		single.request.headers.set_raw("Sec-WebSocket-Protocol", vec![b"chat, superchat,v2 ".to_vec()]);
+
+		let mut repeated = upgrade(RequestUri::Star);
+		repeated.request.headers.set_raw("Sec-WebSocket-Protocol",
+		                                 vec![b"chat".to_vec(), b" superchat, v2".to_vec()]);
+
+		let expected = ["chat".to_string(), "superchat".to_string(), "v2".to_string()];
+		assert_eq!(single.protocols(), &expected[..]);
+		assert_eq!(repeated.protocols(), &expected[..]);
+		assert_eq!(upgrade(RequestUri::Star).protocols(), &[] as &[String]);
+	}
+
+	#[test]
+	fn negotiated_protocol_is_echoed() {
+		let mut upgrade = upgrade(RequestUri::Star);
+		assert_eq!(upgrade.negotiate_protocol(&["chat"], ProtocolPriority::Client), None);
+		assert!(!upgrade.headers.has::<WebSocketProtocol>());
+
+		upgrade.request
+		       .headers
+		       .set(WebSocketProtocol(vec!["chat.v1".to_string(), "chat.v2".to_string()]));
+		let selection = upgrade.negotiate_protocol(&["chat.v2", "chat.v1"], ProtocolPriority::Client)
+		                       .unwrap();
+		assert_eq!(selection.protocol, "chat.v1");
+		assert_eq!(upgrade.headers.get(),
+		           Some(&WebSocketProtocol(vec!["chat.v1".to_string()])));
+	}
+}
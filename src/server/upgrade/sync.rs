@@ -1,16 +1,21 @@
 //! Allows you to take an existing request or stream of data and convert it into a
 //! WebSocket client.
-use std::io;
-use std::net::TcpStream;
+use std::io::{self, Read};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
 use stream::sync::{Stream, AsTcpStream};
 use server::upgrade::{Request, WsUpgrade, HyperIntoWsError, validate};
 use client::sync::Client;
+#[cfg(feature="deflate")]
+use header::WebSocketExtensions;
+#[cfg(feature="deflate")]
+use deflate::{self, PerMessageDeflate};
 
 use hyper::status::StatusCode;
 use hyper::http::h1::Incoming;
 use hyper::buffer::BufReader;
 use hyper::http::h1::parse_request;
-use hyper::header::Headers;
+use hyper::header::{Headers, Connection, ContentLength};
 use hyper::net::NetworkStream;
 
 /// This crate uses buffered readers to read in the handshake quickly, in order to
@@ -62,6 +67,7 @@ impl<S> WsUpgrade<S, Option<Buffer>>
 
 	fn internal_accept(mut self, headers: Option<&Headers>) -> Result<Client<S>, (S, io::Error)> {
 		let status = self.prepare_headers(headers);
+		let request = self.request_info();
 
 		if let Err(e) = self.send(status) {
 			return Err((self.stream, e));
@@ -72,7 +78,38 @@ impl<S> WsUpgrade<S, Option<Buffer>>
 			None => BufReader::new(self.stream),
 		};
 
-		Ok(Client::unchecked(stream, self.headers, false, true))
+		let mut client = Client::unchecked(stream, self.headers, false, true);
+		client.set_request(request);
+		client.set_connection_guard(self.connection_guard);
+		#[cfg(feature="deflate")]
+		{
+			if self.deflate {
+				// the agreed parameters came from `accept_offer`, so they are accepted
+				let _ = client.register_extension(Box::new(PerMessageDeflate::server()));
+			}
+		}
+		Ok(client)
+	}
+
+	/// Agrees to permessage-deflate if the client offered it with parameters
+	/// this crate supports, and returns whether it did.
+	///
+	/// The response then carries the agreed parameters and the client returned
+	/// by `accept` compresses what it sends and decompresses what it
+	/// receives. This is decided per connection, clients that didn't offer
+	/// permessage-deflate aren't affected. See the `deflate` module.
+	#[cfg(feature="deflate")]
+	pub fn negotiate_deflate(&mut self) -> bool {
+		let agreed = match deflate::accept_offer(self.extensions()) {
+			Some(agreed) => agreed,
+			None => return false,
+		};
+		upsert_header!(self.headers; WebSocketExtensions; {
+            Some(extensions) => extensions.0.push(agreed),
+            None => WebSocketExtensions(vec![agreed])
+        });
+		self.deflate = true;
+		true
 	}
 
 	/// Reject the client's request to make a websocket connection.
@@ -86,6 +123,22 @@ impl<S> WsUpgrade<S, Option<Buffer>>
 		self.internal_reject(Some(headers))
 	}
 
+	/// Reject the client's request with the given HTTP status, e.g. 403 for
+	/// a disallowed origin, 404 for an unknown path or 401 for missing
+	/// credentials, and an optional body.
+	///
+	/// The response carries `Content-Length` and `Connection: close` along
+	/// with any headers set on this upgrade.
+	pub fn reject_with_status(mut self, code: u16, body: Option<&[u8]>) -> Result<S, (S, io::Error)> {
+		let body = body.unwrap_or(&[]);
+		self.headers.set(ContentLength(body.len() as u64));
+		self.headers.set(Connection::close());
+		match self.send_with_body(StatusCode::from_u16(code), body) {
+			Ok(()) => Ok(self.stream),
+			Err(e) => Err((self.stream, e)),
+		}
+	}
+
 	fn internal_reject(mut self, headers: Option<&Headers>) -> Result<S, (S, io::Error)> {
 		if let Some(custom) = headers {
 			self.headers.extend(custom.iter());
@@ -105,6 +158,11 @@ impl<S, B> WsUpgrade<S, B>
 	pub fn tcp_stream(&self) -> &TcpStream {
 		self.stream.as_tcp()
 	}
+
+	/// The address of the client, see `TcpStream::peer_addr`.
+	pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+		self.tcp_stream().peer_addr()
+	}
 }
 
 /// Trait to take a stream or similar and attempt to recover the start of a
@@ -150,6 +208,10 @@ pub trait IntoWs {
 	/// Attempt to parse the start of a Websocket handshake, later with the  returned
 	/// `WsUpgrade` struct, call `accept` to start a websocket client, and `reject` to
 	/// send a handshake rejection response.
+	///
+	/// Bytes read past the end of the request, like frames a client sent
+	/// right behind it, are kept in the `Buffer` of the upgrade and read by
+	/// the accepted client before anything else from the stream.
 	fn into_ws(self) -> Result<Upgrade<Self::Stream>, Self::Error>;
 }
 
@@ -164,28 +226,99 @@ impl<S> IntoWs for S
 		let request = parse_request(&mut reader);
 
 		let (stream, buf, pos, cap) = reader.into_parts();
-		let buffer = Some(Buffer {
-		                      buf: buf,
-		                      cap: cap,
-		                      pos: pos,
-		                  });
-
-		let request = match request {
-			Ok(r) => r,
-			Err(e) => return Err((stream, None, buffer, e.into())),
-		};
+		upgrade_from(stream,
+		             request.map_err(Into::into),
+		             Buffer {
+		                 buf,
+		                 cap,
+		                 pos,
+		             })
+	}
+}
 
-		match validate(&request.subject.0, &request.version, &request.headers) {
-			Ok(_) => {
-				Ok(WsUpgrade {
-				       headers: Headers::new(),
-				       stream: stream,
-				       request: request,
-				       buffer: buffer,
-				   })
-			}
-			Err(e) => Err((stream, Some(request), buffer, e)),
+/// Like `into_ws`, but fails with `HyperIntoWsError::Timeout` unless the
+/// whole request is read within `budget`, however slowly its bytes arrive.
+///
+/// The read timeout of the socket is shortened before every read to what is
+/// left of the budget, and restored once the request is read.
+pub fn into_ws_within<S>(stream: S, budget: Duration) -> Result<Upgrade<S>, <S as IntoWs>::Error>
+	where S: Stream + AsTcpStream
+{
+	let deadline = Instant::now() + budget;
+	let previous = match stream.as_tcp().read_timeout() {
+		Ok(previous) => previous,
+		Err(e) => return Err((stream, None, None, e.into())),
+	};
+
+	let mut reader = BufReader::new(Deadline {
+	                                    stream,
+	                                    deadline,
+	                                });
+	let request = parse_request(&mut reader);
+
+	let (deadline, buf, pos, cap) = reader.into_parts();
+	let stream = deadline.stream;
+	let buffer = Buffer {
+		buf,
+		cap,
+		pos,
+	};
+	if let Err(e) = stream.as_tcp().set_read_timeout(previous) {
+		return Err((stream, None, Some(buffer), e.into()));
+	}
+
+	let request = match request {
+		Err(_) if Instant::now() >= deadline.deadline => Err(HyperIntoWsError::Timeout),
+		request => request.map_err(Into::into),
+	};
+	upgrade_from(stream, request, buffer)
+}
+
+fn upgrade_from<S>(
+	stream: S,
+	request: Result<Request, HyperIntoWsError>,
+	buffer: Buffer,
+) -> Result<Upgrade<S>, <S as IntoWs>::Error>
+	where S: Stream
+{
+	let buffer = Some(buffer);
+	let request = match request {
+		Ok(r) => r,
+		Err(e) => return Err((stream, None, buffer, e)),
+	};
+
+	match validate(&request.subject.0, &request.version, &request.headers) {
+		Ok(_) => {
+			Ok(WsUpgrade {
+			       headers: Headers::new(),
+			       stream,
+			       request: request,
+			       buffer: buffer,
+			       handshake_hook: None,
+			       deflate: false,
+			       connection_guard: None,
+			   })
+		}
+		Err(e) => Err((stream, Some(request), buffer, e)),
+	}
+}
+
+// limits every read to what is left until the deadline
+struct Deadline<S> {
+	stream: S,
+	deadline: Instant,
+}
+
+impl<S> Read for Deadline<S>
+    where S: Read + AsTcpStream
+{
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let now = Instant::now();
+		if now >= self.deadline {
+			return Err(io::Error::new(io::ErrorKind::TimedOut, "handshake took too long"));
 		}
+		self.stream.as_tcp().set_read_timeout(Some(self.deadline - now))?;
+		self.stream.read(buf)
 	}
 }
 
@@ -203,6 +336,9 @@ impl<S> IntoWs for RequestStreamPair<S>
 				       stream: self.0,
 				       request: self.1,
 				       buffer: None,
+				       handshake_hook: None,
+				       deflate: false,
+				       connection_guard: None,
 				   })
 			}
 			Err(e) => Err((self.0, self.1, e)),
@@ -276,6 +412,9 @@ impl<'a, 'b> IntoWs for HyperRequest<'a, 'b> {
 		                        pos: pos,
 		                        cap: cap,
 		                    }),
+		       handshake_hook: None,
+		       deflate: false,
+		       connection_guard: None,
 		       request: Incoming {
 		           version: version,
 		           headers: headers,
@@ -284,3 +423,212 @@ impl<'a, 'b> IntoWs for HyperRequest<'a, 'b> {
 		   })
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::{Write, Cursor};
+	use stream::ReadWritePair;
+
+	// counts the writes made to it
+	struct Writes(Vec<Vec<u8>>);
+
+	impl Write for Writes {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.push(buf.to_vec());
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	const REQUEST: &[u8] = b"GET /chat HTTP/1.1\r\n\
+	                                 Host: example.org\r\n\
+	                                 Upgrade: websocket\r\n\
+	                                 Connection: Upgrade\r\n\
+	                                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+	                                 Sec-WebSocket-Version: 13\r\n\
+	                                 \r\n";
+
+	fn upgrade() -> Upgrade<ReadWritePair<Cursor<&'static [u8]>, Writes>> {
+		let stream = ReadWritePair(Cursor::new(REQUEST), Writes(Vec::new()));
+		let upgrade = stream.into_ws().ok().unwrap();
+		assert_eq!(upgrade.path(), "/chat");
+		assert!((upgrade.stream.1).0.is_empty());
+		upgrade
+	}
+
+	#[test]
+	fn nothing_is_written_before_accept() {
+		let client = upgrade().accept().ok().unwrap();
+		let stream = client.into_stream().0;
+
+		let writes = &(stream.1).0;
+		assert_eq!(writes.len(), 1);
+		let response = String::from_utf8(writes[0].clone()).unwrap();
+		assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+		assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+		assert!(response.ends_with("\r\n\r\n"));
+	}
+
+	#[test]
+	fn frames_sent_with_the_request_are_kept() {
+		use dataframe::{DataFrame, Opcode};
+		use message::OwnedMessage;
+		use ws::dataframe::DataFrame as DataFrameable;
+
+		let mut input = REQUEST.to_vec();
+		DataFrame::new(true, Opcode::Text, b"early".to_vec()).write_to(&mut input, true).unwrap();
+		DataFrame::new(true, Opcode::Binary, vec![1, 2, 3]).write_to(&mut input, true).unwrap();
+		let frames_len = input.len() - REQUEST.len();
+
+		let stream = ReadWritePair(Cursor::new(input), Writes(Vec::new()));
+		let upgrade = stream.into_ws().ok().unwrap();
+		{
+			let buffer = upgrade.buffer.as_ref().unwrap();
+			assert_eq!(buffer.cap - buffer.pos, frames_len);
+		}
+
+		let mut client = upgrade.accept().ok().unwrap();
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Text("early".to_string()));
+		assert_eq!(client.recv_message().unwrap(), OwnedMessage::Binary(vec![1, 2, 3]));
+	}
+
+	#[test]
+	fn accepted_clients_keep_the_request() {
+		let client = upgrade().use_protocol("chat").accept().ok().unwrap();
+		let request = client.request().unwrap();
+		assert_eq!(request.path, "/chat");
+		assert_eq!(request.host, Some("example.org".to_string()));
+		assert_eq!(request.selected_protocol, Some("chat".to_string()));
+	}
+
+	#[test]
+	fn handshake_hook_sees_the_response() {
+		use header::{HandshakeHook, HandshakeDirection};
+		use std::sync::{Arc, Mutex};
+
+		let seen = Arc::new(Mutex::new(Vec::new()));
+		let log = seen.clone();
+		let mut upgrade = upgrade();
+		upgrade.handshake_hook = Some(HandshakeHook::new(move |direction, headers| {
+			log.lock().unwrap().push((direction, headers.to_string()));
+		}));
+		upgrade.reject().ok().unwrap();
+
+		let seen = seen.lock().unwrap();
+		assert_eq!(*seen, vec![(HandshakeDirection::Outgoing, String::new())]);
+	}
+
+	#[test]
+	fn requests_with_a_body_are_rejected() {
+		let request = String::from_utf8(REQUEST.to_vec()).unwrap();
+		let with_header = |header: &str| {
+			let request = request.replace("Host", &format!("{}\r\nHost", header));
+			let stream = ReadWritePair(Cursor::new(request.into_bytes()), Writes(Vec::new()));
+			match stream.into_ws() {
+				Ok(_) => None,
+				Err((_, _, _, e)) => Some(e.to_string()),
+			}
+		};
+
+		let error = Some("Upgrade request must not have a body".to_string());
+		assert_eq!(with_header("Content-Length: 5"), error);
+		assert_eq!(with_header("Transfer-Encoding: chunked"), error);
+		assert_eq!(with_header("Content-Length: 0"), None);
+	}
+
+	#[test]
+	fn requests_without_host_are_rejected() {
+		let request = String::from_utf8(REQUEST.to_vec()).unwrap().replace("Host: example.org\r\n", "");
+		let stream = ReadWritePair(Cursor::new(request.into_bytes()), Writes(Vec::new()));
+		match stream.into_ws() {
+			Err((_, Some(_), _, e)) => {
+				assert_eq!(e.to_string(), "Missing Host header");
+				assert_eq!(e.status_code(), Some(400));
+			}
+			Err((_, _, _, e)) => panic!("unexpected error {:?}", e),
+			Ok(_) => panic!("a request without Host was accepted"),
+		}
+		assert_eq!(upgrade().host(), Some("example.org"));
+	}
+
+	#[test]
+	fn malformed_keys_are_rejected() {
+		for key in &["dGhlIHNhbXBsZQ==", "not base64!"] {
+			let request = String::from_utf8(REQUEST.to_vec())
+				.unwrap()
+				.replace("dGhlIHNhbXBsZSBub25jZQ==", key);
+			let stream = ReadWritePair(Cursor::new(request.into_bytes()), Writes(Vec::new()));
+			match stream.into_ws() {
+				Err((_, Some(_), _, e)) => {
+					assert_eq!(e.to_string(), "Invalid Sec-WebSocket-Key header");
+					assert_eq!(e.status_code(), Some(400));
+				}
+				Err((_, _, _, e)) => panic!("unexpected error {:?}", e),
+				Ok(_) => panic!("the key {:?} was accepted", key),
+			}
+		}
+	}
+
+	#[test]
+	fn nothing_is_written_before_reject() {
+		let stream = upgrade().reject().ok().unwrap();
+
+		let writes = &(stream.1).0;
+		assert_eq!(writes.len(), 1);
+		assert!(String::from_utf8(writes[0].clone())
+		            .unwrap()
+		            .starts_with("HTTP/1.1 400 Bad Request\r\n"));
+	}
+
+	#[test]
+	fn rejections_carry_the_given_status() {
+		let stream = upgrade().reject_with_status(403, Some(b"origin not allowed")).ok().unwrap();
+		let writes = &(stream.1).0;
+		assert_eq!(writes.len(), 1);
+		assert_eq!(String::from_utf8(writes[0].clone()).unwrap(),
+		           "HTTP/1.1 403 Forbidden\r\n\
+		            Content-Length: 18\r\n\
+		            Connection: close\r\n\
+		            \r\n\
+		            origin not allowed");
+
+		let stream = upgrade().reject_with_status(404, None).ok().unwrap();
+		assert_eq!(String::from_utf8((stream.1).0[0].clone()).unwrap(),
+		           "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+	}
+
+	#[test]
+	#[cfg(feature="deflate")]
+	fn deflate_is_negotiated_per_connection() {
+		use message::Message;
+
+		let upgrade = |request: String| {
+			let stream = ReadWritePair(Cursor::new(request.into_bytes()), Writes(Vec::new()));
+			stream.into_ws().ok().unwrap()
+		};
+		let request = String::from_utf8(REQUEST.to_vec()).unwrap();
+		let offer = "Sec-WebSocket-Extensions: permessage-deflate\r\nHost";
+		let mut compressed = upgrade(request.replace("Host", offer));
+		let mut plain = upgrade(request);
+		assert!(compressed.negotiate_deflate());
+		assert!(!plain.negotiate_deflate());
+
+		let text = Message::text("hello hello hello hello hello");
+		for (upgrade, deflate) in [(compressed, true), (plain, false)] {
+			let mut client = upgrade.accept().ok().unwrap();
+			assert_eq!(client.uses_deflate(), deflate);
+			client.send_message(&text).unwrap();
+
+			let writes = &(client.into_stream().0).1 .0;
+			let response = String::from_utf8(writes[0].clone()).unwrap();
+			assert_eq!(response.contains("Sec-WebSocket-Extensions: permessage-deflate\r\n"), deflate);
+			let frame: Vec<u8> = writes[1..].concat();
+			assert_eq!(frame[0] & 0x40 != 0, deflate);
+			assert_eq!(frame.len() < 2 + 29, deflate);
+		}
+	}
+}
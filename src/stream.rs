@@ -106,6 +106,10 @@ pub mod sync {
 
 	pub use super::Stream;
 
+	/// A stream whose type is chosen at runtime, e.g. by a plugin picking
+	/// between TCP, TLS, Unix sockets or an in-memory transport.
+	pub type BoxedStream = Box<Stream + Send>;
+
 	/// a `Stream` that can also be used as a borrow to a `TcpStream`
 	/// this is useful when you want to set `TcpStream` options on a
 	/// `Stream` like `nonblocking`.
@@ -154,6 +158,18 @@ pub mod sync {
 	pub trait AsTcpStream {
 		/// Get a borrow of the TcpStream
 		fn as_tcp(&self) -> &TcpStream;
+
+		/// Whether the TLS handshake of this stream resumed an earlier
+		/// session, `None` if that isn't known, like for streams without TLS.
+		fn tls_session_reused(&self) -> Option<bool> {
+			None
+		}
+
+		/// The host name a TLS client asked for with SNI, on the server end
+		/// of a connection. `None` if there was none or it isn't known.
+		fn tls_sni_hostname(&self) -> Option<String> {
+			None
+		}
 	}
 
 	impl AsTcpStream for TcpStream {
@@ -167,6 +183,16 @@ pub mod sync {
 		fn as_tcp(&self) -> &TcpStream {
 			self.get_ref()
 		}
+
+		#[cfg(all(feature="tls-session", not(any(target_os="macos", target_os="ios", windows))))]
+		fn tls_session_reused(&self) -> Option<bool> {
+			Some(::tls::session_reused(self))
+		}
+
+		#[cfg(all(feature="tls-sni", not(any(target_os="macos", target_os="ios", windows))))]
+		fn tls_sni_hostname(&self) -> Option<String> {
+			::tls::sni_hostname(self)
+		}
 	}
 
 	impl<T> AsTcpStream for Box<T>
@@ -175,5 +201,13 @@ pub mod sync {
 		fn as_tcp(&self) -> &TcpStream {
 			self.deref().as_tcp()
 		}
+
+		fn tls_session_reused(&self) -> Option<bool> {
+			self.deref().tls_session_reused()
+		}
+
+		fn tls_sni_hostname(&self) -> Option<String> {
+			self.deref().tls_sni_hostname()
+		}
 	}
 }
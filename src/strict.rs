@@ -0,0 +1,63 @@
+//! Strict conformance checking for connections.
+//!
+//! A connection always checks the frames it receives: the masking role,
+//! reserved bits, opcodes, close status codes, UTF-8 text and fragmentation.
+//! In strict mode a message failing one of these checks also fails the
+//! connection, a close frame with the matching status code is sent to the
+//! peer before the error is returned, as RFC6455 7.1.7 asks. This is what
+//! conformance suites such as Autobahn expect.
+use result::WebSocketError;
+
+/// The close status code for a protocol error.
+pub const PROTOCOL_ERROR: u16 = 1002;
+/// The close status code for a message with invalid data, like text that
+/// isn't UTF-8.
+pub const INVALID_PAYLOAD: u16 = 1007;
+/// The close status code for a message that violates a policy, like a
+/// rate limit.
+pub const POLICY_VIOLATION: u16 = 1008;
+/// The close status code for a message too large to process.
+pub const MESSAGE_TOO_BIG: u16 = 1009;
+/// The close status code reported for a connection that ended without a
+/// close frame. It is never sent, see `Client::set_lenient_close`.
+pub const ABNORMAL_CLOSURE: u16 = 1006;
+
+/// The settings of strict mode.
+///
+///```rust
+///# use websocket::sync::StrictMode;
+///let strict = StrictMode::new().max_message_size(16 * 1024 * 1024);
+///assert_eq!(strict.max_message_size, Some(16 * 1024 * 1024));
+///```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StrictMode {
+	/// The largest total payload of a message, larger messages fail the
+	/// connection with status code 1009. `None` for no limit.
+	pub max_message_size: Option<usize>,
+}
+
+impl StrictMode {
+	/// Strict mode without a message size limit.
+	pub fn new() -> Self {
+		StrictMode::default()
+	}
+
+	/// Limits the total payload of a message to `size` bytes.
+	pub fn max_message_size(mut self, size: usize) -> Self {
+		self.max_message_size = Some(size);
+		self
+	}
+}
+
+/// The status code to close a connection with after `error`, or `None` if
+/// the error isn't a violation by the peer.
+pub fn close_code(error: &WebSocketError) -> Option<u16> {
+	match *error {
+		WebSocketError::ProtocolError(_) |
+		WebSocketError::DataFrameError(_) => Some(PROTOCOL_ERROR),
+		WebSocketError::Utf8Error(_) => Some(INVALID_PAYLOAD),
+		WebSocketError::RateLimited => Some(POLICY_VIOLATION),
+		WebSocketError::MessageTooLarge { .. } => Some(MESSAGE_TOO_BIG),
+		_ => None,
+	}
+}
@@ -0,0 +1,274 @@
+//! TLS settings for the secure server and client.
+//!
+//! `native-tls` has no ALPN or session cache settings of its own, so these
+//! helpers reach into its OpenSSL backend and are only available where
+//! `native-tls` uses it. Some proxies and HTTP/2 aware load balancers in front
+//! of WebSocket servers insist on negotiating `http/1.1` via ALPN, that needs
+//! the `tls-alpn` feature and OpenSSL 1.1.0 or later.
+//!
+//! With the `tls-session` feature, servers accepting many connections can
+//! let clients resume earlier sessions with a session cache and tickets,
+//! which skips most of the handshake. `Client::tls_session_reused` tells
+//! whether a connection did. Connectors of `native-tls` start every
+//! connection with a new session, so only the server end is covered.
+//!
+//! With the `tls-sni` feature, `Client::sni_hostname` gives the host name a
+//! client asked a secure server for in its TLS handshake, to route tenants
+//! before reading the `Host` header.
+//!
+//!```rust,no_run
+//!# extern crate native_tls;
+//!# extern crate websocket;
+//!# fn main() {
+//!use std::fs::File;
+//!use std::io::Read;
+//!use native_tls::{Pkcs12, TlsAcceptor, TlsConnector};
+//!use websocket::ClientBuilder;
+//!use websocket::sync::Server;
+//!use websocket::tls;
+//!
+//!let mut pkcs12 = vec![];
+//!File::open("identity.pfx").unwrap().read_to_end(&mut pkcs12).unwrap();
+//!let pkcs12 = Pkcs12::from_der(&pkcs12, "hacktheplanet").unwrap();
+//!let mut acceptor = TlsAcceptor::builder(pkcs12).unwrap();
+//!tls::set_acceptor_alpn(&mut acceptor, &[tls::HTTP_1_1]).unwrap();
+//!let server = Server::bind_secure("127.0.0.1:1234", acceptor.build().unwrap()).unwrap();
+//!
+//!let mut connector = TlsConnector::builder().unwrap();
+//!tls::set_connector_alpn(&mut connector, &[tls::HTTP_1_1]).unwrap();
+//!let client = ClientBuilder::new("wss://127.0.0.1:1234")
+//!    .unwrap()
+//!    .connect_secure(Some(connector.build().unwrap()))
+//!    .unwrap();
+//!# }
+//!```
+#[cfg(any(feature="tls-alpn", feature="tls-session"))]
+use std::io;
+#[cfg(any(feature="tls-alpn", feature="tls-session"))]
+use native_tls::TlsAcceptorBuilder;
+#[cfg(feature="tls-alpn")]
+use native_tls::TlsConnectorBuilder;
+#[cfg(any(feature="tls-alpn", feature="tls-session"))]
+use native_tls::backend::openssl::TlsAcceptorBuilderExt;
+#[cfg(feature="tls-alpn")]
+use native_tls::backend::openssl::TlsConnectorBuilderExt;
+#[cfg(any(feature="tls-session", feature="tls-sni"))]
+use native_tls::TlsStream;
+#[cfg(any(feature="tls-session", feature="tls-sni"))]
+use native_tls::backend::openssl::TlsStreamExt;
+#[cfg(any(feature="tls-alpn", feature="tls-session"))]
+use openssl::error::ErrorStack;
+#[cfg(feature="tls-session")]
+use openssl::ssl::SslContextBuilder;
+#[cfg(feature="tls-session")]
+use openssl_sys as ffi;
+#[cfg(any(feature="tls-alpn", feature="tls-session"))]
+use result::{WebSocketResult, WebSocketError};
+
+/// The ALPN protocol id of HTTP/1.1, which the WebSocket handshake uses.
+#[cfg(feature="tls-alpn")]
+pub const HTTP_1_1: &'static [u8] = b"http/1.1";
+
+/// Makes acceptors built from `builder` select one of `protocols` when the
+/// client offers it, in the order of preference of the server.
+#[cfg(feature="tls-alpn")]
+pub fn set_acceptor_alpn(builder: &mut TlsAcceptorBuilder, protocols: &[&[u8]]) -> WebSocketResult<()> {
+	builder.builder_mut().set_alpn_protocols(protocols).map_err(openssl_error)
+}
+
+/// Makes connectors built from `builder` offer `protocols` to the server.
+#[cfg(feature="tls-alpn")]
+pub fn set_connector_alpn(builder: &mut TlsConnectorBuilder, protocols: &[&[u8]]) -> WebSocketResult<()> {
+	builder.builder_mut().set_alpn_protocols(protocols).map_err(openssl_error)
+}
+
+// values of the SSL_CTX_ctrl macros in ssl.h
+#[cfg(feature="tls-session")]
+const SSL_CTRL_SET_SESS_CACHE_SIZE: i32 = 42;
+#[cfg(feature="tls-session")]
+const SSL_CTRL_SET_SESS_CACHE_MODE: i32 = 44;
+#[cfg(feature="tls-session")]
+const SSL_CTRL_GET_TLSEXT_TICKET_KEYS: i32 = 58;
+#[cfg(feature="tls-session")]
+const SSL_CTRL_SET_TLSEXT_TICKET_KEYS: i32 = 59;
+#[cfg(feature="tls-session")]
+const SSL_SESS_CACHE_SERVER: i64 = 0x2;
+
+/// The context sessions are cached under, a session is only resumed by an
+/// acceptor with the same one.
+#[cfg(feature="tls-session")]
+const SESSION_ID_CONTEXT: &'static [u8] = b"rust-websocket";
+
+/// Makes acceptors built from `builder` keep up to `size` sessions in their
+/// session ID cache, so clients can resume them. 0 means no limit.
+#[cfg(feature="tls-session")]
+pub fn set_acceptor_session_cache(builder: &mut TlsAcceptorBuilder, size: u32) -> WebSocketResult<()> {
+	let context: &mut SslContextBuilder = builder.builder_mut();
+	context.set_session_id_context(SESSION_ID_CONTEXT).map_err(openssl_error)?;
+	unsafe {
+		let ctx = context.as_ptr();
+		ffi::SSL_CTX_ctrl(ctx, SSL_CTRL_SET_SESS_CACHE_MODE, SSL_SESS_CACHE_SERVER as _, ::std::ptr::null_mut());
+		ffi::SSL_CTX_ctrl(ctx, SSL_CTRL_SET_SESS_CACHE_SIZE, size as _, ::std::ptr::null_mut());
+	}
+	Ok(())
+}
+
+/// The number of bytes `set_acceptor_ticket_keys` takes with the OpenSSL
+/// `builder` is linked to, 48 before OpenSSL 1.1.1 and 80 since.
+#[cfg(feature="tls-session")]
+pub fn acceptor_ticket_keys_len(builder: &mut TlsAcceptorBuilder) -> usize {
+	let context: &mut SslContextBuilder = builder.builder_mut();
+	// without keys to get, OpenSSL answers with their length
+	unsafe {
+		ffi::SSL_CTX_ctrl(context.as_ptr(),
+		                  SSL_CTRL_GET_TLSEXT_TICKET_KEYS,
+		                  0,
+		                  ::std::ptr::null_mut()) as usize
+	}
+}
+
+/// Sets the keys session tickets of acceptors built from `builder` are
+/// encrypted with.
+///
+/// By default every acceptor picks random keys, servers behind a load
+/// balancer must share them for a ticket issued by one to be accepted by
+/// another. `keys` are the key name, the HMAC secret and the AES key, 16
+/// bytes each before OpenSSL 1.1.1 and 16, 32 and 32 bytes since, and must
+/// be `acceptor_ticket_keys_len` bytes long. Keep them as secret as the
+/// private key.
+#[cfg(feature="tls-session")]
+pub fn set_acceptor_ticket_keys(builder: &mut TlsAcceptorBuilder, keys: &[u8]) -> WebSocketResult<()> {
+	let context: &mut SslContextBuilder = builder.builder_mut();
+	let mut keys = keys.to_vec();
+	let set = unsafe {
+		ffi::SSL_CTX_ctrl(context.as_ptr(),
+		                  SSL_CTRL_SET_TLSEXT_TICKET_KEYS,
+		                  keys.len() as _,
+		                  keys.as_mut_ptr() as *mut _)
+	};
+	if set != 1 {
+		return Err(openssl_error(ErrorStack::get()));
+	}
+	Ok(())
+}
+
+/// Whether the handshake of `stream` resumed an earlier session.
+#[cfg(feature="tls-session")]
+pub fn session_reused<S>(stream: &TlsStream<S>) -> bool {
+	stream.raw_stream().ssl().session_reused()
+}
+
+/// The host name the client of `stream` sent with SNI, on the server end of
+/// a connection. OpenSSL keeps it from the client's hello, so no servername
+/// callback is needed.
+#[cfg(feature="tls-sni")]
+pub fn sni_hostname<S>(stream: &TlsStream<S>) -> Option<String> {
+	stream.raw_stream().ssl().servername().map(str::to_string)
+}
+
+#[cfg(any(feature="tls-alpn", feature="tls-session"))]
+fn openssl_error(error: ErrorStack) -> WebSocketError {
+	WebSocketError::IoError(io::Error::new(io::ErrorKind::Other, error))
+}
+
+#[cfg(all(test, feature="tls-session"))]
+mod tests {
+	use std::io::{Read, Write};
+	use std::net::{TcpListener, TcpStream};
+	use std::thread;
+	use native_tls::{Pkcs12, TlsAcceptor};
+	use openssl::asn1::Asn1Time;
+	use openssl::hash::MessageDigest;
+	use openssl::nid;
+	use openssl::pkcs12::Pkcs12 as OpensslPkcs12;
+	use openssl::pkey::PKey;
+	use openssl::rsa::Rsa;
+	use openssl::ssl::{SslConnector, SslConnectorBuilder, SslMethod, SslSession, SSL_VERIFY_NONE};
+	use openssl::x509::{X509Builder, X509NameBuilder};
+	use super::*;
+
+	// a self-signed identity for localhost, as PKCS #12
+	fn identity() -> Vec<u8> {
+		let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+		let mut name = X509NameBuilder::new().unwrap();
+		name.append_entry_by_nid(nid::COMMONNAME, "localhost").unwrap();
+		let name = name.build();
+		let mut cert = X509Builder::new().unwrap();
+		cert.set_version(2).unwrap();
+		cert.set_subject_name(&name).unwrap();
+		cert.set_issuer_name(&name).unwrap();
+		cert.set_pubkey(&key).unwrap();
+		cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+		cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+		cert.sign(&key, MessageDigest::sha256()).unwrap();
+		let cert = cert.build();
+		OpensslPkcs12::builder()
+			.build("password", "localhost", &key, &cert)
+			.unwrap()
+			.to_der()
+			.unwrap()
+	}
+
+	fn acceptor_builder(identity: &[u8]) -> TlsAcceptorBuilder {
+		TlsAcceptor::builder(Pkcs12::from_der(identity, "password").unwrap()).unwrap()
+	}
+
+	// accepts one connection, the thread tells whether it resumed a session
+	fn serve(acceptor: TlsAcceptor) -> (u16, thread::JoinHandle<bool>) {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let server = thread::spawn(move || {
+			let (stream, _) = listener.accept().unwrap();
+			let mut stream = acceptor.accept(stream).unwrap();
+			// TLS 1.3 clients get their ticket after the handshake, this
+			// makes the client wait for it
+			stream.write_all(b"x").unwrap();
+			session_reused(&stream)
+		});
+		(port, server)
+	}
+
+	// native-tls connectors can't resume sessions, so this uses OpenSSL
+	fn connect(connector: &SslConnector, port: u16, session: Option<&SslSession>) -> SslSession {
+		let mut config = connector.configure().unwrap();
+		if let Some(session) = session {
+			unsafe {
+				config.ssl_mut().set_session(session).unwrap();
+			}
+		}
+		let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+		let mut stream = config.danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication(stream)
+		                       .unwrap();
+		stream.read_exact(&mut [0]).unwrap();
+		stream.ssl().session().unwrap().to_owned()
+	}
+
+	#[test]
+	fn shared_ticket_keys_resume_sessions_across_acceptors() {
+		let identity = identity();
+		let len = acceptor_ticket_keys_len(&mut acceptor_builder(&identity));
+		assert!(len == 48 || len == 80, "{} bytes", len);
+		let keys = vec![7; len];
+		let acceptor = || {
+			let mut builder = acceptor_builder(&identity);
+			set_acceptor_ticket_keys(&mut builder, &keys).unwrap();
+			builder.build().unwrap()
+		};
+		let mut connector = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+		connector.builder_mut().set_verify(SSL_VERIFY_NONE);
+		let connector = connector.build();
+
+		let (port, server) = serve(acceptor());
+		let session = connect(&connector, port, None);
+		assert!(!server.join().unwrap());
+
+		// only the ticket can be resumed, the second acceptor has its own
+		// session cache
+		let (port, server) = serve(acceptor());
+		connect(&connector, port, Some(&session));
+		assert!(server.join().unwrap());
+
+		let mut builder = acceptor_builder(&identity);
+		assert!(set_acceptor_ticket_keys(&mut builder, &keys[1..]).is_err());
+	}
+}
@@ -2,10 +2,11 @@
 //! that all dataframes should share. This is so one can
 //! optimize the memory footprint of a dataframe for their
 //! own needs, and be able to use custom dataframes quickly
-use std::io::Write;
+use std::io::{self, IoSlice, Write};
 use result::WebSocketResult;
 use ws::util::header::{self as dfh, DataFrameFlags};
-use ws::util::mask::Masker;
+use ws::util::framing;
+use ws::util::mask::{MaskedWriter, MASK_CHUNK};
 use ws::util::mask;
 
 /// A generic DataFrame. Every dataframe should be able to
@@ -25,22 +26,8 @@ pub trait DataFrame {
 	/// Get's the size of the entire dataframe in bytes,
 	/// i.e. header and payload.
 	fn frame_size(&self, masked: bool) -> usize {
-		// one byte for the opcode & reserved & fin
-		1
-        // depending on the size of the payload, add the right payload len bytes
-        + match self.size() {
-            s if s <= 125 => 1,
-            s if s <= 65535 => 3,
-            _ => 9,
-        }
-        // add the mask size if there is one
-        + if masked {
-            4
-        } else {
-            0
-        }
-        // finally add the payload len
-        + self.size()
+		// the payload is in memory, so the header fits next to it
+		framing::serialized_len(self.size() as u64, masked).expect("frame size overflows usize")
 	}
 
 	/// Write the payload to a writer
@@ -49,6 +36,14 @@ pub trait DataFrame {
 	/// Takes the payload out into a vec
 	fn take_payload(self) -> Vec<u8>;
 
+	/// The payload as a single slice, if it is stored like that.
+	///
+	/// Unmasked frames that return it are sent with one vectored write for
+	/// the header and payload. Returns `None` by default.
+	fn payload_slice(&self) -> Option<&[u8]> {
+		None
+	}
+
 	/// Writes a DataFrame to a Writer.
 	fn write_to(&self, writer: &mut Write, mask: bool) -> WebSocketResult<()> {
 		let mut flags = DataFrameFlags::empty();
@@ -68,6 +63,14 @@ pub trait DataFrame {
 			}
 		}
 
+		// most frames are small, final and unmasked, skip the generic header writer
+		if !mask && flags == DataFrameFlags::FIN && self.size() <= 125 {
+			dfh::write_small_header(writer, self.opcode(), self.size() as u8)?;
+			self.write_payload(writer)?;
+			writer.flush()?;
+			return Ok(());
+		}
+
 		let masking_key = if mask { Some(mask::gen_mask()) } else { None };
 
 		let header = dfh::DataFrameHeader {
@@ -77,16 +80,110 @@ pub trait DataFrame {
 			len: self.size() as u64,
 		};
 
-		dfh::write_header(writer, header)?;
+		let mut header_buf = [0u8; 14];
+		let header_len = {
+			let mut cursor = &mut header_buf[..];
+			dfh::write_header(&mut cursor, header)?;
+			14 - cursor.len()
+		};
+		let header_bytes = &header_buf[..header_len];
 
-		match masking_key {
-			Some(mask) => {
-				let mut masker = Masker::new(mask, writer);
-				self.write_payload(&mut masker)?
+		match (masking_key, self.payload_slice()) {
+			(Some(mask), _) if self.size() <= MASK_CHUNK => {
+				// small payloads are masked on the stack and sent along with the header
+				let mut payload = [0u8; MASK_CHUNK];
+				let len = self.size();
+				self.write_payload(&mut MaskedWriter::new(mask, &mut payload[..len]))?;
+				write_all_vectored(writer, header_bytes, &payload[..len])?;
+			}
+			(Some(mask), _) => {
+				writer.write_all(header_bytes)?;
+				self.write_payload(&mut MaskedWriter::new(mask, &mut *writer))?;
+			}
+			(None, Some(payload)) => write_all_vectored(writer, header_bytes, payload)?,
+			(None, None) => {
+				writer.write_all(header_bytes)?;
+				self.write_payload(writer)?;
 			}
-			None => self.write_payload(writer)?,
 		};
 		writer.flush()?;
 		Ok(())
 	}
 }
+
+/// Writes both buffers, using vectored writes so that writers supporting
+/// them can send a whole frame in one call. Other writers just write the
+/// buffers one after the other.
+fn write_all_vectored(writer: &mut Write, mut first: &[u8], mut second: &[u8]) -> io::Result<()> {
+	while !first.is_empty() {
+		match writer.write_vectored(&[IoSlice::new(first), IoSlice::new(second)]) {
+			Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole frame")),
+			Ok(n) if n < first.len() => first = &first[n..],
+			Ok(n) => {
+				second = &second[n - first.len()..];
+				first = &[];
+			}
+			Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+			Err(e) => return Err(e),
+		}
+	}
+	writer.write_all(second)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use dataframe::{DataFrame as OwnedDataFrame, Opcode};
+
+	// only ever writes the first buffer, like writers without vectored support
+	struct OneAtATime(Vec<u8>, usize);
+
+	impl Write for OneAtATime {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.1 += 1;
+			self.0.extend_from_slice(buf);
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn vectored_and_plain_writes_agree() {
+		let frame = OwnedDataFrame::new(false, Opcode::Binary, vec![7; 300]);
+
+		let mut vectored = Vec::new();
+		frame.write_to(&mut vectored, false).unwrap();
+
+		let mut plain = OneAtATime(Vec::new(), 0);
+		frame.write_to(&mut plain, false).unwrap();
+
+		assert_eq!(&vectored[..4], &[0x02, 126, 1, 44]);
+		assert_eq!(&vectored[4..], &[7; 300][..]);
+		assert_eq!(plain.0, vectored);
+		assert_eq!(plain.1, 2);
+	}
+
+	#[test]
+	fn masked_frames_of_any_size_round_trip() {
+		use receiver::PacketState;
+		use ws::util::header::ReaderState;
+		use uuid::Uuid;
+
+		for &len in &[10, MASK_CHUNK, MASK_CHUNK + 1, 3 * MASK_CHUNK] {
+			let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+			let mut output = Vec::new();
+			OwnedDataFrame::new(true, Opcode::Binary, payload.clone()).write_to(&mut output, true).unwrap();
+
+			let frame = OwnedDataFrame::read_dataframe(&mut &output[..],
+			                                           true,
+			                                           Uuid::nil(),
+			                                           &mut PacketState::default(),
+			                                           &mut ReaderState::new())
+				.unwrap();
+			assert_eq!(frame.data, payload);
+		}
+	}
+}
@@ -0,0 +1,69 @@
+//! Provides a trait for extensions that transform data frames.
+//!
+//! See the `ws` module documentation for more information.
+
+use std::sync::{Arc, Mutex};
+use dataframe::DataFrame;
+use header::extensions::Extension as ExtensionHeader;
+use result::WebSocketResult;
+
+/// An extension that changes the data frames sent and received on a
+/// connection, e.g. to compress them or to make use of the reserved bits.
+///
+/// Extensions are registered on a client after the handshake with
+/// `Client::register_extension`. When several are registered, outgoing
+/// frames go through them in the order they were registered and incoming
+/// frames in the reverse order, so each extension undoes its own changes
+/// to a frame before the ones registered earlier see it.
+pub trait Extension: Send {
+	/// The name of the extension, as used in the `Sec-WebSocket-Extensions` header.
+	fn name(&self) -> &str;
+
+	/// The entry to offer in the handshake, e.g. with
+	/// `ClientBuilder::add_extension`. By default it has no parameters.
+	fn offer(&self) -> ExtensionHeader {
+		ExtensionHeader::new(self.name().to_string())
+	}
+
+	/// Called when the extension is registered, with the entry the peer
+	/// agreed to in the handshake. An error stops the registration.
+	fn negotiated(&mut self, _agreed: &ExtensionHeader) -> WebSocketResult<()> {
+		Ok(())
+	}
+
+	/// Called before the extension is used on another connection, see
+	/// `FrameCodec::reset`. It should forget what carried over from one
+	/// message to the next, e.g. a compression context. Does nothing by
+	/// default.
+	fn reset(&mut self) {}
+
+	/// Changes a data frame before it is sent.
+	fn on_send_frame(&mut self, frame: &mut DataFrame);
+
+	/// Changes a received data frame before it is passed on.
+	///
+	/// Any reserved bits used by the extension must be cleared here, messages
+	/// with reserved bits set are rejected. An error fails the read.
+	fn on_recv_frame(&mut self, frame: &mut DataFrame) -> WebSocketResult<()>;
+
+	/// Like `on_recv_frame`, with the largest payload the frame may have
+	/// afterwards without making its message too large, if there is a limit.
+	///
+	/// Extensions that enlarge frames, like decompression, should stop and
+	/// fail with `WebSocketError::MessageTooLarge` once they go past it
+	/// instead of producing all of their output first. The receiver calls
+	/// this, by default it calls `on_recv_frame`.
+	fn on_recv_frame_within(&mut self, frame: &mut DataFrame, _max_len: Option<usize>) -> WebSocketResult<()> {
+		self.on_recv_frame(frame)
+	}
+}
+
+/// The name of the permessage-deflate extension (RFC7692).
+///
+/// While an extension with this name is registered, RSV1 is cleared on
+/// outgoing continuation frames, and incoming ones with RSV1 set are
+/// rejected: only the first frame of a compressed message may have it.
+pub const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+
+/// An extension shared between the sending and receiving half of a client.
+pub(crate) type SharedExtension = Arc<Mutex<Box<Extension>>>;
@@ -3,6 +3,7 @@
 //! See the `ws` module documentation for more information.
 
 use std::io::Write;
+use dataframe::DataFrame as OwnedDataFrame;
 use ws::dataframe::DataFrame as DataFrameable;
 use result::WebSocketResult;
 
@@ -14,6 +15,31 @@ pub trait Message: Sized {
 	/// Returns how many bytes this message will take up
 	fn message_size(&self, masked: bool) -> usize;
 
+	/// Returns the exact number of bytes `serialize` writes, header and
+	/// payload together, e.g. to size a buffer up front. Defaults to
+	/// `message_size`.
+	fn serialized_len(&self, masked: bool) -> usize {
+		self.message_size(masked)
+	}
+
 	/// Attempt to form a message from a series of data frames
 	fn from_dataframes<D: DataFrameable>(frames: Vec<D>) -> WebSocketResult<Self>;
+
+	/// Whether this is a close message, which senders use to keep track of
+	/// the closing handshake. Defaults to `false`.
+	fn is_close(&self) -> bool {
+		false
+	}
+
+	/// Whether this is a close, ping or pong message. Defaults to `false`.
+	fn is_control(&self) -> bool {
+		false
+	}
+
+	/// The message as the single frame it is sent as, for senders that hand
+	/// frames to extensions. Defaults to `None`, such senders then read the
+	/// frames back from what `serialize` writes.
+	fn to_single_frame(&self) -> Option<OwnedDataFrame> {
+		None
+	}
 }
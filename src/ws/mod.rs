@@ -54,3 +54,5 @@ pub mod dataframe;
 pub mod sender;
 #[cfg(feature="sync")]
 pub mod receiver;
+#[cfg(feature="sync")]
+pub mod extension;
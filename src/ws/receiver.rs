@@ -28,6 +28,10 @@ pub trait Receiver: Sized {
 		where R: Read;
 
 	/// Returns an iterator over incoming data frames.
+	///
+	/// Frames are yielded exactly as they arrive: fragments are not merged
+	/// and control frames interleaved in a fragmented message are returned
+	/// in place. Use `incoming_messages` to get reassembled messages instead.
 	fn incoming_dataframes<'a, R>(&'a mut self, reader: &'a mut R) -> DataFrameIterator<'a, Self, R>
 		where R: Read
 	{
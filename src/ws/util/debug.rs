@@ -0,0 +1,93 @@
+//! Human readable dumps of data frames, for tracking down protocol bugs in
+//! logs without a packet capture.
+use std::fmt::Write;
+use ws::util::framing::{DataFrameFlags, DataFrameHeader};
+
+/// Renders the decoded fields of `header` followed by a hex and ASCII dump
+/// of `payload`, sixteen bytes per line.
+///
+/// The payload is dumped as given, a masked payload is not unmasked.
+///
+///```rust
+///# use websocket::ws::util::debug::debug_frame;
+///# use websocket::ws::util::header::{DataFrameFlags, DataFrameHeader};
+///let header = DataFrameHeader {
+///    flags: DataFrameFlags::FIN,
+///    opcode: 0x1,
+///    mask: None,
+///    len: 2,
+///};
+///assert_eq!(debug_frame(&header, b"hi"),
+///           "FIN=1 RSV=000 opcode=Text (0x1) mask=none len=2\n\
+///            00000000  68 69                                            |hi|\n");
+///```
+pub fn debug_frame(header: &DataFrameHeader, payload: &[u8]) -> String {
+	let mut out = String::new();
+	let bit = |flag| if header.flags.contains(flag) { 1 } else { 0 };
+	let _ = write!(out,
+	               "FIN={} RSV={}{}{} opcode={} (0x{:X}) mask=",
+	               bit(DataFrameFlags::FIN),
+	               bit(DataFrameFlags::RSV1),
+	               bit(DataFrameFlags::RSV2),
+	               bit(DataFrameFlags::RSV3),
+	               opcode_name(header.opcode),
+	               header.opcode);
+	match header.mask {
+		Some(key) => {
+			let _ = write!(out, "{:02x}{:02x}{:02x}{:02x}", key[0], key[1], key[2], key[3]);
+		}
+		None => out.push_str("none"),
+	}
+	let _ = writeln!(out, " len={}", header.len);
+
+	for (line, chunk) in payload.chunks(16).enumerate() {
+		let _ = write!(out, "{:08x} ", line * 16);
+		for i in 0..16 {
+			match chunk.get(i) {
+				Some(byte) => {
+					let _ = write!(out, " {:02x}", byte);
+				}
+				None => out.push_str("   "),
+			}
+		}
+		out.push_str("  |");
+		for &byte in chunk {
+			out.push(if (0x20..0x7f).contains(&byte) { byte as char } else { '.' });
+		}
+		out.push_str("|\n");
+	}
+	out
+}
+
+fn opcode_name(opcode: u8) -> &'static str {
+	match opcode {
+		0x0 => "Continuation",
+		0x1 => "Text",
+		0x2 => "Binary",
+		0x8 => "Close",
+		0x9 => "Ping",
+		0xA => "Pong",
+		0x3..=0x7 => "Reserved data",
+		_ => "Reserved control",
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn masked_frames_show_the_key_and_wrap_the_payload() {
+		let header = DataFrameHeader {
+			flags: DataFrameFlags::RSV1,
+			opcode: 0x2,
+			mask: Some([0x37, 0xfa, 0x21, 0x3d]),
+			len: 17,
+		};
+		let payload = b"0123456789abcdef\x00";
+		assert_eq!(debug_frame(&header, payload),
+		           "FIN=0 RSV=100 opcode=Binary (0x2) mask=37fa213d len=17\n\
+		            00000000  30 31 32 33 34 35 36 37 38 39 61 62 63 64 65 66  |0123456789abcdef|\n\
+		            00000010  00                                               |.|\n");
+	}
+}
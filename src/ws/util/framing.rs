@@ -0,0 +1,362 @@
+//! Data frame header encoding, decoding and masking on plain byte slices.
+//!
+//! The stream based functions of the `header` and `mask` modules are built
+//! on top of the types and checks defined here.
+//!
+//! This module only uses `core`, but it is part of a crate that needs `std`,
+//! so it can't be used in `no_std` builds.
+
+use core::convert::TryFrom;
+use core::fmt;
+
+bitflags! {
+	/// Flags relevant to a WebSocket data frame.
+	pub struct DataFrameFlags: u8 {
+		/// Marks this dataframe as the last dataframe
+		const FIN = 0x80;
+		/// First reserved bit
+		const RSV1 = 0x40;
+		/// Second reserved bit
+		const RSV2 = 0x20;
+		/// Third reserved bit
+		const RSV3 = 0x10;
+	}
+}
+
+/// Represents a data frame header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DataFrameHeader {
+	/// The bit flags for the first byte of the header.
+	pub flags: DataFrameFlags,
+	/// The opcode of the header - must be <= 16.
+	pub opcode: u8,
+	/// The masking key, if any.
+	pub mask: Option<[u8; 4]>,
+	/// The length of the payload.
+	pub len: u64,
+}
+
+impl DataFrameHeader {
+	/// Whether this is the header of a control frame (close, ping, pong or a
+	/// reserved control opcode), that is any opcode from 8 to 15.
+	pub fn is_control(&self) -> bool {
+		is_control_opcode(self.opcode)
+	}
+
+	/// Whether this is the header of a data frame, any opcode below 8.
+	///
+	/// Continuations count as data frames, they carry the rest of a text or
+	/// binary message. Unlike the other data frames they can't start a
+	/// message, see `is_continuation`.
+	pub fn is_data(&self) -> bool {
+		is_data_opcode(self.opcode)
+	}
+
+	/// Whether this is the header of a continuation frame.
+	pub fn is_continuation(&self) -> bool {
+		self.opcode == 0
+	}
+}
+
+/// The largest size of an encoded header: two bytes, an eight byte extended
+/// length and a four byte masking key.
+pub const MAX_HEADER_LEN: usize = 14;
+
+/// The ways a header can be invalid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramingError {
+	/// The opcode doesn't fit in four bits.
+	InvalidOpcode,
+	/// A control frame has a payload longer than 125 bytes.
+	ControlFrameTooLong,
+	/// A control frame doesn't have the FIN bit set.
+	FragmentedControlFrame,
+	/// The length is not in its shortest form, or has the most significant bit set.
+	InvalidLength,
+	/// The buffer to encode the header into is too small.
+	BufferTooSmall,
+}
+
+impl FramingError {
+	/// A short description of the error.
+	pub fn description(&self) -> &'static str {
+		match *self {
+			FramingError::InvalidOpcode => "Invalid data frame opcode",
+			FramingError::ControlFrameTooLong => "Control frame length too long",
+			FramingError::FragmentedControlFrame => "Illegal fragmented control frame",
+			FramingError::InvalidLength => "Invalid data frame length",
+			FramingError::BufferTooSmall => "Buffer too small for data frame header",
+		}
+	}
+}
+
+impl fmt::Display for FramingError {
+	fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+		fmt.write_str(self.description())
+	}
+}
+
+/// Returns the encoded size of a header.
+pub fn header_len(header: &DataFrameHeader) -> usize {
+	2 +
+	if header.len <= 125 {
+		0
+	} else if header.len <= 65535 {
+		2
+	} else {
+		8
+	} + if header.mask.is_some() { 4 } else { 0 }
+}
+
+/// Returns the size of a whole frame on the wire, the header and a
+/// payload of `payload_len` bytes, using the same length encoding as
+/// `encode_header`. `None` if the size doesn't fit in a `usize`.
+pub fn serialized_len(payload_len: u64, masked: bool) -> Option<usize> {
+	let header = DataFrameHeader {
+		flags: DataFrameFlags::empty(),
+		opcode: 0,
+		mask: if masked { Some([0; 4]) } else { None },
+		len: payload_len,
+	};
+	usize::try_from(payload_len)
+		.ok()
+		.and_then(|len| len.checked_add(header_len(&header)))
+}
+
+/// Encodes a header into `buf`, returning the number of bytes used.
+///
+/// A buffer of `MAX_HEADER_LEN` bytes fits every header.
+pub fn encode_header(header: &DataFrameHeader, buf: &mut [u8]) -> Result<usize, FramingError> {
+	if header.opcode > 0xF {
+		return Err(FramingError::InvalidOpcode);
+	}
+	if header.is_control() && header.len >= 126 {
+		return Err(FramingError::ControlFrameTooLong);
+	}
+	let len = header_len(header);
+	if buf.len() < len {
+		return Err(FramingError::BufferTooSmall);
+	}
+
+	// 'FIN', 'RSV1', 'RSV2', 'RSV3' and 'opcode'
+	buf[0] = header.flags.bits | header.opcode;
+	// 'MASK' and 'Payload len'
+	buf[1] = if header.mask.is_some() { 0x80 } else { 0x00 } |
+		if header.len <= 125 { header.len as u8 }
+		else if header.len <= 65535 { 126 }
+		else { 127 };
+
+	// 'Extended payload length'
+	let mut pos = 2;
+	if header.len >= 126 && header.len <= 65535 {
+		buf[2] = (header.len >> 8) as u8;
+		buf[3] = header.len as u8;
+		pos = 4;
+	} else if header.len > 65535 {
+		for i in 0..8 {
+			buf[2 + i] = (header.len >> (56 - 8 * i)) as u8;
+		}
+		pos = 10;
+	}
+
+	// 'Masking-key'
+	if let Some(mask) = header.mask {
+		buf[pos..pos + 4].copy_from_slice(&mask);
+	}
+
+	Ok(len)
+}
+
+/// Decodes a header from the start of `buf`.
+///
+/// Returns the header and the number of bytes it took up, or `None` if
+/// `buf` doesn't contain a whole header yet.
+pub fn decode_header(buf: &[u8]) -> Result<Option<(DataFrameHeader, usize)>, FramingError> {
+	if buf.len() < 2 {
+		return Ok(None);
+	}
+
+	let flags = DataFrameFlags::from_bits_truncate(buf[0]);
+	let opcode = buf[0] & 0x0F;
+	let has_mask = buf[1] & 0x80 == 0x80;
+
+	let (len, mut pos) = match buf[1] & 0x7F {
+		126 => {
+			if buf.len() < 4 {
+				return Ok(None);
+			}
+			((buf[2] as u64) << 8 | buf[3] as u64, 4)
+		}
+		127 => {
+			if buf.len() < 10 {
+				return Ok(None);
+			}
+			(buf[2..10].iter().fold(0u64, |len, &byte| len << 8 | byte as u64), 10)
+		}
+		len => (len as u64, 2),
+	};
+	check_len(buf[1] & 0x7F, len)?;
+	check_control(opcode, flags, len)?;
+
+	let mask = if has_mask {
+		if buf.len() < pos + 4 {
+			return Ok(None);
+		}
+		let mut mask = [0; 4];
+		mask.copy_from_slice(&buf[pos..pos + 4]);
+		pos += 4;
+		Some(mask)
+	} else {
+		None
+	};
+
+	Ok(Some((DataFrameHeader {
+		         flags,
+		         opcode,
+		         mask,
+		         len,
+		     },
+		     pos)))
+}
+
+/// Checks that a length was encoded in its shortest form and, for 64 bit
+/// lengths, that the most significant bit is 0 (RFC6455 5.2).
+pub(crate) fn check_len(len_byte: u8, len: u64) -> Result<(), FramingError> {
+	match len_byte & 0x7F {
+		126 if len <= 125 => Err(FramingError::InvalidLength),
+		127 if len <= 65535 || len >> 63 != 0 => Err(FramingError::InvalidLength),
+		_ => Ok(()),
+	}
+}
+
+/// Checks everything about a header that doesn't depend on how it is
+/// encoded: the opcode fits in four bits, the length in 63 bits, and
+/// control frames are short and not fragmented.
+pub fn validate_header(header: &DataFrameHeader) -> Result<(), FramingError> {
+	if header.opcode > 0xF {
+		return Err(FramingError::InvalidOpcode);
+	}
+	if header.len >> 63 != 0 {
+		return Err(FramingError::InvalidLength);
+	}
+	check_control(header.opcode, header.flags, header.len)
+}
+
+/// Checks that control frames are short and not fragmented.
+pub(crate) fn check_control(opcode: u8, flags: DataFrameFlags, len: u64) -> Result<(), FramingError> {
+	if is_control_opcode(opcode) {
+		if len >= 126 {
+			return Err(FramingError::ControlFrameTooLong);
+		}
+		if !flags.contains(DataFrameFlags::FIN) {
+			return Err(FramingError::FragmentedControlFrame);
+		}
+	}
+	Ok(())
+}
+
+/// Whether an opcode is one of a control frame.
+pub(crate) fn is_control_opcode(opcode: u8) -> bool {
+	(8..=0xF).contains(&opcode)
+}
+
+/// Whether an opcode is one of a data frame, continuations included.
+pub(crate) fn is_data_opcode(opcode: u8) -> bool {
+	opcode < 8
+}
+
+/// Masks or unmasks `data` in place, `offset` being the position of its
+/// first byte in the payload.
+pub fn apply_mask(key: [u8; 4], offset: usize, data: &mut [u8]) {
+	for (i, byte) in data.iter_mut().enumerate() {
+		*byte ^= key[(offset + i) % 4];
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn headers_round_trip() {
+		for &len in &[0, 125, 126, 65535, 65536, 1 << 40] {
+			for &mask in &[None, Some([1, 2, 3, 4])] {
+				let header = DataFrameHeader {
+					flags: DataFrameFlags::FIN | DataFrameFlags::RSV1,
+					opcode: 2,
+					mask,
+					len,
+				};
+				let mut buf = [0; MAX_HEADER_LEN];
+				let encoded = encode_header(&header, &mut buf).unwrap();
+				assert_eq!(encoded, header_len(&header));
+
+				// every shorter prefix is incomplete
+				for end in 0..encoded {
+					assert_eq!(decode_header(&buf[..end]).unwrap(), None);
+				}
+				assert_eq!(decode_header(&buf[..encoded]).unwrap(), Some((header, encoded)));
+			}
+		}
+	}
+
+	#[test]
+	fn serialized_len_counts_header_and_payload() {
+		assert_eq!(serialized_len(125, false), Some(127));
+		assert_eq!(serialized_len(126, true), Some(2 + 2 + 4 + 126));
+		assert_eq!(serialized_len(u64::MAX, false), None);
+	}
+
+	#[test]
+	fn invalid_headers_are_rejected() {
+		assert_eq!(decode_header(&[0x82, 126, 0, 125]), Err(FramingError::InvalidLength));
+		assert_eq!(decode_header(&[0x82, 127, 0x80, 0, 0, 0, 0, 0, 0, 0]),
+		           Err(FramingError::InvalidLength));
+		assert_eq!(decode_header(&[0x09, 0]), Err(FramingError::FragmentedControlFrame));
+		assert_eq!(decode_header(&[0x89, 126, 0, 126]), Err(FramingError::ControlFrameTooLong));
+
+		let header = DataFrameHeader {
+			flags: DataFrameFlags::FIN,
+			opcode: 2,
+			mask: Some([0; 4]),
+			len: 70000,
+		};
+		assert_eq!(encode_header(&header, &mut [0; 13]), Err(FramingError::BufferTooSmall));
+	}
+
+	#[test]
+	fn headers_are_classified_by_opcode() {
+		let mut header = DataFrameHeader {
+			flags: DataFrameFlags::FIN,
+			opcode: 0,
+			mask: None,
+			len: 0,
+		};
+		for opcode in 0..16 {
+			header.opcode = opcode;
+			assert_eq!(header.is_control(), opcode >= 8);
+			assert_eq!(header.is_data(), opcode < 8);
+			assert_eq!(header.is_continuation(), opcode == 0);
+		}
+		header.opcode = 16;
+		assert!(!header.is_control() && !header.is_data());
+	}
+
+	#[test]
+	fn mask_offsets_continue_the_key() {
+		let key = [1, 2, 3, 4];
+		let mut whole = *b"masked payload";
+		apply_mask(key, 0, &mut whole);
+
+		let mut parts = *b"masked payload";
+		{
+			let (first, second) = parts.split_at_mut(5);
+			apply_mask(key, 0, first);
+			apply_mask(key, 5, second);
+		}
+		assert_eq!(whole, parts);
+
+		apply_mask(key, 0, &mut whole);
+		assert_eq!(&whole, b"masked payload");
+	}
+}
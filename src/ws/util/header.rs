@@ -1,129 +1,202 @@
 //! Utility functions for reading and writing data frame headers.
 
-use std::io::{Read, Write};
+use std::io::{self, Read, Write, ErrorKind};
 use result::{WebSocketResult, WebSocketError};
-use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
+use super::framing;
+
+pub use super::framing::{DataFrameFlags, DataFrameHeader};
+
+/// The reason of the `ProtocolError` for a stream that ends in the middle of
+/// a frame.
+pub const TRUNCATED_FRAME: &str = "Truncated frame";
 
 #[allow(missing_docs)]
 pub struct ReaderState {
 	flags: Option<DataFrameFlags>,
 	opcode: Option<u8>,
 	has_mask: bool,
-	mask: Vec<u8>,
-	raw_len: Vec<u8>,
+	mask: [u8; 4],
+	mask_read: usize,
+	raw_len: [u8; 8],
+	raw_len_read: usize,
 	len_byte: Option<u8>,
 	len: Option<u64>,
 }
 
 impl ReaderState {
-	pub(crate) fn new() -> ReaderState {
+	/// A state for reading a header from its first byte.
+	pub fn new() -> ReaderState {
 		ReaderState {
 			flags: None,
 			opcode: None,
 			has_mask: false,
-			mask: Vec::with_capacity(4),
-			raw_len: Vec::with_capacity(8),
+			mask: [0; 4],
+			mask_read: 0,
+			raw_len: [0; 8],
+			raw_len_read: 0,
 			len_byte: None,
 			len: None,
 		}
 	}
 
 	fn reset(&mut self) {
-		self.flags = None;
-		self.opcode = None;
-		self.has_mask = false;
-		self.mask = Vec::with_capacity(4);
-		self.raw_len = Vec::with_capacity(8);
-		self.len_byte = None;
-		self.len = None;
+		*self = ReaderState::new();
 	}
 }
 
-bitflags! {
-	/// Flags relevant to a WebSocket data frame.
-	pub struct DataFrameFlags: u8 {
-		/// Marks this dataframe as the last dataframe
-		const FIN = 0x80;
-		/// First reserved bit
-		const RSV1 = 0x40;
-		/// Second reserved bit
-		const RSV2 = 0x20;
-		/// Third reserved bit
-		const RSV3 = 0x10;
+impl Default for ReaderState {
+	fn default() -> Self {
+		ReaderState::new()
 	}
 }
 
-/// Represents a data frame header.
+/// Builds a `DataFrameHeader`, checking that it is valid.
+///
+/// Headers start out final, unmasked and with an empty payload.
+///
+///```rust
+///# use websocket::ws::util::header::{DataFrameFlags, DataFrameHeaderBuilder};
+///let header = DataFrameHeaderBuilder::new(0x1).fin(false).len(5).build().unwrap();
+///assert_eq!(header.flags, DataFrameFlags::empty());
+///
+///// control frames can't be fragmented
+///assert!(DataFrameHeaderBuilder::new(0x9).fin(false).build().is_err());
+///```
 #[derive(Debug, Clone, Copy, PartialEq)]
-pub struct DataFrameHeader {
-	/// The bit flags for the first byte of the header.
-	pub flags: DataFrameFlags,
-	/// The opcode of the header - must be <= 16.
-	pub opcode: u8,
-	/// The masking key, if any.
-	pub mask: Option<[u8; 4]>,
-	/// The length of the payload.
-	pub len: u64,
+pub struct DataFrameHeaderBuilder {
+	header: DataFrameHeader,
 }
 
-/// Writes a data frame header.
-pub fn write_header(writer: &mut Write, header: DataFrameHeader) -> WebSocketResult<()> {
+impl DataFrameHeaderBuilder {
+	/// Starts a header with the given opcode.
+	pub fn new(opcode: u8) -> Self {
+		DataFrameHeaderBuilder {
+			header: DataFrameHeader {
+				flags: DataFrameFlags::FIN,
+				opcode,
+				mask: None,
+				len: 0,
+			},
+		}
+	}
 
-	if header.opcode > 0xF {
-		return Err(WebSocketError::DataFrameError("Invalid data frame opcode"));
+	/// Sets the opcode.
+	pub fn opcode(mut self, opcode: u8) -> Self {
+		self.header.opcode = opcode;
+		self
 	}
-	if header.opcode >= 8 && header.len >= 126 {
-		return Err(WebSocketError::DataFrameError("Control frame length too long"));
+
+	/// Sets whether this is the last frame of a message.
+	pub fn fin(mut self, fin: bool) -> Self {
+		self.header.flags.set(DataFrameFlags::FIN, fin);
+		self
 	}
 
-	// Write 'FIN', 'RSV1', 'RSV2', 'RSV3' and 'opcode'
-	writer.write_u8((header.flags.bits) | header.opcode)?;
-
-	writer.write_u8(// Write the 'MASK'
-	                if header.mask.is_some() { 0x80 } else { 0x00 } |
-		// Write the 'Payload len'
-		if header.len <= 125 { header.len as u8 }
-		else if header.len <= 65535 { 126 }
-		else { 127 })?;
-
-	// Write 'Extended payload length'
-	if header.len >= 126 && header.len <= 65535 {
-		writer.write_u16::<BigEndian>(header.len as u16)?;
-	} else if header.len > 65535 {
-		writer.write_u64::<BigEndian>(header.len)?;
+	/// Sets the first reserved bit.
+	pub fn rsv1(mut self, rsv1: bool) -> Self {
+		self.header.flags.set(DataFrameFlags::RSV1, rsv1);
+		self
 	}
 
-	// Write 'Masking-key'
-	if let Some(mask) = header.mask {
-		writer.write_all(&mask)?
+	/// Sets the second reserved bit.
+	pub fn rsv2(mut self, rsv2: bool) -> Self {
+		self.header.flags.set(DataFrameFlags::RSV2, rsv2);
+		self
+	}
+
+	/// Sets the third reserved bit.
+	pub fn rsv3(mut self, rsv3: bool) -> Self {
+		self.header.flags.set(DataFrameFlags::RSV3, rsv3);
+		self
+	}
+
+	/// Sets the masking key, `None` for an unmasked frame.
+	pub fn mask(mut self, mask: Option<[u8; 4]>) -> Self {
+		self.header.mask = mask;
+		self
+	}
+
+	/// Sets the length of the payload.
+	pub fn len(mut self, len: u64) -> Self {
+		self.header.len = len;
+		self
+	}
+
+	/// Returns the header, or the error `read_header` would fail with for
+	/// it, see `framing::validate_header`.
+	pub fn build(self) -> WebSocketResult<DataFrameHeader> {
+		framing::validate_header(&self.header)?;
+		Ok(self.header)
 	}
+}
+
+/// Writes a data frame header.
+pub fn write_header(writer: &mut Write, header: DataFrameHeader) -> WebSocketResult<()> {
+	let mut buf = [0; framing::MAX_HEADER_LEN];
+	let len = framing::encode_header(&header, &mut buf)?;
+	writer.write_all(&buf[..len])?;
+	Ok(())
+}
 
+/// Writes the header of a final, unmasked frame with a payload of at most
+/// 125 bytes.
+///
+/// This is the common case for servers sending small updates, the two header
+/// bytes are emitted directly without going through `write_header`.
+pub fn write_small_header(writer: &mut Write, opcode: u8, len: u8) -> WebSocketResult<()> {
+	if opcode > 0xF {
+		return Err(WebSocketError::DataFrameError("Invalid data frame opcode"));
+	}
+	if len > 125 {
+		return Err(WebSocketError::DataFrameError("Small data frame length too long"));
+	}
+	writer.write_all(&[DataFrameFlags::FIN.bits() | opcode, len])?;
 	Ok(())
 }
 
+/// Writes the header of a small, final and unmasked text frame.
+/// See `write_small_header`.
+pub fn write_small_text_header(writer: &mut Write, len: u8) -> WebSocketResult<()> {
+	write_small_header(writer, 0x1, len)
+}
+
+/// Writes the header of a small, final and unmasked binary frame.
+/// See `write_small_header`.
+pub fn write_small_binary_header(writer: &mut Write, len: u8) -> WebSocketResult<()> {
+	write_small_header(writer, 0x2, len)
+}
+
 /// Reads a data frame header.
 pub fn read_header<R>(
 	reader: &mut R,
 	dataframe: &mut ReaderState,
 ) -> WebSocketResult<DataFrameHeader> where R: Read {
 	let ret = {
-		//	If the flag entry is None, then read the first byte of the header
+		//	If the flag entry is None, then read the first byte of the header.
+		//	The stream ending here is the one place it ends cleanly.
 		if dataframe.flags.is_none() {
-			reader.read_u8().and_then(|byte| {
-				dataframe.flags = Some(DataFrameFlags::from_bits_truncate(byte));
-				dataframe.opcode = Some(byte & 0x0F);
-				Ok(())
-			})?;
+			let byte = match reader.read_u8() {
+				Ok(byte) => byte,
+				Err(ref why) if why.kind() == ErrorKind::UnexpectedEof => {
+					return Err(WebSocketError::ConnectionClosed);
+				}
+				Err(why) => return Err(why.into()),
+			};
+			dataframe.flags = Some(DataFrameFlags::from_bits_truncate(byte));
+			dataframe.opcode = Some(byte & 0x0F);
 		}
 
 		//	Save the length byte separate since it is needed if getting the length fails
-		dataframe.len_byte = match reader.read_u8() {
-			Ok(byte) => Some(byte),
-			Err(why) => {
-				debug!("Could not read length: {:?}", why);
-				return Err(WebSocketError::IoError(why))
-			}
-		};
+		if dataframe.len_byte.is_none() {
+			dataframe.len_byte = match reader.read_u8() {
+				Ok(byte) => Some(byte),
+				Err(why) => {
+					debug!("Could not read length: {:?}", why);
+					return Err(truncated(why))
+				}
+			};
+		}
 
 		let byte = dataframe.len_byte.unwrap();
 		dataframe.has_mask = byte & 0x80 == 0x80;
@@ -134,73 +207,70 @@ pub fn read_header<R>(
 				0...125 => (byte & 0x7F) as u64,
 				126 => {
 					//	Make sure 2 bytes are available for read_u16
-					while dataframe.raw_len.len() < 2 {
+					while dataframe.raw_len_read < 2 {
 						let byte = match reader.read_u8() {
 							Ok(byte) => byte,
 							Err(why) => {
 								debug!("Could not read u16 length byte: {:?}", why);
-								return Err(WebSocketError::IoError(why));
+								return Err(truncated(why));
 							}
 						};
-						dataframe.raw_len.push(byte);
+						dataframe.raw_len[dataframe.raw_len_read] = byte;
+						dataframe.raw_len_read += 1;
 					}
 
-					let len = BigEndian::read_u16(&mut dataframe.raw_len) as u64;
-					if len <= 125 {
-						dataframe.reset();
-						return Err(WebSocketError::DataFrameError("Invalid data frame length"));
-					}
-					len
+					BigEndian::read_u16(&dataframe.raw_len[..2]) as u64
 				}
 				127 => {
 					//	Make sure 8 bytes are available for read_u64
-					while dataframe.raw_len.len() < 8 {
+					while dataframe.raw_len_read < 8 {
 						let byte = match reader.read_u8() {
 							Ok(byte) => byte,
 							Err(why) => {
 								debug!("Could not read u64 length byte: {:?}", why);
-								return Err(WebSocketError::IoError(why));
+								return Err(truncated(why));
 							}
 						};
-						dataframe.raw_len.push(byte);
+						dataframe.raw_len[dataframe.raw_len_read] = byte;
+						dataframe.raw_len_read += 1;
 					}
 
-					let len = BigEndian::read_u64(&mut dataframe.raw_len);
-					if len <= 65535 {
-						dataframe.reset();
-						return Err(WebSocketError::DataFrameError("Invalid data frame length"));
-					}
-					len
+					BigEndian::read_u64(&dataframe.raw_len)
 				}
 				_ => unreachable!(),
 			};
 
+			if let Err(e) = framing::check_len(byte, len) {
+				dataframe.reset();
+				return Err(e.into());
+			}
 			dataframe.len = Some(len);
 		}
 
 		//	Check for invalid state
-		if dataframe.opcode.unwrap() >= 8 {
-			if dataframe.len.unwrap() >= 126 {
-				dataframe.reset();
-				return Err(WebSocketError::DataFrameError("Control frame length too long"));
-			}
-			if !dataframe.flags.unwrap().contains(DataFrameFlags::FIN) {
-				dataframe.reset();
-				return Err(WebSocketError::ProtocolError("Illegal fragmented control frame"));
-			}
+		let header = DataFrameHeader {
+			flags: dataframe.flags.unwrap(),
+			opcode: dataframe.opcode.unwrap(),
+			mask: None,
+			len: dataframe.len.unwrap(),
+		};
+		if let Err(e) = framing::validate_header(&header) {
+			dataframe.reset();
+			return Err(e.into());
 		}
 
 		//	Get the mask if one exists, making sure to have exactly 4 bytes
 		if dataframe.has_mask {
-			while dataframe.mask.len() < 4 {
+			while dataframe.mask_read < 4 {
 				let byte = match reader.read_u8() {
 					Ok(byte) => byte,
 					Err(why) => {
 						debug!("Could not read mask byte: {:?}", why);
-						return Err(WebSocketError::IoError(why));
+						return Err(truncated(why));
 					}
 				};
-				dataframe.mask.push(byte);
+				dataframe.mask[dataframe.mask_read] = byte;
+				dataframe.mask_read += 1;
 			}
 		}
 
@@ -209,9 +279,7 @@ pub fn read_header<R>(
 			flags: dataframe.flags.unwrap(),
 			opcode: dataframe.opcode.unwrap(),
 			mask: if dataframe.has_mask {
-				let mut mask = [0; 4];
-				mask.clone_from_slice(&dataframe.mask[0..4]);
-				Some(mask)
+				Some(dataframe.mask)
 			} else {
 				None
 				},
@@ -224,16 +292,180 @@ pub fn read_header<R>(
 	ret
 }
 
-#[cfg(all(feature = "nightly", test))]
+/// The error for a read failing after part of a frame was read.
+pub(crate) fn truncated(why: io::Error) -> WebSocketError {
+	if why.kind() == ErrorKind::UnexpectedEof {
+		WebSocketError::ProtocolError(TRUNCATED_FRAME)
+	} else {
+		WebSocketError::IoError(why)
+	}
+}
+
+/// The error for a frame whose payload doesn't fit in memory.
+pub(crate) fn too_large_for_platform(len: u64) -> WebSocketError {
+	WebSocketError::MessageTooLarge {
+		limit: usize::MAX as u64,
+		actual: len,
+		frame: true,
+	}
+}
+
+/// Reads the payload of the frame with the given header, unmasking it if
+/// the header has a masking key.
+///
+/// Reads are repeated until exactly `header.len` bytes were read, a stream
+/// that ends before that gives a `ProtocolError` of `TRUNCATED_FRAME`. Unlike
+/// `DataFrame::read_dataframe` nothing is kept when a read fails, so this
+/// is meant for blocking streams.
+pub fn read_payload<R>(reader: &mut R, header: &DataFrameHeader) -> WebSocketResult<Vec<u8>>
+	where R: Read
+{
+	if header.len > usize::MAX as u64 {
+		return Err(too_large_for_platform(header.len));
+	}
+
+	let mut payload = vec![0; header.len as usize];
+	let mut read = 0;
+	while read < payload.len() {
+		match reader.read(&mut payload[read..]) {
+			Ok(0) => return Err(WebSocketError::ProtocolError(TRUNCATED_FRAME)),
+			Ok(n) => read += n,
+			Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+			Err(e) => return Err(WebSocketError::IoError(e)),
+		}
+	}
+
+	if let Some(mask) = header.mask {
+		for (byte, key) in payload.iter_mut().zip(mask.iter().cycle()) {
+			*byte ^= *key;
+		}
+	}
+
+	Ok(payload)
+}
+
+#[cfg(test)]
+mod payload_tests {
+	use super::*;
+
+	#[test]
+	fn read_payload_reads_exactly_len_bytes() {
+		let mut input = &[0x81, 0x83, 1, 2, 3, 4, b'a' ^ 1, b'b' ^ 2, b'c' ^ 3, 0xFF][..];
+		let header = read_header(&mut input, &mut ReaderState::new()).unwrap();
+		assert_eq!(read_payload(&mut input, &header).unwrap(), b"abc");
+		assert_eq!(input, &[0xFF]);
+	}
+
+	// fails every other read with `Interrupted`, like a signal arriving
+	struct Interrupting<R> {
+		inner: R,
+		interrupt: bool,
+	}
+
+	impl<R: Read> Read for Interrupting<R> {
+		fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+			self.interrupt = !self.interrupt;
+			if self.interrupt {
+				return Err(io::Error::new(ErrorKind::Interrupted, "signal"));
+			}
+			self.inner.read(buf)
+		}
+	}
+
+	#[test]
+	fn interrupted_reads_are_retried() {
+		use dataframe::{DataFrame, Opcode};
+		use receiver::PacketState;
+		use uuid::Uuid;
+
+		let frame = [0x81, 0x83, 1, 2, 3, 4, b'a' ^ 1, b'b' ^ 2, b'c' ^ 3];
+		let mut input = Interrupting {
+			inner: &frame[..],
+			interrupt: false,
+		};
+		let header = read_header(&mut input, &mut ReaderState::new()).unwrap();
+		assert_eq!(read_payload(&mut input, &header).unwrap(), b"abc");
+
+		let mut input = Interrupting {
+			inner: &frame[..],
+			interrupt: false,
+		};
+		let frame = DataFrame::read_dataframe(&mut input,
+		                                      true,
+		                                      Uuid::nil(),
+		                                      &mut PacketState::default(),
+		                                      &mut ReaderState::new())
+			.unwrap();
+		assert_eq!(frame, DataFrame::new(true, Opcode::Text, b"abc".to_vec()));
+	}
+
+	#[test]
+	fn read_payload_rejects_early_end() {
+		let mut input = &[0x82, 0x05, 1, 2][..];
+		let header = read_header(&mut input, &mut ReaderState::new()).unwrap();
+		match read_payload(&mut input, &header) {
+			Err(WebSocketError::ProtocolError(_)) => (),
+			other => panic!("expected a protocol error, got {:?}", other),
+		}
+	}
+}
+
+#[cfg(test)]
+mod builder_tests {
+	use super::*;
+
+	#[test]
+	fn builder_rejects_invalid_headers() {
+		let header = DataFrameHeaderBuilder::new(0x2)
+			.fin(false)
+			.rsv1(true)
+			.rsv3(true)
+			.mask(Some([1, 2, 3, 4]))
+			.len(70000)
+			.build()
+			.unwrap();
+		assert_eq!(header,
+		           DataFrameHeader {
+		               flags: DataFrameFlags::RSV1 | DataFrameFlags::RSV3,
+		               opcode: 0x2,
+		               mask: Some([1, 2, 3, 4]),
+		               len: 70000,
+		           });
+
+		match DataFrameHeaderBuilder::new(16).build() {
+			Err(WebSocketError::DataFrameError("Invalid data frame opcode")) => (),
+			other => panic!("expected an invalid opcode, got {:?}", other),
+		}
+		assert!(DataFrameHeaderBuilder::new(0x9).len(125).build().is_ok());
+		match DataFrameHeaderBuilder::new(0x9).len(126).build() {
+			Err(WebSocketError::DataFrameError("Control frame length too long")) => (),
+			other => panic!("expected a too long control frame, got {:?}", other),
+		}
+		match DataFrameHeaderBuilder::new(0x8).fin(false).build() {
+			Err(WebSocketError::ProtocolError("Illegal fragmented control frame")) => (),
+			other => panic!("expected a fragmented control frame, got {:?}", other),
+		}
+		match DataFrameHeaderBuilder::new(0x2).len(1 << 63).build() {
+			Err(WebSocketError::DataFrameError("Invalid data frame length")) => (),
+			other => panic!("expected an invalid length, got {:?}", other),
+		}
+
+		// whatever the builder accepts can be written and read back
+		let mut written = Vec::new();
+		write_header(&mut written, header).unwrap();
+		assert_eq!(read_header(&mut &written[..], &mut ReaderState::new()).unwrap(), header);
+	}
+}
+
+#[cfg(test)]
 mod tests {
 	use super::*;
-	use test;
 	#[test]
 	fn test_read_header_simple() {
 		let header = [0x81, 0x2B];
-		let obtained = read_header(&mut &header[..]).unwrap();
+		let obtained = read_header(&mut &header[..], &mut ReaderState::new()).unwrap();
 		let expected = DataFrameHeader {
-			flags: FIN,
+			flags: DataFrameFlags::FIN,
 			opcode: 1,
 			mask: None,
 			len: 43,
@@ -243,7 +475,7 @@ mod tests {
 	#[test]
 	fn test_write_header_simple() {
 		let header = DataFrameHeader {
-			flags: FIN,
+			flags: DataFrameFlags::FIN,
 			opcode: 1,
 			mask: None,
 			len: 43,
@@ -257,9 +489,9 @@ mod tests {
 	#[test]
 	fn test_read_header_complex() {
 		let header = [0x42, 0xFE, 0x02, 0x00, 0x02, 0x04, 0x08, 0x10];
-		let obtained = read_header(&mut &header[..]).unwrap();
+		let obtained = read_header(&mut &header[..], &mut ReaderState::new()).unwrap();
 		let expected = DataFrameHeader {
-			flags: RSV1,
+			flags: DataFrameFlags::RSV1,
 			opcode: 2,
 			mask: Some([2, 4, 8, 16]),
 			len: 512,
@@ -269,7 +501,7 @@ mod tests {
 	#[test]
 	fn test_write_header_complex() {
 		let header = DataFrameHeader {
-			flags: RSV1,
+			flags: DataFrameFlags::RSV1,
 			opcode: 2,
 			mask: Some([2, 4, 8, 16]),
 			len: 512,
@@ -280,20 +512,11 @@ mod tests {
 
 		assert_eq!(&obtained[..], &expected[..]);
 	}
-	#[bench]
-	fn bench_read_header(b: &mut test::Bencher) {
-		let header = vec![0x42u8, 0xFE, 0x02, 0x00, 0x02, 0x04, 0x08, 0x10];
-		b.iter(|| { read_header(&mut &header[..]).unwrap(); });
-	}
-	#[bench]
-	fn bench_write_header(b: &mut test::Bencher) {
-		let header = DataFrameHeader {
-			flags: RSV1,
-			opcode: 2,
-			mask: Some([2, 4, 8, 16]),
-			len: 512,
-		};
-		let mut writer = Vec::with_capacity(8);
-		b.iter(|| { write_header(&mut writer, header).unwrap(); });
+	#[test]
+	fn test_write_small_text_header() {
+		let mut obtained = Vec::with_capacity(2);
+		write_small_text_header(&mut obtained, 43).unwrap();
+		assert_eq!(&obtained[..], &[0x81, 0x2B]);
+		assert!(write_small_text_header(&mut obtained, 126).is_err());
 	}
 }
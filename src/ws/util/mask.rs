@@ -1,37 +1,58 @@
 //! Utility functions for masking data frame payload data
 use rand;
-use std::io::Write;
+use std::cmp;
+use std::io::{Read, Write};
 use std::io::Result as IoResult;
 use std::mem;
+#[cfg(feature="testing")]
+use std::cell::RefCell;
+use super::framing::apply_mask;
+
+/// The most bytes masked at once by a `MaskedWriter`, on the stack.
+pub(crate) const MASK_CHUNK: usize = 4096;
 
 /// Struct to pipe data into another writer,
 /// while masking the data being written
-pub struct Masker<'w> {
+///
+/// The position in the repeating key is kept across writes, so writing a
+/// payload in any number of pieces gives the same bytes as writing it at
+/// once.
+pub struct MaskedWriter<W> {
 	key: [u8; 4],
 	pos: usize,
-	end: &'w mut Write,
+	end: W,
 }
 
-impl<'w> Masker<'w> {
+/// A `MaskedWriter` borrowing a writer trait object.
+pub type Masker<'w> = MaskedWriter<&'w mut Write>;
+
+impl<W> MaskedWriter<W> {
 	/// Create a new Masker with the key and the endpoint
 	/// to be writer to.
-	pub fn new(key: [u8; 4], endpoint: &'w mut Write) -> Self {
-		Masker {
+	pub fn new(key: [u8; 4], endpoint: W) -> Self {
+		MaskedWriter {
 			key: key,
 			pos: 0,
 			end: endpoint,
 		}
 	}
+
+	/// Gives back the endpoint.
+	pub fn into_inner(self) -> W {
+		self.end
+	}
 }
 
-impl<'w> Write for Masker<'w> {
+impl<W: Write> Write for MaskedWriter<W> {
 	fn write(&mut self, data: &[u8]) -> IoResult<usize> {
-		let mut buf = Vec::with_capacity(data.len());
-		for &byte in data.iter() {
-			buf.push(byte ^ self.key[self.pos]);
-			self.pos = (self.pos + 1) % self.key.len();
-		}
-		self.end.write(&buf)
+		let mut buf = [0; MASK_CHUNK];
+		let len = cmp::min(data.len(), MASK_CHUNK);
+		buf[..len].copy_from_slice(&data[..len]);
+		apply_mask(self.key, self.pos, &mut buf[..len]);
+		// only move the key along by what the endpoint actually took
+		let written = self.end.write(&buf[..len])?;
+		self.pos = (self.pos + written) % self.key.len();
+		Ok(written)
 	}
 
 	fn flush(&mut self) -> IoResult<()> {
@@ -39,10 +60,86 @@ impl<'w> Write for Masker<'w> {
 	}
 }
 
+/// Struct to read masked data from another reader, unmasking it.
+///
+/// Like `MaskedWriter` the position in the key is kept across reads.
+pub struct MaskedReader<R> {
+	key: [u8; 4],
+	pos: usize,
+	source: R,
+}
+
+impl<R> MaskedReader<R> {
+	/// Create a new unmasking reader with the key and the source to read
+	/// from.
+	pub fn new(key: [u8; 4], source: R) -> Self {
+		MaskedReader {
+			key,
+			pos: 0,
+			source,
+		}
+	}
+
+	/// Gives back the source.
+	pub fn into_inner(self) -> R {
+		self.source
+	}
+}
+
+impl<R: Read> Read for MaskedReader<R> {
+	fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+		let read = self.source.read(buf)?;
+		apply_mask(self.key, self.pos, &mut buf[..read]);
+		self.pos = (self.pos + read) % self.key.len();
+		Ok(read)
+	}
+}
+
+/// A source of masking keys.
+pub trait MaskKeyGen {
+	/// The key for the next frame, called once for every masked frame.
+	fn gen_key(&mut self) -> [u8; 4];
+}
+
+/// Draws 4 fresh random bytes from the thread's random number generator for
+/// every key, nothing is cached between frames. This is what frames are
+/// masked with.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomMaskKeys;
+
+impl MaskKeyGen for RandomMaskKeys {
+	fn gen_key(&mut self) -> [u8; 4] {
+		// Faster than just calling random() many times
+		unsafe { mem::transmute(rand::random::<u32>()) }
+	}
+}
+
+#[cfg(feature="testing")]
+thread_local!(static KEY_GEN: RefCell<Option<Box<MaskKeyGen>>> = RefCell::new(None));
+
+/// Makes the frames masked on this thread use keys from `key_gen`, or from
+/// `RandomMaskKeys` again for `None`. Returns the generator it replaces.
+///
+/// Deterministic keys defeat the purpose of masking, this only exists for
+/// tests and needs the `testing` feature.
+#[cfg(feature="testing")]
+pub fn set_key_gen(key_gen: Option<Box<MaskKeyGen>>) -> Option<Box<MaskKeyGen>> {
+	KEY_GEN.with(|current| mem::replace(&mut *current.borrow_mut(), key_gen))
+}
+
 /// Generates a random masking key
+///
+/// With the `testing` feature the key comes from the generator given to
+/// `set_key_gen`, if there is one.
 pub fn gen_mask() -> [u8; 4] {
-	// Faster than just calling random() many times
-	unsafe { mem::transmute(rand::random::<u32>()) }
+	#[cfg(feature="testing")]
+	{
+		let key = KEY_GEN.with(|current| current.borrow_mut().as_mut().map(|key_gen| key_gen.gen_key()));
+		if let Some(key) = key {
+			return key;
+		}
+	}
+	RandomMaskKeys.gen_key()
 }
 
 /// Masks data to send to a server and writes
@@ -55,10 +152,145 @@ pub fn mask_data(mask: [u8; 4], data: &[u8]) -> Vec<u8> {
 	out
 }
 
-#[cfg(all(feature = "nightly", test))]
+#[cfg(test)]
+mod rfc_tests {
+	use super::*;
+	use std::io;
+	use ws::util::header::{write_header, DataFrameHeader, DataFrameFlags};
+
+	// the single frame examples of RFC6455 5.7
+	const HELLO_KEY: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+	const UNMASKED_HELLO: [u8; 7] = [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f];
+	const MASKED_HELLO: [u8; 11] = [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58];
+
+	fn hello_frame(mask: Option<[u8; 4]>) -> Vec<u8> {
+		let mut frame = Vec::new();
+		let header = DataFrameHeader {
+			flags: DataFrameFlags::FIN,
+			opcode: 0x1,
+			mask,
+			len: 5,
+		};
+		write_header(&mut frame, header).unwrap();
+		match mask {
+			Some(key) => frame.extend(mask_data(key, b"Hello")),
+			None => frame.extend_from_slice(b"Hello"),
+		}
+		frame
+	}
+
+	// takes at most two bytes per write
+	struct Trickle(Vec<u8>);
+
+	impl Write for Trickle {
+		fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+			let n = data.len().min(2);
+			self.0.extend_from_slice(&data[..n]);
+			Ok(n)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn masking_matches_rfc_example() {
+		assert_eq!(hello_frame(None), &UNMASKED_HELLO[..]);
+		assert_eq!(hello_frame(Some(HELLO_KEY)), &MASKED_HELLO[..]);
+		assert_eq!(mask_data(HELLO_KEY, &MASKED_HELLO[6..]), b"Hello");
+
+		let mut trickle = Trickle(Vec::new());
+		Masker::new(HELLO_KEY, &mut trickle).write_all(b"Hello").unwrap();
+		assert_eq!(trickle.0, &MASKED_HELLO[6..]);
+	}
+
+	#[test]
+	fn chunked_writes_mask_like_one_write() {
+		let payload: Vec<u8> = (0..MASK_CHUNK * 2 + 3).map(|i| i as u8).collect();
+		let mut whole = MaskedWriter::new(HELLO_KEY, Vec::new());
+		whole.write_all(&payload).unwrap();
+		let whole = whole.into_inner();
+		assert_eq!(whole, mask_data(HELLO_KEY, &payload));
+
+		for &size in &[1, 3, 5, 4096, 5000] {
+			let mut chunked = MaskedWriter::new(HELLO_KEY, Vec::new());
+			for chunk in payload.chunks(size) {
+				chunked.write_all(chunk).unwrap();
+			}
+			assert_eq!(chunked.into_inner(), whole);
+
+			let mut unmasked = Vec::new();
+			let mut reader = MaskedReader::new(HELLO_KEY, &whole[..]);
+			let mut buf = vec![0; size];
+			loop {
+				match reader.read(&mut buf).unwrap() {
+					0 => break,
+					n => unmasked.extend_from_slice(&buf[..n]),
+				}
+			}
+			assert_eq!(unmasked, payload);
+		}
+	}
+
+	#[test]
+	fn random_keys_do_not_repeat() {
+		const SAMPLES: usize = 10000;
+		let mut keys: Vec<[u8; 4]> = (0..SAMPLES).map(|_| RandomMaskKeys.gen_key()).collect();
+
+		// every bit is set in about half of the keys
+		for bit in 0..32 {
+			let set = keys.iter().filter(|key| key[bit / 8] & (1 << (bit % 8)) != 0).count();
+			assert!(set > SAMPLES * 2 / 5 && set < SAMPLES * 3 / 5, "bit {} set in {} keys", bit, set);
+		}
+
+		// the bytes are close to 8 bits of entropy
+		let mut counts = [0usize; 256];
+		for byte in keys.iter().flat_map(|key| key.iter()) {
+			counts[*byte as usize] += 1;
+		}
+		let total = (SAMPLES * 4) as f64;
+		let entropy = counts.iter()
+		                    .filter(|&&count| count > 0)
+		                    .map(|&count| {
+			                         let p = count as f64 / total;
+			                         -p * p.log2()
+			                        })
+		                    .sum::<f64>();
+		assert!(entropy > 7.9, "{} bits of entropy", entropy);
+
+		// out of 2^32 keys a pair of equal ones is rare, more would mean reuse
+		keys.sort();
+		keys.dedup();
+		assert!(keys.len() >= SAMPLES - 2, "{} distinct keys", keys.len());
+	}
+
+	#[test]
+	#[cfg(feature="testing")]
+	fn frames_use_the_injected_keys() {
+		use dataframe::{DataFrame, Opcode};
+		use ws::dataframe::DataFrame as DataFrameable;
+
+		struct Fixed;
+		impl MaskKeyGen for Fixed {
+			fn gen_key(&mut self) -> [u8; 4] {
+				HELLO_KEY
+			}
+		}
+
+		set_key_gen(Some(Box::new(Fixed)));
+		let mut frame = Vec::new();
+		DataFrame::new(true, Opcode::Text, b"Hello".to_vec()).write_to(&mut frame, true).unwrap();
+		assert!(set_key_gen(None).is_some());
+		assert_eq!(frame, &MASKED_HELLO[..]);
+
+		assert!(gen_mask() != HELLO_KEY || gen_mask() != HELLO_KEY);
+	}
+}
+
+#[cfg(test)]
 mod tests {
 	use super::*;
-	use test;
 	#[test]
 	fn test_mask_data() {
 		let key = [1u8, 2u8, 3u8, 4u8];
@@ -70,22 +302,4 @@ mod tests {
 		assert_eq!(original, reversed);
 		assert_eq!(obtained, expected);
 	}
-
-	#[bench]
-	fn bench_mask_data(b: &mut test::Bencher) {
-		let buffer = b"The quick brown fox jumps over the lazy dog";
-		let key = gen_mask();
-		b.iter(|| {
-			       let mut output = mask_data(key, buffer);
-			       test::black_box(&mut output);
-			      });
-	}
-
-	#[bench]
-	fn bench_gen_mask(b: &mut test::Bencher) {
-		b.iter(|| {
-			       let mut key = gen_mask();
-			       test::black_box(&mut key);
-			      });
-	}
 }
@@ -1,5 +1,8 @@
 //! Utility functions for various portions of Rust-WebSocket.
 
+#[cfg(feature="debug-tools")]
+pub mod debug;
+pub mod framing;
 pub mod header;
 pub mod mask;
 